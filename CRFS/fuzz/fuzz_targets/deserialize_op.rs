@@ -0,0 +1,13 @@
+// Operations are read from other replicas and servers, so parsing them mustn't panic whatever they contain.
+#![no_main]
+
+use crfs::conflict_res::ast_doc::{crdt::DocOp, md};
+use crfs::conflict_res::file_tree::TreeOp;
+use crfs::conflict_res::CmRDT::Operation;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|json: String| {
+    let _ = TreeOp::deserialize_from_str(json.clone());
+    let _ = DocOp::<md::MDTag, md::MDLeaf>::deserialize_from_str(json);
+});
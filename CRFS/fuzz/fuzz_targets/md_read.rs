@@ -0,0 +1,24 @@
+// Markdown files are read from the working directory, and may contain anything. Reading must fail rather than panic
+// on markdown that can't be represented, and whatever is read must convert to a document and back.
+#![no_main]
+
+use crfs::conflict_res::ast_doc::{md, types::FileInterface};
+use crfs::conflict_res::CmRDT::DiskType;
+use crfs::storage::{self, object};
+
+use libfuzzer_sys::fuzz_target;
+use uuid::Uuid;
+
+fuzz_target!(|raw_md: &[u8]| {
+    let dir = std::env::temp_dir().join(format!("crfs-fuzz-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("fuzz.md"), raw_md).unwrap();
+
+    let config = storage::Config::new(dir);
+    let loc = object::Location::Path("fuzz.md".into(), true);
+
+    if let Ok(int) = md::MDInterface::read(&config, &loc) {
+        let doc = int.generate(Uuid::nil());
+        let _ = markdown_ast::ast_to_markdown(&md::MDInterface::to_blocks(doc.get_root_children(), &doc));
+    }
+});
@@ -0,0 +1,42 @@
+// Insertions are received from other replicas, so may refer to items that don't exist, or to items in the wrong
+// order. Inserting must then fail, rather than panic or break the array.
+#![no_main]
+
+use crfs::conflict_res::ast_doc::yata;
+
+use libfuzzer_sys::fuzz_target;
+
+/// An insertion's origin, left and right, its creator and the ID to insert it with.
+type Refs = (u8, u8, u8, u8, Option<u8>);
+
+fuzz_target!(|input: (Vec<u8>, Vec<Refs>)| {
+    let (base, insertions) = input;
+    let mut arr = yata::Array::from((base.into_iter(), 0u8));
+
+    // Picks either end, an existing item, or an ID not in the array.
+    let to_ref = |arr: &yata::Array<u8, u8>, i: u8| {
+        let in_order = arr.in_order();
+        match i as usize {
+            0 => yata::Ref::Left,
+            1 => yata::Ref::Right,
+            i if i - 2 < in_order.len() => yata::Ref::Item(in_order[i - 2]),
+            i => yata::Ref::Item(i as yata::ID),
+        }
+    };
+
+    for (i, (origin, left, right, creator, id)) in insertions.into_iter().enumerate() {
+        let ins = yata::Insertion {
+            origin: to_ref(&arr, origin), left: to_ref(&arr, left), right: to_ref(&arr, right),
+            content: i as u8, creator, deleted: false,
+        };
+        let id = id.and_then(|i| to_ref(&arr, i.saturating_add(2)).into());
+
+        let len = arr.len();
+        match arr.insert(ins, id) {
+            Some(new) if Some(new) == id && arr.len() == len => {},
+            Some(_) => assert_eq!(arr.len(), len + 1),
+            None => assert_eq!(arr.len(), len),
+        }
+        arr.verify();
+    }
+});
@@ -0,0 +1,144 @@
+// Authentication of API requests, as the Django server does it.
+// Users are issued a secret when they register, which is used to enrol replicas. Each replica is issued its own token,
+// which only grants access to its FS. Both are sent as `Authorization: Bearer <owner uuid>.<secret>`, and only their
+// hashes are stored.
+
+use crfs::{errors, types};
+use crfs::networking::api::{MessagePayload, Role};
+use super::store::{self, Db};
+
+use base64::Engine;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Who a request was authenticated as.
+pub struct Principal {
+    pub user: Uuid,
+    /// The FS a replica token is limited to, or `None` for the user's own secret.
+    pub filesystem: Option<Uuid>,
+}
+
+/// The credentials a message requires.
+#[derive(PartialEq, Eq)]
+enum Scope {
+    None,
+    /// The user's secret.
+    User,
+    /// The user's secret, or the token of a replica of the FS.
+    Fs,
+}
+
+fn scope(payload: &MessagePayload) -> Scope {
+    use MessagePayload as M;
+    match payload {
+        // Unless the user already exists, see `authorise`.
+        M::Ping {} | M::CheckUser {..} | M::RegisterUser {..} => Scope::None,
+        M::RegisterFs {..} | M::Enrol {..} | M::RemoveReplica {..} | M::Invite {..} | M::RemoveMember {..} => Scope::User,
+        _ => Scope::Fs,
+    }
+}
+
+/// The user and FS a message is about.
+fn subjects(payload: &MessagePayload) -> (Option<Uuid>, Option<Uuid>) {
+    use MessagePayload as M;
+    match payload {
+        M::Ping {} => (None, None),
+        M::RegisterUser {user_uuid, ..} | M::CheckUser {user_uuid} => (Some(*user_uuid), None),
+        M::RegisterFs {user_uuid, fs_uuid, ..} | M::CheckFs {user_uuid, fs_uuid} | M::Enrol {user_uuid, fs_uuid, ..} |
+        M::ListReplicas {user_uuid, fs_uuid} | M::RemoveReplica {user_uuid, fs_uuid, ..} |
+        M::Invite {user_uuid, fs_uuid, ..} | M::ListMembers {user_uuid, fs_uuid} | M::RemoveMember {user_uuid, fs_uuid, ..} |
+        M::FetchState {user_uuid, fs_uuid} | M::FetchHeads {user_uuid, fs_uuid} | M::PushState {user_uuid, fs_uuid, ..} |
+        M::Reconcile {user_uuid, fs_uuid, ..} | M::FetchOps {user_uuid, fs_uuid, ..} | M::PushOps {user_uuid, fs_uuid, ..}
+            => (Some(*user_uuid), Some(*fs_uuid)),
+    }
+}
+
+/// Generate a secret for `owner`, returning it and its hash.
+pub fn new_secret(owner: Uuid) -> (String, String) {
+    let bytes: [u8; 32] = rand::rng().random();
+    let secret = format!("{}.{}", owner, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes));
+    let hash = hash_secret(&secret);
+
+    return (secret, hash);
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    types::to_hex(&Sha256::digest(secret.as_bytes()))
+}
+
+/// Compare hashes in constant time, so timing doesn't reveal how much of a guess was right.
+fn hashes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Find who sent a request, from its `Authorization` header.
+pub fn authenticate(db: &mut Db, header: Option<&str>) -> Option<Principal> {
+    let secret = header?.strip_prefix("Bearer ")?.trim();
+    let owner = Uuid::parse_str(secret.split('.').next()?).ok()?;
+    let digest = hash_secret(secret);
+
+    if let Some(replica) = db.replicas.get_mut(&owner) {
+        if hashes_match(&replica.token_hash, &digest) {
+            // Replicas authenticate on every sync, so this tracks when each device last synced.
+            replica.last_seen = store::now();
+            return Some(Principal { user: replica.user, filesystem: Some(replica.filesystem) });
+        }
+    }
+
+    let user = db.users.get(&owner)?;
+    match &user.secret_hash {
+        Some(hash) if hashes_match(hash, &digest) => Some(Principal { user: owner, filesystem: None }),
+        _ => None,
+    }
+}
+
+/// Check a message may be handled, returning an error code and message if not.
+pub fn authorise(db: &Db, payload: &MessagePayload, principal: Option<&Principal>) -> Result<(), (errors::ErrorCode, String)> {
+    let (user_uuid, fs_uuid) = subjects(payload);
+    let user = user_uuid.and_then(|u| db.users.get(&u));
+
+    let mut scope = scope(payload);
+    // Only a user may re-register themselves.
+    if matches!(payload, MessagePayload::RegisterUser {..}) && user.is_some() {
+        scope = Scope::User;
+    }
+
+    if scope == Scope::None {return Ok(());}
+
+    if user.is_none() {
+        return Err((errors::CODE_NO_USER, "User doesn't exist.".to_owned()));
+    }
+
+    let Some(principal) = principal else {
+        return Err((errors::CODE_AUTH_ERR, "Missing or invalid credentials.".to_owned()));
+    };
+
+    if Some(principal.user) != user_uuid {
+        return Err((errors::CODE_AUTH_ERR, "Credentials belong to another user.".to_owned()));
+    }
+
+    if scope == Scope::User && principal.filesystem.is_some() {
+        return Err((errors::CODE_AUTH_ERR, "Requires the user's secret, not a replica token.".to_owned()));
+    }
+
+    if principal.filesystem.is_some() && fs_uuid.is_some() && principal.filesystem != fs_uuid {
+        return Err((errors::CODE_AUTH_ERR, "Replica isn't enrolled in this FS.".to_owned()));
+    }
+
+    return Ok(());
+}
+
+/// Whether a principal may read, and with `write` also write, a FS's operations.
+pub fn may_access_fs(db: &Db, principal: Option<&Principal>, fs_uuid: Uuid, write: bool) -> bool {
+    let Some(principal) = principal else {return false};
+
+    if principal.filesystem.is_some_and(|fs| fs != fs_uuid) {return false;}
+
+    let Some(fs) = db.filesystems.get(&fs_uuid) else {return false};
+
+    return match fs.role_of(principal.user) {
+        Some(role) => !(write && role == Role::ReadOnly),
+        None => false,
+    };
+}
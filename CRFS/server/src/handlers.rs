@@ -0,0 +1,343 @@
+// Handlers for each message type, answering exactly as the Django server does.
+// Messages reaching these have already been authorised, see `auth::authorise`.
+
+use crfs::errors::{self, ErrorCode};
+use crfs::networking::{api, reconcile, Secret};
+use crfs::types::{self, calculate_hash};
+use super::auth;
+use super::store::{self, FileSystem, Replica, Store, User};
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+/// Why a message wasn't handled, and the HTTP status to reply with.
+pub struct Refusal {
+    pub status: u16,
+    pub code: ErrorCode,
+    pub err_msg: String,
+}
+
+fn refuse(code: ErrorCode, err_msg: impl Into<String>) -> Refusal {
+    Refusal { status: 400, code, err_msg: err_msg.into() }
+}
+
+impl From<std::io::Error> for Refusal {
+    fn from(e: std::io::Error) -> Self {
+        Refusal { status: 500, code: errors::CODE_IO_ERR, err_msg: e.to_string() }
+    }
+}
+
+type HandlerResult = Result<api::ReplyPayload, Refusal>;
+
+fn filesystem(store: &Store, fs_uuid: Uuid) -> Result<&FileSystem, Refusal> {
+    store.db.filesystems.get(&fs_uuid).ok_or_else(|| refuse(errors::CODE_NO_FS, "FileSystem doesn't exist."))
+}
+
+/// Check a user may access a FS, and with `write`, change it.
+fn check_member(fs: &FileSystem, user_uuid: Uuid, write: bool) -> Result<api::Role, Refusal> {
+    match fs.role_of(user_uuid) {
+        None => Err(refuse(errors::CODE_AUTH_ERR, "FileSystem with given UUID isn't shared with this user.")),
+        Some(api::Role::ReadOnly) if write => Err(refuse(errors::CODE_AUTH_ERR, "User has read-only access to this FileSystem.")),
+        Some(role) => Ok(role),
+    }
+}
+
+/// A FS the user may access, for handlers which change it.
+fn filesystem_mut(store: &mut Store, fs_uuid: Uuid, user_uuid: Uuid, write: bool) -> Result<&mut FileSystem, Refusal> {
+    let fs = store.db.filesystems.get_mut(&fs_uuid).ok_or_else(|| refuse(errors::CODE_NO_FS, "FileSystem doesn't exist."))?;
+    check_member(fs, user_uuid, write)?;
+
+    return Ok(fs);
+}
+
+pub fn handle(store: &mut Store, payload: api::MessagePayload) -> HandlerResult {
+    use api::MessagePayload as M;
+    match payload {
+        M::Ping {} => Ok(api::ReplyPayload::Ping { code: errors::CODE_OK, err_msg: String::new() }),
+        M::RegisterUser {user_uuid, display_name} => register_user(store, user_uuid, display_name),
+        M::CheckUser {user_uuid} => check_user(store, user_uuid),
+        M::RegisterFs {user_uuid, fs_uuid, display_name, fs_opts} => register_fs(store, user_uuid, fs_uuid, display_name, fs_opts),
+        M::CheckFs {user_uuid, fs_uuid} => check_fs(store, user_uuid, fs_uuid),
+        M::Enrol {user_uuid, fs_uuid, replica_uuid, display_name, public_key} => enrol(store, user_uuid, fs_uuid, replica_uuid, display_name, public_key),
+        M::ListReplicas {user_uuid, fs_uuid} => list_replicas(store, user_uuid, fs_uuid),
+        M::RemoveReplica {user_uuid, fs_uuid, replica_uuid} => remove_replica(store, user_uuid, fs_uuid, replica_uuid),
+        M::Invite {user_uuid, fs_uuid, invitee_uuid, role} => invite(store, user_uuid, fs_uuid, invitee_uuid, role),
+        M::ListMembers {user_uuid, fs_uuid} => list_members(store, user_uuid, fs_uuid),
+        M::RemoveMember {user_uuid, fs_uuid, member_uuid} => remove_member(store, user_uuid, fs_uuid, member_uuid),
+        M::FetchState {user_uuid, fs_uuid} => fetch_state(store, user_uuid, fs_uuid),
+        M::FetchHeads {user_uuid, fs_uuid} => fetch_heads(store, user_uuid, fs_uuid),
+        M::PushState {user_uuid, fs_uuid, ops} => push_state(store, user_uuid, fs_uuid, ops),
+        M::Reconcile {user_uuid, fs_uuid, ranges} => reconcile(store, user_uuid, fs_uuid, ranges),
+        M::FetchOps {user_uuid, fs_uuid, hashes} => fetch_ops(store, user_uuid, fs_uuid, hashes),
+        M::PushOps {user_uuid, fs_uuid, ops} => push_ops(store, user_uuid, fs_uuid, ops),
+    }
+}
+
+fn register_user(store: &mut Store, user_uuid: Uuid, display_name: String) -> HandlerResult {
+    let user = store.db.users.entry(user_uuid).or_insert_with(|| User { display_name: None, last_seen: 0, secret_hash: None });
+    user.display_name = Some(display_name);
+    user.last_seen = store::now();
+
+    // Only new users are issued a secret. Anyone re-registering has already proven they hold it.
+    let mut secret = None;
+    if user.secret_hash.is_none() {
+        let (s, hash) = auth::new_secret(user_uuid);
+        user.secret_hash = Some(hash);
+        secret = Some(Secret::new(s));
+    }
+
+    store.save()?;
+    return Ok(api::ReplyPayload::RegisterUser { code: errors::CODE_OK, err_msg: String::new(), secret });
+}
+
+fn check_user(store: &mut Store, user_uuid: Uuid) -> HandlerResult {
+    let user = store.db.users.get_mut(&user_uuid).ok_or_else(|| refuse(errors::CODE_NO_USER, "User doesn't exist."))?;
+    user.last_seen = store::now();
+
+    store.save()?;
+    return Ok(api::ReplyPayload::CheckUser { code: errors::CODE_OK, err_msg: String::new() });
+}
+
+fn register_fs(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, display_name: String, fs_opts: Vec<String>) -> HandlerResult {
+    match store.db.filesystems.get_mut(&fs_uuid) {
+        Some(fs) if fs.user != user_uuid => {
+            return Err(refuse(errors::CODE_AUTH_ERR, "FileSystem with given UUID is already owned by another user."));
+        },
+        Some(fs) => {
+            fs.display_name = Some(display_name);
+            fs.opts = fs_opts;
+            fs.last_seen = store::now();
+        },
+        None => {
+            store.db.filesystems.insert(fs_uuid, FileSystem {
+                user: user_uuid,
+                display_name: Some(display_name),
+                last_seen: store::now(),
+                opts: fs_opts,
+                members: BTreeMap::new(),
+                ops: Default::default(),
+            });
+        },
+    }
+
+    store.save()?;
+    return Ok(api::ReplyPayload::RegisterFs { code: errors::CODE_OK, err_msg: String::new() });
+}
+
+fn check_fs(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid) -> HandlerResult {
+    let fs = filesystem(store, fs_uuid)?;
+    let role = check_member(fs, user_uuid, false)?;
+
+    return Ok(api::ReplyPayload::CheckFs { code: errors::CODE_OK, err_msg: String::new(), fs_opts: fs.opts.join(" "), role: Some(role) });
+}
+
+fn enrol(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, replica_uuid: Uuid, display_name: String, public_key: Option<crfs::storage::sign::PublicKey>) -> HandlerResult {
+    check_member(filesystem(store, fs_uuid)?, user_uuid, false)?;
+
+    if store.db.revoked.contains(&replica_uuid) {
+        return Err(refuse(errors::CODE_AUTH_ERR, "Replica with given UUID has been removed, and can't enrol again."));
+    }
+    if let Some(replica) = store.db.replicas.get(&replica_uuid) {
+        if replica.filesystem != fs_uuid {
+            return Err(refuse(errors::CODE_COLLISION, "Replica with given UUID belongs to another FileSystem."));
+        }
+
+        // Only the user who enrolled a replica may enrol it again, and never with another key, which would let them sign
+        // operations as it.
+        if replica.user != user_uuid {
+            return Err(refuse(errors::CODE_AUTH_ERR, "Replica with given UUID was enrolled by another user."));
+        }
+        if replica.public_key.is_some() && public_key.is_some() && replica.public_key != public_key {
+            return Err(refuse(errors::CODE_AUTH_ERR, "Replica with given UUID is already enrolled with another key."));
+        }
+    }
+
+    let replica = store.db.replicas.entry(replica_uuid).or_insert(Replica {
+        filesystem: fs_uuid,
+        user: user_uuid,
+        display_name: None,
+        last_seen: 0,
+        token_hash: String::new(),
+        public_key: None,
+    });
+
+    replica.display_name = Some(display_name);
+    if public_key.is_some() {replica.public_key = public_key;}
+
+    // Re-enrolling replaces the replica's token, so a replica which lost its token can log in again.
+    let (token, hash) = auth::new_secret(replica_uuid);
+    replica.token_hash = hash;
+    replica.last_seen = store::now();
+
+    store.save()?;
+    return Ok(api::ReplyPayload::Enrol { code: errors::CODE_OK, err_msg: String::new(), token: Some(Secret::new(token)) });
+}
+
+fn list_replicas(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid) -> HandlerResult {
+    check_member(filesystem(store, fs_uuid)?, user_uuid, false)?;
+
+    let mut replicas: Vec<(&Uuid, &Replica)> = store.db.replicas.iter().filter(|(_, r)| r.filesystem == fs_uuid).collect();
+    replicas.sort_by_key(|(_, r)| std::cmp::Reverse(r.last_seen));
+
+    let replicas = replicas.into_iter().map(|(uuid, r)| api::ReplicaEntry {
+        replica_uuid: *uuid,
+        display_name: r.display_name.clone(),
+        last_seen: store::iso8601(r.last_seen),
+        public_key: r.public_key.clone(),
+    }).collect();
+
+    return Ok(api::ReplyPayload::ListReplicas { code: errors::CODE_OK, err_msg: String::new(), replicas });
+}
+
+/// Removing a replica revokes its token, and it may never enrol again. Owners may remove any replica of the FS, and other
+/// users only the replicas they enrolled.
+fn remove_replica(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, replica_uuid: Uuid) -> HandlerResult {
+    let replica = store.db.replicas.get(&replica_uuid).filter(|r| r.filesystem == fs_uuid)
+        .ok_or_else(|| refuse(errors::CODE_NOT_FOUND, "Replica isn't enrolled in this FileSystem."))?;
+
+    let fs = filesystem(store, fs_uuid)?;
+    if fs.role_of(user_uuid) != Some(api::Role::Owner) && replica.user != user_uuid {
+        return Err(refuse(errors::CODE_AUTH_ERR, "Only owners may remove other users' replicas."));
+    }
+
+    store.db.replicas.remove(&replica_uuid);
+    store.db.revoked.insert(replica_uuid);
+
+    store.save()?;
+    return Ok(api::ReplyPayload::RemoveReplica { code: errors::CODE_OK, err_msg: String::new() });
+}
+
+/// Share a FS with another user. Inviting a member again changes their role.
+fn invite(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, invitee_uuid: Uuid, role: api::Role) -> HandlerResult {
+    let fs = filesystem(store, fs_uuid)?;
+
+    if fs.role_of(user_uuid) != Some(api::Role::Owner) {
+        return Err(refuse(errors::CODE_AUTH_ERR, "Only owners may share a FileSystem."));
+    }
+    if !store.db.users.contains_key(&invitee_uuid) {
+        return Err(refuse(errors::CODE_NO_USER, "Invited user doesn't exist."));
+    }
+    if invitee_uuid == fs.user {
+        return Err(refuse(errors::CODE_MALFORMED, "The FileSystem's creator is always an owner."));
+    }
+
+    filesystem_mut(store, fs_uuid, user_uuid, true)?.members.insert(invitee_uuid, role);
+
+    store.save()?;
+    return Ok(api::ReplyPayload::Invite { code: errors::CODE_OK, err_msg: String::new() });
+}
+
+/// List the users a FS is shared with, creator first.
+fn list_members(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid) -> HandlerResult {
+    let fs = filesystem(store, fs_uuid)?;
+    check_member(fs, user_uuid, false)?;
+
+    let display_name = |uuid: &Uuid| store.db.users.get(uuid).and_then(|u| u.display_name.clone());
+
+    let mut members = vec![api::MemberEntry { user_uuid: fs.user, display_name: display_name(&fs.user), role: api::Role::Owner }];
+
+    let mut shared: Vec<api::MemberEntry> = fs.members.iter()
+        .map(|(uuid, role)| api::MemberEntry { user_uuid: *uuid, display_name: display_name(uuid), role: *role })
+        .collect();
+    shared.sort_by(|a, b| (&a.display_name, a.user_uuid).cmp(&(&b.display_name, b.user_uuid)));
+    members.extend(shared);
+
+    return Ok(api::ReplyPayload::ListMembers { code: errors::CODE_OK, err_msg: String::new(), members });
+}
+
+/// Unshare a FS with a user, removing and revoking their replicas of it. Owners may remove any member, and other users
+/// only themselves.
+fn remove_member(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, member_uuid: Uuid) -> HandlerResult {
+    let fs = store.db.filesystems.get_mut(&fs_uuid).ok_or_else(|| refuse(errors::CODE_NO_FS, "FileSystem doesn't exist."))?;
+
+    if fs.role_of(user_uuid) != Some(api::Role::Owner) && user_uuid != member_uuid {
+        return Err(refuse(errors::CODE_AUTH_ERR, "Only owners may remove other members."));
+    }
+    if fs.user == member_uuid {
+        return Err(refuse(errors::CODE_MALFORMED, "The FileSystem's creator can't be removed."));
+    }
+    if fs.members.remove(&member_uuid).is_none() {
+        return Err(refuse(errors::CODE_NOT_FOUND, "User isn't a member of this FileSystem."));
+    }
+
+    let removed: Vec<Uuid> = store.db.replicas.iter()
+        .filter(|(_, r)| r.filesystem == fs_uuid && r.user == member_uuid)
+        .map(|(uuid, _)| *uuid)
+        .collect();
+    for replica_uuid in removed {
+        store.db.replicas.remove(&replica_uuid);
+        store.db.revoked.insert(replica_uuid);
+    }
+
+    store.save()?;
+    return Ok(api::ReplyPayload::RemoveMember { code: errors::CODE_OK, err_msg: String::new() });
+}
+
+fn fetch_state(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid) -> HandlerResult {
+    let fs = filesystem(store, fs_uuid)?;
+    check_member(fs, user_uuid, false)?;
+
+    return Ok(api::ReplyPayload::FetchState { code: errors::CODE_OK, err_msg: String::new(), state: fs.ops.keys().cloned().collect() });
+}
+
+fn fetch_heads(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid) -> HandlerResult {
+    let fs = filesystem(store, fs_uuid)?;
+    check_member(fs, user_uuid, false)?;
+
+    return Ok(api::ReplyPayload::FetchHeads { code: errors::CODE_OK, err_msg: String::new(), heads: fs.heads() });
+}
+
+fn push_state(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, ops: Vec<api::OpInfo>) -> HandlerResult {
+    let fs = filesystem_mut(store, fs_uuid, user_uuid, true)?;
+
+    for op in ops {
+        fs.ops.entry(op.hash).or_insert(op.parents);
+    }
+
+    store.save()?;
+    return Ok(api::ReplyPayload::PushState { code: errors::CODE_OK, err_msg: String::new() });
+}
+
+/// Answer every range whose fingerprint differs from ours, either with our hashes in that range, or split into smaller
+/// ranges.
+fn reconcile(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, ranges: Vec<reconcile::RangeMsg>) -> HandlerResult {
+    let fs = filesystem(store, fs_uuid)?;
+    check_member(fs, user_uuid, false)?;
+
+    let set = reconcile::SortedSet::new(fs.ops.keys());
+    return Ok(api::ReplyPayload::Reconcile { code: errors::CODE_OK, err_msg: String::new(), ranges: reconcile::respond(&set, &ranges) });
+}
+
+fn fetch_ops(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, hashes: Vec<types::Hash>) -> HandlerResult {
+    check_member(filesystem(store, fs_uuid)?, user_uuid, false)?;
+
+    let mut ops = Vec::new();
+    for hash in hashes {
+        let Some(data) = store.read_op(fs_uuid, &hash)? else {
+            let err_msg = format!("Operation {} doesn't exist.", types::hash_to_str(&hash));
+            return Err(Refusal { status: 404, code: errors::CODE_NOT_FOUND, err_msg });
+        };
+
+        ops.push(api::OpData { hash, data });
+    }
+
+    return Ok(api::ReplyPayload::FetchOps { code: errors::CODE_OK, err_msg: String::new(), ops });
+}
+
+fn push_ops(store: &mut Store, user_uuid: Uuid, fs_uuid: Uuid, ops: Vec<api::OpData>) -> HandlerResult {
+    check_member(filesystem(store, fs_uuid)?, user_uuid, true)?;
+
+    // Check every operation before storing any.
+    for op in ops.iter() {
+        if calculate_hash(&op.data) != op.hash {
+            return Err(refuse(errors::CODE_MALFORMED, format!("Operation {} doesn't match its hash.", types::hash_to_str(&op.hash))));
+        }
+    }
+
+    for op in ops.iter() {
+        store.write_op(fs_uuid, &op.hash, &op.data)?;
+    }
+
+    return Ok(api::ReplyPayload::PushOps { code: errors::CODE_OK, err_msg: String::new() });
+}
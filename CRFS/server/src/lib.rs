@@ -0,0 +1,145 @@
+// Reference implementation of the server, speaking the same protocol as the Django server: JSON messages posted to
+// `/api/`, and operations' contents at `/operation/<fs>/<hash>`. Everything is stored in a data directory, see `store`.
+// Runs as the `crfs-server` binary, or in process, so tests can sync against a real server without any setup.
+
+mod auth;
+mod handlers;
+mod store;
+
+use crfs::errors;
+use crfs::networking::api;
+use crfs::types::{self, calculate_hash};
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{DefaultBodyLimit, Path as UrlPath, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8000";
+
+/// Largest request accepted. Batches of operations can be far larger than axum's default limit.
+const MAX_BODY: usize = 256 * 1024 * 1024;
+
+type Shared = Arc<Mutex<store::Store>>;
+
+/// The server, storing everything in `data_dir`.
+pub fn router(data_dir: &Path) -> std::io::Result<Router> {
+    let store: Shared = Arc::new(Mutex::new(store::Store::open(data_dir)?));
+
+    return Ok(Router::new()
+        .route("/api/", post(message))
+        .route("/operation/{fs}/{hash}", get(get_operation).put(put_operation))
+        .layer(DefaultBodyLimit::max(MAX_BODY))
+        .with_state(store));
+}
+
+/// Serve on `addr` until the process is stopped.
+pub async fn run(addr: &str, data_dir: &Path) -> std::io::Result<()> {
+    let app = router(data_dir)?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Listening on http://{}/", listener.local_addr()?);
+
+    return axum::serve(listener, app).await;
+}
+
+/// Serve from a background thread, on an unused local port, returning the address served on.
+pub fn spawn(data_dir: &Path) -> std::io::Result<SocketAddr> {
+    let app = router(data_dir)?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().expect("Error starting async runtime.");
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener).expect("Error listening.");
+            axum::serve(listener, app).await.expect("Error serving.");
+        });
+    });
+
+    return Ok(addr);
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?.to_str().ok()
+}
+
+/// A reply refusing a message, which may not have been well formed enough to parse.
+fn refusal(request: &Value, status: u16, code: errors::ErrorCode, err_msg: String) -> (StatusCode, Json<Value>) {
+    let version: String = api::VERSION.into();
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST);
+
+    return (status, Json(json!({
+        "version": version,
+        "transaction_id": request.get("transaction_id"),
+        "reply": true,
+        "type": request.get("type"),
+        "payload": {"code": code, "err_msg": err_msg},
+    })));
+}
+
+async fn message(State(store): State<Shared>, headers: HeaderMap, body: String) -> (StatusCode, Json<Value>) {
+    let Ok(request) = serde_json::from_str::<Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({"code": errors::CODE_MALFORMED, "err_msg": "Unable to decode JSON."})));
+    };
+
+    let message: api::Message = match serde_json::from_value(request.clone()) {
+        Ok(message) => message,
+        Err(e) => return refusal(&request, 400, errors::CODE_MALFORMED, format!("Malformed message: {}", e)),
+    };
+
+    let mut store = store.lock().unwrap();
+
+    let principal = auth::authenticate(&mut store.db, bearer(&headers));
+    if let Err((code, err_msg)) = auth::authorise(&store.db, &message.payload, principal.as_ref()) {
+        let status = if code == errors::CODE_AUTH_ERR {401} else {400};
+        return refusal(&request, status, code, err_msg);
+    }
+
+    match handlers::handle(&mut store, message.payload) {
+        Ok(payload) => {
+            let reply = api::Reply { version: api::VERSION, transaction_id: message.transaction_id, reply: true, payload };
+            (StatusCode::OK, Json(serde_json::to_value(reply).expect("Replies can always be serialised.")))
+        },
+        Err(handlers::Refusal {status, code, err_msg}) => refusal(&request, status, code, err_msg),
+    }
+}
+
+async fn get_operation(State(store): State<Shared>, headers: HeaderMap, UrlPath((fs, hash)): UrlPath<(Uuid, String)>) -> Response {
+    let mut store = store.lock().unwrap();
+
+    let principal = auth::authenticate(&mut store.db, bearer(&headers));
+    if !auth::may_access_fs(&store.db, principal.as_ref(), fs, false) {return StatusCode::UNAUTHORIZED.into_response();}
+
+    let Ok(hash) = types::str_to_hash(&hash) else {return StatusCode::NOT_FOUND.into_response()};
+
+    match store.read_op(fs, &hash) {
+        Ok(Some(data)) => data.into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn put_operation(State(store): State<Shared>, headers: HeaderMap, UrlPath((fs, hash)): UrlPath<(Uuid, String)>, body: String) -> Response {
+    let mut store = store.lock().unwrap();
+
+    let principal = auth::authenticate(&mut store.db, bearer(&headers));
+    if !auth::may_access_fs(&store.db, principal.as_ref(), fs, true) {return StatusCode::UNAUTHORIZED.into_response();}
+
+    let hash = match types::str_to_hash(&hash) {
+        Ok(hash) if calculate_hash(&body) == hash => hash,
+        _ => return (StatusCode::BAD_REQUEST, "Operation doesn't match its hash.").into_response(),
+    };
+
+    match store.write_op(fs, &hash, &body) {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,28 @@
+// The `crfs-server` binary, serving the reference server implemented in the library.
+
+use crfs_server as server;
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+/// Serve CRFS filesystems to replicas.
+#[derive(Parser)]
+struct Cli {
+    /// Address to listen on.
+    #[arg(long, default_value = server::DEFAULT_ADDR)]
+    listen: String,
+    /// Directory to store users, filesystems and operations in. Created if it doesn't exist.
+    #[arg(long, default_value = "crfs-data")]
+    data: PathBuf,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(e) = server::run(&cli.listen, &cli.data).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
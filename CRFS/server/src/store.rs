@@ -0,0 +1,155 @@
+// Storage for the reference server. Records are held in memory, and written to `db.json` in the data directory after
+// every change. Operations' contents are stored as files under `operations/<fs>/<hash>`, as the Django server stores
+// them, so a data directory can be inspected the same way.
+
+use crfs::networking::api::Role;
+use crfs::storage::sign::PublicKey;
+use crfs::types;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use serde_with::serde_as;
+use uuid::Uuid;
+
+const DB: &str = "db.json";
+const OPERATIONS: &str = "operations";
+
+/// Microseconds since the Unix epoch.
+pub type Timestamp = i64;
+
+pub fn now() -> Timestamp {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1000) as Timestamp
+}
+
+pub fn iso8601(t: Timestamp) -> String {
+    time::OffsetDateTime::from_unix_timestamp_nanos(t as i128 * 1000)
+        .expect("Timestamps are always in range.")
+        .format(&time::format_description::well_known::Rfc3339)
+        .expect("Timestamps can always be formatted.")
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub display_name: Option<String>,
+    pub last_seen: Timestamp,
+    /// Hash of the secret issued when the user registered.
+    #[serde(default)]
+    pub secret_hash: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileSystem {
+    /// The user who registered the FS, who is always an owner.
+    pub user: Uuid,
+    pub display_name: Option<String>,
+    pub last_seen: Timestamp,
+    pub opts: Vec<String>,
+    /// Users the FS is shared with, other than its creator.
+    #[serde(default)]
+    pub members: BTreeMap<Uuid, Role>,
+    /// Parents of every operation the FS holds.
+    #[serde_as(as = "Vec<(_, _)>")]
+    #[serde(default)]
+    pub ops: HashMap<types::Hash, Vec<types::Hash>>,
+}
+
+impl FileSystem {
+    /// The role a user has in this FS, or `None` if it isn't shared with them.
+    pub fn role_of(&self, user_uuid: Uuid) -> Option<Role> {
+        if self.user == user_uuid {return Some(Role::Owner);}
+
+        return self.members.get(&user_uuid).copied();
+    }
+
+    /// Operations no other operation lists as a parent. Operations may arrive after their children, so this is
+    /// worked out from the whole history rather than tracked as operations arrive.
+    pub fn heads(&self) -> HashSet<types::Hash> {
+        let depended_on: HashSet<&types::Hash> = self.ops.values().flatten().collect();
+
+        return self.ops.keys().filter(|h| !depended_on.contains(h)).cloned().collect();
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Replica {
+    pub filesystem: Uuid,
+    /// The user who enrolled the replica.
+    pub user: Uuid,
+    pub display_name: Option<String>,
+    pub last_seen: Timestamp,
+    pub token_hash: String,
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Db {
+    #[serde(default)]
+    pub users: HashMap<Uuid, User>,
+    #[serde(default)]
+    pub filesystems: HashMap<Uuid, FileSystem>,
+    #[serde(default)]
+    pub replicas: HashMap<Uuid, Replica>,
+    /// Replicas which have been removed, and may not enrol again.
+    #[serde(default)]
+    pub revoked: HashSet<Uuid>,
+}
+
+pub struct Store {
+    dir: PathBuf,
+    pub db: Db,
+}
+
+/// Write a file in one step, so a crash never leaves it half-written.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().expect("Data directory paths always have a parent."))?;
+
+    let tmp = path.with_extension(format!("tmp-{}", Uuid::now_v7()));
+    std::fs::write(&tmp, data)?;
+    return std::fs::rename(&tmp, path);
+}
+
+impl Store {
+    /// Open the store in `dir`, creating it if it doesn't exist.
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.join(OPERATIONS))?;
+
+        let db = match std::fs::read(dir.join(DB)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Db::default(),
+            Err(e) => return Err(e),
+        };
+
+        return Ok(Self { dir: dir.to_owned(), db });
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        return write_atomic(&self.dir.join(DB), &serde_json::to_vec(&self.db)?);
+    }
+
+    fn operation_path(&self, fs_uuid: Uuid, hash: &types::Hash) -> PathBuf {
+        self.dir.join(OPERATIONS).join(fs_uuid.to_string()).join(types::hash_to_str(hash))
+    }
+
+    /// An operation's contents, or `None` if it hasn't been uploaded.
+    pub fn read_op(&self, fs_uuid: Uuid, hash: &types::Hash) -> std::io::Result<Option<String>> {
+        match std::fs::read_to_string(self.operation_path(fs_uuid, hash)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store an operation's contents, which must already be checked against its hash.
+    pub fn write_op(&self, fs_uuid: Uuid, hash: &types::Hash, data: &str) -> std::io::Result<()> {
+        let path = self.operation_path(fs_uuid, hash);
+
+        // Operations are content addressed, so one already stored is identical.
+        if path.exists() {return Ok(());}
+
+        return write_atomic(&path, data.as_bytes());
+    }
+}
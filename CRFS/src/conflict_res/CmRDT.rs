@@ -65,6 +65,12 @@ pub trait Operation: Serialize + DeserializeOwned + Clone {
         Some(self.get_hash())
     }
 
+    /// Hashes of the operations this one causally depends on, i.e. the heads of the history when it was prepared.
+    /// An operation may only be applied once all of its parents have been.
+    fn get_parents(&self) -> Vec<Hash> {
+        Vec::new()
+    }
+
     fn get_driverid(&self) -> super::file_tree::DriverID;
 }
 
@@ -72,26 +78,78 @@ pub trait Operation: Serialize + DeserializeOwned + Clone {
 pub type HistoryItem = Option<Hash>;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "StoredHistory")]
 pub struct History {
     data: Vec<HistoryItem>,
     pub k: K,
+    /// The parents of each item in `data`, forming a DAG of causal dependencies.
+    #[serde(default)]
+    parents: Vec<Vec<Hash>>,
+    /// Every hash in `data`, so lookups don't scan the whole history. Rebuilt when loaded.
+    #[serde(skip)]
+    applied: HashSet<Hash>,
+}
+
+/// A `History` as it's stored, without the derived set of applied hashes.
+#[derive(Deserialize)]
+struct StoredHistory {
+    data: Vec<HistoryItem>,
+    k: K,
+    #[serde(default)]
+    parents: Vec<Vec<Hash>>,
+}
+
+impl From<StoredHistory> for History {
+    fn from(stored: StoredHistory) -> Self {
+        let applied = stored.data.iter().flatten().cloned().collect();
+        Self {data: stored.data, k: stored.k, parents: stored.parents, applied}
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl History {
     pub fn new() -> Self {
         Self {
             data: Vec::from([None]), k: 0,
+            parents: Vec::from([Vec::new()]),
+            applied: HashSet::new(),
         }
     }
 
-    pub fn add(&mut self, item: HistoryItem) -> K {
+    pub fn add(&mut self, item: HistoryItem, parents: &[Hash]) -> K {
+        // Histories from before parents were recorded.
+        self.parents.resize(self.data.len(), Vec::new());
+
+        if let Some(hash) = item {self.applied.insert(hash);}
         self.data.push(item); self.k += 1;
+        self.parents.push(parents.to_vec());
         assert_eq!(self.data.len(), self.k + 1);
         return self.k;
     }
 
+    /// Check if every hash in `hashes` is in the history, i.e. an operation with these parents is causally ready.
+    pub fn contains_all(&self, hashes: &[Hash]) -> bool {
+        return hashes.iter().all(|h| self.applied.contains(h));
+    }
+
+    /// Get the operations which no other operation depends on, in sorted order.
+    /// These are the parents of the next operation prepared locally.
+    pub fn heads(&self) -> Vec<Hash> {
+        let depended_on: HashSet<&Hash> = self.parents.iter().flatten().collect();
+
+        let mut heads: Vec<Hash> = self.applied.iter().filter(|h| !depended_on.contains(h)).cloned().collect();
+        heads.sort();
+
+        return heads;
+    }
+
     pub fn contains(&self, hash: Hash) -> bool {
-        return self.data.contains(&Some(hash));
+        return self.applied.contains(&hash);
     }
 
     pub fn k_contains(&self, hash: Hash, k: K) -> bool {
@@ -99,12 +157,21 @@ impl History {
         return self.data[..k+1].contains(&Some(hash));
     }
 
+    /// Did hash1 happen before hash2? i.e. is hash1 an ancestor of hash2?
+    /// If neither happened before the other, they are concurrent.
     pub fn happened_before(&self, hash1: Hash, hash2: Hash) -> bool {
-        // Did hash1 happen before hash2?
-        let k2 = self.data.iter().position(
-            |h| match h {Some(hash) => *hash == hash2, None => false}
-        ).unwrap();
-        return self.k_contains(hash1, k2 - 1);
+        let parents = self.get_parents();
+        return match parents.get(&hash2) {
+            Some(p) => ancestors(&parents, p).contains(&hash1),
+            None => false,
+        };
+    }
+
+    /// Get the parents of every operation in the history.
+    pub fn get_parents(&self) -> HashMap<Hash, Vec<Hash>> {
+        self.data.iter().zip(self.parents.iter().chain(std::iter::repeat(&Vec::new())))
+            .filter_map(|(h, p)| Some(((*h)?, p.clone())))
+            .collect()
     }
 
     pub fn get_set(&self, k: K) -> HashSet<(K, Hash)> {  // Get c^k
@@ -119,10 +186,24 @@ impl History {
     }
 
     pub fn get_hashes(&self) -> HashSet<Hash> {
-        self.data.iter().filter(|x: &&Option<Hash>| x.is_some()).map(|x| x.unwrap()).collect()
+        self.applied.clone()
     }
 }
 
+/// Get `heads` and all of their ancestors, in the DAG described by `parents`.
+/// Hashes missing from `parents` are included, but can't be walked past.
+pub fn ancestors(parents: &HashMap<Hash, Vec<Hash>>, heads: &[Hash]) -> HashSet<Hash> {
+    let mut result = HashSet::new();
+    let mut stack = heads.to_vec();
+
+    while let Some(hash) = stack.pop() {
+        if !result.insert(hash) {continue;}
+        if let Some(p) = parents.get(&hash) {stack.extend(p.iter().cloned());}
+    }
+
+    return result;
+}
+
 
 // == Main CmRDT Object ==
 // General flow of using this is as follows:
@@ -173,16 +254,21 @@ pub trait Object {
     fn precond(&self, op: &Self::Op) -> bool;  // P
 
     fn apply_op(&mut self, op: &Self::Op) -> Option<()> {
+        self.apply_op_as(op, op.to_history())
+    }
+
+    // Apply a single update, recording it in the history as `hist_obj`, i.e. the address the operation is stored at.
+    fn apply_op_as(&mut self, op: &Self::Op, hist_obj: HistoryItem) -> Option<()> {
         let new_state = self.apply(op)?;
-        self.log_op(op.to_history(), new_state);
+        self.log_op(hist_obj, &op.get_parents(), new_state);
         Some(())
     }
 
-    fn log_op(&mut self, hist_obj: HistoryItem, new_state: Self::StateFormat) -> () {
-        let k = self.append_history(hist_obj);
+    fn log_op(&mut self, hist_obj: HistoryItem, parents: &[Hash], new_state: Self::StateFormat) -> () {
+        let k = self.append_history(hist_obj, parents);
         self.set_state(k, new_state);
     }
 
-    fn append_history(&mut self, hist_obj: HistoryItem) -> K;
+    fn append_history(&mut self, hist_obj: HistoryItem, parents: &[Hash]) -> K;
     fn set_state(&mut self, k: K, state: Self::StateFormat) -> ();
 }
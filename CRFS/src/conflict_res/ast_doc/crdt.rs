@@ -15,36 +15,37 @@ use uuid::Uuid;
 pub struct DocObject<DocFile> where DocFile: FileInterface {
     pub state: CmRDT::State<Doc<DocFile::TagType, DocFile::LeafType>>,
     pub hist: CmRDT::History,
-    /// The last operation created locally.
-    /// Used to satisfy in-order delivery.
-    last_op: Option<Hash>,
     driverid: DriverID,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DocOp<TagType, LeafType>
 {
-    // AddNode{node: Node<TagType, LeafType>, dep: Option<Hash>},
-    DocAddParent{driverid: DriverID, w: ID, tag: TagType, w_parent: ID, i: yata::ID, ins: yata::Insertion<ID, Uuid>, dep: Option<Hash>},
-    DocAddLeaf{driverid: DriverID, w: ID, content: LeafType, w_parent: ID, i: yata::ID, ins: yata::Insertion<ID, Uuid>, dep: Option<Hash>},
-    DocInsChild{driverid: DriverID, w_parent: ID, i: yata::ID, ins: yata::Insertion<ID, Uuid>, dep: Option<Hash>},
-    DocDelChild{driverid: DriverID, w_parent: ID, i: yata::ID, dep: Option<Hash>}, // i is a YATA ID
+    // AddNode{node: Node<TagType, LeafType>, deps: Vec<Hash>},
+    // `deps` are the heads of the history when the operation was prepared, giving full causal delivery.
+    DocAddParent{driverid: DriverID, w: ID, tag: TagType, w_parent: ID, i: yata::ID, ins: yata::Insertion<ID, Uuid>,
+        #[serde(default, alias = "dep", deserialize_with = "deserialize_deps")] deps: Vec<Hash>},
+    DocAddLeaf{driverid: DriverID, w: ID, content: LeafType, w_parent: ID, i: yata::ID, ins: yata::Insertion<ID, Uuid>,
+        #[serde(default, alias = "dep", deserialize_with = "deserialize_deps")] deps: Vec<Hash>},
+    DocInsChild{driverid: DriverID, w_parent: ID, i: yata::ID, ins: yata::Insertion<ID, Uuid>,
+        #[serde(default, alias = "dep", deserialize_with = "deserialize_deps")] deps: Vec<Hash>},
+    DocDelChild{driverid: DriverID, w_parent: ID, i: yata::ID, // i is a YATA ID
+        #[serde(default, alias = "dep", deserialize_with = "deserialize_deps")] deps: Vec<Hash>},
 }
 
-// == Implementations ==
-impl<TagType, LeafType> DocOp<TagType, LeafType>
-where
-    TagType: TagLike + Serialize,
-    LeafType: Serialize,
-{
-    fn get_dep(&self) -> Option<Hash> {
-        match self {
-            Self::DocAddParent{dep, ..} => *dep,
-            Self::DocAddLeaf{dep, ..} => *dep,
-            Self::DocInsChild{dep, ..} => *dep,
-            Self::DocDelChild{dep, ..} => *dep,
-        }
+/// Operations made before every parent was recorded had a single optional `dep`, which is read as a list.
+pub fn deserialize_deps<'de, D>(deserializer: D) -> Result<Vec<Hash>, D::Error> where D: serde::Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Deps {
+        All(Vec<Hash>),
+        One(Option<Hash>),
     }
+
+    return Ok(match Deps::deserialize(deserializer)? {
+        Deps::All(deps) => deps,
+        Deps::One(dep) => dep.into_iter().collect(),
+    });
 }
 
 
@@ -62,6 +63,15 @@ where
             Self::DocDelChild {driverid, ..} => *driverid,
         }
     }
+
+    fn get_parents(&self) -> Vec<Hash> {
+        match self {
+            Self::DocAddParent {deps, ..} => deps.clone(),
+            Self::DocAddLeaf {deps, ..} => deps.clone(),
+            Self::DocInsChild {deps, ..} => deps.clone(),
+            Self::DocDelChild {deps, ..} => deps.clone(),
+        }
+    }
 }
 
 impl<Interface> CmRDT::Object for DocObject<Interface> where Interface: FileInterface, <Interface as FileInterface>::TagType: std::fmt::Debug, <Interface as FileInterface>::LeafType: std::fmt::Debug {
@@ -73,7 +83,6 @@ impl<Interface> CmRDT::Object for DocObject<Interface> where Interface: FileInte
         let mut new = Self {
             state: CmRDT::State::new(),
             hist: CmRDT::History::new(),
-            last_op: None,
             driverid,
         };
 
@@ -111,19 +120,23 @@ impl<Interface> CmRDT::Object for DocObject<Interface> where Interface: FileInte
                     match op {
                         None => {for c in children.in_order_content_undel() {queue.push_back(c);}},
                         Some(yata::Op::Deletion(i)) => {return Some(
-                            Self::Op::DocDelChild {w_parent: current, i, dep: self.last_op, driverid: self.driverid}
+                            Self::Op::DocDelChild {w_parent: current, i, deps: self.hist.heads(), driverid: self.driverid}
+                        )},
+                        // A node which already exists has been moved, so is inserted as it is, keeping its children.
+                        Some(yata::Op::Insertion(i, ins)) if old_state.items.contains_key(&ins.content) => {return Some(
+                            Self::Op::DocInsChild {w_parent: current, i, ins, deps: self.hist.heads(), driverid: self.driverid}
                         )},
                         Some(yata::Op::Insertion(i, ins)) => {
                             let new_node = &new_state.items[&ins.content];
                             match new_node {
                                 Node::Parent {id: new_id, tag: new_tag, ..} => {
                                     return Some(Self::Op::DocAddParent {
-                                        w_parent: current, tag: new_tag.clone(), w: *new_id, i, ins, dep: self.last_op, driverid: self.driverid,
+                                        w_parent: current, tag: new_tag.clone(), w: *new_id, i, ins, deps: self.hist.heads(), driverid: self.driverid,
                                     })
                                 },
                                 Node::Leaf {id: new_id, content} => {
                                     return Some(Self::Op::DocAddLeaf {
-                                        w: *new_id, content: content.clone(), w_parent: current, i, ins, dep: self.last_op, driverid: self.driverid,
+                                        w: *new_id, content: content.clone(), w_parent: current, i, ins, deps: self.hist.heads(), driverid: self.driverid,
                                     })
                                 },
                             }
@@ -173,25 +186,12 @@ impl<Interface> CmRDT::Object for DocObject<Interface> where Interface: FileInte
         return Some(new_state);
     }
 
-    fn apply_op(&mut self, op: &Self::Op) -> Option<()> {
-        let new_state = self.apply(op)?;
-        self.log_op(op.to_history(), new_state);
-        self.last_op = Some(op.get_hash());
-        Some(())
-    }
-
     fn precond(&self, op: &Self::Op) -> bool {
-        if let Some(hash) = op.get_dep() {
-            if !self.hist.contains(hash) {
-                return false;
-            }
-        }
-
-        return true;
+        return self.hist.contains_all(&op.get_parents());
     }
 
-    fn append_history(&mut self, hist_obj: CmRDT::HistoryItem) -> CmRDT::K {
-        self.hist.add(hist_obj)
+    fn append_history(&mut self, hist_obj: CmRDT::HistoryItem, parents: &[Hash]) -> CmRDT::K {
+        self.hist.add(hist_obj, parents)
     }
 
     fn set_state(&mut self, k: CmRDT::K, state: Self::StateFormat) -> () {
@@ -173,9 +173,7 @@ impl DiskType for MDInterface {
         let mut buf = String::new();
         object::read_string(config, loc, &mut buf)?;
 
-        let mdast = mdast::markdown_to_ast(&buf);
-
-        return Ok(Box::new(Self{mdast}));
+        return Ok(Box::new(Self::parse(&buf)?));
     }
 
     fn write(&self, config: &storage::Config, loc: &object::Location) -> Result<(), std::io::Error> {
@@ -235,6 +233,25 @@ impl FileInterface for MDInterface {
 }
 
 impl MDInterface {
+    /// Parse markdown, failing on syntax `markdown_ast` can't represent (e.g. HTML or images), which it panics on.
+    pub fn parse(raw_md: &str) -> std::io::Result<Self> {
+        // The same options `markdown_ast` parses with.
+        let options = pulldown_cmark::Options::ENABLE_STRIKETHROUGH | pulldown_cmark::Options::ENABLE_TABLES;
+
+        for event in pulldown_cmark::Parser::new_ext(raw_md, options) {
+            use pulldown_cmark::{Event as E, Tag as T};
+            match event {
+                E::Html(_) | E::InlineHtml(_) | E::InlineMath(_) | E::DisplayMath(_) | E::FootnoteReference(_) |
+                E::TaskListMarker(_) | E::Start(T::Image {..} | T::HtmlBlock | T::FootnoteDefinition(_) | T::MetadataBlock(_)) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unsupported markdown: {:?}", event)));
+                },
+                _ => {},
+            }
+        }
+
+        return Ok(Self { mdast: mdast::markdown_to_ast(raw_md) });
+    }
+
     pub fn get_canon(&self) -> String {
         return markdown_ast::ast_to_markdown(&self.mdast);
     }
@@ -518,6 +535,21 @@ pub struct MDDriver {
     driverid: DriverID,
 }
 
+impl MDDriver {
+    /// Bring the internal state up to `latest_state`, writing out the operations generated.
+    fn update_to(&mut self, latest_state: &MDInterface) -> Result<(), crate::errors::Error> {
+        while let Some(op) = self.object.prep(latest_state, self.uuid) {
+            let hash = object::op_hash(&self.config, &op)?;
+            self.object.apply_op_as(&op, Some(hash)).unwrap(); // Use unwrap here, since there is no reason a just-prepped update doesn't apply.
+            // If a just-prepped update doesn't apply, then something has gone very wrong!! We *must* always immediately `apply` after a `prep`.
+
+            self.write_op(op)?;
+        }
+
+        return Ok(());
+    }
+}
+
 impl Driver for MDDriver {
     type Object = MDObject;
 
@@ -525,6 +557,13 @@ impl Driver for MDDriver {
         loc.extension() == Some(String::from("md"))
     }
 
+    fn unsupported(config: &storage::Config, loc: &object::Location) -> Option<String> {
+        match MDInterface::read(config, loc) {
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Some(e.to_string()),
+            _ => None,
+        }
+    }
+
     fn new(config: storage::Config, loc: &object::Location, uuid: Uuid, driverid: DriverID) -> Self {
         Self {
             object: MDObject::init(driverid),
@@ -553,15 +592,12 @@ impl Driver for MDDriver {
 
     fn update(&mut self) -> Result<(), crate::errors::Error> {
         let latest_state = *MDInterface::read(&self.config, &self.loc)?;
+        return self.update_to(&latest_state);
+    }
 
-        while let Some(op) = self.object.prep(&latest_state, self.uuid) {
-            self.object.apply_op(&op).unwrap(); // Use unwrap here, since there is no reason a just-prepped update doesn't apply.
-            // If a just-prepped update doesn't apply, then something has gone very wrong!! We *must* always immediately `apply` after a `prep`.
-
-            self.write_op(op)?;
-        }
-
-        return Ok(());
+    fn merge(&mut self, other: &Self) -> Result<(), crate::errors::Error> {
+        let merged = format!("{}\n\n{}", self.object.query().get_canon(), other.object.query().get_canon());
+        return self.update_to(&MDInterface::parse(&merged)?);
     }
 
     /// Operations may have dependencies that do not align with their order in `ops`.
@@ -588,7 +624,7 @@ impl Driver for MDDriver {
                     if op.get_driverid() != self.get_driverid() {continue 'inner;}
 
                     // Attempt to apply op
-                    match self.object.apply_op(&op) {
+                    match self.object.apply_op_as(&op, Some(**hash)) {
                         Some(()) => {applied.insert(*hash);},
                         None => {},
                     }
@@ -615,4 +651,15 @@ impl Driver for MDDriver {
     fn get_path(&self) -> PathBuf {
         self.loc.get_path(&self.config)
     }
+
+    fn set_loc(&mut self, loc: object::Location) {
+        self.loc = loc;
+    }
+
+    fn same_content(&self, loc: &object::Location) -> bool {
+        match MDInterface::read(&self.config, loc) {
+            Ok(disk) => disk.get_canon() == self.object.query().get_canon(),
+            Err(_) => false,
+        }
+    }
 }
@@ -1,10 +1,10 @@
 use super::CmRDT;
 // use crate::storage;
+use crate::types;
 
 use super::yata;
 
 use std::collections::HashMap;
-use rand::Rng;
 
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use uuid::Uuid;
@@ -16,7 +16,7 @@ pub type ID = u128;
 /// Assumes that ID has a large enough range of values that randomly choosing a value is enough to ensure uniqueness.
 /// TODO: improve this
 pub fn unique() -> ID {
-    rand::rng().random()
+    types::random_id()
 }
 
 /// Container type for holding the set of Nodes in a document.
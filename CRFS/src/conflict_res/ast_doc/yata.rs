@@ -1,4 +1,4 @@
-use rand::Rng;
+use crate::types;
 use std::collections::{HashMap, HashSet};
 use std::ops::{Index, IndexMut};
 
@@ -7,7 +7,7 @@ use serde::{Serialize, Deserialize};
 // ID must implement Eq, Hash
 pub type ID = u64;
 fn unique() -> ID {
-    rand::rng().random()
+    types::random_id()
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
@@ -202,6 +202,10 @@ impl<T, C> Array<T, C> where C: Ord {
         return self.in_order().len();
     }
 
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
     pub fn len_undel(&self) -> usize {
         return self.in_order_undel().len();
     }
@@ -277,8 +281,17 @@ impl<T, C> Array<T, C> where C: Ord {
 impl<T, C> Array<T, C> where C: Ord {
     /// Returns None if there is an error with the references in `ins`
     /// Else returns the new ID of `ins` in the list.
+    /// Inserting an ID already in the list has no effect, so operations can safely be applied more than once.
     pub fn insert(&mut self, ins: Insertion<T, C>, id_: Option<ID>) -> Option<ID> {
+        if let Some(id) = id_.filter(|id| self.items.contains_key(id)) {
+            return Some(id);
+        }
+
         let (l, r) = (self.get_index_ref(ins.left)?, self.get_index_ref(ins.right)?);
+        let origin = self.get_index_ref(ins.origin)?;
+        if l >= r {
+            return None;
+        }
         let n_conflicting = r - l - 1;
 
         let id_i = match id_ {
@@ -291,19 +304,14 @@ impl<T, C> Array<T, C> where C: Ord {
         }
 
         let mut new_left = ins.left;
-        for (ind, id_o) in self.in_order().iter().enumerate().skip((l + 1).try_into().unwrap()) {
-            if ind >= r.try_into().unwrap() { break; }
-
-            if (
-                self.get_index_ref(ins.origin).unwrap() > ind.try_into().unwrap() ||
-                self.get_index_ref(ins.origin).unwrap() <= self.get_index_ref(self[*id_o].origin).unwrap()
-            ) && (
-                self.get_index_ref(ins.origin).unwrap() != self.get_index_ref(self[*id_o].origin).unwrap() ||
-                self[*id_o].creator < ins.creator
-            ) {
+        for (ind, id_o) in self.in_order().iter().enumerate().skip((l + 1) as usize) {
+            if ind as isize >= r { break; }
+
+            let origin_o = self.get_index_ref(self[*id_o].origin)?;
+            if (origin > ind as isize || origin <= origin_o) && (origin != origin_o || self[*id_o].creator < ins.creator) {
                 new_left = Ref::Item(*id_o);
             } else {
-                if self.get_index_ref(ins.origin).unwrap() >= self.get_index_ref(self[*id_o].origin).unwrap() {
+                if origin >= origin_o {
                     break;
                 }
             }
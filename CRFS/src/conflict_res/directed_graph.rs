@@ -5,11 +5,11 @@ use storage::object;
 
 use super::file_tree::DriverID;
 use super::CmRDT::{self, StateType};
+use crate::types::{self, Hash};
 
 use std::collections::HashSet;
 use std::hash;
 
-use rand::Rng;
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use serde_json;
 use uuid::Uuid;
@@ -17,7 +17,7 @@ use uuid::Uuid;
 type Tag = u64;
 
 fn unique() -> Tag {
-    rand::rng().random()
+    types::random_id()
 }
 
 // == Data Types ==
@@ -216,8 +216,8 @@ impl<T> CmRDT::Object for GraphObject<T> where T: Clone + Eq + hash::Hash + Seri
         }
     }
 
-    fn append_history(&mut self, hist_obj: CmRDT::HistoryItem) -> CmRDT::K {
-        self.hist.add(hist_obj)
+    fn append_history(&mut self, hist_obj: CmRDT::HistoryItem, parents: &[Hash]) -> CmRDT::K {
+        self.hist.add(hist_obj, parents)
     }
 
     fn set_state(&mut self, k: CmRDT::K, state: Self::StateFormat) -> () {
@@ -27,6 +27,12 @@ pub trait Driver {
     /// Check if a file can be managed by this driver.
     fn check(loc: &object::Location) -> bool;
 
+    /// Why the file at `loc`, which passes `check`, can't be managed by this driver, e.g. because it uses syntax the
+    /// driver can't represent. Such files are skipped rather than synced, until they're supported.
+    fn unsupported(_config: &Config, _loc: &object::Location) -> Option<String> {
+        None
+    }
+
     /// Create a new driver instance for a given file.
     /// loc should be a file in the tree.
     fn new(config: Config, loc: &object::Location, replica_id: Uuid, driverid: DriverID) -> Self;
@@ -44,6 +50,9 @@ pub trait Driver {
     /// Should also write out operations to disk
     fn update(&mut self) -> Result<(), errors::Error>;
 
+    /// Merge the contents of `other`, a file of the same type, into this file, generating operations for the merge.
+    fn merge(&mut self, other: &Self) -> Result<(), errors::Error>;
+
     /// Apply a number of operations fetched from the network or elsewhere.
     /// Not all the ops referred to in `ops` need be for this driver.
     /// As such, drivers should perform two checks:
@@ -57,10 +66,15 @@ pub trait Driver {
 
     fn get_path(&self) -> PathBuf;
 
+    /// Set the location of the managed file, e.g. after it has been moved.
+    fn set_loc(&mut self, loc: object::Location);
+
+    /// Check if the file at `loc` has the same content as the internal state, e.g. for rename detection.
+    fn same_content(&self, loc: &object::Location) -> bool;
+
     fn get_op(&self, hash: Hash) -> std::io::Result<<<Self as Driver>::Object as CmRDT::Object>::Op> {
-        let loc = object::Location::Object(hash);
         let mut json = String::new();
-        object::read_string(&self.get_config(), &loc, &mut json)?;
+        object::read_obj(&self.get_config(), &hash, &mut json)?;
         return Ok(<<Self as Driver>::Object as CmRDT::Object>::Op::deserialize_from_str(json)?);
     }
 
@@ -91,6 +105,13 @@ impl AvailDrivers {
         return None;
     }
 
+    /// Why the file at `loc` can't be managed by the driver `name`, if it can't.
+    pub fn unsupported(name: DriverNames, config: &Config, loc: &object::Location) -> Option<String> {
+        match name {
+            DriverNames::Markdown => MDDriver::unsupported(config, loc),
+        }
+    }
+
     pub fn get(config: Config, loc: &object::Location, replica_id: Uuid, driverid: DriverID) -> Option<Self> {
         let name = Self::get_name(loc)?;
         return Some(Self::new_from_name(name, config, loc, replica_id, driverid));
@@ -99,42 +120,74 @@ impl AvailDrivers {
     pub fn new_from_name(name: DriverNames, config: Config, loc: &object::Location, replica_id: Uuid, driverid: DriverID) -> Self {
         match name {
             DriverNames::Markdown => Self::Markdown(MDDriver::new(config, loc, replica_id, driverid)),
-            _ => todo!(),
+        }
+    }
+
+    pub fn get_config(&self) -> Config {
+        match self {
+            Self::Markdown(md) => md.get_config(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        match self {
+            Self::Markdown(md) => md.set_config(config),
         }
     }
 
     pub fn get_history(&self) -> CmRDT::History {
         match self{
             Self::Markdown(md) => md.get_history(),
-            _ => todo!(),
         }
     }
 
     pub fn update(&mut self) -> Result<(), errors::Error> {
         match self {
             Self::Markdown(md) => md.update(),
-            _ => todo!(),
+        }
+    }
+
+    /// Merge the contents of `other` into this file. Returns `None` if the files are of different types.
+    pub fn merge(&mut self, other: &Self) -> Option<Result<(), errors::Error>> {
+        match (self, other) {
+            (Self::Markdown(md), Self::Markdown(other)) => Some(md.merge(other)),
         }
     }
 
     pub fn apply<'a>(&mut self, ops: &Vec<&'a Hash>) -> std::io::Result<HashSet<&'a Hash>> {
         match self {
             Self::Markdown(driver) => driver.apply(ops),
-            _ => todo!(),
         }
     }
 
     pub fn write_out(&self) -> std::io::Result<()> {
         match self {
             Self::Markdown(driver) => driver.write_out(),
-            _ => todo!(),
         }
     }
 
     pub fn get_path(&self) -> PathBuf {
         match self {
             Self::Markdown(md) => md.get_path(),
-            _ => todo!(),
+        }
+    }
+
+    pub fn set_loc(&mut self, loc: object::Location) {
+        match self {
+            Self::Markdown(md) => md.set_loc(loc),
+        }
+    }
+
+    /// Why the managed file, as it is on disk, can't be managed by this driver, if it can't.
+    pub fn unsupported_on_disk(&self) -> Option<String> {
+        match self {
+            Self::Markdown(md) => MDDriver::unsupported(&md.get_config(), &object::Location::Path(md.get_path(), false)),
+        }
+    }
+
+    pub fn same_content(&self, loc: &object::Location) -> bool {
+        match self {
+            Self::Markdown(md) => md.same_content(loc),
         }
     }
 }
@@ -1,18 +1,24 @@
-// Directed Graph CmRDT, adapted from https://pages.lip6.fr/Marc.Shapiro/papers/RR-7687.pdf#page=15
+// File tree CmRDT.
+// Files and directories are nodes in a tree, each holding a set of (parent, name) placements.
+// The winning placement of every node is found by replaying all placements in timestamp order, skipping any directory
+// move that would introduce a cycle - adapted from https://martin.kleppmann.com/papers/move-op.pdf
 
 #[cfg(test)]
 mod test;
+pub mod pending;
 
 use crate::storage;
 // use crate::storage::{ObjectFile, ObjectLocation};
-use storage::object;
+use storage::{object, sign};
 use crate::{types, errors};
 use super::driver::{AvailDrivers, DriverNames}; // AvailOps;
-use super::ast_doc::yata;
+use super::ast_doc::crdt;
 use super::CmRDT::{self, Operation};
+use storage::{CollisionPolicy, MovePolicy, PathPolicy, SymlinkPolicy};
+use pending::{PendingOps, PendingReason};
 
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 use rand::Rng;
 use serde::{Serialize, Deserialize};
@@ -21,77 +27,366 @@ use serde_with::serde_as;
 use uuid::Uuid;
 
 const IGNORED_DIRS: [&str; 1] = [".crfs"];
+const STAGING_DIR: &str = ".crfs/staging/"; // Appended to the working dir.
+/// Version of the persisted file tree state. Replicas made before directories were tracked have none, i.e. version 0.
+pub const FORMAT_VERSION: u32 = 1;
 
 // Driver container
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum DriverID {
     Driver(u64),
     FileTree,
 }
 pub fn unique() -> DriverID {
-    DriverID::Driver(rand::rng().random())
+    DriverID::Driver(types::random_id())
 }
 pub type DriverContainer = HashMap<DriverID, AvailDrivers>;
 
+// Directory IDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum DirID {
+    Dir(u64),
+    Root,
+}
+pub fn unique_dir() -> DirID {
+    DirID::Dir(types::random_id())
+}
+
+// Symbolic link IDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LinkID(pub u64);
+pub fn unique_link() -> LinkID {
+    LinkID(types::random_id())
+}
+
+/// Any node in the file tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NodeID {
+    File(DriverID),
+    Dir(DirID),
+    Link(LinkID),
+}
+
+/// Lamport timestamp. Ties between replicas are broken by the replica's UUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp(pub u64, pub Uuid);
+
+/// A single assignment of a node to a parent directory and name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Placement {
+    pub ts: Timestamp,
+    pub parent: DirID,
+    pub name: PathBuf, // Single path component.
+    /// For moves, the timestamp of the placement being replaced.
+    /// Two moves of the same node with the same `prev` are concurrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<Timestamp>,
+}
+
+/// Last-writer-wins register.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Register<T> {
+    pub ts: Timestamp,
+    pub value: T,
+}
+
+impl<T> Register<T> {
+    /// Keep whichever of `self` and `other` was written last.
+    pub fn merge(&mut self, other: Self) {
+        if other.ts > self.ts {*self = other;}
+    }
+}
+
 // CRDT State Format
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FileInfo {
     // driver: DriverID,
-    paths: yata::Array<PathBuf, Uuid>,
+    placements: Vec<Placement>,
+    deleted: bool,
+    /// Permission bits (i.e. `mode & 0o777`).
+    #[serde(default)]
+    mode: Option<Register<u32>>,
+    /// Modification time, in whole seconds since the UNIX epoch.
+    #[serde(default)]
+    mtime: Option<Register<u64>>,
+    // Owners are not replicated, as this would require considerations of how the program is run
+    // (i.e. setuid/setgid root may be required)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirInfo {
+    placements: Vec<Placement>,
     deleted: bool,
-    // Potential to add file permissions, owners, etc. here in future.
-    // This would however require considerations of how the program is run (i.e. setuid/setgid root may be required)
 }
-pub type FileState = HashMap<DriverID, FileInfo>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinkInfo {
+    placements: Vec<Placement>,
+    target: Register<PathBuf>,
+    deleted: bool,
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileState {
+    #[serde_as(as = "HashMap<serde_with::json::JsonString, _>")]
+    pub files: HashMap<DriverID, FileInfo>,
+    #[serde_as(as = "HashMap<serde_with::json::JsonString, _>")]
+    pub dirs: HashMap<DirID, DirInfo>,
+    #[serde_as(as = "HashMap<serde_with::json::JsonString, _>")]
+    #[serde(default)]
+    pub links: HashMap<LinkID, LinkInfo>,
+}
 
 impl FileInfo {
-    /// Create a new FileInfo and driver, store the driver, and return a FileInfo
-    /// init_path should be relative to config.working_directory
-    pub fn new_file(
-        container: &mut DriverContainer,
-        driver: DriverNames,
-        config: storage::Config,
-        init_path: PathBuf,
-        replica_id: Uuid
-    ) -> (DriverID, Self) {
-        let id = unique();
-        container.insert(id, AvailDrivers::new_from_name(
-            driver,
-            config.clone(),
-            &object::Location::Path(init_path.clone(), true),
-            replica_id,
-            id
-        ));
+    pub fn add_placement(&mut self, placement: Placement) {
+        if !self.placements.contains(&placement) {self.placements.push(placement);}
+    }
+}
 
-        (id, Self {
-            // driver: id,
-            paths: yata::Array::from(([init_path].into_iter(), replica_id)),
-            deleted: false,
-        })
+impl DirInfo {
+    pub fn add_placement(&mut self, placement: Placement) {
+        if !self.placements.contains(&placement) {self.placements.push(placement);}
+    }
+}
+
+impl LinkInfo {
+    pub fn add_placement(&mut self, placement: Placement) {
+        if !self.placements.contains(&placement) {self.placements.push(placement);}
+    }
+
+    pub fn get_target(&self) -> &PathBuf {
+        &self.target.value
+    }
+}
+
+impl Default for FileState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileState {
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            dirs: HashMap::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    /// Replay every placement in timestamp order, skipping any directory move which would place a directory inside itself.
+    /// Returns the winning placement of every node, which is guaranteed to be acyclic.
+    pub fn resolve(&self) -> HashMap<NodeID, &Placement> {
+        let mut all: Vec<(NodeID, &Placement)> = Vec::new();
+        for (id, info) in self.files.iter() {
+            all.extend(info.placements.iter().map(|p| (NodeID::File(*id), p)));
+        }
+        for (id, info) in self.dirs.iter() {
+            all.extend(info.placements.iter().map(|p| (NodeID::Dir(*id), p)));
+        }
+        for (id, info) in self.links.iter() {
+            all.extend(info.placements.iter().map(|p| (NodeID::Link(*id), p)));
+        }
+
+        all.sort_by(|(n1, p1), (n2, p2)| (p1.ts, n1).cmp(&(p2.ts, n2)));
+
+        let mut result: HashMap<NodeID, &Placement> = HashMap::new();
+        for (node, placement) in all.into_iter() {
+            if let NodeID::Dir(d) = node {
+                if Self::is_ancestor(&result, d, placement.parent) {continue;}
+            }
+
+            result.insert(node, placement);
+        }
+
+        return result;
+    }
+
+    /// Is `dir` equal to, or an ancestor of, `of`?
+    fn is_ancestor(placements: &HashMap<NodeID, &Placement>, dir: DirID, of: DirID) -> bool {
+        let mut current = of;
+        loop {
+            if current == dir {return true;}
+
+            match placements.get(&NodeID::Dir(current)) {
+                Some(p) => current = p.parent,
+                None => return false,
+            }
+        }
     }
 
-    pub fn get_path(&self) -> &PathBuf {
-        let tail = self.paths.tail.expect("paths list empty!");
-        return &self.paths.items.get(&tail).expect("tail reference invalid!").content;
+    /// Get the path of every live node, relative to the working directory.
+    /// A deleted directory is kept alive if it still contains live nodes.
+    pub fn paths(&self, policy: &PathPolicy) -> HashMap<NodeID, PathBuf> {
+        let placements = self.resolve();
+        let live = self.live(&placements);
+        let names = Self::dedup_names(&placements, &live, policy);
+
+        let mut result = HashMap::new();
+        'outer: for node in live.into_iter() {
+            let mut components = Vec::new();
+            let mut current = node;
+
+            while let Some(p) = placements.get(&current) {
+                components.push(&names[&current]);
+                if p.parent == DirID::Root {
+                    result.insert(node, components.into_iter().rev().collect());
+                    continue 'outer;
+                }
+                current = NodeID::Dir(p.parent);
+            }
+            // Nodes without a path to the root (i.e. unknown parent) are left out.
+        }
+
+        return result;
     }
 
-    pub fn insert_path(&mut self, ins: yata::Insertion<PathBuf, Uuid>, id: yata::ID) {
-        self.paths.insert(ins, Some(id));
+    /// Get every live node which has lost a name collision, mapped to the node which kept the name.
+    pub fn collisions(&self, policy: &PathPolicy) -> HashMap<NodeID, NodeID> {
+        let placements = self.resolve();
+        let live = self.live(&placements);
+
+        let mut result = HashMap::new();
+        for (_, nodes) in Self::collision_groups(&placements, &live, policy) {
+            for loser in nodes[1..].iter() {
+                result.insert(*loser, nodes[0]);
+            }
+        }
+
+        return result;
+    }
+
+    /// Get the nodes which are not deleted, or are deleted directories which still contain live nodes.
+    fn live(&self, placements: &HashMap<NodeID, &Placement>) -> HashSet<NodeID> {
+        let mut live: HashSet<NodeID> = self.files.iter().filter(|(_, info)| !info.deleted).map(|(id, _)| NodeID::File(*id))
+            .chain(self.dirs.iter().filter(|(_, info)| !info.deleted).map(|(id, _)| NodeID::Dir(*id)))
+            .chain(self.links.iter().filter(|(_, info)| !info.deleted).map(|(id, _)| NodeID::Link(*id)))
+            .collect();
+
+        // Resurrect the ancestors of any live node.
+        let mut stack: Vec<NodeID> = live.iter().cloned().collect();
+        while let Some(node) = stack.pop() {
+            if let Some(p) = placements.get(&node) {
+                let parent = NodeID::Dir(p.parent);
+                if self.dirs.contains_key(&p.parent) && live.insert(parent) {stack.push(parent);}
+            }
+        }
+
+        return live;
+    }
+
+    /// Group live nodes by the directory and name they claim, in a deterministic order.
+    /// Names are compared under `policy`, e.g. ignoring case. Within a group, the node placed there first comes first.
+    fn collision_groups(placements: &HashMap<NodeID, &Placement>, live: &HashSet<NodeID>, policy: &PathPolicy) -> Vec<((DirID, PathBuf), Vec<NodeID>)> {
+        let mut groups: HashMap<(DirID, PathBuf), Vec<NodeID>> = HashMap::new();
+        for node in live.iter() {
+            if let Some(p) = placements.get(node) {
+                groups.entry((p.parent, policy.key(&p.name))).or_default().push(*node);
+            }
+        }
+
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        for (_, nodes) in groups.iter_mut() {
+            nodes.sort_by_key(|n| (placements[n].ts, *n));
+        }
+
+        return groups;
+    }
+
+    /// Give every live node a name which is unique within its directory.
+    /// Where several nodes claim the same name, the node placed there first keeps it, and the others are renamed
+    /// deterministically, using the replica which placed them, e.g. `todo (0196a3f2).md`.
+    fn dedup_names(placements: &HashMap<NodeID, &Placement>, live: &HashSet<NodeID>, policy: &PathPolicy) -> HashMap<NodeID, PathBuf> {
+        let groups = Self::collision_groups(placements, live, policy);
+        let mut taken: HashSet<(DirID, PathBuf)> = groups.iter().map(|(key, _)| key.clone()).collect();
+
+        let mut result = HashMap::new();
+        for ((dir, _), nodes) in groups.into_iter() {
+            for (i, node) in nodes.into_iter().enumerate() {
+                let name = &placements[&node].name;
+                if i == 0 {result.insert(node, name.clone()); continue;}
+
+                // Tagged with the end of the creating replica's ID, as the start of a v7 UUID is only a timestamp.
+                let id = placements[&node].ts.1.simple().to_string();
+                let tag = id[id.len() - 8..].to_owned();
+                let mut candidate = conflict_name(name, &tag);
+                let mut n = 1;
+                while taken.contains(&(dir, policy.key(&candidate))) {
+                    candidate = conflict_name(name, &format!("{} {}", tag, n)); n += 1;
+                }
+
+                taken.insert((dir, policy.key(&candidate)));
+                result.insert(node, candidate);
+            }
+        }
+
+        return result;
+    }
+
+    /// Get the IDs of the nodes whose winning placement is directly within `dir`.
+    fn children(&self, placements: &HashMap<NodeID, &Placement>, paths: &HashMap<NodeID, PathBuf>, dir: DirID) -> Vec<NodeID> {
+        placements.iter()
+            .filter(|(node, p)| p.parent == dir && paths.contains_key(node))
+            .map(|(node, _)| *node)
+            .collect()
     }
 }
 
 // Operations
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum FileOp {
-    NewFile(DriverID, DriverNames, PathBuf, Uuid), // New ID, Type of Driver, Initial Location, Created by ID
-    MoveFile(DriverID, yata::Insertion<PathBuf, Uuid>, yata::ID),
+    NewFile(DriverID, DriverNames, Placement), // New ID, Type of Driver, Initial placement
+    MoveFile(DriverID, Placement),
     DelFile(DriverID),
+    NewDir(DirID, Placement), // New ID, Initial placement
+    MoveDir(DirID, Placement),
+    DelDir(DirID),
+    NewLink(LinkID, Placement, Register<PathBuf>), // New ID, Initial placement, Link target
+    MoveLink(LinkID, Placement),
+    SetLink(LinkID, Register<PathBuf>),
+    DelLink(LinkID),
+    SetMode(DriverID, Register<u32>),
+    SetMTime(DriverID, Register<u64>),
+}
+
+impl FileOp {
+    fn get_placement(&self) -> Option<&Placement> {
+        match self {
+            Self::NewFile(_, _, p) | Self::MoveFile(_, p) | Self::NewDir(_, p) | Self::MoveDir(_, p) |
+            Self::NewLink(_, p, _) | Self::MoveLink(_, p) => Some(p),
+            Self::DelFile(_) | Self::DelDir(_) | Self::SetLink(..) | Self::DelLink(_) |
+            Self::SetMode(..) | Self::SetMTime(..) => None,
+        }
+    }
+
+    fn get_timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Self::SetLink(_, r) => Some(r.ts),
+            Self::SetMode(_, r) => Some(r.ts),
+            Self::SetMTime(_, r) => Some(r.ts),
+            _ => Some(self.get_placement()?.ts),
+        }
+    }
+}
+
+/// A file tree operation, along with its causal dependencies. This is the form operations are stored in.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TreeOp {
+    pub file_op: FileOp,
+    /// The heads of the file tree's history when the operation was prepared.
+    pub parents: Vec<types::Hash>,
 }
 
-impl CmRDT::Operation for FileOp {
+impl CmRDT::Operation for TreeOp {
     fn get_driverid(&self) -> DriverID {
         DriverID::FileTree
     }
+
+    fn get_parents(&self) -> Vec<types::Hash> {
+        self.parents.clone()
+    }
 }
 
 // Summary of the whole systems Causal Histories
@@ -102,6 +397,27 @@ pub struct SystemHistory {
 }
 
 impl SystemHistory {
+    /// Get the parents of every operation, across the file tree and all drivers.
+    pub fn all_parents(&self) -> HashMap<types::Hash, Vec<types::Hash>> {
+        let mut parents = self.tree.get_parents();
+        for hist in self.drivers.values() {
+            parents.extend(hist.get_parents());
+        }
+
+        return parents;
+    }
+
+    /// Get the operations no other operation depends on, in sorted order. Together they summarise the whole history.
+    pub fn heads(&self) -> Vec<types::Hash> {
+        let parents = self.all_parents();
+        let depended_on: HashSet<&types::Hash> = parents.values().flatten().collect();
+
+        let mut heads: Vec<types::Hash> = self.all_hashes().into_iter().filter(|h| !depended_on.contains(h)).collect();
+        heads.sort();
+
+        return heads;
+    }
+
     pub fn all_hashes(&self) -> HashSet<types::Hash> {
         let mut hashes: Vec<HashSet<types::Hash>> = self.drivers.iter().map(|(_, h)| h.get_hashes()).collect();
         hashes.push(self.tree.get_hashes());
@@ -119,10 +435,16 @@ impl SystemHistory {
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileManager {
+    /// Version of the format this was persisted in, see `FORMAT_VERSION`.
+    #[serde(default)]
+    format: u32,
+
     // CRDT
-    #[serde_as(as = "HashMap<_, HashMap<serde_with::json::JsonString, _>>")]
     state: CmRDT::State<FileState>,
     hist: CmRDT::History,
+    /// Lamport clock, the highest timestamp counter seen so far.
+    #[serde(default)]
+    clock: u64,
 
     // Storage
     config: storage::Config,
@@ -136,8 +458,10 @@ pub struct FileManager {
 impl FileManager {
     pub fn init(config: storage::Config, replica_id: Uuid) -> Self {
         let mut new = Self {
+            format: FORMAT_VERSION,
             state: CmRDT::State::new(),
             hist: CmRDT::History::new(),
+            clock: 0,
             config,
             drivers: DriverContainer::new(),
             replica_id,
@@ -148,27 +472,135 @@ impl FileManager {
         return new;
     }
 
-    fn list_dir(&self) -> std::io::Result<Vec<PathBuf>> {
-        let mut result = Vec::new();
+    /// List all files, directories and symlinks in the working directory, as paths relative to it.
+    /// Returns `(files, dirs, links)`, where `links` holds each link's path and target.
+    /// Symlinks are handled according to `config.symlinks`.
+    fn list_dir(&self) -> std::io::Result<(Vec<PathBuf>, Vec<PathBuf>, Vec<(PathBuf, PathBuf)>)> {
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        let mut links = Vec::new();
+
+        let canonical_root = std::fs::canonicalize(&self.config.working_dir)?;
+        let mut visited = HashSet::new(); // Canonical paths of visited dirs, to avoid following link cycles.
 
         let mut path_stack = VecDeque::new(); path_stack.push_back(self.config.working_dir.clone());
         'outer: while let Some(path) = path_stack.pop_front() {
             // Skip any paths in IGNORED_DIRS
             for i in IGNORED_DIRS.iter() {if path.ends_with(i) {continue 'outer;}}
 
+            let rel_path = path.strip_prefix(&self.config.working_dir).expect("Unable to strip file path prefix.").to_owned();
+
+            if path.is_symlink() {
+                match self.config.symlinks {
+                    SymlinkPolicy::Ignore => continue 'outer,
+                    SymlinkPolicy::Replicate => {
+                        let target = std::fs::read_link(&path)?;
+                        if link_within(&rel_path, &target) {
+                            links.push((rel_path, target));
+                        } else {
+                            println!("Warn: Not replicating symlink {:?}, which points outside the working directory.", rel_path);
+                        }
+                        continue 'outer;
+                    },
+                    SymlinkPolicy::Follow => match std::fs::canonicalize(&path) {
+                        Ok(target) if target.starts_with(&canonical_root) => {},
+                        _ => continue 'outer, // Broken, or points outside the working directory.
+                    },
+                }
+            }
+
             if path.is_file() {
-                result.push(path.strip_prefix(&self.config.working_dir).expect("Unable to strip file path prefix.").to_owned());
+                files.push((rel_path, path.is_symlink()));
             } else if path.is_dir() {
-                for entry in std::fs::read_dir(path)? {
-                    let entry = entry?;
-                    path_stack.push_back(entry.path());
+                if !visited.insert(std::fs::canonicalize(&path)?) {continue 'outer;}
+                if rel_path != PathBuf::new() {dirs.push(rel_path);}
+
+                let mut entries = std::fs::read_dir(path)?.map(|e| Ok(e?.path())).collect::<std::io::Result<Vec<_>>>()?;
+                entries.sort();
+
+                // Names which are the same under the path policy can't both be replicated. `update` refuses to go on
+                // while they clash, so listing just skips them.
+                let mut seen = HashSet::new();
+                for entry in entries.into_iter() {
+                    let entry = self.normalised_entry(entry);
+                    if seen.insert(self.config.paths.key(Path::new(entry.file_name().unwrap_or_default()))) {
+                        path_stack.push_back(entry);
+                    }
                 }
-            } else {
-                panic!("Symlinks not supported!");
             }
+            // Anything else (sockets, FIFOs, etc.) is skipped.
         }
 
-        return Ok(result);
+        // A followed symlink to a file in the working directory is the same file as its target, so each file is only
+        // listed once, under its own path rather than a link's where possible.
+        files.sort_by_key(|(_, is_link)| *is_link);
+        let mut seen = HashSet::new();
+        let files = files.into_iter()
+            .filter(|(p, _)| seen.insert(std::fs::canonicalize(self.config.working_dir.join(p)).unwrap_or(p.clone())))
+            .map(|(p, _)| p)
+            .collect();
+
+        return Ok((files, dirs, links));
+    }
+
+    /// The path an entry found on disk is listed under. On filesystems which normalise names themselves the entry is
+    /// found under either name, so it's listed under its normalised name. Otherwise it's listed as it is, until
+    /// `normalise_names` renames it.
+    fn normalised_entry(&self, path: PathBuf) -> PathBuf {
+        let normalised = match path.file_name() {
+            Some(name) => path.with_file_name(self.config.paths.normalise(Path::new(name))),
+            None => return path,
+        };
+
+        return if exists_nofollow(&normalised) {normalised} else {path};
+    }
+
+    /// Rename entries in the working directory whose names aren't normalised under the FS's path policy, so that they
+    /// can be replicated under the same name everywhere. Fails without renaming anything if any names clash under the
+    /// policy, e.g. `Notes.md` and `notes.md` in a case-insensitive FS, as only one of them could be replicated.
+    fn normalise_names(&self) -> Result<(), errors::Error> {
+        let mut renames = Vec::new();
+        let mut clashes = Vec::new();
+
+        let mut dir_stack = vec![self.config.working_dir.clone()];
+        while let Some(dir) = dir_stack.pop() {
+            let mut groups: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if IGNORED_DIRS.iter().any(|i| path.ends_with(i)) {continue;}
+
+                let name = PathBuf::from(path.file_name().unwrap_or_default());
+                groups.entry(self.config.paths.key(&name)).or_default().push(path);
+            }
+
+            for (_, mut group) in groups.into_iter() {
+                group.sort();
+                if group.len() > 1 {clashes.extend(group); continue;}
+
+                let path = group.pop().unwrap();
+                if path.is_dir() && !path.is_symlink() {dir_stack.push(path.clone());}
+
+                let normalised = path.with_file_name(self.config.paths.normalise(Path::new(path.file_name().unwrap_or_default())));
+                if !exists_nofollow(&normalised) {renames.push((path, normalised));}
+            }
+        }
+
+        if !clashes.is_empty() {
+            clashes.sort();
+            return Err(errors::Error(errors::CODE_NAME_CLASH, format!(
+                "Names in the working directory clash under the FS's path policy, so only one of each could be \
+                replicated. Rename them: {:?}", clashes,
+            )));
+        }
+
+        // Deepest first, so renaming a directory doesn't move entries still to be renamed.
+        renames.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+        for (from, to) in renames.into_iter() {
+            std::fs::rename(&from, &to)?;
+            println!("Renamed {:?} to {:?}, to match the FS's path policy.", from, to);
+        }
+
+        Ok(())
     }
 
     pub fn query(&self) -> &FileState {
@@ -177,25 +609,108 @@ impl FileManager {
         return r;
     }
 
+    /// Drivers of files which haven't been deleted, in a fixed order so drivers are always updated in the same order.
     fn get_active_drivers(&self) -> Vec<DriverID> {
         let state = self.query();
 
-        return state.iter().filter(|(_, info)| !info.deleted).map(|(id, _)| *id).collect();
+        let mut ids: Vec<DriverID> = state.files.iter().filter(|(_, info)| !info.deleted).map(|(id, _)| *id).collect();
+        ids.sort();
+        return ids;
+    }
+
+    /// Timestamp to use for the next locally generated placement.
+    fn next_timestamp(&self) -> Timestamp {
+        Timestamp(self.clock + 1, self.replica_id)
+    }
+
+    /// Create a placement for `path`, provided its parent directory is known.
+    fn placement_for(&self, path: &PathBuf, dir_ids: &HashMap<&PathBuf, DirID>) -> Option<Placement> {
+        let parent = match path.parent() {
+            Some(p) if p != PathBuf::new() => *dir_ids.get(&p.to_owned())?,
+            _ => DirID::Root,
+        };
+
+        Some(Placement {
+            ts: self.next_timestamp(),
+            parent,
+            name: PathBuf::from(path.file_name()?),
+            prev: None,
+        })
     }
 
     fn prep(&self) -> std::io::Result<Option<FileOp>> {
         let old_state = self.query();
+        let paths = old_state.paths(&self.config.paths);
+
+        let (disk_files, mut disk_dirs, disk_links) = self.list_dir()?;
+
+        let dir_ids: HashMap<&PathBuf, DirID> = paths.iter().filter_map(|(node, path)| match node {
+            NodeID::Dir(d) => Some((path, *d)),
+            _ => None,
+        }).collect();
+        let file_ids: HashMap<&PathBuf, DriverID> = paths.iter().filter_map(|(node, path)| match node {
+            NodeID::File(f) => Some((path, *f)),
+            _ => None,
+        }).collect();
+        let link_ids: HashMap<&PathBuf, LinkID> = paths.iter().filter_map(|(node, path)| match node {
+            NodeID::Link(l) => Some((path, *l)),
+            _ => None,
+        }).collect();
+
+        // New or moved directories, shallowest first so parents always exist.
+        disk_dirs.sort_by_key(|d| d.components().count());
+        for new_path in disk_dirs.iter().filter(|d| !dir_ids.contains_key(d)) {
+            let placement = match self.placement_for(new_path, &dir_ids) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            if let Some(dir_id) = self.dir_rename_detection(new_path, &disk_dirs) {
+                let prev = old_state.resolve().get(&NodeID::Dir(dir_id)).map(|p| p.ts);
+                return Ok(Some(FileOp::MoveDir(dir_id, Placement {prev, ..placement})));
+            }
 
-        let mut disk_files = self.list_dir()?;
+            return Ok(Some(FileOp::NewDir(unique_dir(), placement)));
+        }
+
+        // New and retargeted links. Links are only tracked on disk when replicating them.
+        if self.config.symlinks == SymlinkPolicy::Replicate {
+            // Links for which we found no link
+            let disk_link_paths: Vec<_> = disk_links.iter().map(|(path, _)| path).collect();
+            let mut missing_links: Vec<_> = link_ids.iter().filter(|(path, _)| !disk_link_paths.contains(path)).map(|(_, l)| *l).collect();
+            missing_links.sort();
+
+            for (path, target) in disk_links.iter() {
+                let ts = self.next_timestamp();
+                match link_ids.get(path) {
+                    Some(id) if old_state.links[id].get_target() != target => {
+                        return Ok(Some(FileOp::SetLink(*id, Register {ts, value: target.clone()})));
+                    },
+                    Some(_) => {},
+                    None => if let Some(placement) = self.placement_for(path, &dir_ids) {
+                        // Rename detection: a missing link with the same target has been moved here.
+                        if let Some(id) = missing_links.iter().find(|l| old_state.links[*l].get_target() == target) {
+                            let prev = old_state.resolve().get(&NodeID::Link(*id)).map(|p| p.ts);
+                            return Ok(Some(FileOp::MoveLink(*id, Placement {prev, ..placement})));
+                        }
+
+                        return Ok(Some(FileOp::NewLink(unique_link(), placement, Register {ts, value: target.clone()})));
+                    },
+                }
+            }
 
-        // Get "undeleted" drivers (i.e. drivers for which we expect a file to exist)
-        let mut drivers: Vec<_> = self.query().iter().filter(|(_, info)| !info.deleted).map(|(id, ..)| *id).collect();
+            if let Some(l) = missing_links.first() {
+                return Ok(Some(FileOp::DelLink(*l)));
+            }
+        }
 
+        // Drivers for which we expect a file to exist.
+        let mut drivers: Vec<_> = self.get_active_drivers();
         let mut missing = Vec::new();
 
         // Match unmoved files.
-        while let Some(f) = disk_files.pop() {
-            if let Some((id, ..)) = old_state.iter().find(|(_, info)| info.get_path() == &f && !info.deleted) {
+        for f in disk_files.into_iter() {
+            if let Some(id) = file_ids.get(&f) {
                 drivers.retain(|d| d != id);
             } else {
                 missing.push(f);
@@ -204,38 +719,133 @@ impl FileManager {
 
         // Rename detection
         while let Some(new_path) = missing.pop() {
-            if let Some(driver_id) = self.rename_detection(&new_path) {
-                let file_info = old_state.get(&driver_id).unwrap();
-                let (id, ins) = file_info.paths.get_insertion(
-                    file_info.paths.len_undel(), new_path, self.replica_id
-                );
-
-                return Ok(Some(FileOp::MoveFile(driver_id, ins, id)));
+            let placement = match self.placement_for(&new_path, &dir_ids) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            if let Some(driver_id) = self.rename_detection(&new_path, &drivers) {
+                let prev = old_state.resolve().get(&NodeID::File(driver_id)).map(|p| p.ts);
+                return Ok(Some(FileOp::MoveFile(driver_id, Placement {prev, ..placement})));
             } else {
                 let driver = match AvailDrivers::get_name(
                     &object::Location::Path(new_path.clone(), true)
                 ) {
                     Some(name) => name,
-                    None => return Ok(None),
+                    None => continue, // No driver can manage this file.
                 };
+                if AvailDrivers::unsupported(driver, &self.config, &object::Location::Path(new_path.clone(), true)).is_some() {
+                    continue; // Nor can one manage what it holds, see `warn_unsupported`.
+                }
 
-                return Ok(Some(FileOp::NewFile(unique(), driver, new_path, self.replica_id)));
+                return Ok(Some(FileOp::NewFile(unique(), driver, placement)));
             }
         }
 
         // Drivers for which we found no file
-        while let Some(d) = drivers.pop() {
+        if let Some(d) = drivers.pop() {
             return Ok(Some(FileOp::DelFile(d)));
         }
 
+        // Permission and modification time changes
+        let mut tracked: Vec<_> = file_ids.iter().collect(); tracked.sort();
+        for (path, id) in tracked.into_iter() {
+            let info = &old_state.files[id];
+            let meta = match std::fs::metadata(object::Location::Path((*path).clone(), true).get_path(&self.config)) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+
+            if self.config.permissions {
+                if let Some(mode) = get_mode(&meta) {
+                    if info.mode.as_ref().map(|r| r.value) != Some(mode) {
+                        return Ok(Some(FileOp::SetMode(*id, Register {ts: self.next_timestamp(), value: mode})));
+                    }
+                }
+            }
+
+            if self.config.mtimes {
+                if let Some(mtime) = get_mtime(&meta) {
+                    if info.mtime.as_ref().map(|r| r.value) != Some(mtime) {
+                        return Ok(Some(FileOp::SetMTime(*id, Register {ts: self.next_timestamp(), value: mtime})));
+                    }
+                }
+            }
+        }
+
+        // Directories for which we found no directory, deepest first.
+        let mut missing_dirs: Vec<_> = dir_ids.iter().filter(|(path, _)| !disk_dirs.contains(**path)).collect();
+        missing_dirs.sort_by_key(|(path, d)| (std::cmp::Reverse(path.components().count()), **d));
+        if let Some((_, d)) = missing_dirs.into_iter().find(|(_, d)| !old_state.dirs[d].deleted) {
+            return Ok(Some(FileOp::DelDir(*d)));
+        }
+
         return Ok(None);
     }
 
-    /// If a driver exists in the current state that has content similar to the contents of the file at `path`,
-    /// return its id.
-    fn rename_detection(&self, _path: &PathBuf) -> Option<DriverID> {
-        None
-        // TODO - not yet implemented.
+    /// If one of the drivers in `candidates` (i.e. drivers whose file is missing) has the same content as the file at
+    /// `path`, return its id.
+    fn rename_detection(&self, path: &PathBuf, candidates: &Vec<DriverID>) -> Option<DriverID> {
+        let loc = object::Location::Path(path.clone(), true);
+        let mut candidates = candidates.clone(); candidates.sort();
+
+        candidates.into_iter().find(|id| match self.drivers.get(id) {
+            Some(driver) => driver.same_content(&loc),
+            None => false,
+        })
+    }
+
+    /// If a directory exists in the current state which is missing on disk, and whose entries match those of the
+    /// directory at `path`, return its id.
+    fn dir_rename_detection(&self, path: &PathBuf, disk_dirs: &Vec<PathBuf>) -> Option<DirID> {
+        let state = self.query();
+        let placements = state.resolve();
+        let paths = state.paths(&self.config.paths);
+
+        let mut disk_names = HashSet::new();
+        for entry in std::fs::read_dir(object::Location::Path(path.clone(), true).get_path(&self.config)).ok()? {
+            let entry = entry.ok()?;
+            let rel_path = path.join(entry.file_name());
+            let tracked = entry.path().is_dir() ||
+                AvailDrivers::get_name(&object::Location::Path(rel_path, true)).is_some();
+            if tracked {disk_names.insert(PathBuf::from(entry.file_name()));}
+        }
+
+        if disk_names.is_empty() {return None;}
+
+        let mut candidates: Vec<DirID> = paths.iter().filter_map(|(node, p)| match node {
+            NodeID::Dir(d) if !disk_dirs.contains(p) => Some(*d),
+            _ => None,
+        }).collect();
+        candidates.sort();
+
+        candidates.into_iter().find(|d| {
+            let names: HashSet<PathBuf> = state.children(&placements, &paths, *d).iter()
+                .filter_map(|c| Some(PathBuf::from(paths[c].file_name()?)))
+                .collect();
+            names == disk_names
+        })
+    }
+
+    /// Check that every node the operation refers to exists.
+    fn precond(&self, tree_op: &TreeOp) -> bool {
+        if !self.hist.contains_all(&tree_op.parents) {return false;}
+
+        let (state, op) = (self.query(), &tree_op.file_op);
+
+        if let Some(p) = op.get_placement() {
+            if let DirID::Dir(_) = p.parent {
+                if !state.dirs.contains_key(&p.parent) {return false;}
+            }
+        }
+
+        match op {
+            FileOp::MoveFile(id, _) | FileOp::DelFile(id) |
+            FileOp::SetMode(id, _) | FileOp::SetMTime(id, _) => state.files.contains_key(id),
+            FileOp::MoveDir(id, _) | FileOp::DelDir(id) => state.dirs.contains_key(id),
+            FileOp::MoveLink(id, _) | FileOp::SetLink(id, _) | FileOp::DelLink(id) => state.links.contains_key(id),
+            FileOp::NewFile(..) | FileOp::NewDir(..) | FileOp::NewLink(..) => true,
+        }
     }
 
     fn apply<'a>(&mut self, ops: &Vec<&'a types::Hash>) -> std::io::Result<HashSet<&'a types::Hash>> {
@@ -246,17 +856,17 @@ impl FileManager {
 
         while applied.len() < n_ops {
             'inner: for hash in ops.iter() {
-                if !applied.contains(*hash) {
+                if !applied.contains(*hash) && !self.hist.contains(**hash) {
                     let op = match self.get_op(*hash) {
                         Ok(op) => op,
                         Err(_) => continue 'inner,
                     };
 
-                    if op.get_driverid() != DriverID::FileTree {
+                    if op.get_driverid() != DriverID::FileTree || !self.precond(&op) {
                         continue 'inner;
                     }
 
-                    self.apply_op(&op)?;
+                    self.apply_op_as(&op, **hash)?;
                     applied.insert(*hash);
                 }
             }
@@ -270,15 +880,24 @@ impl FileManager {
         Ok(applied)
     }
 
-    fn apply_op(&mut self, op: &FileOp) -> std::io::Result<()> {
-        let old_state = self.query();
+    /// Apply an operation written on this replica.
+    fn apply_op(&mut self, tree_op: &TreeOp) -> std::io::Result<()> {
+        let hash = object::op_hash(&self.config, tree_op)?;
+        self.apply_op_as(tree_op, hash)
+    }
+
+    /// Apply an operation, recording it in the history as `hash`.
+    fn apply_op_as(&mut self, tree_op: &TreeOp, hash: types::Hash) -> std::io::Result<()> {
+        let op = &tree_op.file_op;
+        let old_state = self.query().clone();
         let mut new_state = old_state.clone();
 
         match op {
-            FileOp::NewFile(id, name, path, creator_id) => {
+            FileOp::NewFile(id, name, placement) => {
                 // Create Driver
+                // The location is set properly once the new paths are known, in `relocate`.
                 let driver = AvailDrivers::new_from_name(
-                    *name, self.config.clone(), &object::Location::Path(path.clone(), true),
+                    *name, self.config.clone(), &object::Location::Path(placement.name.clone(), true),
                     self.replica_id, // This ID is used for creating operations locally.
                     *id,
                 );
@@ -287,52 +906,252 @@ impl FileManager {
                 self.drivers.insert(*id, driver);
 
                 let info = FileInfo {
-                    paths: yata::Array::from((
-                        [path.clone()].into_iter(),
-                        *creator_id, // This ID is the ID where the paths was created, and needs to be consistent across all replicas.
-                    )),
+                    placements: Vec::from([placement.clone()]),
                     deleted: false,
+                    mode: None,
+                    mtime: None,
                 };
 
-                new_state.insert(*id, info);
+                new_state.files.insert(*id, info);
             },
-            FileOp::MoveFile(id, ins, ins_id) => {let old_info = old_state.get(id).expect("No driver with given id.");
-                let old_path = old_info.get_path();
-
-                // Insert `ins`
-                let new_info = new_state.get_mut(id).expect("No driver with given id.");
-                new_info.insert_path(ins.clone(), *ins_id);
-                let new_path = new_info.get_path();
-
-                // Check if old_path != new_path
-                //   If so, move the file
-                if old_path != new_path && !new_info.deleted {
-                    std::fs::rename(old_path, new_path)?;
-                }
+            FileOp::MoveFile(id, placement) => {
+                new_state.files.get_mut(id).expect("No driver with given id.").add_placement(placement.clone());
             },
             FileOp::DelFile(id) => {
-                let file_info = new_state.get_mut(id).expect("No driver with given id.");
+                new_state.files.get_mut(id).expect("No driver with given id.").deleted = true;
+            },
+            FileOp::NewDir(id, placement) => {
+                new_state.dirs.insert(*id, DirInfo {
+                    placements: Vec::from([placement.clone()]),
+                    deleted: false,
+                });
+            },
+            FileOp::MoveDir(id, placement) => {
+                new_state.dirs.get_mut(id).expect("No directory with given id.").add_placement(placement.clone());
+            },
+            FileOp::DelDir(id) => {
+                new_state.dirs.get_mut(id).expect("No directory with given id.").deleted = true;
+            },
+            FileOp::NewLink(id, placement, target) => {
+                new_state.links.insert(*id, LinkInfo {
+                    placements: Vec::from([placement.clone()]),
+                    target: target.clone(),
+                    deleted: false,
+                });
+            },
+            FileOp::MoveLink(id, placement) => {
+                new_state.links.get_mut(id).expect("No link with given id.").add_placement(placement.clone());
+            },
+            FileOp::SetLink(id, target) => {
+                new_state.links.get_mut(id).expect("No link with given id.").target.merge(target.clone());
+            },
+            FileOp::DelLink(id) => {
+                new_state.links.get_mut(id).expect("No link with given id.").deleted = true;
+            },
+            FileOp::SetMode(id, mode) => {
+                let info = new_state.files.get_mut(id).expect("No driver with given id.");
+                match info.mode.as_mut() {
+                    Some(r) => r.merge(mode.clone()),
+                    None => info.mode = Some(mode.clone()),
+                }
+            },
+            FileOp::SetMTime(id, mtime) => {
+                let info = new_state.files.get_mut(id).expect("No driver with given id.");
+                match info.mtime.as_mut() {
+                    Some(r) => r.merge(mtime.clone()),
+                    None => info.mtime = Some(mtime.clone()),
+                }
+            },
+        };
+
+        if let Some(ts) = op.get_timestamp() {
+            self.clock = self.clock.max(ts.0);
+        }
 
-                let path = file_info.get_path(); let loc = object::Location::Path(path.clone(), true);
-                if loc.exists(&self.config) {
-                    // Delete file.
-                    // std::fs::remove_file(path)?;
-                    object::delete(&self.config, &loc)?;
+        // If a concurrent move displaces our own move of a file, keep a copy where we moved it.
+        // It is picked up as a new file on the next update.
+        let mut kept_copy = None;
+        if let (FileOp::MoveFile(id, placement), MovePolicy::KeepBoth) = (op, self.config.move_conflicts) {
+            let old_placements = old_state.resolve();
+            let ours = old_placements.get(&NodeID::File(*id));
+            let theirs = new_state.resolve().get(&NodeID::File(*id)).map(|p| p.ts);
+
+            if let (Some(ours), Some(old_path)) = (ours, old_state.paths(&self.config.paths).remove(&NodeID::File(*id))) {
+                let concurrent = ours.ts.1 == self.replica_id && ours.prev == placement.prev && ours.ts != placement.ts;
+                let from = object::Location::Path(old_path.clone(), true).get_path(&self.config);
+
+                if concurrent && theirs == Some(placement.ts) && from.is_file() {
+                    let copy = self.config.working_dir.join(STAGING_DIR).join(rand::rng().random::<u64>().to_string());
+                    std::fs::create_dir_all(copy.parent().unwrap())?;
+                    std::fs::copy(&from, &copy)?;
+                    kept_copy = Some((copy, from));
                 }
+            }
+        }
 
-                file_info.deleted = true;
+        self.relocate(&old_state, &new_state)?;
+
+        // Report name collisions caused by this operation. The losing node has already been given a unique name.
+        let old_collisions = old_state.collisions(&self.config.paths);
+        let (new_paths, new_placements) = (new_state.paths(&self.config.paths), new_state.resolve());
+        let mut collisions: Vec<_> = new_state.collisions(&self.config.paths).into_keys().filter(|n| !old_collisions.contains_key(n)).collect();
+        collisions.sort();
+        for node in collisions.into_iter() {
+            if let (Some(path), Some(p)) = (new_paths.get(&node), new_placements.get(&node)) {
+                println!("Name collision: {:?} renamed to {:?}.", path.with_file_name(&p.name), path);
             }
-        };
+        }
 
-        let k = self.hist.add(op.to_history());
+        if let Some((copy, to)) = kept_copy {
+            if !exists_nofollow(&to) {
+                println!("Conflicting move: kept a copy at {:?}.", to);
+                std::fs::rename(copy, to)?;
+            } else {
+                std::fs::remove_file(copy)?;
+            }
+        }
+
+        let k = self.hist.add(Some(hash), &tree_op.parents);
         self.state.insert(k, new_state);
 
         return Ok(());
     }
 
+    /// Make the on-disk tree match `new_state`, given that it currently matches `old_state`.
+    /// Changes which have already happened on disk (i.e. local changes) are skipped.
+    fn relocate(&mut self, old_state: &FileState, new_state: &FileState) -> std::io::Result<()> {
+        let (old_paths, new_paths) = (old_state.paths(&self.config.paths), new_state.paths(&self.config.paths));
+        let (old_placements, new_placements) = (old_state.resolve(), new_state.resolve());
+
+        let abs = |p: &PathBuf| object::Location::Path(p.clone(), true).get_path(&self.config);
+
+        // Nodes which have been moved - i.e. whose own placement has changed, or which have been renamed to resolve a
+        // name collision, or given their name back once it was resolved.
+        // Nodes moved only because an ancestor has moved will move along with it.
+        let mut moved: Vec<NodeID> = new_paths.keys()
+            .filter(|n| old_paths.contains_key(n) && (
+                old_placements.get(n) != new_placements.get(n) || old_paths[n].file_name() != new_paths[n].file_name()
+            ))
+            .cloned().collect();
+
+        let mut removed: Vec<_> = old_paths.iter().filter(|(n, _)| !new_paths.contains_key(n)).collect();
+
+        // Paths being moved or removed, which nodes may take over, e.g. the name a collision's loser gets back.
+        let vacated: HashSet<PathBuf> = moved.iter().map(|n| abs(&old_paths[n])).chain(removed.iter().map(|(_, p)| abs(p)))
+            .filter(|p| exists_nofollow(p)).collect();
+
+        // Move out to a staging area deepest first, so that old paths remain valid.
+        moved.sort_by_key(|n| (std::cmp::Reverse(old_paths[n].components().count()), *n));
+        let mut staged = Vec::new();
+        for node in moved.into_iter() {
+            let (from, to) = (abs(&old_paths[&node]), abs(&new_paths[&node]));
+            if exists_nofollow(&from) && (!exists_nofollow(&to) || vacated.contains(&to)) {
+                let mut staging = self.config.working_dir.join(STAGING_DIR);
+                std::fs::create_dir_all(&staging)?;
+                staging.push(rand::rng().random::<u64>().to_string());
+
+                std::fs::rename(&from, &staging)?;
+                staged.push((node, staging, from));
+            }
+        }
+
+        // Removed files and links, then removed directories (deepest first), before anything takes their place.
+        removed.sort_by_key(|(_, path)| std::cmp::Reverse(path.components().count()));
+        for (node, path) in removed.into_iter() {
+            let loc = object::Location::Path(path.clone(), true);
+            if !exists_nofollow(&loc.get_path(&self.config)) {continue;}
+
+            match node {
+                NodeID::File(_) => object::delete(&self.config, &loc)?,
+                // Only remove directories which are empty, so as not to lose untracked files.
+                NodeID::Dir(_) => {let _ = std::fs::remove_dir(loc.get_path(&self.config));},
+                NodeID::Link(_) => if loc.get_path(&self.config).is_symlink() {
+                    std::fs::remove_file(loc.get_path(&self.config))?;
+                },
+            }
+        }
+
+        // Move into place shallowest first, so that parents are in place first.
+        staged.sort_by_key(|(n, _, _)| (new_paths[n].components().count(), *n));
+        for (node, staging, from) in staged.into_iter() {
+            let to = abs(&new_paths[&node]);
+            if let Some(parent) = to.parent() {std::fs::create_dir_all(parent)?;}
+            if std::fs::rename(&staging, to).is_err() {
+                // A removed directory still holding untracked files is in the way, so leave the node where it was.
+                if let Some(parent) = from.parent() {std::fs::create_dir_all(parent)?;}
+                std::fs::rename(staging, from)?;
+            }
+        }
+
+        // New directories
+        for (node, path) in new_paths.iter() {
+            if let NodeID::Dir(_) = node {
+                if !old_paths.contains_key(node) {std::fs::create_dir_all(abs(path))?;}
+            }
+        }
+
+        // New and retargeted links. These are only materialised when replicating links.
+        if self.config.symlinks == SymlinkPolicy::Replicate {
+            for (node, path) in new_paths.iter() {
+                let NodeID::Link(id) = node else {continue};
+                let target = new_state.links[id].get_target();
+
+                let retargeted = old_state.links.get(id).is_some_and(|info| info.get_target() != target);
+                if old_paths.contains_key(node) && !retargeted {continue;}
+
+                if !link_within(path, target) {
+                    println!("Warn: Not creating symlink {:?}, which points outside the working directory.", path);
+                    continue;
+                }
+
+                let loc = object::Location::Path(path.clone(), true);
+                let existing = std::fs::read_link(loc.get_path(&self.config)).ok();
+                if existing.as_ref() == Some(target) {continue;}
+                if existing.is_some() {std::fs::remove_file(loc.get_path(&self.config))?;}
+
+                if !exists_nofollow(&loc.get_path(&self.config)) {object::write_link(&self.config, &loc, target)?;}
+            }
+        }
+
+        // Point drivers at their new locations.
+        for (node, path) in new_paths.iter() {
+            if let NodeID::File(id) = node {
+                if let Some(driver) = self.drivers.get_mut(id) {
+                    driver.set_loc(object::Location::Path(path.clone(), true));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn update_drivers(&mut self) -> Result<(), errors::Error> {
         for id in self.get_active_drivers().iter() {
-            self.drivers.get_mut(id).unwrap().update()?;
+            let driver = self.drivers.get_mut(id).unwrap();
+
+            // Changes the driver can't manage are left on disk, rather than stopping every other file syncing.
+            if let Some(reason) = driver.unsupported_on_disk() {
+                println!("Warn: Not syncing changes to {:?}, which can't be managed ({}).", driver.get_path(), reason);
+                continue;
+            }
+
+            driver.update()?;
+        }
+
+        Ok(())
+    }
+
+    /// Warn about files which aren't tracked because their driver can't manage what they hold, e.g. markdown with
+    /// images. They're tracked once they can be.
+    fn warn_unsupported(&self) -> std::io::Result<()> {
+        let paths = self.query().paths(&self.config.paths);
+        let tracked: HashSet<&PathBuf> = paths.values().collect();
+
+        for path in self.list_dir()?.0.iter().filter(|p| !tracked.contains(p)) {
+            let loc = object::Location::Path(path.clone(), true);
+            if let Some(reason) = AvailDrivers::get_name(&loc).and_then(|name| AvailDrivers::unsupported(name, &self.config, &loc)) {
+                println!("Warn: Not syncing {:?}, which can't be managed ({}).", path, reason);
+            }
         }
 
         Ok(())
@@ -342,8 +1161,33 @@ impl FileManager {
         storage::meta::write(&self.config, &String::from("filetree"), self)
     }
 
+    /// Read the persisted file tree. State persisted in another format can't be read, and the replica must be re-initialised.
     pub fn read_in(config: &storage::Config) -> std::io::Result<Self> {
-        storage::meta::read(config, &String::from("filetree"))
+        #[derive(Deserialize)]
+        struct Header {
+            #[serde(default)]
+            format: u32,
+        }
+
+        let Header {format} = storage::meta::read(config, &String::from("filetree"))?;
+        if format != FORMAT_VERSION {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!(
+                "File tree state is in format {}, but this version of CRFS uses format {}. Re-init required: move the \
+                files out of the working directory, delete its `.crfs` directory and run `init` again.",
+                format, FORMAT_VERSION,
+            )));
+        }
+
+        let mut manager: Self = storage::meta::read(config, &String::from("filetree"))?;
+
+        // Keys aren't written out with the rest of the config.
+        manager.config.key = config.key.clone();
+        manager.config.signer = config.signer.clone();
+        for driver in manager.drivers.values_mut() {
+            driver.set_config(storage::Config {key: config.key.clone(), signer: config.signer.clone(), ..driver.get_config()});
+        }
+
+        return Ok(manager);
     }
 
     pub fn read_or_init(config: &storage::Config, replica_id: Uuid) -> std::io::Result<Self> {
@@ -354,18 +1198,22 @@ impl FileManager {
         }
     }
 
-    fn get_op(&self, hash: &types::Hash) -> std::io::Result<FileOp> {
-        let loc = object::Location::Object(hash.clone());
-        let mut json = String::new(); object::read_string(&self.config, &loc, &mut json)?;
-        return FileOp::deserialize_from_str(json);
+    fn get_op(&self, hash: &types::Hash) -> std::io::Result<TreeOp> {
+        let mut json = String::new(); object::read_obj(&self.config, hash, &mut json)?;
+        if is_legacy_op(&json) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, LEGACY_OP_ERR));
+        }
+
+        return TreeOp::deserialize_from_str(json);
     }
 
-    fn write_op(&self, op: FileOp) -> std::io::Result<types::Hash> {
+    fn write_op(&self, op: TreeOp) -> std::io::Result<types::Hash> {
         return object::write_op(&self.config, op);
     }
 
     fn update_self(&mut self) -> std::io::Result<()> {
-        while let Some(op) = self.prep()? {
+        while let Some(file_op) = self.prep()? {
+            let op = TreeOp {file_op, parents: self.hist.heads()};
             self.apply_op(&op)?;
             self.write_op(op)?;
         }
@@ -374,11 +1222,99 @@ impl FileManager {
     }
 
     pub fn update(&mut self) -> Result<(), errors::Error> {
+        self.normalise_names()?;
         self.update_self()?;
+        self.warn_unsupported()?;
         self.update_drivers()?;
+
+        // Merges generate operations, so are only made by replicas which may change the FS, i.e. which update.
+        if self.config.name_collisions == CollisionPolicy::Merge {
+            self.merge_collisions()?;
+        }
+
+        // Retry operations which couldn't be applied before.
+        if !PendingOps::read_in(&self.config)?.is_empty() {
+            self.apply_ops(&Vec::new())?;
+        }
+
         Ok(())
     }
 
+    /// Paths which have been changed locally, without generating operations for them. Only the first change to the
+    /// tree itself is found, as finding the next would depend on it, along with every file whose contents have changed.
+    pub fn local_changes(&self) -> std::io::Result<Vec<PathBuf>> {
+        let paths = self.query().paths(&self.config.paths);
+
+        let mut changed: Vec<PathBuf> = self.prep()?.map(|op| change_path(&paths, &op)).into_iter().collect();
+        changed.extend(self.changed_contents(&paths).into_iter().map(|id| paths[&NodeID::File(id)].clone()));
+
+        changed.sort();
+        changed.dedup();
+        return Ok(changed);
+    }
+
+    /// Discard local changes, so that the working directory matches the replicated state, rather than generating
+    /// operations from them. Used by replicas which may not change the FS.
+    /// Local files which would be discarded are moved to the trash, so nothing is lost. Returns the restored paths.
+    pub fn restore(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        let state = self.query().clone();
+        let paths = state.paths(&self.config.paths);
+        let abs = |p: &PathBuf| object::Location::Path(p.clone(), true).get_path(&self.config);
+
+        // Undo changes to the tree one at a time, as `prep` finds them, since undoing one may reveal others.
+        let mut restored: Vec<PathBuf> = Vec::new();
+        let mut undone = HashSet::new();
+        while let Some(op) = self.prep()? {
+            let path = change_path(&paths, &op);
+            if !undone.insert((std::mem::discriminant(&op), path.clone())) {
+                return Err(std::io::Error::other(format!("Unable to restore {:?}.", path)));
+            }
+
+            match &op {
+                FileOp::NewFile(..) | FileOp::NewDir(..) | FileOp::NewLink(..) => {
+                    object::delete(&self.config, &object::Location::Path(path.clone(), true))?;
+                },
+                FileOp::MoveFile(_, p) | FileOp::MoveDir(_, p) | FileOp::MoveLink(_, p) => {
+                    if let Some(parent) = abs(&path).parent() {std::fs::create_dir_all(parent)?;}
+                    std::fs::rename(abs(&placement_path(&paths, p)), abs(&path))?;
+                },
+                FileOp::DelFile(id) => {
+                    object::ensure_dir(&self.config, &object::Location::Path(path.clone(), true))?;
+                    self.write_file(id, &paths)?;
+                },
+                FileOp::SetMode(id, _) | FileOp::SetMTime(id, _) => self.write_metadata(id, &paths)?,
+                FileOp::DelDir(_) => std::fs::create_dir_all(abs(&path))?,
+                FileOp::SetLink(id, _) | FileOp::DelLink(id) => {
+                    if abs(&path).is_symlink() {std::fs::remove_file(abs(&path))?;}
+                    object::write_link(&self.config, &object::Location::Path(path.clone(), true), state.links[id].get_target())?;
+                },
+            }
+
+            restored.push(path);
+        }
+
+        // Undo changes to the contents of files.
+        for id in self.changed_contents(&paths) {
+            self.write_file(&id, &paths)?;
+            restored.push(paths[&NodeID::File(id)].clone());
+        }
+
+        restored.sort();
+        restored.dedup();
+        return Ok(restored);
+    }
+
+    /// Files whose contents on disk differ from the replicated state.
+    fn changed_contents(&self, paths: &HashMap<NodeID, PathBuf>) -> Vec<DriverID> {
+        let mut changed: Vec<DriverID> = self.get_active_drivers().into_iter()
+            .filter(|id| paths.get(&NodeID::File(*id))
+                .is_some_and(|path| !self.drivers[id].same_content(&object::Location::Path(path.clone(), true))))
+            .collect();
+        changed.sort();
+
+        return changed;
+    }
+
     pub fn get_history(&self) -> SystemHistory {
         return SystemHistory {
             tree: self.hist.clone(),
@@ -386,32 +1322,177 @@ impl FileManager {
         }
     }
 
+    /// Apply the operations `hashes`, along with any pending operations from previous calls.
+    /// Operations which can't be applied are recorded as pending, to be retried later.
     pub fn apply_ops(&mut self, hashes: &Vec<&types::Hash>) -> std::io::Result<()> {
+        let mut pending = PendingOps::read_in(&self.config)?;
+
+        let mut all: Vec<types::Hash> = hashes.iter().map(|h| **h).collect();
+        for hash in pending.hashes() {
+            if !all.contains(&hash) {all.push(hash);}
+        }
+
+        // Replicas which sign their operations only apply operations signed by a replica enrolled in the FS.
+        let mut rejected: Vec<(types::Hash, String)> = Vec::new();
+        if self.config.signer.is_some() {
+            let keyring = sign::Keyring::read_in(&self.config)?;
+            all.retain(|hash| match object::verify_op(&self.config, &keyring, hash) {
+                Ok(_) => true,
+                Err(e) => {rejected.push((*hash, e)); false},
+            });
+        }
+        let hashes: &Vec<&types::Hash> = &all.iter().collect();
+
         let mut applied_ops: HashSet<&types::Hash> = HashSet::new();
 
         applied_ops = applied_ops.union(&self.apply(hashes)?).cloned().collect();
 
+        let paths = self.query().paths(&self.config.paths);
         for id in self.get_active_drivers() {
             let driver = self.drivers.get_mut(&id).unwrap();
             applied_ops = applied_ops.union(
                 &driver.apply(hashes)?
             ).cloned().collect();
 
-            driver.write_out()?;
+            // Local changes the driver can't manage aren't overwritten, see `update_drivers`.
+            if let Some(reason) = driver.unsupported_on_disk() {
+                println!("Warn: Not writing out {:?}, which has local changes that can't be managed ({}).", driver.get_path(), reason);
+                continue;
+            }
+
+            self.write_file(&id, &paths)?;
         }
 
-        let n_unapplied = hashes.len() - applied_ops.len();
+        let history = self.get_history().all_hashes();
+        let mut unapplied: Vec<types::Hash> = hashes.iter().map(|h| **h).filter(|h| !history.contains(h)).collect();
+        unapplied.sort();
+
+        pending.remove_applied(&history);
+        for hash in unapplied.iter() {
+            match self.pending_reason(hash) {
+                Some(reason) => pending.record(*hash, reason),
+                None => pending.remove_applied(&HashSet::from([*hash])),
+            }
+        }
+        for (hash, e) in rejected.iter() {
+            pending.record(*hash, PendingReason::Unverified(e.clone()));
+        }
+        pending.write_out(&self.config)?;
 
-        if n_unapplied > 0 {
-            println!("{} operations unable to be applied!", n_unapplied);
+        if !unapplied.is_empty() || !rejected.is_empty() {
+            println!(
+                "{} operations unable to be applied! Run the `pending` command for details.", unapplied.len() + rejected.len(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Work out why the operation `hash` can't be applied.
+    /// Returns `None` if it never will be, as the file it belongs to has been deleted.
+    fn pending_reason(&self, hash: &types::Hash) -> Option<PendingReason> {
+        let mut json = String::new();
+        if let Err(e) = object::read_obj(&self.config, hash, &mut json) {
+            return Some(PendingReason::DecodeError(e.to_string()));
+        }
+
+        let missing = |hist: &CmRDT::History, parents: Vec<types::Hash>| {
+            let have = hist.get_hashes();
+            PendingReason::MissingDependency(parents.into_iter().filter(|p| !have.contains(p)).collect())
+        };
+
+        let (driverid, parents) = match read_header(&json) {
+            Ok(header) => header,
+            Err(e) => return Some(PendingReason::DecodeError(e)),
+        };
+
+        return match driverid {
+            DriverID::FileTree => Some(missing(&self.hist, parents)),
+            id if self.query().files.get(&id).is_some_and(|f| f.deleted) => None,
+            id => match self.drivers.get(&id) {
+                Some(driver) => Some(missing(&driver.get_history(), parents)),
+                None => Some(PendingReason::UnknownDriver(id)),
+            },
+        };
+    }
+
+    /// Merge files created on this replica which lost a name collision with another new file into the file which kept
+    /// the name. The loser's document is merged into the winner's by its driver, and the loser is deleted.
+    /// Only the replica which created the loser merges, so that the contents aren't duplicated. The collision policy
+    /// belongs to the FS, so every replica agrees on whether to merge.
+    fn merge_collisions(&mut self) -> Result<(), errors::Error> {
+        let state = self.query();
+        let (paths, placements) = (state.paths(&self.config.paths), state.resolve());
+
+        let mut merges: Vec<(DriverID, DriverID)> = state.collisions(&self.config.paths).into_iter()
+            .filter_map(|(loser, winner)| match (loser, winner) {
+                (NodeID::File(l), NodeID::File(w)) => Some((l, w)),
+                _ => None,
+            })
+            .filter(|(l, w)| {
+                let ours = placements[&NodeID::File(*l)].ts.1 == self.replica_id;
+                let unmoved = state.files[l].placements.len() == 1 && state.files[w].placements.len() == 1;
+                ours && unmoved
+            })
+            .collect();
+        merges.sort();
+
+        if merges.is_empty() {return Ok(());}
+
+        for (loser, winner) in merges.iter() {
+            let other = self.drivers[loser].clone();
+            match self.drivers.get_mut(winner).unwrap().merge(&other) {
+                Some(merged) => merged?,
+                None => continue, // Files of different types can't be merged, so keep both.
+            }
+
+            self.write_file(winner, &paths)?;
+            std::fs::remove_file(object::Location::Path(paths[&NodeID::File(*loser)].clone(), true).get_path(&self.config))?;
+
+            println!("Name collision: merged {:?} into {:?}.", paths[&NodeID::File(*loser)], paths[&NodeID::File(*winner)]);
+        }
+
+        // Record the deletion of the merged files.
+        self.update_self()?;
+
+        Ok(())
+    }
+
+    /// Write a file's contents and metadata out to the working directory, even if the file is read-only on disk.
+    fn write_file(&self, id: &DriverID, paths: &HashMap<NodeID, PathBuf>) -> std::io::Result<()> {
+        with_writable(&self.drivers[id].get_path(), || self.drivers[id].write_out())?;
+        self.write_metadata(id, paths)
+    }
+
+    /// Apply the replicated permissions and modification time of a file to the on-disk file.
+    /// This must be done after the driver writes out, as writing will update the modification time.
+    fn write_metadata(&self, id: &DriverID, paths: &HashMap<NodeID, PathBuf>) -> std::io::Result<()> {
+        let info = &self.query().files[id];
+        let path = match paths.get(&NodeID::File(*id)) {
+            Some(p) => object::Location::Path(p.clone(), true).get_path(&self.config),
+            None => return Ok(()),
+        };
+
+        // Setting the modification time doesn't need the file to be writable, e.g. if it was made read-only earlier.
+        if self.config.mtimes {
+            if let Some(mtime) = &info.mtime {
+                filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(mtime.value as i64, 0))?;
+            }
+        }
+
+        if self.config.permissions {
+            if let Some(mode) = &info.mode {set_mode(&path, mode.value)?;}
         }
 
         Ok(())
     }
 
     pub fn canonize(&mut self) -> std::io::Result<()> {
+        let paths = self.query().paths(&self.config.paths);
         for id in self.get_active_drivers() {
-            self.drivers.get_mut(&id).unwrap().write_out()?;
+            if self.drivers[&id].unsupported_on_disk().is_some() {continue;}
+
+            self.write_file(&id, &paths)?;
         }
 
         self.write_out()?;
@@ -419,3 +1500,155 @@ impl FileManager {
         Ok(())
     }
 }
+
+/// Read the parents of the stored operation `hash`, whether it belongs to the file tree or a driver.
+/// Operations in an unrecognised format have no known parents.
+pub fn read_parents(config: &storage::Config, hash: &types::Hash) -> std::io::Result<Vec<types::Hash>> {
+    let mut json = String::new(); object::read_obj(config, hash, &mut json)?;
+    return Ok(read_header(&json).map(|(_, parents)| parents).unwrap_or_default());
+}
+
+/// Get the ID of the driver a stored operation belongs to, and its parents, without knowing the driver's types.
+fn read_header(json: &str) -> Result<(DriverID, Vec<types::Hash>), String> {
+    if let Ok(op) = TreeOp::deserialize_from_str(json.to_owned()) {
+        return Ok((DriverID::FileTree, op.parents));
+    }
+
+    if is_legacy_op(json) {
+        return Err(LEGACY_OP_ERR.to_owned());
+    }
+
+    // Driver operations are externally tagged enums, holding the ID of their driver and their dependencies.
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let fields = value.as_object().and_then(|o| o.values().next());
+
+    let driverid: Option<DriverID> = fields.and_then(|f| serde_json::from_value(f.get("driverid")?.clone()).ok());
+    let deps: Vec<types::Hash> = fields
+        .and_then(|f| crdt::deserialize_deps(f.get("deps").or(f.get("dep"))?.clone()).ok())
+        .unwrap_or_default();
+
+    return match driverid {
+        Some(id) => Ok((id, deps)),
+        None => Err("Not a recognised operation.".to_owned()),
+    };
+}
+
+const LEGACY_OP_ERR: &str = "Operation is in the format from before directories were tracked. Re-init required on \
+    the replica which made it.";
+
+/// Whether a stored operation is a file tree operation from before directories were tracked, when they were bare
+/// `NewFile`, `MoveFile` or `DelFile` operations rather than wrapped in a `TreeOp`.
+fn is_legacy_op(json: &str) -> bool {
+    let value: serde_json::Value = match serde_json::from_str(json) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+
+    return value.as_object().is_some_and(|o| {
+        o.len() == 1 && o.keys().all(|k| ["NewFile", "MoveFile", "DelFile"].contains(&k.as_str()))
+    });
+}
+
+/// Insert `tag` into a file name, before its extension, e.g. `todo.md` -> `todo (tag).md`.
+fn conflict_name(name: &Path, tag: &str) -> PathBuf {
+    let stem = name.file_stem().unwrap_or(name.as_os_str()).to_string_lossy();
+    match name.extension() {
+        Some(ext) => PathBuf::from(format!("{} ({}).{}", stem, tag, ext.to_string_lossy())),
+        None => PathBuf::from(format!("{} ({})", stem, tag)),
+    }
+}
+
+/// Where a node is placed, given the paths of the directories in the current state.
+fn placement_path(paths: &HashMap<NodeID, PathBuf>, placement: &Placement) -> PathBuf {
+    match placement.parent {
+        DirID::Root => placement.name.clone(),
+        dir => paths[&NodeID::Dir(dir)].join(&placement.name),
+    }
+}
+
+/// The path of a local change found by `prep`: where a new node is, or where an existing node was in the current state.
+fn change_path(paths: &HashMap<NodeID, PathBuf>, op: &FileOp) -> PathBuf {
+    match op {
+        FileOp::NewFile(_, _, p) | FileOp::NewDir(_, p) | FileOp::NewLink(_, p, _) => placement_path(paths, p),
+        FileOp::MoveFile(id, _) | FileOp::DelFile(id) | FileOp::SetMode(id, _) | FileOp::SetMTime(id, _) => paths[&NodeID::File(*id)].clone(),
+        FileOp::MoveDir(id, _) | FileOp::DelDir(id) => paths[&NodeID::Dir(*id)].clone(),
+        FileOp::MoveLink(id, _) | FileOp::SetLink(id, _) | FileOp::DelLink(id) => paths[&NodeID::Link(*id)].clone(),
+    }
+}
+
+/// Check if a path exists, without following symlinks.
+fn exists_nofollow(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok()
+}
+
+/// Check that a link at `path` (relative to the working directory) pointing to `target` stays within the working directory.
+/// Absolute targets are never considered within the working directory, since it may be in a different place on each replica.
+fn link_within(path: &Path, target: &Path) -> bool {
+    if target.is_absolute() {return false;}
+
+    let mut depth = 0usize;
+    for c in path.parent().unwrap_or(Path::new("")).join(target).components() {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {},
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    return true;
+}
+
+/// Get the permission bits of a file, if supported on this platform.
+fn get_mode(meta: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        return Some(meta.permissions().mode() & 0o777);
+    }
+
+    #[cfg(not(unix))]
+    return None;
+}
+
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        return std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode));
+    }
+
+    #[cfg(not(unix))]
+    return Ok(());
+}
+
+/// Run `f` with the file at `path` writable by its owner, then restore its permissions, so that files which are
+/// read-only on disk can still be updated.
+fn with_writable<T>(path: &Path, f: impl FnOnce() -> std::io::Result<T>) -> std::io::Result<T> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = match std::fs::metadata(path) {
+            Ok(meta) if meta.permissions().mode() & 0o200 == 0 => meta.permissions().mode(),
+            _ => return f(),
+        };
+
+        set_mode(path, mode | 0o200)?;
+        let result = f();
+        set_mode(path, mode)?;
+        return result;
+    }
+
+    #[cfg(not(unix))]
+    return f();
+}
+
+/// Get the modification time of a file in whole seconds since the UNIX epoch.
+/// Sub-second precision is dropped, as not all filesystems store it.
+fn get_mtime(meta: &std::fs::Metadata) -> Option<u64> {
+    Some(meta.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs())
+}
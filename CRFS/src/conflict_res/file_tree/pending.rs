@@ -0,0 +1,93 @@
+// Queue of operations which have been fetched, but could not yet be applied.
+// Persisted in `.crfs/meta/pending.json`, and retried on every update or sync.
+
+use super::DriverID;
+use crate::storage;
+use crate::types::{self, Hash};
+
+use std::collections::HashSet;
+
+use serde::{Serialize, Deserialize};
+
+const PENDING_META: &str = "pending";
+
+/// Why an operation could not be applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PendingReason {
+    /// Some operations it depends on haven't been applied yet.
+    /// Empty if all its parents have been applied, but its precondition still fails.
+    MissingDependency(Vec<Hash>),
+    /// The driver it belongs to doesn't exist, e.g. the file hasn't been created yet.
+    UnknownDriver(DriverID),
+    /// The operation couldn't be read or decoded.
+    DecodeError(String),
+    /// The operation isn't signed by a replica enrolled in the FS, e.g. as the replica has been removed.
+    /// Kept in case the signer is a replica which enrolled since the keyring was last refreshed.
+    Unverified(String),
+}
+
+impl std::fmt::Display for PendingReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingDependency(deps) if deps.is_empty() => write!(f, "precondition not satisfied"),
+            Self::MissingDependency(deps) => write!(
+                f, "missing dependencies {}", deps.iter().map(types::hash_to_str).collect::<Vec<_>>().join(", "),
+            ),
+            Self::UnknownDriver(id) => write!(f, "unknown driver {:?}", id),
+            Self::DecodeError(e) => write!(f, "decode error: {}", e),
+            Self::Unverified(e) => write!(f, "not verified: {}", e),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingOp {
+    pub hash: Hash,
+    pub reason: PendingReason,
+    /// Number of times applying this operation has failed.
+    pub attempts: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PendingOps {
+    ops: Vec<PendingOp>,
+}
+
+impl PendingOps {
+    pub fn read_in(config: &storage::Config) -> std::io::Result<Self> {
+        match storage::meta::read(config, &PENDING_META.to_owned()) {
+            Ok(p) => Ok(p),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write_out(&self, config: &storage::Config) -> std::io::Result<()> {
+        storage::meta::write(config, &PENDING_META.to_owned(), self)
+    }
+
+    pub fn hashes(&self) -> Vec<Hash> {
+        self.ops.iter().map(|op| op.hash).collect()
+    }
+
+    pub fn ops(&self) -> &Vec<PendingOp> {
+        &self.ops
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Record a failed attempt to apply the operation `hash`.
+    pub fn record(&mut self, hash: Hash, reason: PendingReason) {
+        match self.ops.iter_mut().find(|op| op.hash == hash) {
+            Some(op) => {op.reason = reason; op.attempts += 1;},
+            None => self.ops.push(PendingOp {hash, reason, attempts: 1}),
+        }
+    }
+
+    /// Remove every operation which has now been applied.
+    pub fn remove_applied(&mut self, applied: &HashSet<Hash>) {
+        self.ops.retain(|op| !applied.contains(&op.hash));
+    }
+}
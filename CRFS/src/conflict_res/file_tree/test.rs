@@ -1,8 +1,11 @@
-use super::{DriverID, FileManager};
+use super::{DirID, DriverID, FileManager, FileOp, NodeID, TreeOp};
+use super::pending::{PendingOps, PendingReason};
+use crate::types;
 use crate::storage;
-use crate::tests::storage_test::TESTFILEDIR;
+use crate::tests::storage_test::{TESTFILEDIR, temp_working_dir};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
@@ -13,12 +16,12 @@ use uuid::Uuid;
 fn test_listdir() {
     let mut path = PathBuf::from(TESTFILEDIR); path.push("managertest");
 
-    let config = storage::Config {working_dir: path};
+    let config = storage::Config::new(path);
     let manager = FileManager::init(config, Uuid::from_u128(1));
 
     dbg!(manager.list_dir().unwrap());
 
-    let mut result = manager.list_dir().unwrap(); result.sort();
+    let (mut result, mut dirs, _) = manager.list_dir().unwrap(); result.sort(); dirs.sort();
     let mut expected: Vec<_> = vec!(
         "a.md",
         "1/b.md",
@@ -26,20 +29,27 @@ fn test_listdir() {
         "2/d.md",
         "2/3/e.md",
     ).into_iter().map(|x| PathBuf::from(x)).collect(); expected.sort();
+    let mut expected_dirs: Vec<_> = vec!(
+        "1",
+        "2",
+        "2/3",
+    ).into_iter().map(|x| PathBuf::from(x)).collect(); expected_dirs.sort();
 
     assert_eq!(result, expected);
+    assert_eq!(dirs, expected_dirs);
 }
 
 #[test]
 fn test_filemanager() {
     let mut path = PathBuf::from(TESTFILEDIR); path.push("managertest");
 
-    let config = storage::Config {working_dir: path}; let uuid = Uuid::from_u128(1);
+    let config = storage::Config::new(path); let uuid = Uuid::from_u128(1);
     let mut manager = FileManager::init(config, uuid);
 
     while let Ok(Some(op)) = manager.prep() {
         dbg!(&op);
 
+        let op = TreeOp {file_op: op, parents: manager.hist.heads()};
         manager.apply_op(&op).unwrap();
     }
 
@@ -49,12 +59,13 @@ fn test_filemanager() {
 #[test]
 fn test_persistent_fm() {
     let mut path = PathBuf::from(TESTFILEDIR); path.push("managertest");
-    let config = storage::Config {working_dir: path};
+    let config = storage::Config::new(path);
     let mut manager = FileManager::read_or_init(&config, Uuid::from_u128(1)).unwrap();
 
     while let Ok(Some(op)) = manager.prep() {
         dbg!(&op);
 
+        let op = TreeOp {file_op: op, parents: manager.hist.heads()};
         manager.apply_op(&op).unwrap();
     }
 
@@ -70,7 +81,7 @@ fn test_apply() {
     let mut path1 = PathBuf::from(TESTFILEDIR); path1.push("apply1");
     let mut path2 = PathBuf::from(TESTFILEDIR); path2.push("apply2");
 
-    let conf1 = storage::Config {working_dir: path1}; let conf2 = storage::Config {working_dir: path2};
+    let conf1 = storage::Config::new(path1); let conf2 = storage::Config::new(path2);
 
     let mut manager1 = FileManager::read_or_init(&conf1, Uuid::from_u128(1)).unwrap();
     let mut manager2 = FileManager::read_or_init(&conf2, Uuid::from_u128(2)).unwrap();
@@ -123,3 +134,855 @@ fn test_driverid_serde() {
     let res = serde_json::from_str(&json).unwrap();
     assert_eq!(&map, &res);
 }
+
+/// Copy every op `to` is missing from `from`'s object store, then apply them.
+fn exchange(from: &FileManager, to: &mut FileManager) {
+    let have = to.get_history().all_hashes();
+    let new: Vec<_> = from.get_history().all_hashes().difference(&have).cloned().collect();
+
+    for hash in new.iter() {
+        let loc = storage::object::Location::Object(*hash);
+        let mut buf = Vec::new(); storage::object::read_bytes(&from.config, &loc, &mut buf).unwrap();
+        storage::object::write(&to.config, &loc, &buf).unwrap();
+    }
+
+    to.apply_ops(&new.iter().collect()).unwrap();
+}
+
+fn new_replica(name: &str, id: u128) -> FileManager {
+    let config = storage::Config::new(temp_working_dir(name));
+    FileManager::init(config, Uuid::from_u128(id))
+}
+
+fn sorted_paths(manager: &FileManager) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = manager.query().paths(&manager.config.paths).into_values().collect(); paths.sort();
+    paths
+}
+
+#[test]
+fn test_dir_replication() {
+    let mut manager1 = new_replica("dir_replication1", 1);
+    let mut manager2 = new_replica("dir_replication2", 2);
+
+    let root1 = manager1.config.working_dir.clone();
+    fs::create_dir_all(root1.join("empty")).unwrap();
+    fs::create_dir_all(root1.join("docs/nested")).unwrap();
+    fs::write(root1.join("docs/nested/a.md"), "# Hello\n").unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    let root2 = manager2.config.working_dir.clone();
+    assert!(root2.join("empty").is_dir());
+    assert_eq!(fs::read_to_string(root2.join("docs/nested/a.md")).unwrap(), "# Hello");
+    assert_eq!(sorted_paths(&manager1), sorted_paths(&manager2));
+}
+
+#[test]
+fn test_dir_rename_single_op() {
+    let mut manager1 = new_replica("dir_rename1", 1);
+    let mut manager2 = new_replica("dir_rename2", 2);
+
+    let root1 = manager1.config.working_dir.clone();
+    fs::create_dir_all(root1.join("a/sub")).unwrap();
+    fs::write(root1.join("a/x.md"), "x\n").unwrap();
+    fs::write(root1.join("a/y.md"), "y\n").unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    let before: HashSet<_> = manager1.hist.get_hashes();
+    fs::rename(root1.join("a"), root1.join("b")).unwrap();
+    manager1.update().unwrap();
+
+    let new_ops: Vec<_> = manager1.hist.get_hashes().difference(&before).map(|h| manager1.get_op(h).unwrap()).collect();
+    assert_eq!(new_ops.len(), 1);
+    assert!(matches!(new_ops[0].file_op, FileOp::MoveDir(..)));
+
+    exchange(&manager1, &mut manager2);
+
+    let root2 = manager2.config.working_dir.clone();
+    assert!(!root2.join("a").exists());
+    assert!(root2.join("b/sub").is_dir());
+    assert_eq!(fs::read_to_string(root2.join("b/x.md")).unwrap(), "x");
+    assert_eq!(sorted_paths(&manager1), sorted_paths(&manager2));
+}
+
+#[test]
+fn test_concurrent_dir_cycle() {
+    let mut manager1 = new_replica("dir_cycle1", 1);
+    let mut manager2 = new_replica("dir_cycle2", 2);
+
+    let root1 = manager1.config.working_dir.clone();
+    fs::create_dir_all(root1.join("a")).unwrap(); fs::write(root1.join("a/x.md"), "x\n").unwrap();
+    fs::create_dir_all(root1.join("b")).unwrap(); fs::write(root1.join("b/y.md"), "y\n").unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    // Concurrently move a into b, and b into a.
+    let root2 = manager2.config.working_dir.clone();
+    fs::rename(root1.join("a"), root1.join("b/a")).unwrap();
+    fs::rename(root2.join("b"), root2.join("a/b")).unwrap();
+    manager1.update().unwrap(); manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    let paths = sorted_paths(&manager1);
+    assert_eq!(paths, sorted_paths(&manager2));
+
+    // Exactly one of the moves wins, and every directory is still reachable from the root.
+    let dirs: Vec<_> = manager1.query().paths(&manager1.config.paths).into_iter()
+        .filter(|(n, _)| matches!(n, NodeID::Dir(DirID::Dir(_))))
+        .map(|(_, p)| p).collect();
+    assert_eq!(dirs.len(), 2);
+    assert!(dirs.iter().any(|p| p.components().count() == 1));
+
+    for path in paths.iter() {
+        assert!(root1.join(path).exists() && root2.join(path).exists(), "{:?} missing on disk", path);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_replication() {
+    use std::os::unix::fs::symlink;
+
+    let mut manager1 = new_replica("symlink1", 1);
+    let mut manager2 = new_replica("symlink2", 2);
+
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+    fs::create_dir_all(root1.join("notes")).unwrap();
+    fs::write(root1.join("notes/a.md"), "a").unwrap();
+    fs::write(root1.join("notes/b.md"), "b").unwrap();
+    symlink("notes/a.md", root1.join("latest.md")).unwrap();
+    symlink("missing.md", root1.join("broken.md")).unwrap();
+    symlink("../../outside", root1.join("notes/escape")).unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    assert_eq!(fs::read_link(root2.join("latest.md")).unwrap(), PathBuf::from("notes/a.md"));
+    assert_eq!(fs::read_link(root2.join("broken.md")).unwrap(), PathBuf::from("missing.md"));
+    assert!(fs::symlink_metadata(root2.join("notes/escape")).is_err());
+    assert_eq!(manager1.query().links.len(), 2);
+
+    // Retarget
+    fs::remove_file(root1.join("latest.md")).unwrap();
+    symlink("notes/b.md", root1.join("latest.md")).unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    assert_eq!(fs::read_link(root2.join("latest.md")).unwrap(), PathBuf::from("notes/b.md"));
+    assert_eq!(fs::read_to_string(root2.join("latest.md")).unwrap(), "b");
+
+    // Delete
+    fs::remove_file(root1.join("broken.md")).unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    assert!(fs::symlink_metadata(root2.join("broken.md")).is_err());
+    assert_eq!(sorted_paths(&manager1), sorted_paths(&manager2));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_ignore() {
+    use std::os::unix::fs::symlink;
+
+    let mut config = storage::Config::new(temp_working_dir("symlink_ignore"));
+    config.symlinks = storage::SymlinkPolicy::Ignore;
+    let root = config.working_dir.clone();
+
+    fs::write(root.join("a.md"), "a").unwrap();
+    symlink("a.md", root.join("link.md")).unwrap();
+    symlink("missing.md", root.join("broken.md")).unwrap();
+
+    let mut manager = FileManager::init(config, Uuid::from_u128(1));
+    manager.update().unwrap();
+
+    assert_eq!(sorted_paths(&manager), vec!(PathBuf::from("a.md")));
+    assert!(manager.query().links.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_move() {
+    use std::os::unix::fs::symlink;
+
+    let mut manager1 = new_replica("symlinkmove1", 1);
+    let mut manager2 = new_replica("symlinkmove2", 2);
+
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+    fs::write(root1.join("a.md"), "a").unwrap();
+    fs::write(root1.join("b.md"), "b").unwrap();
+    symlink("a.md", root1.join("latest.md")).unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+    let ids: Vec<_> = manager1.query().links.keys().cloned().collect();
+
+    // A rename on one replica, concurrent with a retarget on the other.
+    fs::remove_file(root1.join("latest.md")).unwrap();
+    symlink("a.md", root1.join("current.md")).unwrap();
+    manager1.update().unwrap();
+    assert_eq!(manager1.query().links.keys().cloned().collect::<Vec<_>>(), ids);
+
+    fs::remove_file(root2.join("latest.md")).unwrap();
+    symlink("b.md", root2.join("latest.md")).unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    for root in [&root1, &root2] {
+        assert_eq!(fs::read_link(root.join("current.md")).unwrap(), PathBuf::from("b.md"));
+        assert!(fs::symlink_metadata(root.join("latest.md")).is_err());
+    }
+    assert_eq!(sorted_paths(&manager1), sorted_paths(&manager2));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_follow() {
+    use std::os::unix::fs::symlink;
+
+    let mut config = storage::Config::new(temp_working_dir("symlink_follow"));
+    config.symlinks = storage::SymlinkPolicy::Follow;
+    let root = config.working_dir.clone();
+
+    fs::write(root.join("b.md"), "b").unwrap();
+    symlink("b.md", root.join("a.md")).unwrap();
+
+    // The link comes first, but the file is only tracked once, under its own path.
+    let mut manager = FileManager::init(config, Uuid::from_u128(1));
+    manager.update().unwrap();
+
+    assert_eq!(sorted_paths(&manager), vec!(PathBuf::from("b.md")));
+    assert_eq!(manager.query().files.len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata_replication() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut manager1 = new_replica("metadata1", 1);
+    let mut manager2 = new_replica("metadata2", 2);
+    manager1.config.mtimes = true; manager2.config.mtimes = true;
+
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+    fs::write(root1.join("script.md"), "run").unwrap();
+    fs::set_permissions(root1.join("script.md"), fs::Permissions::from_mode(0o755)).unwrap();
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    fs::File::options().write(true).open(root1.join("script.md")).unwrap().set_modified(mtime).unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    let meta = fs::metadata(root2.join("script.md")).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+    assert_eq!(meta.modified().unwrap(), mtime);
+
+    // Applying remote metadata must not generate new operations.
+    let before = manager2.hist.get_hashes();
+    manager2.update().unwrap();
+    assert_eq!(manager2.hist.get_hashes(), before);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata_read_only() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut manager1 = new_replica("metadata_read_only1", 1);
+    let mut manager2 = new_replica("metadata_read_only2", 2);
+    manager1.config.mtimes = true; manager2.config.mtimes = true;
+
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+    fs::write(root1.join("frozen.md"), "frozen").unwrap();
+    fs::set_permissions(root1.join("frozen.md"), fs::Permissions::from_mode(0o444)).unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+    assert_eq!(fs::metadata(root2.join("frozen.md")).unwrap().permissions().mode() & 0o777, 0o444);
+
+    // The file is already read-only on the second replica when its modification time changes.
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    filetime::set_file_mtime(root1.join("frozen.md"), filetime::FileTime::from_system_time(mtime)).unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    assert_eq!(fs::metadata(root2.join("frozen.md")).unwrap().modified().unwrap(), mtime);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata_opt_out() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut manager1 = new_replica("metadata_opt_out1", 1);
+    let mut manager2 = new_replica("metadata_opt_out2", 2);
+    manager2.config.permissions = false;
+
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+    fs::write(root1.join("script.md"), "run").unwrap();
+    fs::set_permissions(root1.join("script.md"), fs::Permissions::from_mode(0o700)).unwrap();
+
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    let mode = fs::metadata(root2.join("script.md")).unwrap().permissions().mode() & 0o777;
+    assert_ne!(mode, 0o700);
+
+    // Neither does the opted-out replica report its own permissions.
+    let before = manager2.hist.get_hashes();
+    manager2.update().unwrap();
+    assert_eq!(manager2.hist.get_hashes(), before);
+}
+
+/// Create `a.md` on the first replica and replicate it to the second.
+fn shared_file_replicas(name: &str) -> (FileManager, FileManager) {
+    let mut manager1 = new_replica(&format!("{}1", name), 1);
+    let mut manager2 = new_replica(&format!("{}2", name), 2);
+
+    fs::write(manager1.config.working_dir.join("a.md"), "x").unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    return (manager1, manager2);
+}
+
+#[test]
+fn test_unsupported_markdown() {
+    let (mut manager1, mut manager2) = shared_file_replicas("unsupported");
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    // Files the driver can't represent aren't tracked, and don't stop others syncing.
+    fs::write(root1.join("image.md"), "![diagram](diagram.png)\n").unwrap();
+    fs::write(root1.join("b.md"), "# B\n").unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+    assert_eq!(sorted_paths(&manager2), vec![PathBuf::from("a.md"), PathBuf::from("b.md")]);
+
+    // Tracked files which stop being representable keep their local changes, rather than being overwritten.
+    let html = "<details>local</details>\n";
+    fs::write(root1.join("a.md"), html).unwrap();
+    fs::write(root2.join("a.md"), "remote\n").unwrap();
+    fs::write(root2.join("b.md"), "# B\n\nremote\n").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+    exchange(&manager2, &mut manager1);
+    assert_eq!(fs::read_to_string(root1.join("a.md")).unwrap(), html);
+    assert_eq!(fs::read_to_string(root1.join("b.md")).unwrap(), "# B\n\nremote");
+}
+
+#[test]
+fn test_concurrent_move_lww() {
+    let (mut manager1, mut manager2) = shared_file_replicas("movelww");
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::rename(root1.join("a.md"), root1.join("b.md")).unwrap();
+    fs::rename(root2.join("a.md"), root2.join("c.md")).unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    // Local renames are detected as moves, not as a delete and a create.
+    assert_eq!(manager1.query().files.len(), 1);
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // Both moves have the same clock value, so the replica with the larger id wins.
+    assert_eq!(sorted_paths(&manager1), vec![PathBuf::from("c.md")]);
+    assert_eq!(sorted_paths(&manager2), vec![PathBuf::from("c.md")]);
+    assert!(root1.join("c.md").exists() && !root1.join("b.md").exists());
+    assert!(root2.join("c.md").exists());
+}
+
+#[test]
+fn test_concurrent_move_keep_both() {
+    let (mut manager1, mut manager2) = shared_file_replicas("movekeep");
+    manager1.config.move_conflicts = storage::MovePolicy::KeepBoth;
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::rename(root1.join("a.md"), root1.join("b.md")).unwrap();
+    fs::rename(root2.join("a.md"), root2.join("c.md")).unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // The losing replica keeps a copy where it moved the file, which is replicated as a new file.
+    assert_eq!(fs::read_to_string(root1.join("b.md")).unwrap(), "x");
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    let expected = vec![PathBuf::from("b.md"), PathBuf::from("c.md")];
+    assert_eq!(sorted_paths(&manager1), expected);
+    assert_eq!(sorted_paths(&manager2), expected);
+    assert_eq!(fs::read_to_string(root2.join("b.md")).unwrap(), "x");
+}
+
+#[test]
+fn test_concurrent_move_create() {
+    let (mut manager1, mut manager2) = shared_file_replicas("movecreate");
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::rename(root1.join("a.md"), root1.join("b.md")).unwrap();
+    fs::write(root2.join("b.md"), "y").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // The colliding file is given a deterministic name on both replicas.
+    let expected = vec![PathBuf::from("b (00000002).md"), PathBuf::from("b.md")];
+    assert_eq!(sorted_paths(&manager1), expected);
+    assert_eq!(sorted_paths(&manager2), expected);
+    for root in [&root1, &root2] {
+        let mut contents = vec![
+            fs::read_to_string(root.join("b.md")).unwrap(),
+            fs::read_to_string(root.join("b (00000002).md")).unwrap(),
+        ];
+        contents.sort();
+        assert_eq!(contents, vec!["x", "y"]);
+    }
+
+    // Resolving the collision must not generate new operations.
+    let before = manager1.hist.get_hashes();
+    manager1.update().unwrap();
+    assert_eq!(manager1.hist.get_hashes(), before);
+}
+
+#[test]
+fn test_concurrent_move_delete() {
+    let (mut manager1, mut manager2) = shared_file_replicas("movedelete");
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::rename(root1.join("a.md"), root1.join("b.md")).unwrap();
+    fs::remove_file(root2.join("a.md")).unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // Deletion wins over a concurrent move.
+    assert!(sorted_paths(&manager1).is_empty());
+    assert!(sorted_paths(&manager2).is_empty());
+    assert!(!root1.join("b.md").exists());
+    assert!(!root2.join("b.md").exists());
+}
+
+#[test]
+fn test_concurrent_create_rename() {
+    let mut manager1 = new_replica("createrename1", 1);
+    let mut manager2 = new_replica("createrename2", 2);
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::write(root1.join("todo.md"), "x").unwrap();
+    fs::write(root2.join("todo.md"), "y").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // The file created by the replica with the larger id loses, and is renamed on both replicas.
+    let expected = vec![PathBuf::from("todo (00000002).md"), PathBuf::from("todo.md")];
+    assert_eq!(sorted_paths(&manager1), expected);
+    assert_eq!(sorted_paths(&manager2), expected);
+    assert_eq!(manager1.query().collisions(&manager1.config.paths).len(), 1);
+    for root in [&root1, &root2] {
+        assert_eq!(fs::read_to_string(root.join("todo.md")).unwrap(), "x");
+        assert_eq!(fs::read_to_string(root.join("todo (00000002).md")).unwrap(), "y");
+    }
+
+    // Each driver writes to its own file, so nothing changes locally.
+    let before = manager2.hist.get_hashes();
+    manager2.update().unwrap();
+    assert_eq!(manager2.hist.get_hashes(), before);
+}
+
+#[test]
+fn test_collision_resolved() {
+    let mut manager1 = new_replica("collisionresolved1", 1);
+    let mut manager2 = new_replica("collisionresolved2", 2);
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::write(root1.join("todo.md"), "x").unwrap();
+    fs::write(root2.join("todo.md"), "y").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // Once the file which kept the name is deleted, the renamed file gets it back, leaving nothing behind.
+    fs::remove_file(root1.join("todo.md")).unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    for (manager, root) in [(&manager1, &root1), (&manager2, &root2)] {
+        assert_eq!(sorted_paths(manager), vec![PathBuf::from("todo.md")]);
+        assert_eq!(fs::read_to_string(root.join("todo.md")).unwrap(), "y");
+        assert!(!root.join("todo (00000002).md").exists());
+    }
+}
+
+#[test]
+fn test_concurrent_create_merge() {
+    let mut manager1 = new_replica("createmerge1", 1);
+    let mut manager2 = new_replica("createmerge2", 2);
+    manager1.config.name_collisions = storage::CollisionPolicy::Merge;
+    manager2.config.name_collisions = storage::CollisionPolicy::Merge;
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::write(root1.join("todo.md"), "x").unwrap();
+    fs::write(root2.join("todo.md"), "y").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // Merging generates operations, so is left for the loser's creator to do when it next updates.
+    assert_eq!(sorted_paths(&manager1).len(), 2);
+    manager2.update().unwrap();
+    exchange(&manager2, &mut manager1);
+
+    assert_eq!(sorted_paths(&manager1), vec![PathBuf::from("todo.md")]);
+    assert_eq!(sorted_paths(&manager2), vec![PathBuf::from("todo.md")]);
+    for root in [&root1, &root2] {
+        assert_eq!(fs::read_to_string(root.join("todo.md")).unwrap(), "x\n\ny");
+        assert!(!root.join("todo (00000002).md").exists());
+    }
+}
+
+#[test]
+fn test_nfc_paths() {
+    let mut manager1 = new_replica("nfc1", 1);
+    let mut manager2 = new_replica("nfc2", 2);
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    let (nfd, nfc) = ("cafe\u{301}.md", "caf\u{e9}.md");
+    fs::write(root1.join(nfd), "x").unwrap();
+
+    // Listing the working directory leaves names alone. Only updating renames them.
+    manager1.list_dir().unwrap();
+    assert!(root1.join(nfd).exists());
+    manager1.update().unwrap();
+    assert!(!root1.join(nfd).exists());
+    exchange(&manager1, &mut manager2);
+
+    assert_eq!(sorted_paths(&manager1), vec![PathBuf::from(nfc)]);
+    assert_eq!(fs::read_to_string(root2.join(nfc)).unwrap(), "x");
+
+    // A differently normalised name for the same file must not look like a move.
+    let before = manager1.hist.get_hashes();
+    manager1.update().unwrap();
+    assert_eq!(manager1.hist.get_hashes(), before);
+}
+
+#[test]
+fn test_case_insensitive_paths() {
+    let mut manager1 = new_replica("case1", 1);
+    let mut manager2 = new_replica("case2", 2);
+    manager1.config.paths.case_insensitive = true;
+    manager2.config.paths.case_insensitive = true;
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::write(root1.join("Notes.md"), "x").unwrap();
+    fs::write(root2.join("notes.md"), "y").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    exchange(&manager1, &mut manager2);
+    exchange(&manager2, &mut manager1);
+
+    // Case-only collisions are renamed, as they would be the same file on a case-insensitive filesystem.
+    let expected = vec![PathBuf::from("Notes.md"), PathBuf::from("notes (00000002).md")];
+    assert_eq!(sorted_paths(&manager1), expected);
+    assert_eq!(sorted_paths(&manager2), expected);
+
+    let before = (manager1.hist.get_hashes(), manager2.hist.get_hashes());
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+    assert_eq!((manager1.hist.get_hashes(), manager2.hist.get_hashes()), before);
+
+    // Entries whose names clash locally are refused, rather than one of them being left out.
+    fs::write(root1.join("notes.md"), "z").unwrap();
+    assert_eq!(manager1.update().unwrap_err().0, crate::errors::CODE_NAME_CLASH);
+    assert_eq!(sorted_paths(&manager1), expected);
+    assert_eq!(manager1.list_dir().unwrap().0.len(), 2);
+
+    fs::rename(root1.join("notes.md"), root1.join("notes 2.md")).unwrap();
+    manager1.update().unwrap();
+    assert_eq!(sorted_paths(&manager1).len(), 3);
+}
+
+#[test]
+fn test_causal_delivery() {
+    let mut manager1 = new_replica("causal1", 1);
+    let mut manager2 = new_replica("causal2", 2);
+    let root1 = manager1.config.working_dir.clone();
+
+    fs::write(root1.join("a.md"), "x").unwrap();
+    manager1.update().unwrap();
+    let created = manager1.hist.get_hashes();
+    fs::rename(root1.join("a.md"), root1.join("b.md")).unwrap();
+    manager1.update().unwrap();
+
+    let later: Vec<_> = manager1.hist.get_hashes().difference(&created).cloned().collect();
+    assert_eq!(later.len(), 1);
+    let parents = manager1.get_op(&later[0]).unwrap().parents;
+    assert_eq!(parents.len(), 1);
+    assert!(created.iter().all(|h| manager1.hist.happened_before(*h, later[0])));
+
+    // The move isn't applied before the creation it depends on.
+    let loc = storage::object::Location::Object(later[0]);
+    let mut buf = Vec::new(); storage::object::read_bytes(&manager1.config, &loc, &mut buf).unwrap();
+    storage::object::write(&manager2.config, &loc, &buf).unwrap();
+    manager2.apply_ops(&vec![&later[0]]).unwrap();
+    assert!(manager2.hist.get_hashes().is_empty());
+
+    exchange(&manager1, &mut manager2);
+    assert_eq!(manager2.hist.get_hashes(), manager1.hist.get_hashes());
+}
+
+#[test]
+fn test_pending_ops() {
+    let mut manager1 = new_replica("pending1", 1);
+    let mut manager2 = new_replica("pending2", 2);
+    let root1 = manager1.config.working_dir.clone();
+
+    fs::write(root1.join("a.md"), "x").unwrap();
+    manager1.update().unwrap();
+    let created = manager1.hist.get_hashes();
+    fs::rename(root1.join("a.md"), root1.join("b.md")).unwrap();
+    manager1.update().unwrap();
+    let moved = *manager1.hist.get_hashes().difference(&created).next().unwrap();
+
+    let copy = |hash: &types::Hash, to: &FileManager| {
+        let loc = storage::object::Location::Object(*hash);
+        let mut buf = Vec::new(); storage::object::read_bytes(&manager1.config, &loc, &mut buf).unwrap();
+        storage::object::write(&to.config, &loc, &buf).unwrap();
+    };
+
+    // The move arrives before the creation it depends on, and is queued.
+    copy(&moved, &manager2);
+    manager2.apply_ops(&vec![&moved]).unwrap();
+
+    let pending = PendingOps::read_in(&manager2.config).unwrap();
+    assert_eq!(pending.hashes(), vec![moved]);
+    match &pending.ops()[0].reason {
+        PendingReason::MissingDependency(deps) => assert!(!deps.is_empty()),
+        r => panic!("Unexpected reason {:?}", r),
+    }
+
+    // Undecodable operations are queued too.
+    let garbage = types::calculate_hash("garbage");
+    storage::object::write(&manager2.config, &storage::object::Location::Object(garbage), b"garbage").unwrap();
+    manager2.apply_ops(&vec![&garbage]).unwrap();
+    let pending = PendingOps::read_in(&manager2.config).unwrap();
+    assert!(matches!(pending.ops().iter().find(|op| op.hash == garbage).unwrap().reason, PendingReason::DecodeError(_)));
+    assert_eq!(pending.ops().iter().find(|op| op.hash == moved).unwrap().attempts, 2);
+
+    // Once the dependencies arrive, the queued move is retried without being listed again.
+    for hash in created.iter() {copy(hash, &manager2);}
+    manager2.apply_ops(&created.iter().collect()).unwrap();
+
+    assert_eq!(sorted_paths(&manager2), vec![PathBuf::from("b.md")]);
+    assert_eq!(PendingOps::read_in(&manager2.config).unwrap().hashes(), vec![garbage]);
+}
+
+#[test]
+fn test_encrypted_replication() {
+    let key = storage::crypt::Key::from_passphrase("correct horse battery staple", Uuid::from_u128(100));
+    let encrypted = |name: &str, id: u128, key: &storage::crypt::Key| {
+        let config = storage::Config {key: Some(key.clone()), ..storage::Config::new(temp_working_dir(name))};
+        FileManager::init(config, Uuid::from_u128(id))
+    };
+    let mut manager1 = encrypted("encrypted1", 1, &key);
+    let mut manager2 = encrypted("encrypted2", 2, &key);
+
+    let root1 = manager1.config.working_dir.clone();
+    fs::write(root1.join("secret-plans.md"), "# Top secret\n").unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+
+    assert_eq!(fs::read_to_string(manager2.config.working_dir.join("secret-plans.md")).unwrap(), "# Top secret");
+    assert_eq!(manager1.get_history().all_hashes(), manager2.get_history().all_hashes());
+
+    // Stored operations reveal nothing, and are addressed by the hash of their ciphertext.
+    for hash in manager1.get_history().all_hashes() {
+        let mut buf = String::new();
+        storage::object::read_string(&manager1.config, &storage::object::Location::Object(hash), &mut buf).unwrap();
+        assert!(!buf.contains("secret") && !buf.contains("Top"));
+        assert_eq!(types::calculate_hash(&buf), hash);
+    }
+
+    // The key isn't written out, so must be given again when reading the tree back in.
+    manager1.write_out().unwrap();
+    let meta = fs::read_to_string(root1.join(".crfs/meta/filetree.json")).unwrap();
+    assert!(!meta.contains(&key.to_hex()));
+    let reread = FileManager::read_in(&manager1.config).unwrap();
+    assert_eq!(reread.config.key, Some(key));
+
+    // A replica with the wrong key can't read any of them.
+    let mut manager3 = encrypted("encrypted3", 3, &storage::crypt::Key::from_passphrase("wrong", Uuid::from_u128(100)));
+    exchange(&manager1, &mut manager3);
+    assert!(sorted_paths(&manager3).is_empty());
+    let pending = PendingOps::read_in(&manager3.config).unwrap();
+    assert!(pending.ops().iter().all(|op| matches!(op.reason, PendingReason::DecodeError(_))));
+}
+
+#[test]
+fn test_signed_replication() {
+    let keys: Vec<_> = (0..3).map(|_| storage::sign::SigningKey::generate()).collect();
+    let enrolled = storage::sign::Keyring::new([(Uuid::from_u128(1), keys[0].public_key()), (Uuid::from_u128(2), keys[1].public_key())]);
+    let signed = |name: &str, id: u128, key: &storage::sign::SigningKey| {
+        let signer = storage::sign::Signer {replica: Uuid::from_u128(id), key: key.clone()};
+        let config = storage::Config {signer: Some(signer), ..storage::Config::new(temp_working_dir(name))};
+        enrolled.write_out(&config).unwrap();
+        FileManager::init(config, Uuid::from_u128(id))
+    };
+    let mut manager1 = signed("signed1", 1, &keys[0]);
+    let mut manager2 = signed("signed2", 2, &keys[1]);
+
+    fs::write(manager1.config.working_dir.join("notes.md"), "# Notes\n").unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+    assert_eq!(fs::read_to_string(manager2.config.working_dir.join("notes.md")).unwrap(), "# Notes");
+    assert_eq!(manager1.get_history().all_hashes(), manager2.get_history().all_hashes());
+
+    // Every operation carries its signer.
+    for hash in manager1.get_history().all_hashes() {
+        assert_eq!(storage::object::verify_op(&manager2.config, &enrolled, &hash), Ok(Uuid::from_u128(1)));
+    }
+
+    // Operations from a replica which isn't enrolled, or which claims to be another replica, are rejected.
+    let mut rogue = signed("signed-rogue", 3, &keys[2]);
+    let mut impostor = signed("signed-impostor", 1, &keys[2]);
+    for manager in [&mut rogue, &mut impostor] {
+        fs::write(manager.config.working_dir.join("injected.md"), "# Injected\n").unwrap();
+        manager.update().unwrap();
+        exchange(manager, &mut manager2);
+    }
+    assert_eq!(sorted_paths(&manager2), vec![PathBuf::from("notes.md")]);
+    let pending = PendingOps::read_in(&manager2.config).unwrap();
+    assert!(!pending.is_empty());
+    assert!(pending.ops().iter().all(|op| matches!(op.reason, PendingReason::Unverified(_))));
+
+    // Once replica 2 is removed, its operations are rejected too.
+    storage::sign::Keyring::new([(Uuid::from_u128(1), keys[0].public_key())]).write_out(&manager1.config).unwrap();
+    fs::write(manager2.config.working_dir.join("notes.md"), "# Notes\n\nEdited.\n").unwrap();
+    manager2.update().unwrap();
+    exchange(&manager2, &mut manager1);
+    assert_eq!(fs::read_to_string(manager1.config.working_dir.join("notes.md")).unwrap(), "# Notes");
+}
+
+#[test]
+fn test_restore() {
+    let mut manager1 = new_replica("restore1", 1);
+    let mut manager2 = new_replica("restore2", 2);
+    let (root1, root2) = (manager1.config.working_dir.clone(), manager2.config.working_dir.clone());
+
+    fs::create_dir(root1.join("docs")).unwrap();
+    fs::write(root1.join("docs/a.md"), "# A\n").unwrap();
+    fs::write(root1.join("b.md"), "# B\n").unwrap();
+    manager1.update().unwrap();
+    exchange(&manager1, &mut manager2);
+    let synced = sorted_paths(&manager2);
+    let history = manager2.get_history().all_hashes();
+
+    // Edit, move, delete and create files, and create a directory.
+    fs::write(root2.join("docs/a.md"), "# A\n\nEdited.\n").unwrap();
+    fs::rename(root2.join("docs"), root2.join("notes")).unwrap();
+    fs::remove_file(root2.join("b.md")).unwrap();
+    fs::create_dir(root2.join("new")).unwrap();
+    fs::write(root2.join("new/c.md"), "# C\n").unwrap();
+
+    let restored = manager2.restore().unwrap();
+    assert_eq!(restored, vec![PathBuf::from("b.md"), PathBuf::from("docs"), PathBuf::from("docs/a.md"), PathBuf::from("new")]);
+
+    // The working directory matches the replicated state again, without any operations being generated.
+    assert_eq!(fs::read_to_string(root2.join("docs/a.md")).unwrap(), "# A");
+    assert_eq!(fs::read_to_string(root2.join("b.md")).unwrap(), "# B");
+    assert!(!root2.join("notes").exists() && !root2.join("new").exists());
+    assert!(manager2.prep().unwrap().is_none());
+    assert_eq!(sorted_paths(&manager2), synced);
+    assert_eq!(manager2.get_history().all_hashes(), history);
+}
+
+#[test]
+fn test_local_changes() {
+    let mut manager = new_replica("local-changes", 1);
+    let root = manager.config.working_dir.clone();
+
+    fs::write(root.join("a.md"), "# A\n").unwrap();
+    fs::write(root.join("b.md"), "# B\n").unwrap();
+    manager.update().unwrap();
+    assert!(manager.local_changes().unwrap().is_empty());
+
+    // Changes are found without being undone, or generating operations.
+    let history = manager.get_history().all_hashes();
+    fs::write(root.join("a.md"), "# A\n\nEdited.\n").unwrap();
+    fs::write(root.join("c.md"), "# C\n").unwrap();
+    assert_eq!(manager.local_changes().unwrap(), vec![PathBuf::from("a.md"), PathBuf::from("c.md")]);
+    assert_eq!(fs::read_to_string(root.join("a.md")).unwrap(), "# A\n\nEdited.\n");
+    assert_eq!(manager.get_history().all_hashes(), history);
+
+    manager.restore().unwrap();
+    assert!(manager.local_changes().unwrap().is_empty());
+}
+
+#[test]
+fn test_active_drivers_sorted() {
+    let mut manager = new_replica("activedrivers", 1);
+    for name in ["a.md", "b.md", "c.md", "d.md"] {
+        fs::write(manager.config.working_dir.join(name), name).unwrap();
+    }
+    manager.update().unwrap();
+
+    // Drivers are always updated in the same order, so the same edits give the same operations in the same order.
+    let drivers = manager.get_active_drivers();
+    assert_eq!(drivers.len(), 4);
+    assert!(drivers.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn test_baseline_format() {
+    // State and an operation persisted by a replica from before directories were tracked.
+    const BASELINE_STATE: &str = r#"{"state":{"0":{}},"hist":{"data":[null],"k":0},"config":{"working_dir":"."},"drivers":{},"replica_id":"00000000-0000-0000-0000-000000000001"}"#;
+    const BASELINE_OP: &str = r#"{"NewFile":[{"Driver":2553637495092389199},"Markdown","a.md","00000000-0000-0000-0000-000000000001"]}"#;
+
+    let config = storage::Config::new(temp_working_dir("baselineformat"));
+    fs::create_dir_all(config.working_dir.join(".crfs/meta")).unwrap();
+    fs::write(config.working_dir.join(".crfs/meta/filetree.json"), BASELINE_STATE).unwrap();
+
+    // The old state isn't mistaken for an empty replica.
+    let e = FileManager::read_or_init(&config, Uuid::from_u128(1)).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("Re-init required"));
+
+    // State persisted now is read back.
+    let manager = new_replica("currentformat", 1);
+    manager.write_out().unwrap();
+    assert!(FileManager::read_in(&manager.config).is_ok());
+
+    // Old operations from peers are queued with the reason, rather than applied.
+    let mut manager = new_replica("baselineops", 1);
+    let hash = types::calculate_hash(BASELINE_OP);
+    storage::object::write(&manager.config, &storage::object::Location::Object(hash), BASELINE_OP.as_bytes()).unwrap();
+    manager.apply_ops(&vec![&hash]).unwrap();
+
+    assert!(sorted_paths(&manager).is_empty());
+    match &PendingOps::read_in(&manager.config).unwrap().ops()[0].reason {
+        PendingReason::DecodeError(e) => assert!(e.contains("Re-init required")),
+        r => panic!("Unexpected reason {:?}", r),
+    }
+}
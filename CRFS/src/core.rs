@@ -1,22 +1,102 @@
 use crate::{storage, networking, conflict_res, errors, types};
 
-use conflict_res::file_tree;
+use conflict_res::{file_tree, CmRDT};
+use networking::transport::Transport;
 
 use std::fs;
 use std::path::PathBuf;
 use std::collections::HashSet;
+use std::sync::Mutex;
 
 use homedir::my_home;
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
+/// Where the encryption key for a new replica comes from.
+pub enum KeySource {
+    Key(storage::crypt::Key),
+    Passphrase(String),
+    Prompt,
+}
+
+impl KeySource {
+    fn key(self, fs_uuid: Uuid) -> storage::crypt::Key {
+        match self {
+            Self::Key(key) => key,
+            Self::Passphrase(passphrase) => storage::crypt::Key::from_passphrase(&passphrase, fs_uuid),
+            Self::Prompt => {
+                let passphrase = rpassword::prompt_password("Filesystem passphrase: ").expect("Error reading passphrase.");
+                storage::crypt::Key::from_passphrase(&passphrase, fs_uuid)
+            },
+        }
+    }
+}
+
+/// Options which only affect a single replica.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReplicaOpts {
+    /// Mirror the FS: pull and apply remote changes, but never generate or push operations.
+    #[serde(default)]
+    pub read_only: bool,
+    /// What a read-only replica does with local changes.
+    #[serde(default)]
+    pub local_changes: LocalChangePolicy,
+}
+
+/// What a read-only replica does with changes made to its working directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LocalChangePolicy {
+    /// Undo them, restoring the replicated state. Discarded files are moved to the trash.
+    #[default]
+    Revert,
+    /// Refuse to sync until they've been undone.
+    Error,
+}
+
 // Single replica config
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SystemConfig(pub storage::Config, pub networking::Config);
+pub struct SystemConfig(pub storage::Config, pub networking::Config, #[serde(default)] pub ReplicaOpts);
 
 impl SystemConfig {
     pub fn get_replica_id(&self) -> Option<Uuid> { self.1.info.get_replica_id() }
 
+    /// Options the FS is registered with, which every replica must share.
+    pub fn fs_opts(&self) -> Vec<String> {
+        let mut opts = self.0.paths.to_opts();
+        opts.extend(self.0.name_collisions.to_opts());
+        if let Some(key) = &self.0.key { opts.push(key.to_opt()); }
+
+        return opts;
+    }
+
+    /// Adopt the options of the existing FS this replica is joining, checking we hold its key if it's encrypted.
+    fn join_fs(&mut self, fs_opts: &[String], key_source: Option<KeySource>) -> Result<(), errors::Error> {
+        // The path policy belongs to the FS, so must match the other replicas.
+        let policy = storage::PathPolicy::from_opts(fs_opts);
+        if policy != self.0.paths {
+            println!("Warn: Using the existing FS's path policy: {:?}.", policy);
+            self.0.paths = policy;
+        }
+
+        let collisions = storage::CollisionPolicy::from_opts(fs_opts);
+        if collisions != self.0.name_collisions {
+            println!("Warn: Using the existing FS's name collision policy: {:?}.", collisions);
+            self.0.name_collisions = collisions;
+        }
+
+        if storage::crypt::Key::required(fs_opts) {
+            let key = key_source.unwrap_or(KeySource::Prompt).key(self.1.info.get_fs_id().unwrap());
+            if !key.matches(fs_opts) {
+                return Err(errors::Error(errors::CODE_WRONG_KEY, "Incorrect passphrase or key for this FS.".to_owned()));
+            }
+            self.0.key = Some(key);
+        } else if key_source.is_some() {
+            println!("Warn: The existing FS isn't encrypted, so no key will be used.");
+        }
+
+        Ok(())
+    }
+
     pub fn init(&self) -> Result<(), errors::Error> {
         let tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
         tree.write_out()?;
@@ -24,30 +104,55 @@ impl SystemConfig {
         Ok(())
     }
 
-    pub fn sync(&self) -> Result<(), errors::Error> {
+    pub fn sync(&mut self, mode: networking::SyncMode) -> Result<(), errors::Error> {
+        // Replicas syncing through a shared directory have no server to enrol with, or to fetch their role from.
+        let server = self.1.local_dir()?.is_none();
+
+        // Enrolling a new key is only done when the user asks, as it replaces the replica's credentials.
+        if server && self.1.credentials.signing_key.is_none() {
+            return Err(errors::Error(errors::CODE_NOT_LOGGED_IN, "This replica has no signing key. Run `login` to enrol it with one.".to_owned()));
+        }
+
+        // The user's role may have changed since the last sync. A missing user or FS is re-registered below.
+        if server {
+            match self.1.blocking().fetch_role() {
+                Ok(role) => self.1.role = Some(role),
+                Err(errors::Error(code, _)) if (code == errors::CODE_NO_USER) || (code == errors::CODE_NO_FS) => {},
+                Err(e) => return Err(e),
+            }
+        }
+
         let mut tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
 
         println!("-> File Tree loaded. Checking for local updates...");
 
-        tree.update()?;
+        self.update_local(&mut tree)?;
 
         println!("-> Internal state up-to-date. Syncing with server...");
 
-        let remote_hashes = match self.network_sync(&tree) {
+        let remote_hashes = match self.network_sync(&tree, mode) {
             Ok(h) => h,
-            Err(errors::Error(code, _)) if (code == errors::CODE_NO_USER) || (code == errors::CODE_NO_FS) => {
-                let (u, f) = self.1.check_info()?;
+            Err(errors::Error(code, _)) if server && ((code == errors::CODE_NO_USER) || (code == errors::CODE_NO_FS)) => {
+                let (u, f) = self.1.blocking().check_info()?;
 
-                if !u { self.1.register_user().expect("Error registering user."); }
-                if !f { self.1.register_fs().expect("Error registering fs."); }
+                if !u {
+                    let secret = self.1.blocking().register_user().expect("Error registering user.");
+                    if let Some(secret) = secret { self.store_user_secret(secret); }
+                }
+                if !f { self.1.blocking().register_fs(self.fs_opts()).expect("Error registering fs."); }
+                // The replica's enrolment went with the FS.
+                self.login(None)?;
 
-                let res = self.network_sync(&tree)?;
+                let res = self.network_sync(&tree, mode)?;
                 println!("Warn: Re-registered user and/or FS. Continuing sync...");
                 res
             },
             e => {e?; panic!()}
         };
 
+        // Only operations signed by replicas still enrolled in the FS are applied.
+        if server { self.refresh_keyring()?; }
+
         if remote_hashes.len() > 0 {
             // println!("Applying {} ops...", remote_hashes.len());
             tree.apply_ops(&remote_hashes.iter().collect())?;
@@ -60,17 +165,256 @@ impl SystemConfig {
         Ok(())
     }
 
-    fn network_sync(&self, tree: &file_tree::FileManager) -> Result<HashSet<types::Hash>, errors::Error> {
-        let remote_hashes = self.1.fetch_state()?;
+    /// Bring the file tree up-to-date with the working directory. Replicas which may not write undo or report local
+    /// changes instead.
+    fn update_local(&self, tree: &mut file_tree::FileManager) -> errors::Result<()> {
+        if self.may_write() {
+            tree.update()?;
+        } else if self.2.read_only && self.2.local_changes == LocalChangePolicy::Error {
+            let changed = tree.local_changes()?;
+            if !changed.is_empty() {
+                return Err(errors::Error(errors::CODE_LOCAL_CHANGES, format!(
+                    "Read-only replica has local changes to {:?}. Undo them, or run `read-only --on-local-changes revert`.", changed,
+                )));
+            }
+        } else {
+            let restored = tree.restore()?;
+            if !restored.is_empty() {
+                let reason = if self.2.read_only {"This replica is read-only"} else {"You have read-only access to this FS"};
+                println!("Warn: {}, so local changes have been undone. Restored:", reason);
+                for path in restored.iter() { println!("  {:?}", path); }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sync directly with a replica of the same FS serving at `addr`, without the server.
+    /// Operations are verified against the keyring from the last sync with the server.
+    pub fn peer_sync(&self, addr: &str) -> Result<(), errors::Error> {
+        let mut tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
+
+        println!("-> File Tree loaded. Checking for local updates...");
+
+        self.update_local(&mut tree)?;
+
+        println!("-> Internal state up-to-date. Syncing with peer {}...", addr);
+
+        let mut peer = networking::peer::Peer::connect(addr, &self.0, &self.1.info, &self.1.transfer)?;
+        let fetched = peer.sync(&self.0, &tree.get_history().all_hashes(), self.may_write())?;
+
+        if fetched.len() > 0 {
+            tree.apply_ops(&fetched.iter().collect())?;
+        }
+
+        println!("-> Up-to-date with peer.");
+
+        tree.write_out()?;
+
+        Ok(())
+    }
+
+    /// Serve this replica to peers at `addr`, until interrupted. Each peer is served on its own thread, sent the
+    /// operations it's missing, and the operations it pushes are applied once it disconnects.
+    /// Failing to serve one peer is logged, and doesn't stop the others being served.
+    pub fn serve(&self, addr: &str) -> Result<(), errors::Error> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        let fs_uuid = self.1.info.get_fs_id().unwrap();
+
+        println!("Serving FS {} to peers on {}.", fs_uuid, listener.local_addr()?);
+
+        // Connections are served concurrently, but the tree is only read and written by one at a time.
+        let tree_lock = Mutex::new(());
+
+        std::thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {println!("Warn: Error accepting peer: {}", e); continue;},
+                };
+                let tree_lock = &tree_lock;
+
+                scope.spawn(move || {
+                    let peer = stream.peer_addr().map_or_else(|_| "unknown peer".to_owned(), |a| a.to_string());
+                    match self.serve_peer(stream, fs_uuid, tree_lock) {
+                        Ok(received) => println!("-> Synced with {}. Received {} operations.", peer, received),
+                        Err(errors::Error(code, err_msg)) => println!("Warn: Sync with {} failed ({}): {}", peer, code, err_msg),
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Serve a single peer, then apply the operations it pushed. Returns how many it pushed.
+    fn serve_peer(&self, stream: std::net::TcpStream, fs_uuid: Uuid, tree_lock: &Mutex<()>) -> Result<usize, errors::Error> {
+        let lock = || tree_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let state = {
+            let _guard = lock();
+            let mut tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
+            self.update_local(&mut tree)?;
+            tree.write_out()?;
+
+            tree.get_history().all_hashes()
+        };
+
+        let received = networking::peer::serve_connection(stream, &self.0, fs_uuid, &state, &self.1.transfer)?;
+
+        if received.len() > 0 {
+            let _guard = lock();
+            let mut tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
+            tree.apply_ops(&received.iter().collect())?;
+            tree.write_out()?;
+        }
+
+        Ok(received.len())
+    }
+
+    /// Write the operations this replica holds to a bundle at `path`, leaving out those before the heads `since`.
+    pub fn export_bundle(&self, path: &PathBuf, since: &[types::Hash]) -> errors::Result<networking::bundle::Manifest> {
+        let mut tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
+
+        println!("-> File Tree loaded. Checking for local updates...");
+
+        self.update_local(&mut tree)?;
+        tree.write_out()?;
+
+        println!("-> Internal state up-to-date. Exporting bundle...");
+
+        return networking::bundle::export(&self.0, self.1.info.get_fs_id().unwrap(), &tree.get_history(), since, path);
+    }
+
+    /// Apply the operations in the bundle at `path`.
+    /// Operations are verified against the keyring from the last sync with the server.
+    pub fn import_bundle(&self, path: &PathBuf) -> errors::Result<()> {
+        let mut tree = file_tree::FileManager::read_or_init(&self.0, self.get_replica_id().unwrap())?;
+
+        println!("-> File Tree loaded. Checking for local updates...");
+
+        self.update_local(&mut tree)?;
+
+        println!("-> Internal state up-to-date. Importing bundle...");
+
+        let manifest = networking::bundle::import(&self.0, self.1.info.get_fs_id().unwrap(), path)?;
+
+        let history = tree.get_history().all_hashes();
+        let new: Vec<&types::Hash> = manifest.ops.iter().filter(|h| !history.contains(*h)).collect();
+        if new.len() > 0 {
+            tree.apply_ops(&new)?;
+        }
+
+        println!("-> Imported {} new operations.", new.len());
+
+        tree.write_out()?;
+
+        Ok(())
+    }
+
+    /// Whether this replica may generate operations. Read-only replicas, and replicas of read-only users, only pull.
+    fn may_write(&self) -> bool {
+        !self.2.read_only && self.1.role.is_none_or(|role| role.may_write())
+    }
+
+    fn network_sync(&self, tree: &file_tree::FileManager, mode: networking::SyncMode) -> Result<HashSet<types::Hash>, errors::Error> {
+        let transport = self.1.transport()?;
+
+        match mode {
+            networking::SyncMode::Heads => self.heads_sync(tree, transport.as_ref()),
+            networking::SyncMode::Reconcile => self.reconcile_sync(tree, transport.as_ref()),
+        }
+    }
+
+    /// Exchange operations with the server. Only the server's heads are fetched, from which we walk back to find the
+    /// operations we're missing, so each sync costs O(new operations).
+    fn heads_sync(&self, tree: &file_tree::FileManager, transport: &dyn Transport) -> Result<HashSet<types::Hash>, errors::Error> {
+        let remote_heads = transport.fetch_heads()?;
+
+        // Pull
+        let history = tree.get_history();
+        let local_hashes = history.all_hashes();
+        let fetched = transport.pull(&self.0, &local_hashes, &remote_heads)?;
+
+        // Push everything the server's heads don't already account for.
+        let mut parents = history.all_parents(); parents.extend(fetched.clone());
+        let on_server = CmRDT::ancestors(&parents, &remote_heads.into_iter().collect::<Vec<_>>());
+
+        let mut new_ops: Vec<networking::api::OpInfo> = local_hashes.difference(&on_server)
+            .map(|h| networking::api::OpInfo {hash: *h, parents: parents.get(h).cloned().unwrap_or_default()})
+            .collect();
+        new_ops.sort_by_key(|op| op.hash);
+        if self.may_write() { transport.push(&self.0, new_ops)?; }
+
+        return Ok(fetched.into_keys().collect());
+    }
+
+    /// Exchange operations with the server, using set reconciliation to find the differences between our histories.
+    /// Small histories fall back to exchanging the full state.
+    fn reconcile_sync(&self, tree: &file_tree::FileManager, transport: &dyn Transport) -> Result<HashSet<types::Hash>, errors::Error> {
+        let history = tree.get_history();
+        let local_hashes = history.all_hashes();
+
+        let diff = if local_hashes.len() < networking::reconcile::SMALL_SET {
+            let remote_hashes = transport.fetch_state()?;
+            networking::reconcile::Difference {
+                have: local_hashes.difference(&remote_hashes).cloned().collect(),
+                need: remote_hashes.difference(&local_hashes).cloned().collect(),
+            }
+        } else {
+            transport.reconcile(&local_hashes)?
+        };
 
         // Pull
-        let local_hashes = tree.get_history().all_hashes();
-        let new_hashes = self.1.pull(&self.0, &local_hashes, &remote_hashes)?;
+        let fetched = transport.fetch_ops(&self.0, &diff.need)?;
 
         // Push
-        self.1.push(&self.0, &local_hashes, &remote_hashes)?;
+        let parents = history.all_parents();
+        let mut new_ops: Vec<networking::api::OpInfo> = diff.have.iter()
+            .map(|h| networking::api::OpInfo {hash: *h, parents: parents.get(h).cloned().unwrap_or_default()})
+            .collect();
+        new_ops.sort_by_key(|op| op.hash);
+        if self.may_write() { transport.push(&self.0, new_ops)?; }
+
+        return Ok(fetched.into_keys().collect());
+    }
+
+    /// Enrol this replica, storing the token it's issued. Uses `secret` if given, else the stored user secret.
+    /// Generates the replica's signing key the first time, registering it with the server.
+    pub fn login(&mut self, secret: Option<networking::Secret>) -> errors::Result<()> {
+        if secret.is_some() { self.1.credentials.user_secret = secret; }
+        if self.1.credentials.signing_key.is_none() {
+            self.1.credentials.signing_key = Some(storage::sign::SigningKey::generate());
+        }
+
+        self.1.credentials.token = Some(self.1.blocking().enrol()?);
+        self.0.signer = self.signer();
+
+        Ok(())
+    }
+
+    /// Who operations written by this replica are signed as.
+    fn signer(&self) -> Option<storage::sign::Signer> {
+        Some(storage::sign::Signer {
+            replica: self.get_replica_id()?,
+            key: self.1.credentials.signing_key.clone()?,
+        })
+    }
+
+    /// Fetch the keys of the replicas enrolled in the FS, which operations are verified against.
+    fn refresh_keyring(&self) -> errors::Result<()> {
+        let replicas = self.1.blocking().list_replicas()?;
+        let keyring = storage::sign::Keyring::new(
+            replicas.into_iter().filter_map(|r| Some((r.replica_uuid, r.public_key?)))
+        );
+
+        Ok(keyring.write_out(&self.0)?)
+    }
 
-        return Ok(new_hashes);
+    fn store_user_secret(&mut self, secret: networking::Secret) {
+        println!("Your user secret is: {}", secret.expose());
+        println!("Keep it safe: it's needed to set up or log in to any other replica.");
+        self.1.credentials.user_secret = Some(secret);
     }
 
     pub fn canonize(&self) -> errors::Result<()> {
@@ -103,7 +447,20 @@ impl GlobalConfig {
 
     pub fn find_replica_by_dir(&self, dir: PathBuf) -> Option<SystemConfig> {
         let dir_abs = fs::canonicalize(dir).expect("Error finding absolute path of dir.");
-        Some(self.replicas[self.replicas.iter().position(|x| x.0.working_dir == dir_abs)?].clone())
+        let mut replica = self.replicas[self.replicas.iter().position(|x| x.0.working_dir == dir_abs)?].clone();
+
+        // Keys are stored with the other credentials.
+        replica.0.key = replica.1.credentials.fs_key.clone();
+        replica.0.signer = replica.signer();
+        Some(replica)
+    }
+
+    /// Replace the stored config of the replica in the same directory.
+    pub fn update_replica(&mut self, replica: SystemConfig) {
+        match self.replicas.iter_mut().find(|x| x.0.working_dir == replica.0.working_dir) {
+            Some(x) => *x = replica,
+            None => self.replicas.push(replica),
+        }
     }
 
     pub fn empty() -> Self {
@@ -112,12 +469,14 @@ impl GlobalConfig {
         }
     }
 
+    /// Write out the config. It holds credentials, so is only readable by its owner.
     pub fn write_out(&self, path: &PathBuf) -> std::io::Result<()> {
-        storage::meta::write_at(&storage::Config{working_dir: PathBuf::new(), }, path, false, self)
+        // Holds the user secret, replica tokens and keys.
+        storage::meta::write_private(path, self)
     }
 
     pub fn read(path: &PathBuf) -> std::io::Result<Self> {
-        storage::meta::read_at(&storage::Config{working_dir: PathBuf::new(), }, path, false)
+        storage::meta::read_at(&storage::Config::new(PathBuf::new()), path, false)
     }
 }
 
@@ -131,7 +490,101 @@ pub fn init(path: &PathBuf) -> std::io::Result<()> {
     }
 }
 
-pub fn setup(conf: &mut GlobalConfig, conf_path: &PathBuf, server: &std::net::SocketAddr, user_id: &Option<Uuid>, fs_id: &Option<Uuid>, user_name: &Option<String>, fs_name: &Option<String>, dir: &Option<PathBuf>) {
+/// Prompt for the user's secret, without echoing it.
+fn read_secret() -> networking::Secret {
+    let secret = rpassword::prompt_password("User secret: ").expect("Error reading secret.");
+    return networking::Secret::new(secret.trim().to_owned());
+}
+
+/// Log in again, trying the stored user secret before prompting for it.
+fn relogin(system_config: &mut SystemConfig) {
+    match system_config.login(None) {
+        Err(errors::Error(code, _)) if code == errors::CODE_AUTH_ERR => {
+            system_config.login(Some(read_secret())).expect("Error logging in.");
+        },
+        res => res.expect("Error logging in."),
+    }
+}
+
+/// Register the user and FS with the server if they're new, and enrol the replica.
+fn register(system_config: &mut SystemConfig, user_secret: &Option<String>, key_source: Option<KeySource>) -> Result<(), errors::Error> {
+    // Without a secret, try registering: new users are issued one. Existing users are refused, and asked for theirs.
+    // Users from before authentication need one issued by the server's administrator.
+    let secret = match user_secret {
+        Some(s) => Some(networking::Secret::new(s.clone())),
+        None => match system_config.1.blocking().register_user() {
+            Ok(secret) => secret,
+            Err(errors::Error(code, _)) if code == errors::CODE_AUTH_ERR => None,
+            Err(e) => return Err(e),
+        },
+    };
+    match secret {
+        Some(secret) if user_secret.is_none() => system_config.store_user_secret(secret),
+        Some(secret) => system_config.1.credentials.user_secret = Some(secret),
+        None => system_config.1.credentials.user_secret = Some(read_secret()),
+    }
+
+    let (user_ok, fs_ok) = system_config.1.blocking().check_info()?;
+
+    if !user_ok {
+        let secret = system_config.1.blocking().register_user()?;
+        if let Some(secret) = secret { system_config.store_user_secret(secret); }
+    }
+
+    let fs_uuid = system_config.1.info.get_fs_id().unwrap();
+    if !fs_ok {
+        system_config.0.key = key_source.map(|s| s.key(fs_uuid));
+        system_config.1.blocking().register_fs(system_config.fs_opts())?;
+        system_config.1.role = Some(networking::api::Role::Owner);
+    } else {
+        let role = system_config.1.blocking().fetch_role()?;
+        if role != networking::api::Role::Owner {
+            println!("Joining a FS shared with you, as {:?}.", role);
+        }
+        system_config.1.role = Some(role);
+
+        let fs_opts = system_config.1.blocking().fetch_fs_opts()?;
+        system_config.join_fs(&fs_opts, key_source)?;
+    }
+    system_config.1.credentials.fs_key = system_config.0.key.clone();
+
+    system_config.login(None)?;
+
+    println!("Identity confirmed with server.");
+
+    Ok(())
+}
+
+/// Print an error the user can act on, like a wrong passphrase or secret, and exit without a backtrace.
+fn exit_with(context: &str, errors::Error(code, err_msg): errors::Error) -> ! {
+    eprintln!("{}: {} (code {:#x})", context, err_msg, code);
+    std::process::exit(1);
+}
+
+/// Everything needed to set up a new replica, besides where.
+pub struct SetupOpts {
+    pub server: url::Url,
+    /// The user and FS to join. New ones are created if not given.
+    pub user_id: Option<Uuid>,
+    pub user_secret: Option<String>,
+    pub fs_id: Option<Uuid>,
+    pub user_name: Option<String>,
+    pub fs_name: Option<String>,
+    pub replica_name: Option<String>,
+    /// Where to get the FS key from, if the FS is encrypted.
+    pub key_source: Option<KeySource>,
+    pub storage: storage::Config,
+    pub transfer: networking::TransferConfig,
+    pub tls: networking::TlsConfig,
+    pub replica: ReplicaOpts,
+}
+
+pub fn setup(conf: &mut GlobalConfig, conf_path: &PathBuf, opts: SetupOpts, dir: &Option<PathBuf>) {
+    let SetupOpts {
+        server, user_id, user_secret, fs_id, user_name, fs_name, replica_name, key_source,
+        storage: storage_opts, transfer, tls, replica: replica_opts,
+    } = opts;
+
     let working_dir = match dir {
         Some(d) => d.clone(),
         None => std::env::current_dir().expect("Error opening working directory. Move to a different directory, or specify a working directory."),
@@ -139,37 +592,58 @@ pub fn setup(conf: &mut GlobalConfig, conf_path: &PathBuf, server: &std::net::So
 
     let working_dir = fs::canonicalize(working_dir).expect("Error getting absolute path of working dir.");
 
+    // Certificates are given relative to where setup is run, but used from anywhere.
+    let absolute = |p: &Option<PathBuf>| p.as_ref().map(|p| fs::canonicalize(p).expect("Error finding TLS certificate or key."));
+    let tls = networking::TlsConfig {
+        ca_bundle: absolute(&tls.ca_bundle), client_cert: absolute(&tls.client_cert), client_key: absolute(&tls.client_key),
+    };
+
     let mut system_config = SystemConfig(
-        storage::Config {working_dir}, networking::Config {
-            server: Some(server.clone()),
+        storage::Config {working_dir, ..storage_opts}, networking::Config {
+            server: Some(server),
             info: networking::ReplicaInfo {
-                id: user_id.clone(),
-                disp_name: None,
+                id: None,
+                disp_name: replica_name,
                 fs: networking::FileSystemInfo {
-                    id: fs_id.clone(),
-                    disp_name: fs_name.clone(),
+                    id: fs_id,
+                    disp_name: fs_name,
                     user: networking::UserInfo {
-                        id: user_id.clone(),
-                        disp_name: user_name.clone(),
+                        id: user_id,
+                        disp_name: user_name,
                     }
                 }
-            }
-        }
+            },
+            transfer,
+            tls,
+            credentials: networking::Credentials::default(),
+            role: None,
+        },
+        replica_opts,
     );
     system_config.1.gen_blanks();
 
-    let (user_ok, fs_ok) = system_config.1.check_info().expect("Error checking info with server.");
-
-    if !user_ok {
-        system_config.1.register_user().expect("Error registering user.");
-    }
-
-    if !fs_ok {
-        system_config.1.register_fs().expect("Error registering FS");
+    let fs_uuid = system_config.1.info.get_fs_id().unwrap();
+    let local_dir = system_config.1.local_dir().unwrap_or_else(|e| exit_with("Error finding shared directory", e));
+    match local_dir {
+        // Shared directories have no users or enrolment. The directory only holds the FS's options.
+        Some(shared) => {
+            let local = networking::transport::LocalDir::new(&shared, fs_uuid);
+            match local.fetch_fs_opts().expect("Error reading shared directory.") {
+                None => {
+                    system_config.0.key = key_source.map(|s| s.key(fs_uuid));
+                    local.register_fs(system_config.fs_opts()).expect("Error creating FS in shared directory.");
+                },
+                Some(fs_opts) => if let Err(e) = system_config.join_fs(&fs_opts, key_source) {
+                    exit_with("Error joining FS", e);
+                },
+            }
+            system_config.1.credentials.fs_key = system_config.0.key.clone();
+        },
+        None => if let Err(e) = register(&mut system_config, &user_secret, key_source) {
+            exit_with("Error registering with server", e);
+        },
     }
 
-    println!("Identity confirmed with server.");
-
     system_config.init().expect("Error setting up replica config.");
 
     conf.replicas.push(system_config);
@@ -178,28 +652,176 @@ pub fn setup(conf: &mut GlobalConfig, conf_path: &PathBuf, server: &std::net::So
     println!("Done!");
 }
 
-pub fn sync(conf: GlobalConfig, dir_: &Option<PathBuf>) {
+/// Find the replica in `dir_`, or the current directory if not given.
+fn replica_for(conf: &GlobalConfig, dir_: &Option<PathBuf>) -> SystemConfig {
     let dir = match dir_ {
         Some(d) => d.clone(),
         None => std::env::current_dir().expect("Error opening working directory. Move to a different directory, or specify a working directory."),
     };
 
-    let system_config = conf.find_replica_by_dir(dir).expect("Replica not found. Please run the setup command first.");
+    conf.find_replica_by_dir(dir).expect("Replica not found. Please run the setup command first.")
+}
+
+pub fn sync(mut conf: GlobalConfig, conf_path: &PathBuf, dir_: &Option<PathBuf>, mode: networking::SyncMode) {
+    let mut system_config = replica_for(&conf, dir_);
+
+    // Credentials may have been revoked on purpose, so they're only reissued when the user asks, with `login`.
+    match system_config.sync(mode) {
+        Err(errors::Error(code, err_msg)) if code == errors::CODE_AUTH_ERR => {
+            panic!("Server rejected credentials ({}). If this replica hasn't been removed, run `login` to log in again.", err_msg);
+        },
+        res => res.expect("Sync error."),
+    }
 
-    system_config.sync().expect("Sync error.");
+    conf.update_replica(system_config);
+    conf.write_out(conf_path).expect("Error writing global config.");
 
     println!("Sync OK!");
 }
 
-pub fn canonize(conf: GlobalConfig, dir_: &Option<PathBuf>) {
-    let dir = match dir_ {
-        Some(d) => d.clone(),
-        None => std::env::current_dir().expect("Error opening working directory. Move to a different directory, or specify a working directory."),
-    };
+pub fn sync_peer(conf: GlobalConfig, addr: &str, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    system_config.peer_sync(addr).expect("Sync error.");
+
+    println!("Sync OK!");
+}
 
-    let system_config = conf.find_replica_by_dir(dir).expect("Replica not found. Please run the setup command first.");
+pub fn serve(conf: GlobalConfig, addr: &str, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    system_config.serve(addr).expect("Error serving replica.");
+}
+
+pub fn export_bundle(conf: GlobalConfig, file: &PathBuf, since: &[types::Hash], dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    let manifest = system_config.export_bundle(file, since).expect("Error exporting bundle.");
+
+    println!("Exported {} operations to {:?}.", manifest.ops.len(), file);
+    println!(
+        "To export only newer operations next time, use `--since {}`.",
+        manifest.heads.iter().map(types::hash_to_str).collect::<Vec<_>>().join(","),
+    );
+}
+
+pub fn import_bundle(conf: GlobalConfig, file: &PathBuf, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    system_config.import_bundle(file).expect("Error importing bundle.");
+
+    println!("Import OK!");
+}
+
+pub fn login(mut conf: GlobalConfig, conf_path: &PathBuf, secret: &Option<String>, dir_: &Option<PathBuf>) {
+    let mut system_config = replica_for(&conf, dir_);
+
+    match secret {
+        Some(s) => system_config.login(Some(networking::Secret::new(s.clone()))).expect("Error logging in."),
+        None => relogin(&mut system_config),
+    }
+
+    conf.update_replica(system_config);
+    conf.write_out(conf_path).expect("Error writing global config.");
+
+    println!("Logged in.");
+}
+
+pub fn set_read_only(mut conf: GlobalConfig, conf_path: &PathBuf, read_only: bool, local_changes: LocalChangePolicy, dir_: &Option<PathBuf>) {
+    let mut system_config = replica_for(&conf, dir_);
+
+    system_config.2 = ReplicaOpts {read_only, local_changes};
+
+    conf.update_replica(system_config);
+    conf.write_out(conf_path).expect("Error writing global config.");
+
+    if read_only {
+        println!("Replica is now read-only. Local changes will be {}.", match local_changes {
+            LocalChangePolicy::Revert => "undone on sync",
+            LocalChangePolicy::Error => "reported, and block syncing",
+        });
+    } else {
+        println!("Replica is now writable. Local changes will be synced.");
+    }
+}
+
+pub fn canonize(conf: GlobalConfig, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
 
     system_config.canonize().expect("Canonize error.");
 
     println!("Wrote out canonical forms.");
 }
+
+pub fn pending(conf: GlobalConfig, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    let pending = file_tree::pending::PendingOps::read_in(&system_config.0).expect("Error reading pending operations.");
+
+    for op in pending.ops().iter() {
+        println!("{} ({} attempts): {}", types::hash_to_str(&op.hash), op.attempts, op.reason);
+    }
+
+    println!("{} operations pending.", pending.ops().len());
+}
+
+pub fn list_replicas(conf: GlobalConfig, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    let replicas = system_config.1.blocking().list_replicas().expect("Error listing replicas.");
+
+    for replica in replicas.iter() {
+        let this = if Some(replica.replica_uuid) == system_config.get_replica_id() {" (this replica)"} else {""};
+        println!(
+            "{} {}{}, last seen {}", replica.replica_uuid,
+            replica.display_name.as_deref().unwrap_or("Unnamed Replica"), this, replica.last_seen,
+        );
+    }
+
+    println!("{} replicas enrolled.", replicas.len());
+}
+
+pub fn remove_replica(conf: GlobalConfig, replica_id: &Uuid, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    system_config.1.blocking().remove_replica(*replica_id).expect("Error removing replica.");
+
+    if Some(*replica_id) == system_config.get_replica_id() {
+        println!("Warn: Removed this replica. Run the login command to enrol it again.");
+    }
+
+    println!("Removed replica {}. It can no longer sync.", replica_id);
+}
+
+pub fn invite(conf: GlobalConfig, user_id: &Uuid, role: networking::api::Role, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    system_config.1.blocking().invite(*user_id, role).expect("Error inviting user.");
+
+    println!("Shared FS {} with user {}, as {:?}.", system_config.1.info.get_fs_id().unwrap(), user_id, role);
+    println!("They can set up a replica by running the setup command with `--fs-id {}`.", system_config.1.info.get_fs_id().unwrap());
+}
+
+pub fn list_members(conf: GlobalConfig, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    let members = system_config.1.blocking().list_members().expect("Error listing members.");
+
+    for member in members.iter() {
+        let this = if Some(member.user_uuid) == system_config.1.info.get_user_id() {" (you)"} else {""};
+        println!(
+            "{} {}{}: {:?}", member.user_uuid,
+            member.display_name.as_deref().unwrap_or("Unnamed User"), this, member.role,
+        );
+    }
+
+    println!("{} members.", members.len());
+}
+
+pub fn remove_member(conf: GlobalConfig, user_id: &Uuid, dir_: &Option<PathBuf>) {
+    let system_config = replica_for(&conf, dir_);
+
+    system_config.1.blocking().remove_member(*user_id).expect("Error removing member.");
+
+    println!("Stopped sharing FS with user {}. Their replicas can no longer sync.", user_id);
+}
@@ -12,6 +12,7 @@ impl From<networking::NetError> for Error {
             networking::NetError::CRFSErr(code, msg) => Self(code, msg),
             networking::NetError::ReqwestErr(e) => Self(CODE_NET_ERR, format!("Reqwest Error: {:#?}", e)),
             networking::NetError::SerdeErr(e) => Self(CODE_JSON_ERR, format!("Serde JSON Decode Error: {:#?}", e)),
+            networking::NetError::TlsErr(msg) => Self(CODE_NET_ERR, format!("TLS Error: {}", msg)),
         }
     }
 }
@@ -47,3 +48,8 @@ pub const CODE_JSON_ERR: ErrorCode = 0x00010001; // Includes Serde errors
 pub const CODE_NET_ERR: ErrorCode = 0x00010002;
 pub const CODE_IO_ERR: ErrorCode = 0x00010003;
 pub const CODE_INVALID_DATA: ErrorCode = 0x00010004; // Data doesn't match hash.
+pub const CODE_NAME_CLASH: ErrorCode = 0x00010005; // Names in the working directory clash under the path policy.
+pub const CODE_CANCELLED: ErrorCode = 0x00010006;
+pub const CODE_WRONG_KEY: ErrorCode = 0x00010007; // The passphrase or key doesn't match the FS's.
+pub const CODE_NOT_LOGGED_IN: ErrorCode = 0x00010008; // The replica is missing credentials, and must log in.
+pub const CODE_LOCAL_CHANGES: ErrorCode = 0x00010009; // Local changes in a read-only replica.
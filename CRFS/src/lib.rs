@@ -0,0 +1,11 @@
+// The client's modules, shared by the `CRFS` binary, the reference server and the fuzz targets.
+
+pub mod storage;
+pub mod networking;
+pub mod conflict_res;
+pub mod core;
+pub mod errors;
+pub mod types;
+
+#[cfg(test)]
+mod tests;
@@ -1,12 +1,4 @@
-mod storage;
-mod networking;
-mod conflict_res;
-mod core;
-mod errors;
-mod types;
-
-#[cfg(test)]
-mod tests;
+use crfs::{core, networking, storage, types};
 
 use std::path::PathBuf;
 
@@ -29,12 +21,16 @@ enum Commands {
     Init,
     /// Create and set up a replica.
     Setup {
-        /// Hostname of the remote server to use.
-        #[arg(short)]
-        server: std::net::SocketAddr,
+        /// URL of the remote server to use, e.g. https://crfs.example.org/base/
+        /// A bare host:port uses plain HTTP. A file:// URL syncs through a directory shared between replicas instead.
+        #[arg(short, value_parser = networking::parse_server_url)]
+        server: url::Url,
         /// User UUID. If omitted, a new user will be created.
         #[arg(short)]
         user_id: Option<Uuid>,
+        /// Secret issued when the user was created. Prompted for if needed and omitted.
+        #[arg(long, requires = "user_id")]
+        secret: Option<String>,
         /// Filesystem UUID. If omitted, a new FS will be created.
         #[arg(short)]
         fs_id: Option<Uuid>,
@@ -46,12 +42,154 @@ enum Commands {
         /// If the filesystem exists, this will overwrite any existing name.
         #[arg(long)]
         fs_name: Option<String>,
+        /// Name for this replica, e.g. the device it's on. Optional
+        #[arg(long)]
+        replica_name: Option<String>,
+        /// Encrypt a new filesystem end-to-end, prompting for a passphrase.
+        /// Replicas of an encrypted filesystem always prompt for its passphrase, unless given one.
+        #[arg(long)]
+        encrypt: bool,
+        /// Passphrase the filesystem's encryption key is derived from. Implies --encrypt.
+        #[arg(long, conflicts_with = "key")]
+        passphrase: Option<String>,
+        /// Encryption key shared out-of-band, as 64 hex digits. Implies --encrypt.
+        #[arg(long, value_parser = storage::crypt::Key::from_hex)]
+        key: Option<storage::crypt::Key>,
+        /// How to handle symbolic links in the replica.
+        #[arg(long, value_enum, default_value_t)]
+        symlinks: storage::SymlinkPolicy,
+        /// Don't replicate permission bits, e.g. on filesystems which don't support them.
+        #[arg(long)]
+        no_permissions: bool,
+        /// Replicate file modification times.
+        #[arg(long)]
+        mtimes: bool,
+        /// How to resolve concurrent moves of the same file.
+        #[arg(long, value_enum, default_value_t)]
+        move_conflicts: storage::MovePolicy,
+        /// How to resolve files created concurrently with the same name. Only used when creating a new FS.
+        #[arg(long, value_enum, default_value_t)]
+        name_collisions: storage::CollisionPolicy,
+        /// Don't normalise file names to Unicode NFC. Only used when creating a new FS.
+        #[arg(long)]
+        no_nfc: bool,
+        /// Treat file names which differ only by case as the same name. Only used when creating a new FS.
+        #[arg(long)]
+        case_insensitive: bool,
+        /// Maximum number of operations sent to or fetched from the server in one request.
+        #[arg(long, default_value_t = networking::DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
+        /// Maximum number of requests to the server in flight at once.
+        #[arg(long, default_value_t = networking::DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        /// Seconds allowed for each request to the server.
+        #[arg(long, default_value_t = networking::DEFAULT_TIMEOUT_SECS)]
+        timeout: u64,
+        /// Number of times to retry a request after a transient failure.
+        #[arg(long, default_value_t = networking::DEFAULT_RETRIES)]
+        retries: u32,
+        /// PEM bundle of extra CA certificates to trust when connecting to the server.
+        #[arg(long)]
+        ca_bundle: Option<PathBuf>,
+        /// PEM client certificate, for servers which require one. Requires --client-key.
+        #[arg(long, requires = "client_key")]
+        client_cert: Option<PathBuf>,
+        /// PEM PKCS#8 private key for the client certificate.
+        #[arg(long, requires = "client_cert")]
+        client_key: Option<PathBuf>,
+        /// Mirror the filesystem: pull remote changes, but never push local ones.
+        #[arg(long)]
+        read_only: bool,
+        /// What a read-only replica does with local changes.
+        #[arg(long, value_enum, default_value_t)]
+        on_local_changes: core::LocalChangePolicy,
         /// Replica directory. Defaults to the current directory.
         #[arg(short)]
         dir: Option<PathBuf>,
     },
-    /// Synchronise a replica with the server.
+    /// Synchronise a replica with the server, or with a peer.
     Sync {
+        /// How to work out which operations to exchange with the server.
+        #[arg(long, value_enum, default_value_t)]
+        mode: networking::SyncMode,
+        /// Sync directly with a replica serving at this address, e.g. 192.168.1.20:7878, instead of the server.
+        #[arg(long)]
+        peer: Option<String>,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Serve a replica to peers, which sync with it using `sync --peer`. Peers must hold the FS's key, or be enrolled
+    /// replicas.
+    Serve {
+        /// Address to listen on. Only this device by default: use e.g. 0.0.0.0:7878 to serve the local network.
+        #[arg(long, default_value = networking::peer::DEFAULT_ADDR)]
+        listen: String,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Sync through bundle files, for machines with no network between them.
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommands,
+    },
+    /// Log a replica in again, e.g. after its token was lost. Replicas removed with remove-replica can't log in again.
+    Login {
+        /// The user's secret. If omitted, the stored secret is tried, then prompted for.
+        #[arg(long)]
+        secret: Option<String>,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Make a replica read-only, so that it mirrors the filesystem without pushing changes, or make it writable again.
+    ReadOnly {
+        /// Make the replica writable again.
+        #[arg(long)]
+        off: bool,
+        /// What the replica does with local changes.
+        #[arg(long, value_enum, default_value_t)]
+        on_local_changes: core::LocalChangePolicy,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// List the replicas enrolled in a replica's filesystem.
+    ListReplicas {
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Remove a replica from a filesystem, so it can no longer sync or log in again.
+    RemoveReplica {
+        /// UUID of the replica to remove, as shown by list-replicas.
+        replica_id: Uuid,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Share a replica's filesystem with another user, or change their role.
+    Invite {
+        /// UUID of the user to share the filesystem with.
+        user_id: Uuid,
+        /// What the user may do. Read-only users' replicas never push changes.
+        #[arg(long, value_enum, default_value = "editor")]
+        role: networking::api::Role,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// List the users a replica's filesystem is shared with, and their roles.
+    ListMembers {
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Stop sharing a filesystem with a user, removing their replicas of it.
+    RemoveMember {
+        /// UUID of the user to remove, as shown by list-members.
+        user_id: Uuid,
         /// Replica directory. Defaults to the current directory.
         #[arg(short)]
         dir: Option<PathBuf>
@@ -61,9 +199,38 @@ enum Commands {
         /// Replica directory. Defaults to the current directory.
         #[arg(short)]
         dir: Option<PathBuf>
+    },
+    /// List operations which have been fetched but not yet applied, and why.
+    Pending {
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
     }
 }
 
+#[derive(Subcommand, PartialEq, Eq)]
+enum BundleCommands {
+    /// Write a replica's operations to a bundle file.
+    Export {
+        /// Bundle file to create.
+        file: PathBuf,
+        /// Only export operations newer than these heads, as printed by a previous export. Comma separated.
+        #[arg(long, value_parser = types::str_to_hash, value_delimiter = ',')]
+        since: Vec<types::Hash>,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+    /// Apply the operations in a bundle file to a replica.
+    Import {
+        /// Bundle file to import.
+        file: PathBuf,
+        /// Replica directory. Defaults to the current directory.
+        #[arg(short)]
+        dir: Option<PathBuf>
+    },
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -77,11 +244,47 @@ fn main() {
     let mut conf = core::GlobalConfig::read(&conf_path).expect("Error reading global config. Please run the init command first.");
 
     match &cli.command {
-        Commands::Setup {server, user_id, fs_id, user_name, fs_name, dir} => {
-            core::setup(&mut conf, &conf_path, server, user_id, fs_id, user_name, fs_name, dir);
+        Commands::Setup {server, user_id, secret, fs_id, user_name, fs_name, replica_name, encrypt, passphrase, key, symlinks, no_permissions, mtimes, move_conflicts, name_collisions, no_nfc, case_insensitive, batch_size, concurrency, timeout, retries, ca_bundle, client_cert, client_key, read_only, on_local_changes, dir} => {
+            let storage_opts = storage::Config {
+                working_dir: PathBuf::new(), symlinks: *symlinks, permissions: !no_permissions, mtimes: *mtimes,
+                move_conflicts: *move_conflicts, name_collisions: *name_collisions,
+                paths: storage::PathPolicy {nfc: !no_nfc, case_insensitive: *case_insensitive},
+                key: None, signer: None,
+            };
+            let key_source = match (key, passphrase, encrypt) {
+                (Some(k), _, _) => Some(core::KeySource::Key(k.clone())),
+                (None, Some(p), _) => Some(core::KeySource::Passphrase(p.clone())),
+                (None, None, true) => Some(core::KeySource::Prompt),
+                (None, None, false) => None,
+            };
+            let transfer = networking::TransferConfig {
+                batch_size: *batch_size, concurrency: *concurrency, timeout_secs: *timeout, retries: *retries,
+            };
+            let tls = networking::TlsConfig {
+                ca_bundle: ca_bundle.clone(), client_cert: client_cert.clone(), client_key: client_key.clone(),
+            };
+            let replica_opts = core::ReplicaOpts {read_only: *read_only, local_changes: *on_local_changes};
+            let opts = core::SetupOpts {
+                server: server.clone(), user_id: *user_id, user_secret: secret.clone(), fs_id: *fs_id,
+                user_name: user_name.clone(), fs_name: fs_name.clone(), replica_name: replica_name.clone(), key_source,
+                storage: storage_opts, transfer, tls, replica: replica_opts,
+            };
+            core::setup(&mut conf, &conf_path, opts, dir);
         },
-        Commands::Sync {dir} => core::sync(conf, dir),
+        Commands::Sync {peer: Some(addr), dir, ..} => core::sync_peer(conf, addr, dir),
+        Commands::Sync {mode, peer: None, dir} => core::sync(conf, &conf_path, dir, *mode),
+        Commands::Serve {listen, dir} => core::serve(conf, listen, dir),
+        Commands::Bundle {command: BundleCommands::Export {file, since, dir}} => core::export_bundle(conf, file, since, dir),
+        Commands::Bundle {command: BundleCommands::Import {file, dir}} => core::import_bundle(conf, file, dir),
+        Commands::Login {secret, dir} => core::login(conf, &conf_path, secret, dir),
+        Commands::ReadOnly {off, on_local_changes, dir} => core::set_read_only(conf, &conf_path, !off, *on_local_changes, dir),
+        Commands::ListReplicas {dir} => core::list_replicas(conf, dir),
+        Commands::RemoveReplica {replica_id, dir} => core::remove_replica(conf, replica_id, dir),
+        Commands::Invite {user_id, role, dir} => core::invite(conf, user_id, *role, dir),
+        Commands::ListMembers {dir} => core::list_members(conf, dir),
+        Commands::RemoveMember {user_id, dir} => core::remove_member(conf, user_id, dir),
         Commands::Canonize {dir} => core::canonize(conf, dir),
+        Commands::Pending {dir} => core::pending(conf, dir),
         _ => {panic!();}
     }
 }
@@ -1,4 +1,6 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
 use rand::Rng;
 use regex::Regex;
@@ -9,6 +11,8 @@ use uuid::Uuid;
 use url;
 
 use crate::{errors, types};
+use crate::storage::sign::PublicKey;
+use super::reconcile;
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(from = "String", into = "String")]
@@ -49,6 +53,59 @@ fn unique() -> TID {
     rand::rng().random()
 }
 
+/// An operation pushed to the server, along with its parents, so the server can track the heads of the history.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OpInfo {
+    pub hash: types::Hash,
+    #[serde(default)]
+    pub parents: Vec<types::Hash>,
+}
+
+/// The contents of an operation, for batch transfers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OpData {
+    pub hash: types::Hash,
+    pub data: String,
+}
+
+/// A replica enrolled in a FS, as listed by the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaEntry {
+    pub replica_uuid: Uuid,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// ISO 8601 time the replica last contacted the server.
+    pub last_seen: String,
+    /// Key the replica signs its operations with, if it has registered one.
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+}
+
+/// A user's role in a FS. Owners may share it, editors may change it, and read-only users may only sync it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner,
+    Editor,
+    ReadOnly,
+}
+
+impl Role {
+    /// Whether replicas of users with this role may generate operations.
+    pub fn may_write(&self) -> bool {
+        *self != Self::ReadOnly
+    }
+}
+
+/// A user a FS is shared with, as listed by the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemberEntry {
+    pub user_uuid: Uuid,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    pub role: Role,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type", content = "payload")]
@@ -58,15 +115,38 @@ pub enum MessagePayload {
     CheckUser { user_uuid: Uuid },
     RegisterFs { user_uuid: Uuid, fs_uuid: Uuid, display_name: String, fs_opts: Vec<String> },
     CheckFs { user_uuid: Uuid, fs_uuid: Uuid },
-    Enrol { user_uuid: Uuid, fs_uuid: Uuid, replica_uuid: Uuid },
+    Enrol { user_uuid: Uuid, fs_uuid: Uuid, replica_uuid: Uuid, display_name: String, public_key: Option<PublicKey> },
+    ListReplicas { user_uuid: Uuid, fs_uuid: Uuid },
+    RemoveReplica { user_uuid: Uuid, fs_uuid: Uuid, replica_uuid: Uuid },
+    Invite { user_uuid: Uuid, fs_uuid: Uuid, invitee_uuid: Uuid, role: Role },
+    ListMembers { user_uuid: Uuid, fs_uuid: Uuid },
+    RemoveMember { user_uuid: Uuid, fs_uuid: Uuid, member_uuid: Uuid },
     // FetchData {  },
     // PostData,
     // AckData,
     FetchState { user_uuid: Uuid, fs_uuid: Uuid },
-    PushState { user_uuid: Uuid, fs_uuid: Uuid, ops: HashSet<types::Hash> },
+    FetchHeads { user_uuid: Uuid, fs_uuid: Uuid },
+    PushState { user_uuid: Uuid, fs_uuid: Uuid, ops: Vec<OpInfo> },
+    Reconcile { user_uuid: Uuid, fs_uuid: Uuid, ranges: Vec<reconcile::RangeMsg> },
+    FetchOps { user_uuid: Uuid, fs_uuid: Uuid, hashes: Vec<types::Hash> },
+    PushOps { user_uuid: Uuid, fs_uuid: Uuid, ops: Vec<OpData> },
     // AckOperation,
 }
 
+impl MessagePayload {
+    /// Whether sending the message twice has the same effect as sending it once, so it's safe to retry if the reply is
+    /// lost. Registering and enrolling issue one-time credentials, and a retry would find them already issued.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Self::Ping {..} | Self::CheckUser {..} | Self::CheckFs {..} | Self::ListReplicas {..} | Self::ListMembers {..} |
+            Self::FetchState {..} | Self::FetchHeads {..} | Self::Reconcile {..} | Self::FetchOps {..} |
+            Self::PushState {..} | Self::PushOps {..} => true,
+            Self::RegisterUser {..} | Self::RegisterFs {..} | Self::Enrol {..} | Self::RemoveReplica {..} |
+            Self::Invite {..} | Self::RemoveMember {..} => false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type", content = "payload")]
@@ -84,6 +164,10 @@ pub enum ReplyPayload {
 
         #[serde(default)]
         err_msg: String,
+
+        /// Only issued the first time a user registers.
+        #[serde(default)]
+        secret: Option<super::Secret>,
     },
     CheckUser {
         #[serde(default = "errors::ok")]
@@ -105,6 +189,13 @@ pub enum ReplyPayload {
 
         #[serde(default)]
         err_msg: String,
+
+        #[serde(default)]
+        fs_opts: String, // Space separated.
+
+        /// The requesting user's role. Servers without sharing don't send this, as only the owner has access.
+        #[serde(default)]
+        role: Option<Role>,
     },
     Enrol {
         #[serde(default = "errors::ok")]
@@ -112,6 +203,50 @@ pub enum ReplyPayload {
 
         #[serde(default)]
         err_msg: String,
+
+        #[serde(default)]
+        token: Option<super::Secret>,
+    },
+    ListReplicas {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+
+        #[serde(default)]
+        replicas: Vec<ReplicaEntry>,
+    },
+    RemoveReplica {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+    },
+    Invite {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+    },
+    ListMembers {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+
+        #[serde(default)]
+        members: Vec<MemberEntry>,
+    },
+    RemoveMember {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
     },
     // FetchData {  },
     // PostData,
@@ -126,6 +261,16 @@ pub enum ReplyPayload {
         #[serde(default)]
         state: HashSet<types::Hash>,
     },
+    FetchHeads {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+
+        #[serde(default)]
+        heads: HashSet<types::Hash>,
+    },
     PushState {
         #[serde(default = "errors::ok")]
         code: errors::ErrorCode,
@@ -133,6 +278,33 @@ pub enum ReplyPayload {
         #[serde(default)]
         err_msg: String,
     },
+    Reconcile {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+
+        #[serde(default)]
+        ranges: Vec<reconcile::RangeMsg>,
+    },
+    FetchOps {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+
+        #[serde(default)]
+        ops: Vec<OpData>,
+    },
+    PushOps {
+        #[serde(default = "errors::ok")]
+        code: errors::ErrorCode,
+
+        #[serde(default)]
+        err_msg: String,
+    },
     // AckOperation,
 }
 
@@ -145,7 +317,16 @@ pub fn correct_reply_type(msg: &MessagePayload, reply: &ReplyPayload) -> bool {
         (M::RegisterFs {..}, R::RegisterFs {..}) |
         (M::CheckFs {..}, R::CheckFs {..}) |
         (M::Enrol {..}, R::Enrol {..}) |
+        (M::ListReplicas {..}, R::ListReplicas {..}) |
+        (M::RemoveReplica {..}, R::RemoveReplica {..}) |
+        (M::Invite {..}, R::Invite {..}) |
+        (M::ListMembers {..}, R::ListMembers {..}) |
+        (M::RemoveMember {..}, R::RemoveMember {..}) |
         (M::FetchState {..}, R::FetchState {..}) |
+        (M::FetchHeads {..}, R::FetchHeads {..}) |
+        (M::Reconcile {..}, R::Reconcile {..}) |
+        (M::FetchOps {..}, R::FetchOps {..}) |
+        (M::PushOps {..}, R::PushOps {..}) |
         (M::PushState {..}, R::PushState {..})
             => true,
         _ => false,
@@ -172,11 +353,14 @@ impl Message {
         }
     }
 
-    pub fn send(&self, config: &super::Config) -> super::NetResult<(reqwest::StatusCode, Reply)> {
+    pub async fn send(&self, config: &super::Config) -> super::NetResult<(reqwest::StatusCode, Reply)> {
         let json = serde_json::to_string(&self)?;
         let endpoint = config.get_endpoint("api").expect("Server hostname not configured.");
 
-        let (status, res_body) = post(endpoint, json)?;
+        let (status, res_body) = match self.payload.is_idempotent() {
+            true => post(endpoint, json, config).await?,
+            false => post_once(endpoint, json, config).await?,
+        };
         // println!("{}", &res_body);
         let reply = serde_json::from_str(&res_body)?;
 
@@ -196,29 +380,127 @@ pub struct Reply {
 
 impl Reply {
     pub fn unwrap(self, message: &Message) -> ReplyPayload {
-        if !correct_reply_type(&message.payload, &self.payload) {panic!("Unexpected reply type.")}
-        if message.version != self.version {panic!("API version mismatch.")}
-        if message.transaction_id != self.transaction_id {panic!("TID mismatch.")}
-        if !self.reply {panic!("Not a reply!")}
+        match self.check(message) {
+            Ok(payload) => payload,
+            Err(errors::Error(_, err_msg)) => panic!("{}", err_msg),
+        }
+    }
+
+    /// Check this is the reply to `message`, for replies from peers, which can't be trusted to send one.
+    /// Peers answer requests they don't support with an error `Ping`, which is returned as the error.
+    pub fn check(self, message: &Message) -> errors::Result<ReplyPayload> {
+        let invalid = |err_msg: &str| Err(errors::Error(errors::CODE_INVALID_DATA, err_msg.to_owned()));
+
+        if message.version != self.version {return invalid("API version mismatch.")}
+        if message.transaction_id != self.transaction_id {return invalid("TID mismatch.")}
+        if !self.reply {return invalid("Not a reply!")}
+        if !correct_reply_type(&message.payload, &self.payload) {
+            return match self.payload {
+                ReplyPayload::Ping {code, err_msg} if code != errors::CODE_OK => Err(errors::Error(code, err_msg)),
+                _ => invalid("Unexpected reply type."),
+            };
+        }
+
+        return Ok(self.payload);
+    }
+}
+
+/// Client shared by every request with the same TLS settings, so connections to the server are pooled and reused.
+fn client(tls: &super::TlsConfig) -> super::NetResult<reqwest::Client> {
+    static CLIENTS: OnceLock<Mutex<HashMap<super::TlsConfig, reqwest::Client>>> = OnceLock::new();
+    let mut clients = CLIENTS.get_or_init(Default::default).lock().unwrap();
+
+    if let Some(client) = clients.get(tls) {return Ok(client.clone());}
+
+    let client = build_client(tls)?;
+    clients.insert(tls.clone(), client.clone());
+    return Ok(client);
+}
+
+fn build_client(tls: &super::TlsConfig) -> super::NetResult<reqwest::Client> {
+    let read = |path: &std::path::PathBuf| std::fs::read(path)
+        .map_err(|e| super::NetError::TlsErr(format!("Error reading {:?}: {}", path, e)));
+
+    let mut builder = reqwest::Client::builder();
+
+    if let Some(path) = &tls.ca_bundle {
+        for cert in reqwest::Certificate::from_pem_bundle(&read(path)?)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
 
-        return self.payload;
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            builder = builder.identity(reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)?);
+        },
+        (None, None) => {},
+        _ => return Err(super::NetError::TlsErr("A client certificate and key must be given together.".to_owned())),
     }
+
+    return Ok(builder.build()?);
+}
+
+/// Base delay before retrying a failed request, doubled on each subsequent retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Whether a request which failed this way might succeed if retried.
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.status().is_some_and(is_transient_status)
+}
+
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+/// Exponential backoff, with jitter so concurrent requests don't retry in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY.saturating_mul(1 << attempt.min(16)).min(RETRY_MAX_DELAY);
+    return delay.mul_f64(rand::rng().random_range(0.5..1.0));
 }
 
-pub fn get(endpoint: url::Url) -> super::NetResult<(reqwest::StatusCode, String)> {
-    let client = reqwest::blocking::Client::new();
-    let res = client.get(endpoint).send()?;
-    return Ok((res.status(), res.text()?));
+/// Send a request with the configured timeout, retrying transient failures.
+async fn send(request: reqwest::RequestBuilder, transfer: &super::TransferConfig) -> super::NetResult<(reqwest::StatusCode, String)> {
+    let mut attempt = 0;
+
+    loop {
+        // Bodies are always in memory, so requests can always be cloned.
+        let req = request.try_clone().expect("Request body can't be retried.").timeout(transfer.timeout());
+
+        match req.send().await {
+            Ok(res) if attempt < transfer.retries && is_transient_status(res.status()) => {},
+            Ok(res) => {
+                let status = res.status();
+                match res.text().await {
+                    Ok(text) => return Ok((status, text)),
+                    Err(e) if attempt < transfer.retries && is_transient(&e) => {},
+                    Err(e) => return Err(e.into()),
+                }
+            },
+            Err(e) if attempt < transfer.retries && is_transient(&e) => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        tokio::time::sleep(backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Attach the configured credentials, if any.
+fn authorise(request: reqwest::RequestBuilder, config: &super::Config) -> reqwest::RequestBuilder {
+    match config.credentials.bearer() {
+        Some(secret) => request.bearer_auth(secret.expose()),
+        None => request,
+    }
 }
 
-pub fn post(endpoint: url::Url, body: String) -> super::NetResult<(reqwest::StatusCode, String)> {
-    let client = reqwest::blocking::Client::new();
-    let res = client.post(endpoint).body(body).send()?;
-    return Ok((res.status(), res.text()?));
+pub async fn post(endpoint: url::Url, body: String, config: &super::Config) -> super::NetResult<(reqwest::StatusCode, String)> {
+    let request = authorise(client(&config.tls)?.post(endpoint).body(body), config);
+    return send(request, &config.transfer).await;
 }
 
-pub fn put(endpoint: url::Url, body: String) -> super::NetResult<(reqwest::StatusCode, String)> {
-    let client = reqwest::blocking::Client::new();
-    let res = client.put(endpoint).body(body).send()?;
-    return Ok((res.status(), res.text()?));
+/// Post without retrying, for requests which mustn't be repeated.
+pub async fn post_once(endpoint: url::Url, body: String, config: &super::Config) -> super::NetResult<(reqwest::StatusCode, String)> {
+    let request = authorise(client(&config.tls)?.post(endpoint).body(body), config);
+    return send(request, &super::TransferConfig {retries: 0, ..config.transfer}).await;
 }
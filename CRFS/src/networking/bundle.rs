@@ -0,0 +1,108 @@
+// Offline sync through bundle files, e.g. carried between machines on a USB stick.
+// A bundle is a tar archive of operations exactly as they're stored, under `objects/`, along with a manifest. Imported
+// operations are verified as if they'd been fetched from the server, and applied by the normal application path.
+
+use crate::{errors, storage, types};
+use crate::conflict_res::{file_tree, CmRDT};
+use super::{api, verify_op};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+const MANIFEST: &str = "manifest.json";
+const OBJECTS: &str = "objects";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub version: api::VersionNumber,
+    pub fs_uuid: Uuid,
+    /// Heads of the exporting replica's history. A later bundle can be exported `--since` these, holding only newer
+    /// operations.
+    pub heads: Vec<types::Hash>,
+    /// Every operation in the bundle.
+    pub ops: Vec<types::Hash>,
+}
+
+fn append(builder: &mut tar::Builder<File>, path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    return builder.append_data(&mut header, path, data);
+}
+
+/// Write every operation in `history` to a bundle at `path`, leaving out `since` and everything before it.
+pub fn export(storage: &storage::Config, fs_uuid: Uuid, history: &file_tree::SystemHistory, since: &[types::Hash], path: &Path) -> errors::Result<Manifest> {
+    let exported = CmRDT::ancestors(&history.all_parents(), since);
+
+    let mut ops: Vec<types::Hash> = history.all_hashes().difference(&exported).cloned().collect();
+    ops.sort();
+
+    let manifest = Manifest {version: api::VERSION, fs_uuid, heads: history.heads(), ops};
+
+    let mut builder = tar::Builder::new(File::create(path)?);
+    append(&mut builder, MANIFEST, serde_json::to_string(&manifest).map_err(super::NetError::from)?.as_bytes())?;
+    for hash in manifest.ops.iter() {
+        let mut data = Vec::new();
+        storage::object::read_bytes(storage, &storage::object::Location::Object(*hash), &mut data)?;
+        append(&mut builder, &format!("{}/{}", OBJECTS, types::hash_to_str(hash)), &data)?;
+    }
+    builder.into_inner()?;
+
+    return Ok(manifest);
+}
+
+/// Read the bundle at `path`, checking it's for this FS and that every operation matches its hash and decrypts, then
+/// store its operations. They aren't applied, so the returned manifest lists the operations to apply.
+pub fn import(storage: &storage::Config, fs_uuid: Uuid, path: &Path) -> errors::Result<Manifest> {
+    let invalid = |msg: String| errors::Error(errors::CODE_INVALID_DATA, msg);
+
+    let mut manifest: Option<Manifest> = None;
+    let mut objects: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+
+        let mut data = Vec::new(); entry.read_to_end(&mut data)?;
+        if name == Path::new(MANIFEST) {
+            manifest = Some(serde_json::from_slice(&data).map_err(super::NetError::from)?);
+        } else {
+            objects.insert(name, data);
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid("Bundle has no manifest.".to_owned()))?;
+    if manifest.fs_uuid != fs_uuid {
+        return Err(errors::Error(errors::CODE_NO_FS, format!("Bundle is for FS {}, not {}.", manifest.fs_uuid, fs_uuid)));
+    }
+
+    // Check everything before storing anything, so a damaged bundle is rejected whole.
+    let mut verified = Vec::new();
+    let mut seen = HashSet::new();
+    for hash in manifest.ops.iter() {
+        if !seen.insert(*hash) {continue;}
+
+        let name = Path::new(OBJECTS).join(types::hash_to_str(hash));
+        let data = objects.remove(&name).ok_or_else(|| invalid(format!("Bundle is missing operation {}.", types::hash_to_str(hash))))?;
+        let data = String::from_utf8(data).map_err(|e| invalid(e.to_string()))?;
+
+        verify_op(storage, &data, hash)?;
+
+        verified.push((*hash, data));
+    }
+
+    for (hash, data) in verified.iter() {
+        let loc = storage::object::Location::Object(*hash);
+        if !loc.exists(storage) { storage::object::write(storage, &loc, data.as_bytes())?; }
+    }
+
+    return Ok(manifest);
+}
@@ -1,13 +1,22 @@
 use crate::{errors, storage, types::{self, calculate_hash}};
+use crate::conflict_res::file_tree;
 
-use std::net;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
 
+use futures::{stream, StreamExt, TryStreamExt};
 use reqwest;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 use uuid::Uuid;
 
 pub mod api;
+pub mod reconcile;
+pub mod peer;
+pub mod bundle;
+pub mod transport;
 
 #[cfg(test)]
 mod tests;
@@ -17,6 +26,7 @@ pub enum NetError {
     CRFSErr(errors::ErrorCode, String),
     ReqwestErr(reqwest::Error),
     SerdeErr(serde_json::Error),
+    TlsErr(String),
 }
 
 type NetResult<T> = std::result::Result<T, NetError>;
@@ -75,10 +85,173 @@ impl ReplicaInfo {
     }
 }
 
+/// How a replica works out which operations to exchange with the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SyncMode {
+    /// Fetch the server's heads, and walk back to the operations we're missing.
+    #[default]
+    Heads,
+    /// Reconcile the sets of operation hashes by comparing fingerprints of ranges, for large histories.
+    /// Small histories exchange their full state instead.
+    Reconcile,
+}
+
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+pub const DEFAULT_CONCURRENCY: usize = 4;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Limits on transferring operations to and from the server.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// Maximum number of operations sent in one request.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Maximum number of requests in flight at once.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Time allowed for each request, including reading the response.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Number of times a request is retried after a transient failure.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_batch_size() -> usize { DEFAULT_BATCH_SIZE }
+fn default_concurrency() -> usize { DEFAULT_CONCURRENCY }
+fn default_timeout_secs() -> u64 { DEFAULT_TIMEOUT_SECS }
+fn default_retries() -> u32 { DEFAULT_RETRIES }
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: DEFAULT_BATCH_SIZE, concurrency: DEFAULT_CONCURRENCY,
+            timeout_secs: DEFAULT_TIMEOUT_SECS, retries: DEFAULT_RETRIES,
+        }
+    }
+}
+
+impl TransferConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// Runtime driving every request. Shared, so connections are pooled across calls.
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    return RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread().enable_all().build().expect("Error starting async runtime.")
+    });
+}
+
+/// Run a future to completion on the networking runtime.
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    return runtime().block_on(f);
+}
+
+/// Run a future to completion, cancelling it if the user interrupts the process.
+fn block_on_cancellable<T>(f: impl Future<Output = errors::Result<T>>) -> errors::Result<T> {
+    return block_on(async {
+        tokio::select! {
+            res = f => res,
+            _ = tokio::signal::ctrl_c() => Err(errors::Error(errors::CODE_CANCELLED, "Interrupted.".to_owned())),
+        }
+    });
+}
+
+/// TLS settings for connecting to the server, on top of the system's trusted certificates.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TlsConfig {
+    /// PEM bundle of extra CA certificates to trust, e.g. for a server with a private CA.
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM certificate identifying this client, for servers which require client certificates.
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// PEM PKCS#8 private key for `client_cert`.
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+}
+
+/// Parse the URL of a server. Bare `host:port` addresses, as older configs store, are assumed to be plain HTTP.
+/// The path always ends in `/`, so endpoints are placed beneath any prefix the server is mounted under.
+pub fn parse_server_url(s: &str) -> Result<url::Url, url::ParseError> {
+    let mut url = if s.contains("://") {url::Url::parse(s)?} else {url::Url::parse(&format!("http://{}", s))?};
+
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+
+    return Ok(url);
+}
+
+/// A credential issued by the server. Never printed by `Debug`, so it can't leak into logs.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+/// Credentials used to authenticate with the server.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Credentials {
+    /// Issued when the user registers. Needed to register filesystems and enrol replicas.
+    #[serde(default)]
+    pub user_secret: Option<Secret>,
+    /// Issued when this replica is enrolled. Only grants access to its filesystem.
+    #[serde(default)]
+    pub token: Option<Secret>,
+    /// Key the FS is encrypted with, if it's encrypted.
+    #[serde(default)]
+    pub fs_key: Option<storage::crypt::Key>,
+    /// Key this replica signs its operations with. Its public half is registered when the replica enrols.
+    #[serde(default)]
+    pub signing_key: Option<storage::sign::SigningKey>,
+}
+
+impl Credentials {
+    /// The credential to send with requests: the replica's token where it has one, as it grants the least.
+    pub fn bearer(&self) -> Option<&Secret> {
+        self.token.as_ref().or(self.user_secret.as_ref())
+    }
+}
+
+fn deserialize_server<'de, D: Deserializer<'de>>(d: D) -> Result<Option<url::Url>, D::Error> {
+    let server: Option<String> = Option::deserialize(d)?;
+    return server.map(|s| parse_server_url(&s).map_err(serde::de::Error::custom)).transpose();
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
-    pub server: Option<net::SocketAddr>,
+    #[serde(default, deserialize_with = "deserialize_server")]
+    pub server: Option<url::Url>,
     pub info: ReplicaInfo,
+    #[serde(default)]
+    pub transfer: TransferConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub credentials: Credentials,
+    /// This user's role in the FS, as of the last sync.
+    #[serde(default)]
+    pub role: Option<api::Role>,
 }
 
 impl Config {
@@ -86,6 +259,10 @@ impl Config {
         Self {
             server: None,
             info: ReplicaInfo::empty(),
+            transfer: TransferConfig::default(),
+            tls: TlsConfig::default(),
+            credentials: Credentials::default(),
+            role: None,
         }
     }
 
@@ -94,8 +271,16 @@ impl Config {
         self.info.gen_blanks();
     }
 
+    /// This config, authenticating with the user's secret rather than the replica's token.
+    /// Registering and enrolling act on behalf of the user, so replica tokens aren't accepted for them.
+    fn as_user(&self) -> Self {
+        let mut config = self.clone();
+        config.credentials.token = None;
+        return config;
+    }
+
     /// Returns 2 bools, represting if the server holds info on the user, and fs, respectively.
-    pub fn check_info(&self) -> errors::Result<(bool, bool)> {
+    pub async fn check_info(&self) -> errors::Result<(bool, bool)> {
         let (user_uuid, fs_uuid) = (
             self.info.get_user_id().expect("No user UUID configured!"),
             self.info.get_fs_id().expect("No FS UUID configured!"),
@@ -106,7 +291,7 @@ impl Config {
             user_uuid,
         });
 
-        let (_, res) = user_msg.send(&self)?;
+        let (_, res) = user_msg.send(&self).await?;
         match res.unwrap(&user_msg) {
             api::ReplyPayload::CheckUser {code, ..} => {
                 if code != 0 {return Ok((false, false))};
@@ -119,7 +304,7 @@ impl Config {
             user_uuid, fs_uuid,
         });
 
-        let (_, res) = fs_msg.send(&self)?;
+        let (_, res) = fs_msg.send(&self).await?;
         match res.unwrap(&fs_msg) {
             api::ReplyPayload::CheckFs {code, ..} => {
                 if code != 0 {return Ok((true, false))};
@@ -130,7 +315,8 @@ impl Config {
         return Ok((true, true));
     }
 
-    pub fn register_user(&self) -> errors::Result<()> {
+    /// Register the user, returning their secret if this is the first time they've registered.
+    pub async fn register_user(&self) -> errors::Result<Option<Secret>> {
         let user_uuid = self.info.fs.user.id.expect("No user UUID configured!");
         let display_name = self.info.fs.user.disp_name.clone().unwrap_or("Unnamed User".to_owned());
 
@@ -139,20 +325,60 @@ impl Config {
             display_name,
         });
 
-        let (_, res) = message.send(&self)?;
-        let (code, err_msg) = match res.unwrap(&message) {
-            api::ReplyPayload::RegisterUser {code, err_msg} => (code, err_msg),
+        let (_, res) = message.send(&self.as_user()).await?;
+        let (code, err_msg, secret) = match res.unwrap(&message) {
+            api::ReplyPayload::RegisterUser {code, err_msg, secret} => (code, err_msg, secret),
             _ => panic!(), // Unreachable
         };
 
         if code == 0 {
-            return Ok(())
+            return Ok(secret)
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// Get the options the FS was registered with.
+    pub async fn fetch_fs_opts(&self) -> errors::Result<Vec<String>> {
+        let message = api::Message::new(api::MessagePayload::CheckFs {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+        });
+
+        let (_, res) = message.send(&self).await?;
+        let (code, err_msg, fs_opts) = match res.unwrap(&message) {
+            api::ReplyPayload::CheckFs {code, err_msg, fs_opts, ..} => (code, err_msg, fs_opts),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(fs_opts.split_whitespace().map(|o| o.to_owned()).collect())
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// Get this user's role in the FS.
+    pub async fn fetch_role(&self) -> errors::Result<api::Role> {
+        let message = api::Message::new(api::MessagePayload::CheckFs {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+        });
+
+        let (_, res) = message.send(&self).await?;
+        let (code, err_msg, role) = match res.unwrap(&message) {
+            api::ReplyPayload::CheckFs {code, err_msg, role, ..} => (code, err_msg, role),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(role.unwrap_or(api::Role::Owner))
         } else {
             return Err(errors::Error(code, err_msg));
         }
     }
 
-    pub fn register_fs(&self) -> errors::Result<()> {
+    pub async fn register_fs(&self, fs_opts: Vec<String>) -> errors::Result<()> {
         let user_uuid = self.info.fs.user.id.expect("No user UUID configured!");
         let fs_uuid = self.info.fs.id.expect("No FS UUID configured!");
         let display_name = self.info.fs.disp_name.clone().unwrap_or("Unnamed Filesystem".to_owned());
@@ -161,10 +387,10 @@ impl Config {
             user_uuid,
             fs_uuid,
             display_name,
-            fs_opts: Vec::new(),
+            fs_opts,
         });
 
-        let (_, res) = message.send(&self)?;
+        let (_, res) = message.send(&self.as_user()).await?;
         let (code, err_msg) = match res.unwrap(&message) {
             api::ReplyPayload::RegisterFs {code, err_msg} => (code, err_msg),
             _ => panic!(), // Unreachable
@@ -177,43 +403,284 @@ impl Config {
         }
     }
 
+    /// Enrol this replica in its FS, returning a token for it. Requires the user's secret.
+    pub async fn enrol(&self) -> errors::Result<Secret> {
+        let message = api::Message::new(api::MessagePayload::Enrol {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+            replica_uuid: self.info.get_replica_id().expect("No replica UUID configured!"),
+            display_name: self.info.disp_name.clone().unwrap_or("Unnamed Replica".to_owned()),
+            public_key: self.credentials.signing_key.as_ref().map(|k| k.public_key()),
+        });
+
+        let (_, res) = message.send(&self.as_user()).await?;
+        let (code, err_msg, token) = match res.unwrap(&message) {
+            api::ReplyPayload::Enrol {code, err_msg, token} => (code, err_msg, token),
+            _ => panic!(), // Unreachable
+        };
+
+        match (code, token) {
+            (0, Some(token)) => return Ok(token),
+            (0, None) => return Err(errors::Error(errors::CODE_INVALID_DATA, "Server didn't issue a token.".to_owned())),
+            _ => return Err(errors::Error(code, err_msg)),
+        }
+    }
+
+    /// List the replicas enrolled in this FS, most recently seen first.
+    pub async fn list_replicas(&self) -> errors::Result<Vec<api::ReplicaEntry>> {
+        let message = api::Message::new(api::MessagePayload::ListReplicas {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+        });
+
+        let (_, res) = message.send(&self).await?;
+        let (code, err_msg, replicas) = match res.unwrap(&message) {
+            api::ReplyPayload::ListReplicas {code, err_msg, replicas} => (code, err_msg, replicas),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(replicas)
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// Remove a replica from this FS, revoking its token. Requires the user's secret.
+    pub async fn remove_replica(&self, replica_uuid: Uuid) -> errors::Result<()> {
+        let message = api::Message::new(api::MessagePayload::RemoveReplica {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+            replica_uuid,
+        });
+
+        let (_, res) = message.send(&self.as_user()).await?;
+        let (code, err_msg) = match res.unwrap(&message) {
+            api::ReplyPayload::RemoveReplica {code, err_msg} => (code, err_msg),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(())
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// Share this FS with another user, or change their role if it's already shared with them. Requires the user's
+    /// secret, and that they own the FS.
+    pub async fn invite(&self, invitee_uuid: Uuid, role: api::Role) -> errors::Result<()> {
+        let message = api::Message::new(api::MessagePayload::Invite {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+            invitee_uuid, role,
+        });
+
+        let (_, res) = message.send(&self.as_user()).await?;
+        let (code, err_msg) = match res.unwrap(&message) {
+            api::ReplyPayload::Invite {code, err_msg} => (code, err_msg),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(())
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// List the users this FS is shared with, and their roles.
+    pub async fn list_members(&self) -> errors::Result<Vec<api::MemberEntry>> {
+        let message = api::Message::new(api::MessagePayload::ListMembers {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+        });
+
+        let (_, res) = message.send(&self).await?;
+        let (code, err_msg, members) = match res.unwrap(&message) {
+            api::ReplyPayload::ListMembers {code, err_msg, members} => (code, err_msg, members),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(members)
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// Stop sharing this FS with a user, removing their replicas of it. Requires the user's secret.
+    pub async fn remove_member(&self, member_uuid: Uuid) -> errors::Result<()> {
+        let message = api::Message::new(api::MessagePayload::RemoveMember {
+            user_uuid: self.info.get_user_id().expect("No user UUID configured!"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured!"),
+            member_uuid,
+        });
+
+        let (_, res) = message.send(&self.as_user()).await?;
+        let (code, err_msg) = match res.unwrap(&message) {
+            api::ReplyPayload::RemoveMember {code, err_msg} => (code, err_msg),
+            _ => panic!(), // Unreachable
+        };
+
+        if code == 0 {
+            return Ok(())
+        } else {
+            return Err(errors::Error(code, err_msg));
+        }
+    }
+
+    /// The shared directory replicas sync through, if the server is a `file://` URL rather than a CRFS server.
+    pub fn local_dir(&self) -> errors::Result<Option<PathBuf>> {
+        let server = match self.server.as_ref() {
+            Some(server) if server.scheme() == "file" => server,
+            _ => return Ok(None),
+        };
+
+        match server.to_file_path() {
+            Ok(dir) => Ok(Some(dir)),
+            Err(()) => Err(errors::Error(errors::CODE_INVALID_DATA, format!("Malformed file URL: {}", server))),
+        }
+    }
+
+    /// The backend this replica syncs through.
+    pub fn transport(&self) -> errors::Result<Box<dyn transport::Transport + '_>> {
+        Ok(match self.local_dir()? {
+            Some(dir) => Box::new(transport::LocalDir::new(&dir, self.info.get_fs_id().expect("No FS UUID configured"))),
+            None => Box::new(self.blocking()),
+        })
+    }
+
     pub fn get_endpoint(&self, endpoint: &str) -> Option<url::Url> {
-        let server = self.server?;
-        let base_url = format!("http://{}/{}/", server, endpoint);
-        return Some(url::Url::parse(&base_url).expect("Malformed URL."));
+        let server = self.server.as_ref()?;
+        return Some(server.join(&format!("{}/", endpoint)).expect("Malformed URL."));
     }
 
-    pub fn pull(&self, storage: &storage::Config, local_hashes: &HashSet<types::Hash>, remote_hashes: &HashSet<types::Hash>) -> errors::Result<HashSet<types::Hash>> {
-        // let remote_hashes = self.fetch_state()?;
-        let new_hashes: HashSet<_> = remote_hashes.difference(local_hashes).cloned().collect();
+    /// Fetch the given operations, skipping any already stored, and return the parents of each.
+    pub async fn fetch_ops(&self, storage: &storage::Config, ops: &[types::Hash]) -> errors::Result<HashMap<types::Hash, Vec<types::Hash>>> {
+        // Operations fetched by a previous sync, but not yet applied, are already stored.
+        let missing: Vec<types::Hash> = ops.iter()
+            .filter(|op| !storage::object::Location::Object(**op).exists(storage))
+            .cloned().collect();
 
-        for op in new_hashes.iter() {
-            self.fetch_op(&storage, op)?;
+        self.in_batches(&missing, |batch| self.fetch_batch(storage, batch)).await?;
+
+        let mut fetched = HashMap::new();
+        for op in ops.iter() {
+            fetched.insert(*op, file_tree::read_parents(storage, op)?);
         }
 
-        return Ok(new_hashes);
+        return Ok(fetched);
     }
 
-    pub fn push(&self, storage: &storage::Config, local_hashes: &HashSet<types::Hash>, remote_hashes: &HashSet<types::Hash>) -> errors::Result<()> {
-        // let remote_hashes = self.fetch_state()?; // De-duplicate this?
-        let new_hashes: HashSet<_> = local_hashes.difference(&remote_hashes).cloned().collect();
+    /// Run `f` over batches of `items`, with at most `transfer.concurrency` batches in flight at once.
+    /// The first error cancels every batch still in flight.
+    async fn in_batches<'a, T, F>(&self, items: &'a [T], f: impl Fn(&'a [T]) -> F) -> errors::Result<()>
+    where F: Future<Output = errors::Result<()>> {
+        stream::iter(items.chunks(self.transfer.batch_size.max(1)))
+            .map(f)
+            .buffer_unordered(self.transfer.concurrency.max(1))
+            .try_collect::<()>().await
+    }
 
-        for op in new_hashes.iter() {
-            self.push_op(&storage, op)?;
+    async fn fetch_batch(&self, storage: &storage::Config, hashes: &[types::Hash]) -> errors::Result<()> {
+        let message = api::Message::new(api::MessagePayload::FetchOps {
+            user_uuid: self.info.get_user_id().expect("No User UUID configured"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured"),
+            hashes: hashes.to_vec(),
+        });
+
+        let (_, reply) = message.send(self).await?;
+        let ops = match reply.unwrap(&message) {
+            api::ReplyPayload::FetchOps {code, err_msg, ops} => {
+                if code == errors::CODE_OK {ops}
+                else {return Err(errors::Error(code, err_msg))}
+            },
+            _ => {panic!()} // should never be reached due to reply.unwrap() handling unexpected reply types.
+        };
+
+        if ops.len() != hashes.len() {
+            return Err(errors::Error(errors::CODE_NOT_FOUND, format!("Requested {} operations, received {}.", hashes.len(), ops.len())));
         }
 
-        self.push_state(new_hashes)?;
+        for op in ops.iter() {
+            if !hashes.contains(&op.hash) {
+                return Err(errors::Error(errors::CODE_INVALID_DATA, format!("Received unrequested operation {}.", types::hash_to_str(&op.hash))));
+            }
+            verify_and_store(storage, &op.data, &op.hash)?;
+        }
 
         return Ok(());
     }
 
-    pub fn fetch_state(&self) -> errors::Result<HashSet<types::Hash>> {
+    async fn push_batch(&self, storage: &storage::Config, hashes: &[api::OpInfo]) -> errors::Result<()> {
+        let mut ops = Vec::new();
+        for op in hashes.iter() {
+            let mut data = String::new();
+            storage::object::read_string(storage, &storage::object::Location::Object(op.hash), &mut data)?;
+            ops.push(api::OpData {hash: op.hash, data});
+        }
+
+        let message = api::Message::new(api::MessagePayload::PushOps {
+            user_uuid: self.info.get_user_id().expect("No User UUID configured"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured"),
+            ops,
+        });
+
+        let (_, reply) = message.send(self).await?;
+        match reply.unwrap(&message) {
+            api::ReplyPayload::PushOps {code, err_msg} => {
+                if code == errors::CODE_OK {return Ok(())}
+                else {return Err(errors::Error(code, err_msg))}
+            },
+            _ => {panic!()} // should never be reached due to reply.unwrap() handling unexpected reply types.
+        }
+    }
+
+    /// Work out which operations differ between us and the server, without exchanging the full set of hashes.
+    pub async fn reconcile(&self, local_hashes: &HashSet<types::Hash>) -> errors::Result<reconcile::Difference> {
+        let set = reconcile::SortedSet::new(local_hashes);
+        let mut diff = reconcile::Difference::default();
+
+        let mut ranges = reconcile::initial(&set);
+        while !ranges.is_empty() {
+            let reply = self.reconcile_round(ranges).await?;
+            ranges = reconcile::process(&set, &reply, &mut diff);
+        }
+
+        return Ok(diff);
+    }
+
+    async fn reconcile_round(&self, ranges: Vec<reconcile::RangeMsg>) -> errors::Result<Vec<reconcile::RangeMsg>> {
+        let message = api::Message::new(api::MessagePayload::Reconcile {
+            user_uuid: self.info.get_user_id().expect("No User UUID configured"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured"),
+            ranges,
+        });
+
+        let (_, reply) = message.send(self).await?;
+        let payload = reply.unwrap(&message);
+
+        match payload {
+            api::ReplyPayload::Reconcile {code, err_msg, ranges} => {
+                if code == errors::CODE_OK {return Ok(ranges)}
+                else {return Err(errors::Error(code, err_msg))}
+            },
+            _ => {panic!()} // should never be reached due to reply.unwrap() handling unexpected reply types.
+        }
+    }
+
+    pub async fn push_ops(&self, storage: &storage::Config, ops: &[api::OpInfo]) -> errors::Result<()> {
+        self.in_batches(ops, |batch| self.push_batch(storage, batch)).await
+    }
+
+    pub async fn fetch_state(&self) -> errors::Result<HashSet<types::Hash>> {
         let message = api::Message::new(api::MessagePayload::FetchState {
             user_uuid: self.info.get_user_id().expect("No User UUID configured"),
             fs_uuid: self.info.get_fs_id().expect("No FS UUID configured"),
         });
 
-        let (_, reply) = message.send(self)?;
+        let (_, reply) = message.send(self).await?;
         let payload = reply.unwrap(&message);
 
         match payload {
@@ -225,14 +692,33 @@ impl Config {
         }
     }
 
-    pub fn push_state(&self, ops: HashSet<types::Hash>) -> errors::Result<()> {
+    /// Fetch the heads of the server's history, i.e. the operations no other operation depends on.
+    pub async fn fetch_heads(&self) -> errors::Result<HashSet<types::Hash>> {
+        let message = api::Message::new(api::MessagePayload::FetchHeads {
+            user_uuid: self.info.get_user_id().expect("No User UUID configured"),
+            fs_uuid: self.info.get_fs_id().expect("No FS UUID configured"),
+        });
+
+        let (_, reply) = message.send(self).await?;
+        let payload = reply.unwrap(&message);
+
+        match payload {
+            api::ReplyPayload::FetchHeads {code, err_msg, heads} => {
+                if code == errors::CODE_OK {return Ok(heads)}
+                else {return Err(errors::Error(code, err_msg))}
+            },
+            _ => {panic!()} // should never be reached due to reply.unwrap() handling unexpected reply types.
+        }
+    }
+
+    pub async fn push_state(&self, ops: Vec<api::OpInfo>) -> errors::Result<()> {
         let message = api::Message::new(api::MessagePayload::PushState {
             user_uuid: self.info.get_user_id().expect("No User UUID configured"),
             fs_uuid: self.info.get_fs_id().expect("No FS UUID configured"),
             ops,
         });
 
-        let (_, reply) = message.send(self)?;
+        let (_, reply) = message.send(self).await?;
         let payload = reply.unwrap(&message);
 
         match payload {
@@ -243,50 +729,106 @@ impl Config {
             _ => {panic!()} // should never be reached due to reply.unwrap() handling unexpected reply types.
         }
     }
+}
 
-    fn fetch_op(&self, storage: &storage::Config, op: &types::Hash) -> errors::Result<()> {
-        let endpoint = self.get_endpoint("operation").unwrap();
-        let fs_uuid: String = self.info.get_fs_id().expect("No FS UUID configured").into();
+/// Check an operation from a remote matches its hash, and decrypts with its plaintext matching the hash sealed inside it.
+/// The outer hash only shows the remote returned what was uploaded, not that a replica with the key sealed it.
+fn verify_op(storage: &storage::Config, data: &str, hash: &types::Hash) -> errors::Result<()> {
+    if calculate_hash(data) != *hash {
+        return Err(errors::Error(errors::CODE_INVALID_DATA, format!("Operation {} doesn't match its hash.", types::hash_to_str(hash))));
+    }
 
-        let full_url = endpoint
-            .join(&format!("{}/", &fs_uuid)).unwrap()
-            .join(&types::hash_to_str(op)).unwrap();
+    match storage::object::unseal(storage, data.as_bytes().to_vec()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(errors::Error(errors::CODE_INVALID_DATA, e.to_string())),
+    }
+}
 
-        let (code, data) = api::get(full_url)?;
+/// Verify an operation from a remote with `verify_op`, then store it.
+fn verify_and_store(storage: &storage::Config, data: &str, hash: &types::Hash) -> errors::Result<()> {
+    verify_op(storage, data, hash)?;
+    storage::object::write(storage, &storage::object::Location::Object(*hash), data.as_bytes())?;
 
-        if !code.is_success() {
-            return Err(errors::Error(errors::CODE_NET_ERR, format!("Received code {}", code)));
-        }
+    return Ok(());
+}
 
-        if calculate_hash(&data) != *op {
-            return Err(errors::Error(errors::CODE_INVALID_DATA, "Hash doesn't match downloaded data.".to_owned()));
-        }
+/// Blocking facade over the async client, for the CLI. Every call can be interrupted with Ctrl-C.
+pub struct Blocking<'a>(&'a Config);
 
-        let loc = storage::object::Location::Object(op.clone());
-        storage::object::write(storage, &loc, data.as_bytes())?;
+impl Config {
+    pub fn blocking(&self) -> Blocking<'_> {
+        return Blocking(self);
+    }
+}
 
-        return Ok(());
+impl Blocking<'_> {
+    pub fn check_info(&self) -> errors::Result<(bool, bool)> {
+        block_on_cancellable(self.0.check_info())
     }
 
-    fn push_op(&self, storage: &storage::Config, op: &types::Hash) -> errors::Result<()> {
-        let endpoint = self.get_endpoint("operation").unwrap();
-        let fs_uuid: String = self.info.get_fs_id().expect("No FS UUID configured").into();
+    pub fn register_user(&self) -> errors::Result<Option<Secret>> {
+        block_on_cancellable(self.0.register_user())
+    }
 
-        let full_url = endpoint
-            .join(&format!("{}/", &fs_uuid)).unwrap()
-            .join(&types::hash_to_str(op)).unwrap();
+    pub fn enrol(&self) -> errors::Result<Secret> {
+        block_on_cancellable(self.0.enrol())
+    }
 
-        // dbg!(&full_url, &fs_uuid);
+    pub fn list_replicas(&self) -> errors::Result<Vec<api::ReplicaEntry>> {
+        block_on_cancellable(self.0.list_replicas())
+    }
 
-        let loc = storage::object::Location::Object(op.clone());
-        let mut buf = String::new(); storage::object::read_string(storage, &loc, &mut buf)?;
+    pub fn remove_replica(&self, replica_uuid: Uuid) -> errors::Result<()> {
+        block_on_cancellable(self.0.remove_replica(replica_uuid))
+    }
 
-        let (code, res) = api::put(full_url, buf)?;
+    pub fn fetch_fs_opts(&self) -> errors::Result<Vec<String>> {
+        block_on_cancellable(self.0.fetch_fs_opts())
+    }
 
-        if !code.is_success() {
-            return Err(errors::Error(errors::CODE_NET_ERR, format!("Received code {}, message {}", code, res)));
-        }
+    pub fn fetch_role(&self) -> errors::Result<api::Role> {
+        block_on_cancellable(self.0.fetch_role())
+    }
 
-        return Ok(());
+    pub fn invite(&self, invitee_uuid: Uuid, role: api::Role) -> errors::Result<()> {
+        block_on_cancellable(self.0.invite(invitee_uuid, role))
+    }
+
+    pub fn list_members(&self) -> errors::Result<Vec<api::MemberEntry>> {
+        block_on_cancellable(self.0.list_members())
+    }
+
+    pub fn remove_member(&self, member_uuid: Uuid) -> errors::Result<()> {
+        block_on_cancellable(self.0.remove_member(member_uuid))
+    }
+
+    pub fn register_fs(&self, fs_opts: Vec<String>) -> errors::Result<()> {
+        block_on_cancellable(self.0.register_fs(fs_opts))
+    }
+}
+
+impl transport::Transport for Blocking<'_> {
+    fn fetch_heads(&self) -> errors::Result<HashSet<types::Hash>> {
+        block_on_cancellable(self.0.fetch_heads())
+    }
+
+    fn fetch_state(&self) -> errors::Result<HashSet<types::Hash>> {
+        block_on_cancellable(self.0.fetch_state())
+    }
+
+    fn fetch_ops(&self, storage: &storage::Config, ops: &[types::Hash]) -> errors::Result<HashMap<types::Hash, Vec<types::Hash>>> {
+        block_on_cancellable(self.0.fetch_ops(storage, ops))
+    }
+
+    fn push_ops(&self, storage: &storage::Config, ops: &[api::OpInfo]) -> errors::Result<()> {
+        block_on_cancellable(self.0.push_ops(storage, ops))
+    }
+
+    fn push_state(&self, ops: Vec<api::OpInfo>) -> errors::Result<()> {
+        block_on_cancellable(self.0.push_state(ops))
+    }
+
+    fn reconcile(&self, local_hashes: &HashSet<types::Hash>) -> errors::Result<reconcile::Difference> {
+        block_on_cancellable(self.0.reconcile(local_hashes))
     }
 }
@@ -0,0 +1,355 @@
+// Peer-to-peer sync between replicas of the same FS, over TCP, without the server.
+// One replica serves, and others connect to it. Requests are the `api::Message`s sent to the server, one JSON object per
+// line, answered by an `api::Reply` on the next line. Operations are exchanged exactly as they're stored, so they're
+// verified and applied just as if they'd been fetched from the server.
+// Before answering anything, the serving replica sends a challenge, which the connecting one must answer with a MAC
+// under the FS's key, or a signature by a replica in the serving replica's keyring. The connecting replica sends a
+// challenge of its own with its answer, which the serving replica must answer in the same way before any request is
+// sent. Each proof covers both challenges, and which side it's from. Operations are still only applied if they're
+// signed by a replica in the local keyring.
+
+use crate::{errors, storage, types};
+use super::{api, verify_and_store, ReplicaInfo, TransferConfig};
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use uuid::Uuid;
+
+/// Only this device by default. Listen on another address to serve the local network.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+/// Longest line read before the peer has proven itself.
+const MAX_HANDSHAKE_LINE: u64 = 4096;
+/// Longest line read after. Batches of operations can be large, so this matches the server's limit on requests.
+const MAX_LINE: u64 = 256 * 1024 * 1024;
+
+/// Sent by the serving replica as soon as a peer connects.
+#[derive(Serialize, Deserialize)]
+struct Challenge {
+    nonce: String,
+}
+
+/// How a replica proves to a peer that it may sync the FS.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Proof {
+    /// A MAC of the challenge under the FS's key.
+    FsKey {mac: String},
+    /// A signature of the challenge by an enrolled replica.
+    Replica {replica: Uuid, signature: String},
+}
+
+/// The connecting replica's proof, and its challenge for the serving replica.
+#[derive(Serialize, Deserialize)]
+struct Answer {
+    proof: Proof,
+    nonce: String,
+}
+
+/// Whether the serving replica accepted the connecting one's proof, and if so, its own proof.
+#[derive(Serialize, Deserialize)]
+struct Verdict {
+    code: errors::ErrorCode,
+    err_msg: String,
+    #[serde(default)]
+    proof: Option<Proof>,
+}
+
+/// Which side of the handshake a proof is from, so neither side's proof can be reflected back as the other's.
+#[derive(Clone, Copy)]
+enum Side {
+    Connecting,
+    Serving,
+}
+
+/// What a proof covers. Never a valid operation, so a peer can't get operations signed or MACed this way.
+fn challenge_message(side: Side, fs_uuid: Uuid, serving_nonce: &str, connecting_nonce: &str) -> String {
+    let side = match side {
+        Side::Connecting => "connecting",
+        Side::Serving => "serving",
+    };
+    format!("crfs-peer-v2:{}:{}:{}:{}", side, fs_uuid, serving_nonce, connecting_nonce)
+}
+
+fn new_nonce() -> String {
+    types::to_hex(&rand::random::<[u8; 32]>())
+}
+
+fn check_nonce(nonce: &str) -> errors::Result<()> {
+    match types::from_hex::<32>(nonce) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(errors::Error(errors::CODE_INVALID_DATA, "Malformed challenge from peer.".to_owned())),
+    }
+}
+
+/// Prove we may sync the FS, with its key if we hold it, else with the replica's signing key.
+fn prove(storage: &storage::Config, message: &str) -> errors::Result<Proof> {
+    match (&storage.key, &storage.signer) {
+        (Some(key), _) => Ok(Proof::FsKey {mac: key.prove(message)}),
+        (None, Some(signer)) => Ok(Proof::Replica {replica: signer.replica, signature: signer.sign_challenge(message)}),
+        (None, None) => Err(auth_err("Peers only sync with replicas holding the FS's key or enrolled with the server. Run `login` first.")),
+    }
+}
+
+/// Check a peer's proof that it holds the FS's key, or is a replica in our keyring.
+fn check_proof(storage: &storage::Config, proof: &Proof, message: &str) -> errors::Result<bool> {
+    Ok(match proof {
+        Proof::FsKey {mac} => storage.key.as_ref().is_some_and(|key| key.check_proof(message, mac)),
+        Proof::Replica {replica, signature} => storage::sign::Keyring::read_in(storage)?.check_challenge(replica, message, signature),
+    })
+}
+
+fn auth_err(err_msg: &str) -> errors::Error {
+    errors::Error(errors::CODE_AUTH_ERR, err_msg.to_owned())
+}
+
+/// Read the next line, of at most `limit` bytes, or `None` if the other side has disconnected.
+fn read_line<T: DeserializeOwned>(reader: &mut BufReader<TcpStream>, limit: u64) -> errors::Result<Option<T>> {
+    let mut line = String::new();
+    let read = reader.by_ref().take(limit).read_line(&mut line)?;
+    if read == 0 {return Ok(None);}
+    if read as u64 == limit && !line.ends_with('\n') {
+        return Err(errors::Error(errors::CODE_INVALID_DATA, format!("Line longer than {} bytes.", limit)));
+    }
+
+    return Ok(Some(serde_json::from_str(&line).map_err(super::NetError::from)?));
+}
+
+fn write_line<T: Serialize>(mut writer: &TcpStream, value: &T) -> errors::Result<()> {
+    let mut line = serde_json::to_string(value).map_err(super::NetError::from)?;
+    line.push('\n');
+
+    return Ok(writer.write_all(line.as_bytes())?);
+}
+
+/// A connection to a peer serving the same FS.
+pub struct Peer {
+    reader: BufReader<TcpStream>,
+    user_uuid: Uuid,
+    fs_uuid: Uuid,
+    batch_size: usize,
+}
+
+impl Peer {
+    /// Connect to the replica serving at `addr`. Each side proves it may sync with the FS's key if it has one, else
+    /// with its replica's signing key.
+    pub fn connect(addr: &str, storage: &storage::Config, info: &ReplicaInfo, transfer: &TransferConfig) -> errors::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| errors::Error(errors::CODE_NET_ERR, format!("Unable to resolve peer address {}.", addr)))?;
+
+        let stream = TcpStream::connect_timeout(&addr, transfer.timeout())?;
+        stream.set_read_timeout(Some(transfer.timeout()))?;
+
+        let mut peer = Self {
+            reader: BufReader::new(stream),
+            user_uuid: info.get_user_id().expect("No User UUID configured"),
+            fs_uuid: info.get_fs_id().expect("No FS UUID configured"),
+            batch_size: transfer.batch_size.max(1),
+        };
+        peer.handshake(storage)?;
+
+        return Ok(peer);
+    }
+
+    fn handshake(&mut self, storage: &storage::Config) -> errors::Result<()> {
+        let challenge: Challenge = read_line(&mut self.reader, MAX_HANDSHAKE_LINE)?
+            .ok_or_else(|| errors::Error(errors::CODE_NET_ERR, "Peer closed the connection.".to_owned()))?;
+        check_nonce(&challenge.nonce)?;
+        let nonce = new_nonce();
+
+        let proof = prove(storage, &challenge_message(Side::Connecting, self.fs_uuid, &challenge.nonce, &nonce))?;
+        write_line(self.reader.get_ref(), &Answer {proof, nonce: nonce.clone()})?;
+
+        let verdict: Verdict = read_line(&mut self.reader, MAX_HANDSHAKE_LINE)?
+            .ok_or_else(|| errors::Error(errors::CODE_NET_ERR, "Peer closed the connection.".to_owned()))?;
+        if verdict.code != errors::CODE_OK {return Err(errors::Error(verdict.code, verdict.err_msg));}
+
+        // Operations are checked anyway, but a replica which can't prove itself isn't sent ours either.
+        let message = challenge_message(Side::Serving, self.fs_uuid, &challenge.nonce, &nonce);
+        match verdict.proof {
+            Some(proof) if check_proof(storage, &proof, &message)? => Ok(()),
+            _ => Err(auth_err("Peer holds neither the FS's key, nor an enrolled replica's key.")),
+        }
+    }
+
+    fn request(&mut self, payload: api::MessagePayload) -> errors::Result<api::ReplyPayload> {
+        let message = api::Message::new(payload);
+        write_line(self.reader.get_ref(), &message)?;
+
+        let reply: api::Reply = read_line(&mut self.reader, MAX_LINE)?
+            .ok_or_else(|| errors::Error(errors::CODE_NET_ERR, "Peer closed the connection.".to_owned()))?;
+
+        return reply.check(&message);
+    }
+
+    pub fn fetch_state(&mut self) -> errors::Result<HashSet<types::Hash>> {
+        let payload = api::MessagePayload::FetchState {user_uuid: self.user_uuid, fs_uuid: self.fs_uuid};
+
+        match self.request(payload)? {
+            api::ReplyPayload::FetchState {code, err_msg, state} => {
+                if code == errors::CODE_OK {return Ok(state)}
+                else {return Err(errors::Error(code, err_msg))}
+            },
+            _ => {panic!()} // should never be reached due to reply.check() rejecting unexpected reply types.
+        }
+    }
+
+    /// Fetch and store the operations `hashes`, checking each matches its hash and decrypts.
+    pub fn fetch_ops(&mut self, storage: &storage::Config, hashes: &[types::Hash]) -> errors::Result<()> {
+        for batch in hashes.chunks(self.batch_size) {
+            let payload = api::MessagePayload::FetchOps {user_uuid: self.user_uuid, fs_uuid: self.fs_uuid, hashes: batch.to_vec()};
+
+            let ops = match self.request(payload)? {
+                api::ReplyPayload::FetchOps {code, err_msg, ops} => {
+                    if code == errors::CODE_OK {ops}
+                    else {return Err(errors::Error(code, err_msg))}
+                },
+                _ => {panic!()} // should never be reached due to reply.check() rejecting unexpected reply types.
+            };
+
+            if ops.len() != batch.len() {
+                return Err(errors::Error(errors::CODE_NOT_FOUND, format!("Requested {} operations, received {}.", batch.len(), ops.len())));
+            }
+
+            for op in ops.iter() {
+                if !batch.contains(&op.hash) {
+                    return Err(errors::Error(errors::CODE_INVALID_DATA, format!("Received unrequested operation {}.", types::hash_to_str(&op.hash))));
+                }
+                verify_and_store(storage, &op.data, &op.hash)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn push_ops(&mut self, storage: &storage::Config, hashes: &[types::Hash]) -> errors::Result<()> {
+        for batch in hashes.chunks(self.batch_size) {
+            let mut ops = Vec::new();
+            for hash in batch.iter() {
+                let mut data = String::new();
+                storage::object::read_string(storage, &storage::object::Location::Object(*hash), &mut data)?;
+                ops.push(api::OpData {hash: *hash, data});
+            }
+
+            let payload = api::MessagePayload::PushOps {user_uuid: self.user_uuid, fs_uuid: self.fs_uuid, ops};
+
+            match self.request(payload)? {
+                api::ReplyPayload::PushOps {code, err_msg} => {
+                    if code != errors::CODE_OK {return Err(errors::Error(code, err_msg))}
+                },
+                _ => {panic!()} // should never be reached due to reply.check() rejecting unexpected reply types.
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Exchange operations with the peer: fetch every operation it holds which we don't, and push ours if `push`.
+    /// Returns the fetched operations, which are stored but not yet applied.
+    pub fn sync(&mut self, storage: &storage::Config, local_hashes: &HashSet<types::Hash>, push: bool) -> errors::Result<HashSet<types::Hash>> {
+        let remote_hashes = self.fetch_state()?;
+
+        // Operations fetched before, but not yet applied, are already stored.
+        let need: HashSet<types::Hash> = remote_hashes.difference(local_hashes).cloned().collect();
+        let mut missing: Vec<types::Hash> = need.iter()
+            .filter(|op| !storage::object::Location::Object(**op).exists(storage))
+            .cloned().collect();
+        missing.sort();
+        self.fetch_ops(storage, &missing)?;
+
+        if push {
+            let mut have: Vec<types::Hash> = local_hashes.difference(&remote_hashes).cloned().collect();
+            have.sort();
+            self.push_ops(storage, &have)?;
+        }
+
+        return Ok(need);
+    }
+}
+
+/// Challenge a peer to prove it holds the FS's key, or is a replica in our keyring, then answer its challenge.
+fn check_peer(reader: &mut BufReader<TcpStream>, storage: &storage::Config, fs_uuid: Uuid) -> errors::Result<()> {
+    let nonce = new_nonce();
+    write_line(reader.get_ref(), &Challenge {nonce: nonce.clone()})?;
+
+    let answer: Answer = read_line(reader, MAX_HANDSHAKE_LINE)?
+        .ok_or_else(|| errors::Error(errors::CODE_NET_ERR, "Peer closed the connection.".to_owned()))?;
+    check_nonce(&answer.nonce)?;
+    let accepted = check_proof(storage, &answer.proof, &challenge_message(Side::Connecting, fs_uuid, &nonce, &answer.nonce))?;
+
+    let verdict = match accepted {
+        false => Verdict {code: errors::CODE_AUTH_ERR, err_msg: "Peer holds neither the FS's key, nor an enrolled replica's key.".to_owned(), proof: None},
+        true => match prove(storage, &challenge_message(Side::Serving, fs_uuid, &nonce, &answer.nonce)) {
+            Ok(proof) => Verdict {code: errors::CODE_OK, err_msg: String::new(), proof: Some(proof)},
+            Err(errors::Error(code, _)) => Verdict {code, err_msg: "The serving replica can't prove it may sync the FS.".to_owned(), proof: None},
+        },
+    };
+    write_line(reader.get_ref(), &verdict)?;
+
+    return match verdict.code {
+        errors::CODE_OK => Ok(()),
+        code => Err(errors::Error(code, verdict.err_msg)),
+    };
+}
+
+/// Answer a peer's requests until it disconnects, once it's proven it may sync. `state` is every operation we've applied.
+/// Returns the operations the peer pushed, which are stored but not yet applied.
+pub fn serve_connection(stream: TcpStream, storage: &storage::Config, fs_uuid: Uuid, state: &HashSet<types::Hash>, transfer: &TransferConfig) -> errors::Result<Vec<types::Hash>> {
+    stream.set_read_timeout(Some(transfer.timeout()))?;
+    let mut reader = BufReader::new(stream);
+    let mut received = Vec::new();
+
+    check_peer(&mut reader, storage, fs_uuid)?;
+
+    while let Some(message) = read_line::<api::Message>(&mut reader, MAX_LINE)? {
+        let payload = respond(storage, fs_uuid, state, message.payload, &mut received);
+
+        let reply = api::Reply {version: api::VERSION, transaction_id: message.transaction_id, reply: true, payload};
+        write_line(reader.get_ref(), &reply)?;
+    }
+
+    return Ok(received);
+}
+
+fn respond(storage: &storage::Config, fs_uuid: Uuid, state: &HashSet<types::Hash>, payload: api::MessagePayload, received: &mut Vec<types::Hash>) -> api::ReplyPayload {
+    use api::MessagePayload as M; use api::ReplyPayload as R;
+    let wrong_fs = |fs: Uuid| (fs != fs_uuid).then(|| (errors::CODE_NO_FS, format!("This peer doesn't hold FS {}.", fs)));
+
+    match payload {
+        M::Ping {} => R::Ping {code: errors::CODE_OK, err_msg: String::new()},
+        M::FetchState {fs_uuid, ..} => match wrong_fs(fs_uuid) {
+            Some((code, err_msg)) => R::FetchState {code, err_msg, state: HashSet::new()},
+            None => R::FetchState {code: errors::CODE_OK, err_msg: String::new(), state: state.clone()},
+        },
+        M::FetchOps {fs_uuid, hashes, ..} => {
+            if let Some((code, err_msg)) = wrong_fs(fs_uuid) {return R::FetchOps {code, err_msg, ops: Vec::new()};}
+
+            let mut ops = Vec::new();
+            for hash in hashes {
+                let mut data = String::new();
+                if storage::object::read_string(storage, &storage::object::Location::Object(hash), &mut data).is_err() {
+                    return R::FetchOps {code: errors::CODE_NOT_FOUND, err_msg: format!("No operation {}.", types::hash_to_str(&hash)), ops: Vec::new()};
+                }
+                ops.push(api::OpData {hash, data});
+            }
+
+            R::FetchOps {code: errors::CODE_OK, err_msg: String::new(), ops}
+        },
+        M::PushOps {fs_uuid, ops, ..} => {
+            if let Some((code, err_msg)) = wrong_fs(fs_uuid) {return R::PushOps {code, err_msg};}
+
+            for op in ops {
+                if let Err(errors::Error(code, err_msg)) = verify_and_store(storage, &op.data, &op.hash) {return R::PushOps {code, err_msg};}
+
+                received.push(op.hash);
+            }
+
+            R::PushOps {code: errors::CODE_OK, err_msg: String::new()}
+        },
+        // Peers only exchange operations. Everything else is the server's business.
+        _ => R::Ping {code: errors::CODE_NOT_IMPL, err_msg: "Not supported by peers.".to_owned()},
+    }
+}
@@ -0,0 +1,159 @@
+// Range-based set reconciliation over sorted operation hashes.
+// Each side summarises a range of the hash space by a fingerprint. Ranges whose fingerprints differ are split, until
+// they are small enough to send in full. Only the client learns the difference, so the server side is stateless.
+
+use crate::types::Hash;
+
+use std::collections::HashSet;
+
+use serde::{Serialize, Deserialize};
+
+/// Ranges holding at most this many hashes are sent in full, rather than split further.
+pub const ITEM_THRESHOLD: usize = 16;
+/// Number of sub-ranges a mismatched range is split into.
+pub const BRANCHING: usize = 16;
+/// Below this many local operations, exchanging the full state is cheap enough.
+pub const SMALL_SET: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// XOR of every hash in the range.
+    pub xor: Hash,
+    pub count: u64,
+}
+
+/// A range of the hash space, from `lower` (inclusive) to `upper` (exclusive). No `upper` means the end of the space.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Bounds {
+    pub lower: Hash,
+    pub upper: Option<Hash>,
+}
+
+impl Bounds {
+    pub fn full() -> Self {
+        Self { lower: Hash::default(), upper: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum RangeMsg {
+    /// The sender's fingerprint of the range.
+    Fingerprint { bounds: Bounds, fingerprint: Fingerprint },
+    /// Every hash the sender holds in the range.
+    Items { bounds: Bounds, items: Vec<Hash> },
+}
+
+/// Hashes the client holds which the server doesn't (`have`), and vice versa (`need`).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub have: Vec<Hash>,
+    pub need: Vec<Hash>,
+}
+
+pub struct SortedSet(Vec<Hash>);
+
+impl SortedSet {
+    pub fn new<'a>(hashes: impl IntoIterator<Item = &'a Hash>) -> Self {
+        let mut items: Vec<Hash> = hashes.into_iter().cloned().collect();
+        items.sort();
+        items.dedup();
+
+        return Self(items);
+    }
+
+    fn range(&self, bounds: &Bounds) -> &[Hash] {
+        let lo = self.0.partition_point(|h| *h < bounds.lower);
+        let hi = match &bounds.upper {
+            Some(upper) => self.0.partition_point(|h| h < upper),
+            None => self.0.len(),
+        };
+
+        return &self.0[lo..hi.max(lo)];
+    }
+}
+
+pub fn fingerprint(items: &[Hash]) -> Fingerprint {
+    let mut xor = Hash::default();
+    for h in items {
+        for (x, b) in xor.iter_mut().zip(h.iter()) { *x ^= b; }
+    }
+
+    return Fingerprint { xor, count: items.len() as u64 };
+}
+
+/// The client's first message, covering the whole hash space.
+pub fn initial(set: &SortedSet) -> Vec<RangeMsg> {
+    return vec![RangeMsg::Fingerprint { bounds: Bounds::full(), fingerprint: fingerprint(&set.0) }];
+}
+
+/// Server side: answer every range whose contents differ from ours, either in full or split into smaller ranges.
+/// Matching ranges are left out of the reply.
+pub fn respond(set: &SortedSet, ranges: &[RangeMsg]) -> Vec<RangeMsg> {
+    let mut reply = Vec::new();
+
+    for msg in ranges {
+        let (bounds, matches) = match msg {
+            RangeMsg::Fingerprint { bounds, fingerprint: theirs } => (bounds, fingerprint(set.range(bounds)) == *theirs),
+            RangeMsg::Items { bounds, items } => {
+                let theirs: HashSet<&Hash> = items.iter().collect();
+                (bounds, theirs == set.range(bounds).iter().collect())
+            },
+        };
+        if matches {continue;}
+
+        let own = set.range(bounds);
+        if own.len() <= ITEM_THRESHOLD {
+            reply.push(RangeMsg::Items { bounds: bounds.clone(), items: own.to_vec() });
+        } else {
+            reply.extend(split(bounds, own));
+        }
+    }
+
+    return reply;
+}
+
+/// Split `items`, which lie in `bounds`, into at most `BRANCHING` sub-ranges.
+fn split(bounds: &Bounds, items: &[Hash]) -> Vec<RangeMsg> {
+    let chunks: Vec<&[Hash]> = items.chunks(items.len().div_ceil(BRANCHING)).collect();
+
+    return chunks.iter().enumerate().map(|(i, chunk)| {
+        let sub = Bounds {
+            lower: if i == 0 {bounds.lower} else {chunk[0]},
+            upper: match chunks.get(i + 1) {Some(next) => Some(next[0]), None => bounds.upper},
+        };
+
+        if chunk.len() <= ITEM_THRESHOLD {
+            RangeMsg::Items { bounds: sub, items: chunk.to_vec() }
+        } else {
+            RangeMsg::Fingerprint { bounds: sub, fingerprint: fingerprint(chunk) }
+        }
+    }).collect();
+}
+
+/// Client side: record differences from the server's reply, and return the ranges to send next.
+/// Reconciliation is complete once this returns no ranges.
+pub fn process(set: &SortedSet, reply: &[RangeMsg], diff: &mut Difference) -> Vec<RangeMsg> {
+    let mut next = Vec::new();
+
+    for msg in reply {
+        match msg {
+            RangeMsg::Items { bounds, items } => {
+                let own = set.range(bounds);
+                let theirs: HashSet<&Hash> = items.iter().collect();
+
+                diff.have.extend(own.iter().filter(|h| !theirs.contains(h)));
+                diff.need.extend(items.iter().filter(|h| own.binary_search(h).is_err()));
+            },
+            RangeMsg::Fingerprint { bounds, fingerprint: theirs } => {
+                let own = fingerprint(set.range(bounds));
+                if own != *theirs {
+                    next.push(RangeMsg::Fingerprint { bounds: bounds.clone(), fingerprint: own });
+                }
+            },
+        }
+    }
+
+    return next;
+}
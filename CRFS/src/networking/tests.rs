@@ -1,25 +1,68 @@
-use std::{collections::HashSet, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, path::PathBuf};
+use std::{collections::HashSet, io::{BufRead, Read, Write}, net::SocketAddr, path::PathBuf, sync::OnceLock};
 
-use uuid::uuid;
+use uuid::{uuid, Uuid};
 
-use crate::{storage, networking, tests::storage_test::TESTFILEDIR, types};
+use crate::{storage, networking, tests::storage_test::{temp_working_dir, TESTFILEDIR}, types};
 use networking::api;
+use networking::transport::Transport;
 
 const TEST_USER: &'static str = "ad0ff637-87a8-4c64-a1a0-2ed08ec15e66";
-const TEST_FS: &'static str = "00000000-0000-0000-0000-000000000001"; // Will not be a "valid" FS!
+/// The reference server, spawned in process on first use, with `TEST_USER` already registered.
+fn test_server() -> url::Url {
+    static SERVER: OnceLock<url::Url> = OnceLock::new();
+
+    return SERVER.get_or_init(|| {
+        let addr = crfs_server::spawn(&temp_working_dir("server")).expect("Error starting test server");
+        let url = networking::parse_server_url(&format!("http://{}/", addr)).unwrap();
+
+        let mut config = networking::Config::empty();
+        config.server = Some(url.clone());
+        config.info.fs.user.id = Some(uuid!(TEST_USER));
+        config.blocking().register_user().expect("Error registering test user");
+
+        url
+    }).clone();
+}
+
+/// A config for a fresh user, registered with the test server, and authenticated as the user.
+fn registered_user() -> networking::Config {
+    let mut config = networking::Config::empty();
+    config.server = Some(test_server());
+    config.gen_blanks();
+    config.info.fs.user.id = Some(Uuid::now_v7());
+
+    config.credentials.user_secret = config.blocking().register_user().expect("Error registering user");
+
+    return config;
+}
+
+/// A config for a fresh user and FS, registered with the test server, and authenticated as the user.
+fn registered_config() -> networking::Config {
+    let mut config = registered_user();
+    config.info.fs.id = Some(Uuid::now_v7());
+    config.blocking().register_fs(Vec::new()).expect("Error registering FS");
+
+    return config;
+}
+
+/// Check a request was refused for its credentials.
+fn assert_auth_err<T>(res: crate::errors::Result<T>) {
+    match res {
+        Err(crate::errors::Error(code, err_msg)) => assert_eq!(code, crate::errors::CODE_AUTH_ERR, "{}", err_msg),
+        Ok(_) => panic!("Expected an auth error."),
+    }
+}
 
 #[test]
 fn ping_test() {
     let mut config = networking::Config::empty();
-    config.server = Some(SocketAddr::V4(
-        SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000)
-    ));
+    config.server = Some(test_server());
 
     let message = api::Message::new(
         api::MessagePayload::Ping {  }
     );
 
-    let (code, res) = message.send(&config).unwrap();
+    let (code, res) = networking::block_on(message.send(&config)).unwrap();
     res.unwrap(&message);
 
     assert_eq!(code, 200);
@@ -27,11 +70,10 @@ fn ping_test() {
 
 #[test]
 fn register_user_test() {
-    let socket = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000);
 
     let mut config = networking::Config::empty();
-    config.server = Some(std::net::SocketAddr::V4(socket));
-    config.info.fs.user.id = Some(uuid!(TEST_USER));
+    config.server = Some(test_server());
+    config.info.fs.user.id = Some(Uuid::now_v7());
 
     let message = api::Message::new(api::MessagePayload::RegisterUser {
         user_uuid: config.info.get_user_id().unwrap(),
@@ -39,24 +81,34 @@ fn register_user_test() {
     });
     println!("{}", serde_json::to_string_pretty(&message).unwrap());
 
-    let (code, res) = message.send(&config).unwrap();
+    let (code, res) = networking::block_on(message.send(&config)).unwrap();
     assert_eq!(code, 200);
 
-    dbg!(&res);
+    // New users are issued a secret.
+    match res.unwrap(&message) {
+        api::ReplyPayload::RegisterUser {secret, ..} => assert!(secret.is_some()),
+        _ => panic!(),
+    }
+
+    // Which is then needed to register again.
+    let (_, res) = networking::block_on(message.send(&config)).unwrap();
+    match res.unwrap(&message) {
+        api::ReplyPayload::RegisterUser {code, ..} => assert_eq!(code, crate::errors::CODE_AUTH_ERR),
+        _ => panic!(),
+    }
 }
 
 #[test]
 fn check_user_test() {
-    let socket = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000);
 
     let mut config = networking::Config::empty();
-    config.server = Some(std::net::SocketAddr::V4(socket));
+    config.server = Some(test_server());
     config.info.fs.user.id = Some(uuid!(TEST_USER));
 
     let message = api::Message::new(api::MessagePayload::CheckUser { user_uuid: config.info.get_user_id().unwrap() });
     println!("{}", serde_json::to_string_pretty(&message).unwrap());
 
-    let (code, res) = message.send(&config).unwrap();
+    let (code, res) = networking::block_on(message.send(&config)).unwrap();
     assert_eq!(code, 200);
 
     dbg!(&res);
@@ -64,12 +116,12 @@ fn check_user_test() {
 
 #[test]
 fn register_fs_test() {
-    let socket = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000);
 
     let mut config = networking::Config::empty();
-    config.server = Some(std::net::SocketAddr::V4(socket));
-    config.info.fs.user.id = Some(uuid!(TEST_USER));
-    config.info.fs.id = Some(uuid!(TEST_FS));
+    config.server = Some(test_server());
+    config.info.fs.user.id = Some(Uuid::now_v7());
+    config.info.fs.id = Some(Uuid::now_v7());
+    config.credentials.user_secret = config.blocking().register_user().expect("Error registering user");
 
     let message = api::Message::new(api::MessagePayload::RegisterFs {
         user_uuid: config.info.get_user_id().unwrap(),
@@ -79,7 +131,7 @@ fn register_fs_test() {
     });
     println!("{}", serde_json::to_string_pretty(&message).unwrap());
 
-    let (code, res) = message.send(&config).unwrap();
+    let (code, res) = networking::block_on(message.send(&config)).unwrap();
     dbg!(&res);
 
     assert_eq!(code, 200);
@@ -87,12 +139,8 @@ fn register_fs_test() {
 
 #[test]
 fn check_fs_test() {
-    let socket = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000);
 
-    let mut config = networking::Config::empty();
-    config.server = Some(std::net::SocketAddr::V4(socket));
-    config.info.fs.user.id = Some(uuid!(TEST_USER));
-    config.info.fs.id = Some(uuid!(TEST_FS));
+    let config = registered_config();
 
     let message = api::Message::new(api::MessagePayload::CheckFs {
         user_uuid: config.info.get_user_id().unwrap(),
@@ -100,7 +148,7 @@ fn check_fs_test() {
     });
     println!("{}", serde_json::to_string_pretty(&message).unwrap());
 
-    let (code, res) = message.send(&config).unwrap();
+    let (code, res) = networking::block_on(message.send(&config)).unwrap();
     dbg!(&res);
 
     assert_eq!(code, 200);
@@ -108,19 +156,17 @@ fn check_fs_test() {
 
 #[test]
 fn put_get_test() {
-    let socket = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000);
 
-    let mut netconfig = networking::Config::empty();
-    netconfig.server = Some(std::net::SocketAddr::V4(socket));
-    netconfig.info.fs.id = Some(uuid!(TEST_FS));
+    let netconfig = registered_config();
 
-    let storageconfig = storage::Config {working_dir: PathBuf::from(TESTFILEDIR)};
+    let storageconfig = storage::Config::new(PathBuf::from(TESTFILEDIR));
 
     let data = "test operation data";
     let hash = storage::object::write_obj(&storageconfig, data.as_bytes()).expect("Write error");
 
-    netconfig.push_op(&storageconfig, &hash).expect("Push error");
-    netconfig.fetch_op(&storageconfig, &hash).expect("Pull error");
+    netconfig.blocking().push(&storageconfig, vec!(api::OpInfo {hash, parents: vec!()})).expect("Push error");
+    std::fs::remove_file(storage::object::Location::Object(hash).get_path(&storageconfig)).expect("Remove error");
+    networking::block_on(netconfig.fetch_batch(&storageconfig, &[hash])).expect("Pull error");
 
     let mut read_buf = String::new();
     storage::object::read_string(&storageconfig, &storage::object::Location::Object(hash), &mut read_buf).expect("Read error");
@@ -130,19 +176,604 @@ fn put_get_test() {
 
 #[test]
 fn push_fetch_state_test() {
-    let socket = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8000);
 
-    let mut config = networking::Config::empty();
-    config.server = Some(std::net::SocketAddr::V4(socket));
-    config.info.fs.user.id = Some(uuid!(TEST_USER));
-    config.info.fs.id = Some(uuid!(TEST_FS));
+    let config = registered_config();
 
     let push_state: HashSet<types::Hash> = vec!(types::calculate_hash("one"), types::calculate_hash("two"), types::calculate_hash("three")).into_iter().collect();
-    config.push_state(push_state.clone()).expect("Push error");
+    config.blocking().push_state(push_state.iter().map(|h| api::OpInfo {hash: *h, parents: vec!()}).collect()).expect("Push error");
 
-    let pull_state = config.fetch_state().expect("Fetch error");
+    let pull_state = config.blocking().fetch_state().expect("Fetch error");
 
     for h in push_state.iter() {
         assert!(pull_state.contains(h))
     }
 }
+
+#[test]
+fn push_fetch_heads_test() {
+
+    let config = registered_config();
+
+    let root = types::calculate_hash("heads root");
+    let child = types::calculate_hash("heads child");
+    config.blocking().push_state(vec!(
+        api::OpInfo {hash: child, parents: vec!(root)},
+        api::OpInfo {hash: root, parents: vec!()},
+    )).expect("Push error");
+
+    let heads = config.blocking().fetch_heads().expect("Fetch error");
+
+    assert!(heads.contains(&child));
+    assert!(!heads.contains(&root));
+}
+
+#[test]
+fn reconcile_test() {
+
+    let config = registered_config();
+
+    let pushed: Vec<types::Hash> = (0..100).map(|i| types::calculate_hash(&format!("reconcile {}", i))).collect();
+    config.blocking().push_state(pushed.iter().map(|h| api::OpInfo {hash: *h, parents: vec!()}).collect()).expect("Push error");
+
+    let local_only = types::calculate_hash("reconcile local");
+    let local: HashSet<types::Hash> = pushed.iter().cloned().chain([local_only]).collect();
+
+    let diff = config.blocking().reconcile(&local).expect("Reconcile error");
+
+    assert!(diff.have.contains(&local_only));
+    assert!(pushed.iter().all(|h| !diff.have.contains(h) && !diff.need.contains(h)));
+}
+
+#[test]
+fn in_batches_test() {
+    let mut config = networking::Config::empty();
+    config.transfer = networking::TransferConfig {batch_size: 3, concurrency: 2, ..Default::default()};
+
+    let items: Vec<u32> = (0..10).collect();
+    let seen = std::sync::Mutex::new(Vec::new());
+
+    networking::block_on(config.in_batches(&items, |batch| async {
+        assert!(batch.len() <= 3);
+        seen.lock().unwrap().extend_from_slice(batch);
+        Ok(())
+    })).expect("Batch error");
+
+    let mut seen = seen.into_inner().unwrap(); seen.sort();
+    assert_eq!(seen, items);
+
+    // Errors from any batch are returned.
+    let res = networking::block_on(config.in_batches(&items, |batch| async move {
+        if batch.contains(&7) {Err(crate::errors::Error(crate::errors::CODE_ERROR, "Batch failed".to_owned()))} else {Ok(())}
+    }));
+    assert!(res.is_err());
+}
+
+#[test]
+fn push_fetch_ops_test() {
+
+    let mut netconfig = registered_config();
+    netconfig.transfer = networking::TransferConfig {batch_size: 4, concurrency: 2, ..Default::default()};
+
+    let storageconfig = storage::Config::new(PathBuf::from(TESTFILEDIR));
+
+    let ops: Vec<api::OpInfo> = (0..10).map(|i| {
+        let hash = storage::object::write_obj(&storageconfig, format!("batch op {}", i).as_bytes()).expect("Write error");
+        api::OpInfo {hash, parents: vec!()}
+    }).collect();
+
+    netconfig.blocking().push(&storageconfig, ops.clone()).expect("Push error");
+
+    for op in ops.iter() {
+        std::fs::remove_file(storage::object::Location::Object(op.hash).get_path(&storageconfig)).expect("Remove error");
+    }
+    networking::block_on(netconfig.fetch_batch(&storageconfig, &ops.iter().map(|op| op.hash).collect::<Vec<_>>())).expect("Fetch error");
+
+    for (i, op) in ops.iter().enumerate() {
+        let mut read_buf = String::new();
+        storage::object::read_string(&storageconfig, &storage::object::Location::Object(op.hash), &mut read_buf).expect("Read error");
+        assert_eq!(read_buf, format!("batch op {}", i));
+    }
+}
+
+/// Serve one canned HTTP response per connection, returning the listener's address.
+fn canned_server(responses: Vec<&'static str>) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // Read the request headers before replying.
+            let mut buf = Vec::new(); let mut byte = [0u8];
+            while !buf.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() > 0 { buf.push(byte[0]); }
+
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    return addr;
+}
+
+#[test]
+fn retry_test() {
+    let addr = canned_server(vec![
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+    ]);
+    let endpoint = url::Url::parse(&format!("http://{}/operation/", addr)).unwrap();
+
+    let mut config = networking::Config::empty();
+    config.transfer = networking::TransferConfig {retries: 2, ..Default::default()};
+    let (code, body) = networking::block_on(api::post(endpoint, String::new(), &config)).expect("Request error");
+
+    assert_eq!(code, 200);
+    assert_eq!(body, "ok");
+}
+
+#[test]
+fn retry_idempotent_test() {
+    let addr = canned_server(vec![
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length: 78\r\nConnection: close\r\n\r\n\
+        {\"version\":\"0.0.1\",\"transaction_id\":1,\"reply\":true,\"type\":\"ping\",\"payload\":{}}",
+    ]);
+
+    let mut config = networking::Config::empty();
+    config.server = Some(networking::parse_server_url(&addr.to_string()).unwrap());
+    config.transfer = networking::TransferConfig {retries: 2, ..Default::default()};
+
+    // Registering isn't retried, as the server may have issued a secret before the reply was lost.
+    let register = api::Message::new(api::MessagePayload::RegisterUser {user_uuid: Uuid::now_v7(), display_name: String::new()});
+    assert!(networking::block_on(register.send(&config)).is_err());
+
+    // So the next request gets the second response.
+    let (code, _) = networking::block_on(api::Message::new(api::MessagePayload::Ping {}).send(&config)).unwrap();
+    assert_eq!(code, 200);
+}
+
+#[test]
+fn timeout_test() {
+    // Accepts connections, but never replies.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = url::Url::parse(&format!("http://{}/operation/", listener.local_addr().unwrap())).unwrap();
+
+    let mut config = networking::Config::empty();
+    config.transfer = networking::TransferConfig {timeout_secs: 1, retries: 0, ..Default::default()};
+    let start = std::time::Instant::now();
+    let res = networking::block_on(api::post(endpoint, String::new(), &config));
+
+    match res {
+        Err(networking::NetError::ReqwestErr(e)) => assert!(e.is_timeout()),
+        r => panic!("Expected a timeout, got {:?}", r),
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    drop(listener);
+}
+
+#[test]
+fn server_url_test() {
+    let parse = |s| networking::parse_server_url(s).unwrap().to_string();
+
+    assert_eq!(parse("127.0.0.1:8000"), "http://127.0.0.1:8000/");
+    assert_eq!(parse("https://crfs.example.org"), "https://crfs.example.org/");
+    assert_eq!(parse("https://crfs.example.org/base"), "https://crfs.example.org/base/");
+
+    // Endpoints are placed beneath the server's prefix.
+    let mut config = networking::Config::empty();
+    config.server = Some(networking::parse_server_url("https://crfs.example.org/base").unwrap());
+    assert_eq!(config.get_endpoint("api").unwrap().as_str(), "https://crfs.example.org/base/api/");
+}
+
+#[test]
+fn server_migration_test() {
+    // Configs written before servers were URLs store a socket address.
+    let old = r#"{"server": "127.0.0.1:8000", "info": {"fs": {"user": {"id": null, "disp_name": null}, "id": null, "disp_name": null}, "id": null, "disp_name": null}}"#;
+    let config: networking::Config = serde_json::from_str(old).unwrap();
+    assert_eq!(config.server.as_ref().unwrap().as_str(), "http://127.0.0.1:8000/");
+
+    // And round-trip as URLs.
+    let new: networking::Config = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+    assert_eq!(new.server, config.server);
+}
+
+#[test]
+fn tls_config_test() {
+    let mut config = networking::Config::empty();
+    config.server = Some(networking::parse_server_url("https://127.0.0.1:1/").unwrap());
+
+    // A certificate without a key is refused before connecting.
+    config.tls = networking::TlsConfig {client_cert: Some(PathBuf::from("cert.pem")), ..Default::default()};
+    match networking::block_on(api::post(config.get_endpoint("api").unwrap(), String::new(), &config)) {
+        Err(networking::NetError::TlsErr(_)) => {},
+        r => panic!("Expected a TLS error, got {:?}", r),
+    }
+
+    config.tls = networking::TlsConfig {ca_bundle: Some(PathBuf::from("does-not-exist.pem")), ..Default::default()};
+    match networking::block_on(api::post(config.get_endpoint("api").unwrap(), String::new(), &config)) {
+        Err(networking::NetError::TlsErr(_)) => {},
+        r => panic!("Expected a TLS error, got {:?}", r),
+    }
+}
+
+#[test]
+fn auth_test() {
+    let mut config = registered_config();
+
+    // Replicas are issued tokens, which grant access to their FS.
+    config.credentials.token = Some(config.blocking().enrol().expect("Enrol error"));
+    config.blocking().fetch_heads().expect("Fetch error");
+
+    // Unauthenticated and forged requests are refused.
+    for credentials in [
+        networking::Credentials::default(),
+        networking::Credentials {token: Some(networking::Secret::new(format!("{}.forged", config.info.get_replica_id().unwrap()))), ..Default::default()},
+    ] {
+        let mut forged = config.clone();
+        forged.credentials = credentials;
+        assert_auth_err(forged.blocking().fetch_heads());
+    }
+
+    // As is another user's FS.
+    let other = registered_config();
+    let mut forged = config.clone();
+    forged.info.fs.id = other.info.fs.id;
+    assert_auth_err(forged.blocking().fetch_heads());
+}
+
+#[test]
+fn credentials_test() {
+    let secret = networking::Secret::new("0190a0b0-0000-7000-8000-000000000000.hunter2".to_owned());
+    assert!(!format!("{:?}", secret).contains("hunter2"));
+
+    // The replica's token is preferred, as it grants the least.
+    let mut config = networking::Config::empty();
+    config.credentials.user_secret = Some(secret.clone());
+    assert_eq!(config.credentials.bearer(), Some(&secret));
+    let token = networking::Secret::new("token".to_owned());
+    config.credentials.token = Some(token.clone());
+    assert_eq!(config.credentials.bearer(), Some(&token));
+
+    // Credentials are stored as plain strings, and configs without them still load.
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["credentials"]["token"], "token");
+    let old = r#"{"server": null, "info": {"fs": {"user": {"id": null, "disp_name": null}, "id": null, "disp_name": null}, "id": null, "disp_name": null}}"#;
+    let config: networking::Config = serde_json::from_str(old).unwrap();
+    assert!(config.credentials.bearer().is_none());
+}
+
+#[test]
+fn replicas_test() {
+    let mut config = registered_config();
+    config.info.disp_name = Some("Test Replica".to_owned());
+    config.credentials.token = Some(config.blocking().enrol().expect("Enrol error"));
+
+    let replicas = config.blocking().list_replicas().expect("List error");
+    let entry = replicas.iter().find(|r| Some(r.replica_uuid) == config.info.get_replica_id()).expect("Replica not listed");
+    assert_eq!(entry.display_name.as_deref(), Some("Test Replica"));
+
+    // Removing a replica revokes its token.
+    config.blocking().remove_replica(config.info.get_replica_id().unwrap()).expect("Remove error");
+    assert_auth_err(config.blocking().list_replicas());
+
+    config.credentials.token = None;
+    let replicas = config.blocking().list_replicas().expect("List error");
+    assert!(replicas.iter().all(|r| Some(r.replica_uuid) != config.info.get_replica_id()));
+
+    // And it can't enrol again, even with the user's secret.
+    assert_auth_err(config.blocking().enrol());
+}
+
+#[test]
+fn enrol_takeover_test() {
+    let mut owner = registered_config();
+    owner.credentials.signing_key = Some(storage::sign::SigningKey::generate());
+    owner.blocking().enrol().expect("Enrol error");
+
+    let mut other = registered_user();
+    other.info.id = owner.info.get_replica_id();
+    other.info.fs.id = owner.info.get_fs_id();
+    owner.blocking().invite(other.info.get_user_id().unwrap(), api::Role::Editor).expect("Invite error");
+
+    // A member of the FS can't take over a replica someone else enrolled.
+    assert_auth_err(other.blocking().enrol());
+
+    // Nor can its own user replace its key.
+    let mut rekeyed = owner.clone();
+    rekeyed.credentials.signing_key = Some(storage::sign::SigningKey::generate());
+    assert_auth_err(rekeyed.blocking().enrol());
+
+    // But it may enrol again with the same key.
+    owner.blocking().enrol().expect("Enrol error");
+}
+
+#[test]
+fn sharing_test() {
+    let owner = registered_config();
+    let op = api::OpInfo {hash: types::calculate_hash("shared op"), parents: vec!()};
+    owner.blocking().push_state(vec!(op.clone())).expect("Push error");
+
+    let mut member = registered_user();
+    member.info.fs.id = owner.info.get_fs_id();
+
+    // The FS isn't shared with the member until they're invited.
+    assert_auth_err(member.blocking().fetch_state());
+
+    owner.blocking().invite(member.info.get_user_id().unwrap(), api::Role::ReadOnly).expect("Invite error");
+    assert_eq!(owner.blocking().fetch_role().expect("Role error"), api::Role::Owner);
+    assert_eq!(member.blocking().fetch_role().expect("Role error"), api::Role::ReadOnly);
+
+    let members = owner.blocking().list_members().expect("List error");
+    assert_eq!(members.len(), 2);
+    assert!(members.iter().any(|m| Some(m.user_uuid) == member.info.get_user_id() && m.role == api::Role::ReadOnly));
+
+    // Read-only members may enrol replicas and fetch, but not push.
+    member.credentials.token = Some(member.blocking().enrol().expect("Enrol error"));
+    assert!(member.blocking().fetch_state().expect("Fetch error").contains(&op.hash));
+    assert_auth_err(member.blocking().push_state(vec!(api::OpInfo {hash: types::calculate_hash("read-only op"), parents: vec!()})));
+
+    // Editors may push.
+    owner.blocking().invite(member.info.get_user_id().unwrap(), api::Role::Editor).expect("Invite error");
+    member.blocking().push_state(vec!(api::OpInfo {hash: types::calculate_hash("editor op"), parents: vec!()})).expect("Push error");
+
+    // Removing a member revokes their replicas' tokens.
+    owner.blocking().remove_member(member.info.get_user_id().unwrap()).expect("Remove error");
+    assert_auth_err(member.blocking().fetch_state());
+}
+
+#[test]
+fn peer_test() {
+    use crate::conflict_res::file_tree::FileManager;
+
+    let mut info = networking::ReplicaInfo::empty();
+    info.gen_blanks();
+    let transfer = networking::TransferConfig::default();
+
+    // Both replicas are enrolled, so replica 1 proves itself with its signing key.
+    let mut config1 = storage::Config::new(temp_working_dir("peer1"));
+    let mut config2 = storage::Config::new(temp_working_dir("peer2"));
+    config1.signer = Some(storage::sign::Signer {replica: Uuid::from_u128(1), key: storage::sign::SigningKey::generate()});
+    config2.signer = Some(storage::sign::Signer {replica: Uuid::from_u128(2), key: storage::sign::SigningKey::generate()});
+    let keyring = storage::sign::Keyring::new([&config1, &config2].map(|c| {
+        let signer = c.signer.as_ref().unwrap();
+        (signer.replica, signer.key.public_key())
+    }));
+    keyring.write_out(&config1).unwrap();
+    keyring.write_out(&config2).unwrap();
+    let mut manager1 = FileManager::init(config1.clone(), Uuid::from_u128(1));
+    let mut manager2 = FileManager::init(config2.clone(), Uuid::from_u128(2));
+
+    std::fs::write(config1.working_dir.join("a.md"), "# A\n").unwrap();
+    std::fs::write(config2.working_dir.join("b.md"), "# B\n").unwrap();
+    manager1.update().unwrap();
+    manager2.update().unwrap();
+
+    // Replica 2 serves a single peer on an ephemeral port.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let state = manager2.get_history().all_hashes();
+    let root2 = config2.working_dir.clone();
+    let fs_uuid = info.get_fs_id().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        networking::peer::serve_connection(stream, &config2, fs_uuid, &state, &transfer).unwrap()
+    });
+
+    let mut peer = networking::peer::Peer::connect(&addr, &config1, &info, &transfer).unwrap();
+    let fetched = peer.sync(&config1, &manager1.get_history().all_hashes(), true).unwrap();
+    drop(peer);
+    let received = server.join().unwrap();
+
+    manager1.apply_ops(&fetched.iter().collect()).unwrap();
+    manager2.apply_ops(&received.iter().collect()).unwrap();
+    assert_eq!(manager1.get_history().all_hashes(), manager2.get_history().all_hashes());
+    for root in [&config1.working_dir, &root2] {
+        assert_eq!(std::fs::read_to_string(root.join("a.md")).unwrap(), "# A");
+        assert_eq!(std::fs::read_to_string(root.join("b.md")).unwrap(), "# B");
+    }
+}
+
+#[test]
+fn peer_auth_test() {
+    let mut info = networking::ReplicaInfo::empty();
+    info.gen_blanks();
+    let transfer = networking::TransferConfig::default();
+    let fs_uuid = info.get_fs_id().unwrap();
+
+    let serving = storage::Config::new(temp_working_dir("peer_auth"));
+    let mut stranger = storage::Config::new(temp_working_dir("peer_auth_stranger"));
+
+    let serve = |config: storage::Config| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            networking::peer::serve_connection(stream, &config, fs_uuid, &HashSet::new(), &transfer)
+        });
+        (addr, server)
+    };
+
+    // Replicas which aren't in the serving replica's keyring are refused, as are wrong keys.
+    stranger.signer = Some(storage::sign::Signer {replica: Uuid::now_v7(), key: storage::sign::SigningKey::generate()});
+    let (addr, server) = serve(serving.clone());
+    assert_auth_err(networking::peer::Peer::connect(&addr, &stranger, &info, &transfer));
+    assert_auth_err(server.join().unwrap());
+
+    let mut keyed = serving.clone();
+    keyed.key = Some(storage::crypt::Key::from_passphrase("serving", fs_uuid));
+    stranger.key = Some(storage::crypt::Key::from_passphrase("stranger", fs_uuid));
+    let (addr, server) = serve(keyed.clone());
+    assert!(networking::peer::Peer::connect(&addr, &stranger, &info, &transfer).is_err());
+    assert!(server.join().unwrap().is_err());
+
+    // Holding the FS's key is enough.
+    stranger.key = keyed.key.clone();
+    let (addr, server) = serve(keyed);
+    networking::peer::Peer::connect(&addr, &stranger, &info, &transfer).unwrap().fetch_state().unwrap();
+    assert!(server.join().is_ok());
+
+    // The serving replica has to prove itself too. One accepting anyone, without a proof, is refused.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let impostor = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(format!("{{\"nonce\":\"{}\"}}\n", "ab".repeat(32)).as_bytes()).unwrap();
+        let mut answer = String::new();
+        std::io::BufReader::new(&stream).read_line(&mut answer).unwrap();
+        stream.write_all(b"{\"code\":0,\"err_msg\":\"\"}\n").unwrap();
+    });
+    assert_auth_err(networking::peer::Peer::connect(&addr, &stranger, &info, &transfer));
+    impostor.join().unwrap();
+
+    // Lines are bounded before a peer has proven itself.
+    let (addr, server) = serve(serving);
+    let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+    let _ = stream.write_all(&vec![b'x'; 8192]);
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn reply_check_test() {
+    use crate::errors::{Error, CODE_INVALID_DATA, CODE_NOT_IMPL};
+
+    let message = api::Message::new(api::MessagePayload::FetchState {user_uuid: Uuid::now_v7(), fs_uuid: Uuid::now_v7()});
+    let reply = |transaction_id, payload| api::Reply {version: api::VERSION, transaction_id, reply: true, payload};
+    let state = api::ReplyPayload::FetchState {code: 0, err_msg: String::new(), state: HashSet::new()};
+
+    assert!(reply(message.transaction_id, state.clone()).check(&message).is_ok());
+
+    // Replies to other requests, and of the wrong type, are rejected rather than trusted.
+    assert!(matches!(reply(message.transaction_id.wrapping_add(1), state).check(&message), Err(Error(CODE_INVALID_DATA, _))));
+    let pushed = api::ReplyPayload::PushOps {code: 0, err_msg: String::new()};
+    assert!(matches!(reply(message.transaction_id, pushed).check(&message), Err(Error(CODE_INVALID_DATA, _))));
+
+    // As peers answer unsupported requests.
+    let unsupported = api::ReplyPayload::Ping {code: CODE_NOT_IMPL, err_msg: "Not supported by peers.".to_owned()};
+    assert!(matches!(reply(message.transaction_id, unsupported).check(&message), Err(Error(CODE_NOT_IMPL, _))));
+}
+
+#[test]
+fn bundle_test() {
+    use crate::conflict_res::file_tree::FileManager;
+
+    let fs_uuid = Uuid::now_v7();
+    let config1 = storage::Config::new(temp_working_dir("bundle1"));
+    let config2 = storage::Config::new(temp_working_dir("bundle2"));
+    let mut manager1 = FileManager::init(config1.clone(), Uuid::from_u128(1));
+    let mut manager2 = FileManager::init(config2.clone(), Uuid::from_u128(2));
+    let bundle = temp_working_dir("bundle").join("ops.tar");
+
+    std::fs::write(config1.working_dir.join("a.md"), "# A\n").unwrap();
+    manager1.update().unwrap();
+
+    let manifest = networking::bundle::export(&config1, fs_uuid, &manager1.get_history(), &[], &bundle).unwrap();
+    assert_eq!(manifest.ops.len(), manager1.get_history().all_hashes().len());
+
+    // Bundles are only imported into replicas of the same FS.
+    assert!(networking::bundle::import(&config2, Uuid::now_v7(), &bundle).is_err());
+
+    let imported = networking::bundle::import(&config2, fs_uuid, &bundle).unwrap();
+    manager2.apply_ops(&imported.ops.iter().collect()).unwrap();
+    assert_eq!(std::fs::read_to_string(config2.working_dir.join("a.md")).unwrap(), "# A");
+
+    // Exporting since the last bundle's heads only includes newer operations.
+    std::fs::write(config1.working_dir.join("a.md"), "# A\n\nEdited.\n").unwrap();
+    manager1.update().unwrap();
+    let newer = networking::bundle::export(&config1, fs_uuid, &manager1.get_history(), &manifest.heads, &bundle).unwrap();
+    assert!(!newer.ops.is_empty());
+    assert!(newer.ops.iter().all(|op| !manifest.ops.contains(op)));
+
+    let imported = networking::bundle::import(&config2, fs_uuid, &bundle).unwrap();
+    manager2.apply_ops(&imported.ops.iter().collect()).unwrap();
+    assert_eq!(manager1.get_history().all_hashes(), manager2.get_history().all_hashes());
+    assert_eq!(std::fs::read_to_string(config2.working_dir.join("a.md")).unwrap(), "# A\n\nEdited.");
+}
+
+#[test]
+fn local_dir_test() {
+    use crate::conflict_res::file_tree::FileManager;
+    use crate::errors::{Error, CODE_NO_FS, CODE_NOT_FOUND};
+
+    let fs_uuid = Uuid::now_v7();
+    let shared = networking::transport::LocalDir::new(&temp_working_dir("shared"), fs_uuid);
+    let config1 = storage::Config::new(temp_working_dir("local-dir1"));
+    let config2 = storage::Config::new(temp_working_dir("local-dir2"));
+    let mut manager1 = FileManager::init(config1.clone(), Uuid::from_u128(1));
+    let mut manager2 = FileManager::init(config2.clone(), Uuid::from_u128(2));
+
+    // The FS has to be created before it's synced through.
+    match shared.fetch_state() {
+        Err(Error(code, _)) => assert_eq!(code, CODE_NO_FS),
+        r => panic!("Expected a missing FS, got {:?}", r),
+    }
+    shared.register_fs(vec!["nfc".to_owned()]).unwrap();
+    assert_eq!(shared.fetch_fs_opts().unwrap(), Some(vec!["nfc".to_owned()]));
+
+    std::fs::write(config1.working_dir.join("a.md"), "# A\n").unwrap();
+    manager1.update().unwrap();
+
+    let history = manager1.get_history();
+    let parents = history.all_parents();
+    let ops: Vec<api::OpInfo> = history.all_hashes().into_iter()
+        .map(|hash| api::OpInfo {hash, parents: parents.get(&hash).cloned().unwrap_or_default()})
+        .collect();
+    shared.push(&config1, ops).unwrap();
+
+    assert_eq!(shared.fetch_state().unwrap(), history.all_hashes());
+    assert_eq!(shared.fetch_heads().unwrap(), history.heads().into_iter().collect());
+
+    // Replica 2 walks back from the heads to find every operation.
+    let fetched = shared.pull(&config2, &HashSet::new(), &shared.fetch_heads().unwrap()).unwrap();
+    manager2.apply_ops(&fetched.keys().collect()).unwrap();
+    assert_eq!(manager2.get_history().all_hashes(), history.all_hashes());
+    assert_eq!(std::fs::read_to_string(config2.working_dir.join("a.md")).unwrap(), "# A");
+
+    // Operations can't be recorded before they're uploaded.
+    match shared.push_state(vec!(api::OpInfo {hash: types::calculate_hash("missing op"), parents: vec!()})) {
+        Err(Error(code, _)) => assert_eq!(code, CODE_NOT_FOUND),
+        r => panic!("Expected a missing operation, got {:?}", r),
+    }
+}
+
+/// The whole sync path, from setting up replicas through to syncing changes between them, against the test server.
+#[test]
+fn server_sync_test() {
+    use crate::core;
+
+    let conf_path = temp_working_dir("server-sync").join("config.json");
+    core::init(&conf_path).unwrap();
+    let mut conf = core::GlobalConfig::read(&conf_path).unwrap();
+
+    let setup = |conf: &mut core::GlobalConfig, user_id: Option<Uuid>, secret: Option<String>, fs_id: Option<Uuid>, dir: PathBuf| {
+        let opts = core::SetupOpts {
+            server: test_server(), user_id, user_secret: secret, fs_id, user_name: None, fs_name: None, replica_name: None,
+            key_source: None, storage: storage::Config::new(PathBuf::new()), transfer: networking::TransferConfig::default(),
+            tls: networking::TlsConfig::default(), replica: core::ReplicaOpts::default(),
+        };
+        core::setup(conf, &conf_path, opts, &Some(dir));
+    };
+
+    // The first replica creates the user and FS, and the second joins it.
+    setup(&mut conf, None, None, None, temp_working_dir("server-sync1"));
+    let info = conf.replicas[0].1.info.clone();
+    let secret = conf.replicas[0].1.credentials.user_secret.as_ref().unwrap().expose().to_owned();
+    setup(&mut conf, info.get_user_id(), Some(secret), info.get_fs_id(), temp_working_dir("server-sync2"));
+
+    let (dir1, dir2) = (conf.replicas[0].0.working_dir.clone(), conf.replicas[1].0.working_dir.clone());
+
+    std::fs::write(dir1.join("a.md"), "# A\n").unwrap();
+    conf.replicas[0].sync(networking::SyncMode::Heads).unwrap();
+    conf.replicas[1].sync(networking::SyncMode::Heads).unwrap();
+    assert_eq!(std::fs::read_to_string(dir2.join("a.md")).unwrap(), "# A");
+
+    // And back again, by reconciliation this time.
+    std::fs::write(dir2.join("b.md"), "# B\n").unwrap();
+    conf.replicas[1].sync(networking::SyncMode::Reconcile).unwrap();
+    conf.replicas[0].sync(networking::SyncMode::Reconcile).unwrap();
+    assert_eq!(std::fs::read_to_string(dir1.join("b.md")).unwrap(), "# B");
+
+    // Both replicas are enrolled, with their signing keys.
+    let replicas = conf.replicas[0].1.blocking().list_replicas().unwrap();
+    assert_eq!(replicas.len(), 2);
+    assert!(replicas.iter().all(|r| r.public_key.is_some()));
+}
@@ -0,0 +1,210 @@
+// Backends replicas sync through. The server is one; a directory shared between replicas, e.g. over NFS, is another.
+// Sync only needs to exchange operations and the set of operations the backend holds, so that's all `Transport` covers.
+// Users, enrolment and sharing are left to the server.
+
+use crate::{errors, storage, types};
+use crate::conflict_res::file_tree;
+use super::{api, reconcile, verify_and_store};
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+pub trait Transport {
+    /// Fetch the heads of the remote history, i.e. the operations no other operation depends on.
+    fn fetch_heads(&self) -> errors::Result<HashSet<types::Hash>>;
+
+    /// Fetch every operation the remote holds.
+    fn fetch_state(&self) -> errors::Result<HashSet<types::Hash>>;
+
+    /// Fetch the given operations, skipping any already stored, and return the parents of each.
+    /// Each operation is checked against its hash, and must decrypt, before it's stored.
+    fn fetch_ops(&self, storage: &storage::Config, ops: &[types::Hash]) -> errors::Result<HashMap<types::Hash, Vec<types::Hash>>>;
+
+    /// Upload the given operations, without recording them as held by the remote.
+    fn push_ops(&self, storage: &storage::Config, ops: &[api::OpInfo]) -> errors::Result<()>;
+
+    /// Record operations, which must already be uploaded, as held by the remote.
+    fn push_state(&self, ops: Vec<api::OpInfo>) -> errors::Result<()>;
+
+    /// Work out which operations differ between us and the remote.
+    /// Remotes without set reconciliation exchange the full state.
+    fn reconcile(&self, local_hashes: &HashSet<types::Hash>) -> errors::Result<reconcile::Difference> {
+        let remote_hashes = self.fetch_state()?;
+
+        return Ok(reconcile::Difference {
+            have: local_hashes.difference(&remote_hashes).cloned().collect(),
+            need: remote_hashes.difference(local_hashes).cloned().collect(),
+        });
+    }
+
+    /// Fetch every operation we don't have, by walking back from the remote's heads until we reach known operations.
+    /// Returns the parents of each fetched operation.
+    fn pull(&self, storage: &storage::Config, local_hashes: &HashSet<types::Hash>, remote_heads: &HashSet<types::Hash>) -> errors::Result<HashMap<types::Hash, Vec<types::Hash>>> {
+        let mut fetched = HashMap::new();
+        let mut frontier: Vec<types::Hash> = remote_heads.difference(local_hashes).cloned().collect();
+
+        while !frontier.is_empty() {
+            let parents = self.fetch_ops(storage, &frontier)?;
+
+            let mut next: Vec<types::Hash> = parents.values().flatten()
+                .filter(|p| !local_hashes.contains(*p) && !fetched.contains_key(*p) && !parents.contains_key(*p))
+                .cloned().collect();
+            next.sort(); next.dedup();
+
+            fetched.extend(parents);
+            frontier = next;
+        }
+
+        return Ok(fetched);
+    }
+
+    /// Upload the given operations, then record them as held by the remote.
+    fn push(&self, storage: &storage::Config, ops: Vec<api::OpInfo>) -> errors::Result<()> {
+        self.push_ops(storage, &ops)?;
+
+        // Only record the operations once they're all uploaded, so the remote never advertises one it doesn't hold.
+        return self.push_state(ops);
+    }
+}
+
+const FS_OPTS: &str = "fs_opts.json";
+const OBJECTS: &str = "objects";
+const STATE: &str = "state";
+
+/// A directory shared between replicas, standing in for the server.
+/// Each FS is kept in its own directory. Operations are stored under `objects/` exactly as the server stores them, and
+/// recorded as held by writing their parents under `state/`, so replicas never rewrite a file another might be reading.
+pub struct LocalDir {
+    root: PathBuf,
+}
+
+/// Write a file in one step, so replicas reading the directory never see it half-written.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().expect("Shared directory paths always have a parent."))?;
+
+    let tmp = path.with_extension(format!("tmp-{}", Uuid::now_v7()));
+    std::fs::write(&tmp, data)?;
+    return std::fs::rename(&tmp, path);
+}
+
+impl LocalDir {
+    pub fn new(dir: &Path, fs_uuid: Uuid) -> Self {
+        Self { root: dir.join(fs_uuid.to_string()) }
+    }
+
+    fn object(&self, hash: &types::Hash) -> PathBuf {
+        self.root.join(OBJECTS).join(types::hash_to_str(hash))
+    }
+
+    fn exists(&self) -> errors::Result<()> {
+        match self.root.join(FS_OPTS).exists() {
+            true => Ok(()),
+            false => Err(errors::Error(errors::CODE_NO_FS, format!("No FS in {:?}.", self.root))),
+        }
+    }
+
+    /// Create the FS in the directory, with the options every replica must share.
+    pub fn register_fs(&self, fs_opts: Vec<String>) -> errors::Result<()> {
+        std::fs::create_dir_all(self.root.join(OBJECTS))?;
+        std::fs::create_dir_all(self.root.join(STATE))?;
+
+        let data = serde_json::to_vec(&fs_opts).map_err(super::NetError::from)?;
+        return Ok(write_atomic(&self.root.join(FS_OPTS), &data)?);
+    }
+
+    /// The FS's options, or `None` if the FS hasn't been created in the directory.
+    pub fn fetch_fs_opts(&self) -> errors::Result<Option<Vec<String>>> {
+        match std::fs::read(self.root.join(FS_OPTS)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data).map_err(super::NetError::from)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The parents of every operation held.
+    fn read_state(&self) -> errors::Result<HashMap<types::Hash, Vec<types::Hash>>> {
+        self.exists()?;
+
+        let mut state = HashMap::new();
+        for entry in std::fs::read_dir(self.root.join(STATE))? {
+            let entry = entry?;
+            // Skips files still being written.
+            let Ok(hash) = types::str_to_hash(&entry.file_name().to_string_lossy()) else {continue};
+
+            let parents = serde_json::from_slice(&std::fs::read(entry.path())?).map_err(super::NetError::from)?;
+            state.insert(hash, parents);
+        }
+
+        return Ok(state);
+    }
+}
+
+impl Transport for LocalDir {
+    fn fetch_heads(&self) -> errors::Result<HashSet<types::Hash>> {
+        let state = self.read_state()?;
+        let depended_on: HashSet<&types::Hash> = state.values().flatten().collect();
+
+        return Ok(state.keys().filter(|h| !depended_on.contains(h)).cloned().collect());
+    }
+
+    fn fetch_state(&self) -> errors::Result<HashSet<types::Hash>> {
+        return Ok(self.read_state()?.into_keys().collect());
+    }
+
+    fn fetch_ops(&self, storage: &storage::Config, ops: &[types::Hash]) -> errors::Result<HashMap<types::Hash, Vec<types::Hash>>> {
+        self.exists()?;
+
+        let mut fetched = HashMap::new();
+        for op in ops.iter() {
+            let loc = storage::object::Location::Object(*op);
+
+            if !loc.exists(storage) {
+                let data = match std::fs::read_to_string(self.object(op)) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(errors::Error(errors::CODE_NOT_FOUND, format!("No operation {}.", types::hash_to_str(op))));
+                    },
+                    Err(e) => return Err(e.into()),
+                };
+
+                verify_and_store(storage, &data, op)?;
+            }
+
+            fetched.insert(*op, file_tree::read_parents(storage, op)?);
+        }
+
+        return Ok(fetched);
+    }
+
+    fn push_ops(&self, storage: &storage::Config, ops: &[api::OpInfo]) -> errors::Result<()> {
+        self.exists()?;
+
+        for op in ops.iter() {
+            let path = self.object(&op.hash);
+            if path.exists() {continue;}
+
+            let mut data = Vec::new();
+            storage::object::read_bytes(storage, &storage::object::Location::Object(op.hash), &mut data)?;
+            write_atomic(&path, &data)?;
+        }
+
+        return Ok(());
+    }
+
+    fn push_state(&self, ops: Vec<api::OpInfo>) -> errors::Result<()> {
+        self.exists()?;
+
+        for op in ops.iter() {
+            if !self.object(&op.hash).exists() {
+                return Err(errors::Error(errors::CODE_NOT_FOUND, format!("Operation {} hasn't been uploaded.", types::hash_to_str(&op.hash))));
+            }
+
+            let data = serde_json::to_vec(&op.parents).map_err(super::NetError::from)?;
+            write_atomic(&self.root.join(STATE).join(types::hash_to_str(&op.hash)), &data)?;
+        }
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,155 @@
+// End-to-end encryption of objects, with a key per filesystem which the server never sees.
+// Sealing is deterministic: an object's nonce is derived from its contents, so the same object always seals to the same
+// ciphertext. Objects are addressed by the hash of their ciphertext, so the server can verify and dedupe them, and every
+// replica agrees on the address of each operation. The cost is that the server can tell when two objects are identical.
+
+use crate::types;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::Aead};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Filesystem option marking a FS as encrypted, followed by the key's check value, e.g. `encrypted=0123456789abcdef`.
+pub const OPT_ENCRYPTED: &str = "encrypted";
+/// Prefix of every sealed object, so sealed objects are never mistaken for plaintext.
+const SEALED_PREFIX: &str = "crfs-sealed-v1:";
+const NONCE_LEN: usize = 12;
+
+/// A filesystem's encryption key. Never printed by `Debug`, so it can't leak into logs.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Derive the key for a FS from a passphrase. The FS's UUID is the salt, so every replica derives the same key.
+    pub fn from_passphrase(passphrase: &str, fs_uuid: Uuid) -> Self {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), fs_uuid.as_bytes(), &mut key)
+            .expect("Invalid key derivation parameters.");
+
+        return Self(key);
+    }
+
+    /// Parse a key shared out-of-band, as 64 hex digits.
+    pub fn from_hex(s: &str) -> Result<Self, String> {
+        types::from_hex(s).map(Self)
+    }
+
+    pub fn to_hex(&self) -> String {
+        types::to_hex(&self.0)
+    }
+
+    /// A value identifying the key without revealing it, stored in the FS's options so other replicas can check theirs.
+    pub fn check_value(&self) -> String {
+        types::to_hex(&self.subkey(b"check")[..8])
+    }
+
+    /// The FS option recording that the FS is encrypted with this key.
+    pub fn to_opt(&self) -> String {
+        format!("{}={}", OPT_ENCRYPTED, self.check_value())
+    }
+
+    /// Whether the FS options mark the FS as encrypted.
+    pub fn required(opts: &[String]) -> bool {
+        opts.iter().any(|o| o.split('=').next() == Some(OPT_ENCRYPTED))
+    }
+
+    /// Whether this is the key the FS options were registered with.
+    pub fn matches(&self, opts: &[String]) -> bool {
+        opts.contains(&self.to_opt())
+    }
+
+    /// Prove to a peer that we hold the key, without revealing it, by MACing the peer's `challenge`.
+    pub fn prove(&self, challenge: &str) -> String {
+        let mut mac = self.mac(b"peer");
+        mac.update(challenge.as_bytes());
+        return types::to_hex(&mac.finalize().into_bytes());
+    }
+
+    /// Check a peer's proof that it holds the key, in constant time.
+    pub fn check_proof(&self, challenge: &str, proof: &str) -> bool {
+        let Ok(proof) = types::from_hex::<32>(proof) else {return false};
+
+        let mut mac = self.mac(b"peer");
+        mac.update(challenge.as_bytes());
+        return mac.verify_slice(&proof).is_ok();
+    }
+
+    fn mac(&self, label: &[u8]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("HMAC accepts any key length.");
+        mac.update(label);
+        return mac;
+    }
+
+    /// Separate keys for encryption, nonces and check values, all derived from the FS's key.
+    fn subkey(&self, label: &[u8]) -> [u8; 32] {
+        self.mac(label).finalize().into_bytes().into()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.subkey(b"encrypt").into())
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key(<redacted>)")
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_hex(&s)
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> String {
+        key.to_hex()
+    }
+}
+
+/// Encrypt `plaintext`, along with its hash, which is checked when it's opened.
+pub fn seal(key: &Key, plaintext: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.subkey(b"nonce")).expect("HMAC accepts any key length.");
+    mac.update(plaintext);
+    let nonce_bytes: [u8; 32] = mac.finalize().into_bytes().into();
+    let nonce_bytes: [u8; NONCE_LEN] = nonce_bytes[..NONCE_LEN].try_into().unwrap();
+    let nonce = Nonce::from(nonce_bytes);
+
+    let mut inner = Sha256::digest(plaintext).to_vec();
+    inner.extend_from_slice(plaintext);
+
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend(key.cipher().encrypt(&nonce, inner.as_slice()).expect("Encryption error."));
+
+    return format!("{}{}", SEALED_PREFIX, BASE64.encode(sealed));
+}
+
+/// Decrypt a sealed object, and verify the hash of its plaintext.
+pub fn open(key: &Key, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned());
+
+    let encoded = sealed.strip_prefix(SEALED_PREFIX.as_bytes()).ok_or_else(|| invalid("Object isn't encrypted."))?;
+    let sealed = BASE64.decode(encoded).map_err(|_| invalid("Malformed encrypted object."))?;
+    if sealed.len() < NONCE_LEN {return Err(invalid("Malformed encrypted object."));}
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+    let inner = key.cipher().decrypt(&Nonce::from(nonce), ciphertext)
+        .map_err(|_| invalid("Unable to decrypt object. Is the filesystem's key correct?"))?;
+
+    if inner.len() < 32 {return Err(invalid("Malformed encrypted object."));}
+    let (hash, plaintext) = inner.split_at(32);
+    if hash != &Sha256::digest(plaintext)[..] {
+        return Err(invalid("Decrypted object doesn't match its hash."));
+    }
+
+    return Ok(plaintext.to_vec());
+}
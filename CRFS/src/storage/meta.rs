@@ -1,12 +1,13 @@
 use super::Config;
 
 use std::{
-    fs::{File, create_dir_all},
+    fs::{File, OpenOptions, create_dir_all},
     io::{Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use serde::{Serialize, de::DeserializeOwned};
+use uuid::Uuid;
 
 fn get_root(config: &Config) -> PathBuf {
     let mut path = PathBuf::new();
@@ -70,6 +71,30 @@ pub fn write<T>(config: &Config, name: &String, data: &T) -> std::io::Result<()>
     return write_at(config, &path, false, data);
 }
 
+/// Write `data` to `path`, readable only by its owner, as it holds secrets. It's written to a file created with those
+/// permissions, then renamed into place, so it's never readable by anyone else, even briefly.
+pub fn write_private<T>(path: &Path, data: &T) -> std::io::Result<()> where T: Serialize {
+    let json = serde_json::to_string(data)?;
+    let tmp = path.with_extension(format!("tmp-{}", Uuid::now_v7()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut f = options.open(&tmp)?;
+    if let Err(e) = f.write_all(json.as_bytes()) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    drop(f);
+
+    return std::fs::rename(&tmp, path);
+}
+
 pub fn write_at<T>(config: &Config, path: &PathBuf, relative: bool, data: &T) -> std::io::Result<()> where T: Serialize + std::fmt::Debug {
     let path = if relative {
         let mut _path = config.working_dir.clone(); _path.push(path); _path
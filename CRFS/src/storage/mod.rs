@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde::{Serialize, Deserialize};
 
@@ -9,7 +9,161 @@ const METADIR: &str = ".crfs/meta/"; // Appended to the working dir.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub working_dir: PathBuf,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Replicate permission bits. Disable on filesystems which don't support them.
+    #[serde(default = "default_true")]
+    pub permissions: bool,
+    /// Replicate modification times.
+    #[serde(default)]
+    pub mtimes: bool,
+    #[serde(default)]
+    pub move_conflicts: MovePolicy,
+    #[serde(default)]
+    pub name_collisions: CollisionPolicy,
+    #[serde(default)]
+    pub paths: PathPolicy,
+    /// Key which operations are encrypted with, if the FS is encrypted.
+    /// Stored with the replica's credentials, and never written out with the rest of the config.
+    #[serde(skip)]
+    pub key: Option<crypt::Key>,
+    /// Replica which operations written locally are signed as. Like `key`, never written out.
+    #[serde(skip)]
+    pub signer: Option<sign::Signer>,
+}
+
+fn default_true() -> bool { true }
+
+impl Config {
+    pub fn new(working_dir: PathBuf) -> Self {
+        Self {
+            working_dir,
+            symlinks: SymlinkPolicy::default(),
+            permissions: true,
+            mtimes: false,
+            move_conflicts: MovePolicy::default(),
+            name_collisions: CollisionPolicy::default(),
+            paths: PathPolicy::default(),
+            key: None,
+            signer: None,
+        }
+    }
+}
+
+/// How symbolic links in the working directory are handled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Treat links as the file or directory they point to. Links pointing outside the working directory are skipped.
+    Follow,
+    /// Skip links entirely.
+    Ignore,
+    /// Replicate the links themselves. Links pointing outside the working directory are not replicated.
+    #[default]
+    Replicate,
 }
 
 pub mod object;
 pub mod meta;
+pub mod crypt;
+pub mod sign;
+
+/// How concurrent moves of the same file are resolved.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MovePolicy {
+    /// The move with the latest timestamp wins.
+    #[default]
+    LastWriterWins,
+    /// The move with the latest timestamp wins, but replicas whose move lost keep a copy of the file where they moved it.
+    KeepBoth,
+}
+
+/// How files created concurrently with the same name are resolved.
+/// Like the path policy, this belongs to the whole filesystem and must match on every replica.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CollisionPolicy {
+    /// The file created last is renamed, tagged with the end of its creator's ID, e.g. `todo (7c2e94d1).md`.
+    #[default]
+    Rename,
+    /// The file created last is merged into the other by its driver, appending its contents.
+    Merge,
+}
+
+const OPT_MERGE_COLLISIONS: &str = "merge-collisions";
+
+impl CollisionPolicy {
+    /// Encode the policy as filesystem options, for storing on the server.
+    pub fn to_opts(self) -> Vec<String> {
+        match self {
+            Self::Rename => Vec::new(),
+            Self::Merge => vec![OPT_MERGE_COLLISIONS.to_owned()],
+        }
+    }
+
+    pub fn from_opts(opts: &[String]) -> Self {
+        match opts.iter().any(|o| o == OPT_MERGE_COLLISIONS) {
+            true => Self::Merge,
+            false => Self::Rename,
+        }
+    }
+}
+
+/// How file and directory names are compared.
+/// This is a property of the whole filesystem, stored in its options on the server, and must match on every replica.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// Normalise names to Unicode NFC.
+    #[serde(default = "default_true")]
+    pub nfc: bool,
+    /// Treat names which differ only by case as the same name, e.g. `Notes.md` and `notes.md`.
+    #[serde(default)]
+    pub case_insensitive: bool,
+}
+
+const OPT_NO_NFC: &str = "no-nfc";
+const OPT_CASE_INSENSITIVE: &str = "case-insensitive";
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        Self {nfc: true, case_insensitive: false}
+    }
+}
+
+impl PathPolicy {
+    /// Normalise every component of `path`. Components which aren't valid unicode are left unchanged.
+    pub fn normalise(&self, path: &Path) -> PathBuf {
+        if !self.nfc {return path.to_owned();}
+
+        let nfc = icu_normalizer::ComposingNormalizerBorrowed::new_nfc();
+        return path.components().map(|c| match c.as_os_str().to_str() {
+            Some(s) => PathBuf::from(nfc.normalize(s).as_ref()),
+            None => PathBuf::from(c.as_os_str()),
+        }).collect();
+    }
+
+    /// Get the key under which two names are considered to be the same name.
+    pub fn key(&self, name: &Path) -> PathBuf {
+        let name = self.normalise(name);
+        if !self.case_insensitive {return name;}
+
+        return match name.to_str() {
+            Some(s) => PathBuf::from(s.to_lowercase()),
+            None => name,
+        };
+    }
+
+    /// Encode the policy as filesystem options, for storing on the server.
+    pub fn to_opts(self) -> Vec<String> {
+        let mut opts = Vec::new();
+        if !self.nfc {opts.push(OPT_NO_NFC.to_owned());}
+        if self.case_insensitive {opts.push(OPT_CASE_INSENSITIVE.to_owned());}
+
+        return opts;
+    }
+
+    pub fn from_opts(opts: &[String]) -> Self {
+        Self {
+            nfc: !opts.iter().any(|o| o == OPT_NO_NFC),
+            case_insensitive: opts.iter().any(|o| o == OPT_CASE_INSENSITIVE),
+        }
+    }
+}
@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use trash;
 
-use super::{Config, OBJECTDIR};
+use super::{crypt, sign, Config, OBJECTDIR};
 use crate::types::{Hash, hash_to_str};
 use crate::conflict_res::CmRDT;
 
@@ -106,24 +106,96 @@ pub fn write(config: &Config, loc: &Location, buf: &[u8]) -> std::io::Result<()>
     return f.write_all(buf);
 }
 
+/// Encrypt an object's contents if the FS is encrypted. Objects are addressed by the hash of what this returns.
+pub fn seal(config: &Config, buf: &[u8]) -> Vec<u8> {
+    match &config.key {
+        Some(key) => crypt::seal(key, buf).into_bytes(),
+        None => buf.to_vec(),
+    }
+}
+
+/// Decrypt an object's contents if the FS is encrypted, verifying them.
+pub fn unseal(config: &Config, buf: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match &config.key {
+        Some(key) => crypt::open(key, &buf),
+        None => Ok(buf),
+    }
+}
+
 pub fn write_obj(config: &Config, buf: &[u8]) -> std::io::Result<Hash> {
+    let sealed = seal(config, buf);
+
     let mut hasher = Sha256::new();
-    hasher.update(buf);
+    hasher.update(&sealed);
     let hash: Hash = hasher.finalize();
     let loc = Location::Object(hash);
 
-    write(config, &loc, buf)?;
+    write(config, &loc, &sealed)?;
     return Ok(hash);
 }
 
+/// Read an object, decrypting it if needed. Signed operations are read without their signature.
+pub fn read_obj(config: &Config, hash: &Hash, buf: &mut String) -> std::io::Result<usize> {
+    let plain = sign::strip(read_plain(config, hash)?);
+    buf.push_str(&plain);
+
+    return Ok(plain.len());
+}
+
+fn read_plain(config: &Config, hash: &Hash) -> std::io::Result<String> {
+    let mut sealed = Vec::new(); read_bytes(config, &Location::Object(*hash), &mut sealed)?;
+
+    return String::from_utf8(unseal(config, sealed)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+}
+
+/// Check the operation `hash` was signed by a replica in `keyring`, returning the signer.
+pub fn verify_op(config: &Config, keyring: &sign::Keyring, hash: &Hash) -> Result<uuid::Uuid, String> {
+    sign::verify(keyring, &read_plain(config, hash).map_err(|e| e.to_string())?)
+}
+
+/// An operation as it's stored: signed if the replica has a signing key, then sealed if the FS is encrypted.
+fn encode_op<T>(config: &Config, op: &T) -> std::io::Result<Vec<u8>> where T: CmRDT::Operation {
+    let json = op.serialize_to_str()?;
+    let json = match &config.signer {
+        Some(signer) => sign::sign(signer, &json),
+        None => json,
+    };
+
+    return Ok(seal(config, json.as_bytes()));
+}
+
+/// The address of an operation written on this replica, i.e. the hash it's stored and referred to by.
+/// This is the hash of the operation as stored, so it covers the signature, and the ciphertext if the FS is encrypted.
+/// Operations from other replicas must be referred to by the hash they were fetched by.
+pub fn op_hash<T>(config: &Config, op: &T) -> std::io::Result<Hash> where T: CmRDT::Operation {
+    match (&config.key, &config.signer) {
+        (None, None) => Ok(op.get_hash()),
+        _ => Ok(Sha256::digest(encode_op(config, op)?)),
+    }
+}
+
 pub fn write_op<T>(config: &Config, op: T) -> std::io::Result<Hash> where T: CmRDT::Operation {
-    let hash = op.get_hash();
+    let encoded = encode_op(config, &op)?;
+    let hash: Hash = Sha256::digest(&encoded);
     let loc = Location::Object(hash);
 
-    write(config, &loc, op.serialize_to_str()?.as_bytes())?;
+    write(config, &loc, &encoded)?;
     return Ok(hash);
 }
 
+/// Create a symbolic link at `loc`, pointing to `target`.
+pub fn write_link(config: &Config, loc: &Location, target: &PathBuf) -> std::io::Result<()> {
+    ensure_dir(config, loc)?;
+    let path = loc.get_path(config);
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, path);
+
+    #[cfg(not(unix))]
+    return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("Unable to create symlink {:?}.", path)));
+}
+
 pub fn delete(config: &Config, loc: &Location) -> std::io::Result<()> {
     let path = loc.get_path(config);
     Ok(trash::delete(&path).expect("Error deleting file."))
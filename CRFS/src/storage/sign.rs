@@ -0,0 +1,174 @@
+// Signatures on operations, so that replicas only apply operations written by replicas enrolled in the FS.
+// Each replica holds an Ed25519 keypair, whose public half is registered with the server when the replica enrols.
+// Operations are wrapped in a `SignedOp` before they're sealed, and checked against a `Keyring` of the FS's enrolled
+// replicas before they're applied. Removing a replica drops its key from the keyring, so its operations are rejected.
+
+use super::{meta, Config};
+use crate::types::{from_hex, to_hex};
+
+use std::collections::HashMap;
+
+use ed25519_dalek::{Signer as _, Verifier as _};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+const KEYRING_META: &str = "keyring";
+
+/// A replica's private signing key. Never printed by `Debug`, so it can't leak into logs.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        Self(ed25519_dalek::SigningKey::from_bytes(&rand::random()))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SigningKey(<redacted>)")
+    }
+}
+
+impl TryFrom<String> for SigningKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Ok(Self(ed25519_dalek::SigningKey::from_bytes(&from_hex(&s)?)))
+    }
+}
+
+impl From<SigningKey> for String {
+    fn from(key: SigningKey) -> String {
+        to_hex(&key.0.to_bytes())
+    }
+}
+
+/// A replica's public key, as registered with the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PublicKey(ed25519_dalek::VerifyingKey);
+
+impl PublicKey {
+    pub fn from_hex(s: &str) -> Result<Self, String> {
+        ed25519_dalek::VerifyingKey::from_bytes(&from_hex(s)?).map(Self).map_err(|e| e.to_string())
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(self.0.as_bytes())
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_hex(&s)
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(key: PublicKey) -> String {
+        key.to_hex()
+    }
+}
+
+/// The replica operations written locally are signed as.
+#[derive(Debug, Clone)]
+pub struct Signer {
+    pub replica: Uuid,
+    pub key: SigningKey,
+}
+
+/// An operation, as written out by `Signer`. The operation is kept as the exact string which was signed.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SignedOp {
+    signed_op: String,
+    signer: Uuid,
+    signature: String,
+}
+
+/// The bytes signed for an operation. Includes the signer, so a signature can't be claimed by another replica.
+fn message(signer: &Uuid, op: &str) -> Vec<u8> {
+    let mut msg = signer.as_bytes().to_vec();
+    msg.extend_from_slice(op.as_bytes());
+    return msg;
+}
+
+/// Wrap the serialised operation `op` with `signer`'s signature.
+pub fn sign(signer: &Signer, op: &str) -> String {
+    let signature = signer.key.0.sign(&message(&signer.replica, op));
+
+    return serde_json::to_string(&SignedOp {
+        signed_op: op.to_owned(),
+        signer: signer.replica,
+        signature: to_hex(&signature.to_bytes()),
+    }).expect("Error serialising signed operation.");
+}
+
+impl Signer {
+    /// Prove to a peer that we're this replica, by signing the peer's `challenge`. Challenges must never be valid
+    /// operations, or a peer could get operations signed this way.
+    pub fn sign_challenge(&self, challenge: &str) -> String {
+        to_hex(&self.key.0.sign(&message(&self.replica, challenge)).to_bytes())
+    }
+}
+
+/// The operation inside `data`, whether or not it's signed.
+pub fn strip(data: String) -> String {
+    match serde_json::from_str::<SignedOp>(&data) {
+        Ok(signed) => signed.signed_op,
+        Err(_) => data,
+    }
+}
+
+/// Check that `data` is an operation signed by a replica in `keyring`, returning the signer.
+pub fn verify(keyring: &Keyring, data: &str) -> Result<Uuid, String> {
+    let signed: SignedOp = serde_json::from_str(data).map_err(|_| "operation isn't signed".to_owned())?;
+
+    let key = keyring.keys.get(&signed.signer)
+        .ok_or_else(|| format!("signer {} isn't a known replica", signed.signer))?;
+    let signature = ed25519_dalek::Signature::from_bytes(&from_hex(&signed.signature)?);
+
+    key.0.verify(&message(&signed.signer, &signed.signed_op), &signature)
+        .map_err(|_| format!("invalid signature from replica {}", signed.signer))?;
+
+    return Ok(signed.signer);
+}
+
+/// Public keys of the replicas enrolled in a FS. Refreshed from the server on every sync.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Keyring {
+    keys: HashMap<Uuid, PublicKey>,
+}
+
+impl Keyring {
+    pub fn new(keys: impl IntoIterator<Item = (Uuid, PublicKey)>) -> Self {
+        Self { keys: keys.into_iter().collect() }
+    }
+
+    /// Check a peer's proof that it's the enrolled replica `replica`.
+    pub fn check_challenge(&self, replica: &Uuid, challenge: &str, signature: &str) -> bool {
+        let (Some(key), Ok(signature)) = (self.keys.get(replica), from_hex(signature)) else {return false};
+
+        return key.0.verify(&message(replica, challenge), &ed25519_dalek::Signature::from_bytes(&signature)).is_ok();
+    }
+
+    pub fn read_in(config: &Config) -> std::io::Result<Self> {
+        match meta::read(config, &KEYRING_META.to_owned()) {
+            Ok(k) => Ok(k),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write_out(&self, config: &Config) -> std::io::Result<()> {
+        meta::write(config, &KEYRING_META.to_owned(), self)
+    }
+}
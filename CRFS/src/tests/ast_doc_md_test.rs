@@ -2,10 +2,11 @@ use std::path::PathBuf;
 use std::collections::HashSet;
 
 use crate::conflict_res::file_tree::DriverID;
-use crate::conflict_res::CmRDT::{DiskType, Object};
+use crate::conflict_res::CmRDT::{DiskType, Object, Operation};
 use crate::conflict_res::ast_doc;
 use ast_doc::types::{Node, FileInterface, Children};
 use ast_doc::md;
+use ast_doc::crdt::DocOp;
 
 use crate::storage;
 use storage::object;
@@ -16,7 +17,7 @@ const TESTFILEDIR: &str = ".testfiles";
 
 #[test]
 fn read_test() {
-    let config = storage::Config {working_dir: PathBuf::from(TESTFILEDIR),};
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
     let path = PathBuf::from("test.md");
 
     let loc = object::Location::Path(path.clone(), true);
@@ -51,7 +52,7 @@ fn eq_content_test() {
 
 #[test]
 fn generate_against_test() {
-    let config = storage::Config {working_dir: PathBuf::from(TESTFILEDIR),};
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
     let path = PathBuf::from("test.md");
 
     let loc = object::Location::Path(path.clone(), true);
@@ -80,7 +81,7 @@ fn generate_against_test() {
 
 #[test]
 fn md_merge_test() {
-    let config = storage::Config {working_dir: PathBuf::from(TESTFILEDIR),};
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
 
     let paths = [
         PathBuf::from("test1.md"),
@@ -134,3 +135,55 @@ fn md_merge_test() {
     assert_eq!(object1.query().get_canon(), object2.query().get_canon());
     // assert_eq!(object1.query().get_canon(), ints[3].get_canon());
 }
+
+#[test]
+fn md_move_merge_test() {
+    let config = storage::Config::new(crate::tests::storage_test::temp_working_dir("mdmovemerge"));
+    let loc = object::Location::Path(PathBuf::from("a.md"), true);
+    let read = |raw_md: &str| {
+        std::fs::write(config.working_dir.join("a.md"), raw_md).unwrap();
+        *md::MDInterface::read(&config, &loc).unwrap()
+    };
+    let prep = |object: &mut md::MDObject, raw_md: &str, replica: u128| {
+        let (int, mut ops) = (read(raw_md), Vec::new());
+        while let Some(op) = object.prep(&int, Uuid::from_u128(replica)) {
+            object.apply_op(&op);
+            ops.push(op);
+        }
+        ops
+    };
+
+    let mut object1 = md::MDObject::init(DriverID::Driver(0));
+    let mut object2 = md::MDObject::init(DriverID::Driver(1));
+
+    for op in prep(&mut object1, "# alpha\n\nbeta", 1).iter() {object2.apply_op(op);}
+
+    // Both replicas reuse the existing text nodes in new blocks, which must keep them when moved.
+    let ops1 = prep(&mut object1, "alpha", 1);
+    let ops2 = prep(&mut object2, "alpha\n\n# beta", 2);
+    for op in ops2.iter() {object1.apply_op(op);}
+    for op in ops1.iter() {object2.apply_op(op);}
+
+    assert_eq!(object1.query().get_canon(), object2.query().get_canon());
+}
+
+#[test]
+fn baseline_op_test() {
+    // Operations made before every parent was recorded, with a single optional `dep`.
+    const ADD_PARENT: &str = r#"{"DocAddParent":{"driverid":{"Driver":2553637495092389199},"w":205457826403352853376179128126430618881,"tag":"Paragraph","w_parent":0,"i":5133121397597915828,"ins":{"origin":"Left","left":"Left","right":"Right","content":205457826403352853376179128126430618881,"creator":"00000000-0000-0000-0000-000000000001","deleted":false},"dep":null}}"#;
+    const ADD_LEAF: &str = r#"{"DocAddLeaf":{"driverid":{"Driver":2553637495092389199},"w":236069297065315409245060133459316964138,"content":{"InlineText":"alpha"},"w_parent":205457826403352853376179128126430618881,"i":8012235012231480902,"ins":{"origin":"Left","left":"Left","right":"Right","content":236069297065315409245060133459316964138,"creator":"00000000-0000-0000-0000-000000000001","deleted":false},"dep":[217,229,28,127,161,51,245,202,67,48,201,87,232,244,219,29,4,189,179,40,57,45,215,63,150,180,120,167,46,149,220,111]}}"#;
+
+    let parent = DocOp::<md::MDTag, md::MDLeaf>::deserialize_from_str(ADD_PARENT.to_owned()).unwrap();
+    let leaf = DocOp::<md::MDTag, md::MDLeaf>::deserialize_from_str(ADD_LEAF.to_owned()).unwrap();
+    assert!(parent.get_parents().is_empty());
+    assert_eq!(leaf.get_parents(), vec![crate::types::calculate_hash(ADD_PARENT)]);
+
+    let mut object = md::MDObject::init(DriverID::Driver(2553637495092389199));
+    object.apply_op_as(&parent, Some(crate::types::calculate_hash(ADD_PARENT))).unwrap();
+    object.apply_op_as(&leaf, Some(crate::types::calculate_hash(ADD_LEAF))).unwrap();
+    assert_eq!(object.query().get_canon(), "alpha");
+
+    // Operations made now still record every parent.
+    let reread = DocOp::<md::MDTag, md::MDLeaf>::deserialize_from_str(leaf.serialize_to_str().unwrap()).unwrap();
+    assert_eq!(reread.get_parents(), leaf.get_parents());
+}
@@ -1,4 +1,5 @@
 use crate::conflict_res::{directed_graph, file_tree::DriverID, CmRDT};
+use crate::types::calculate_hash;
 use CmRDT::Object;
 use directed_graph::{Graph, GraphObject, GraphOp};
 
@@ -47,3 +48,66 @@ fn directed_graph_test() -> () {
 
     assert_eq!(obj1.query(), obj2.query());
 }
+
+#[test]
+fn history_partial_order_test() {
+    let (h1, h2, h3, h4) = (calculate_hash("1"), calculate_hash("2"), calculate_hash("3"), calculate_hash("4"));
+
+    // h1 <- h2, h1 <- h3, (h2, h3) <- h4
+    let mut hist = CmRDT::History::new();
+    hist.add(Some(h1), &[]);
+    hist.add(Some(h2), &[h1]);
+    hist.add(Some(h3), &[h1]);
+
+    let mut heads = vec![h2, h3]; heads.sort();
+    assert_eq!(hist.heads(), heads);
+    assert!(hist.contains_all(&heads));
+    assert!(!hist.contains_all(&[h4]));
+
+    hist.add(Some(h4), &heads);
+    assert_eq!(hist.heads(), vec![h4]);
+
+    assert!(hist.happened_before(h1, h2));
+    assert!(hist.happened_before(h1, h4));
+    assert!(!hist.happened_before(h4, h1));
+
+    // Concurrent operations
+    assert!(!hist.happened_before(h2, h3));
+    assert!(!hist.happened_before(h3, h2));
+}
+
+#[test]
+fn history_ancestors_test() {
+    let (h1, h2, h3, h4) = (calculate_hash("1"), calculate_hash("2"), calculate_hash("3"), calculate_hash("4"));
+
+    let mut hist = CmRDT::History::new();
+    hist.add(Some(h1), &[]);
+    hist.add(Some(h2), &[h1]);
+    hist.add(Some(h3), &[h1]);
+    hist.add(Some(h4), &[h2]);
+
+    let parents = hist.get_parents();
+
+    // Everything the head h3 accounts for.
+    assert_eq!(CmRDT::ancestors(&parents, &[h3]), HashSet::from([h1, h3]));
+    assert_eq!(CmRDT::ancestors(&parents, &[h3, h4]), HashSet::from([h1, h2, h3, h4]));
+    assert!(CmRDT::ancestors(&parents, &[]).is_empty());
+}
+
+#[test]
+fn history_reload_test() {
+    let (h1, h2) = (calculate_hash("1"), calculate_hash("2"));
+
+    let mut hist = CmRDT::History::new();
+    hist.add(Some(h1), &[]);
+    hist.add(Some(h2), &[h1]);
+
+    // The set of applied hashes isn't stored, so must be rebuilt when the history is loaded.
+    let json = serde_json::to_string(&hist).unwrap();
+    assert!(!json.contains("applied"));
+    let loaded: CmRDT::History = serde_json::from_str(&json).unwrap();
+
+    assert!(loaded.contains_all(&[h1, h2]));
+    assert_eq!(loaded.heads(), vec![h2]);
+    assert_eq!(loaded.get_hashes(), HashSet::from([h1, h2]));
+}
@@ -5,3 +5,8 @@ mod ast_doc_test;
 mod ast_doc_md_test;
 
 mod yata_test;
+mod yata_prop_test;
+
+mod reconcile_test;
+
+mod simulation_test;
@@ -0,0 +1,71 @@
+use crate::networking::reconcile::{self, Difference, SortedSet};
+use crate::types::{calculate_hash, Hash};
+
+use std::collections::HashSet;
+
+/// Reconcile two sets in-process, returning the difference found, the number of round trips, and the number of
+/// bytes sent in both directions.
+fn measure(client: &[Hash], server: &[Hash]) -> (Difference, usize, usize) {
+    let (client, server) = (SortedSet::new(client), SortedSet::new(server));
+    let mut diff = Difference::default();
+    let (mut rounds, mut bytes) = (0, 0);
+
+    let mut ranges = reconcile::initial(&client);
+    while !ranges.is_empty() {
+        bytes += serde_json::to_vec(&ranges).unwrap().len();
+        let reply = reconcile::respond(&server, &ranges);
+        bytes += serde_json::to_vec(&reply).unwrap().len();
+
+        ranges = reconcile::process(&client, &reply, &mut diff);
+        rounds += 1;
+    }
+
+    return (diff, rounds, bytes);
+}
+
+fn hashes(prefix: &str, n: usize) -> Vec<Hash> {
+    (0..n).map(|i| calculate_hash(&format!("{}{}", prefix, i))).collect()
+}
+
+fn assert_diff(diff: &Difference, have: &[Hash], need: &[Hash]) {
+    assert_eq!(diff.have.iter().collect::<HashSet<_>>(), have.iter().collect());
+    assert_eq!(diff.need.iter().collect::<HashSet<_>>(), need.iter().collect());
+}
+
+#[test]
+fn reconcile_small_test() {
+    let shared = hashes("shared", 100);
+    let (ours, theirs) = (hashes("ours", 3), hashes("theirs", 5));
+
+    let (diff, ..) = measure(&[shared.clone(), ours.clone()].concat(), &[shared.clone(), theirs.clone()].concat());
+    assert_diff(&diff, &ours, &theirs);
+
+    // Identical sets are settled in one round, with nothing to exchange.
+    let (diff, rounds, _) = measure(&shared, &shared);
+    assert_eq!(diff, Difference::default());
+    assert_eq!(rounds, 1);
+
+    // Either side empty
+    let (diff, ..) = measure(&[], &shared);
+    assert_diff(&diff, &[], &shared);
+    let (diff, ..) = measure(&shared, &[]);
+    assert_diff(&diff, &shared, &[]);
+}
+
+#[test]
+fn reconcile_bytes_test() {
+    let shared = hashes("shared", 100_000);
+    let (ours, theirs) = (hashes("ours", 20), hashes("theirs", 20));
+
+    let client = [shared.clone(), ours.clone()].concat();
+    let server = [shared.clone(), theirs.clone()].concat();
+
+    let (diff, rounds, bytes) = measure(&client, &server);
+    assert_diff(&diff, &ours, &theirs);
+
+    // The full exchange sends both sets of hashes.
+    let full_bytes = serde_json::to_vec(&client).unwrap().len() + serde_json::to_vec(&server).unwrap().len();
+    println!("100k ops: reconciliation sent {} bytes in {} rounds, full exchange {} bytes.", bytes, rounds, full_bytes);
+
+    assert!(bytes * 20 < full_bytes);
+}
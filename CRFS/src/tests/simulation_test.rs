@@ -0,0 +1,283 @@
+// Deterministic simulation of replicas of a FS, editing concurrently and exchanging operations in arbitrary orders.
+// Each replica makes random edits, moves and deletions, and receives random subsets of other replicas' operations, in
+// random orders which needn't respect causality. Once every operation has been delivered everywhere, nothing may be left
+// pending, and every replica must have identical files on disk.
+// Every run is driven by a seed, so a failing run can be replayed with `CRFS_SIM_SEED=<seed> cargo test simulation`.
+// Actions are printed as they happen, and shown by the test harness when a run fails.
+
+use crate::conflict_res::file_tree::{pending::PendingOps, FileManager};
+use crate::storage;
+use crate::tests::storage_test::temp_working_dir;
+use crate::types;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rand::{rngs::StdRng, seq::{IndexedRandom, SliceRandom}, Rng, SeedableRng};
+use uuid::Uuid;
+
+/// Seeds run by default.
+const SEEDS: std::ops::Range<u64> = 0..16;
+const STEPS: usize = 60;
+
+/// Directories and names files are created in, kept few so replicas often touch the same files.
+const DIRS: [&str; 4] = ["", "docs", "docs/drafts", "notes"];
+const NAMES: [&str; 4] = ["a.md", "b.md", "todo.md", "readme.md"];
+const WORDS: [&str; 8] = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta"];
+
+struct Replica {
+    manager: FileManager,
+    config: storage::Config,
+}
+
+struct Simulation {
+    rng: StdRng,
+    replicas: Vec<Replica>,
+}
+
+/// Every file and directory in a working directory, with the contents of each file.
+fn snapshot(root: &Path) -> BTreeMap<PathBuf, Option<String>> {
+    fn walk(root: &Path, dir: &Path, out: &mut BTreeMap<PathBuf, Option<String>>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let rel = path.strip_prefix(root).unwrap().to_owned();
+            if rel == Path::new(".crfs") {continue;}
+
+            if path.is_dir() {
+                walk(root, &path, out);
+                out.insert(rel, None);
+            } else {
+                out.insert(rel, Some(fs::read_to_string(&path).unwrap()));
+            }
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    walk(root, root, &mut out);
+    return out;
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        // CRDTs give new objects random IDs, which operations' hashes depend on.
+        types::seed_ids(Some(seed));
+        let n = rng.random_range(2..=4);
+
+        let replicas = (0..n).map(|i| {
+            let config = storage::Config::new(temp_working_dir(&format!("sim-{}-{}", seed, i)));
+            Replica { manager: FileManager::init(config.clone(), Uuid::from_u128(i as u128 + 1)), config }
+        }).collect();
+
+        return Self { rng, replicas };
+    }
+
+    fn paragraph(&mut self) -> String {
+        let len = self.rng.random_range(1..=4);
+        let words: Vec<&str> = (0..len).map(|_| *WORDS.choose(&mut self.rng).unwrap()).collect();
+
+        return match self.rng.random_bool(0.2) {
+            true => format!("# {}", words.join(" ")),
+            false => words.join(" "),
+        };
+    }
+
+    fn random_path(&mut self) -> PathBuf {
+        let dir = DIRS.choose(&mut self.rng).unwrap();
+        let name = NAMES.choose(&mut self.rng).unwrap();
+        return Path::new(dir).join(name);
+    }
+
+    /// Make a random change to replica `r`'s working directory, and record it.
+    fn edit(&mut self, r: usize) {
+        let root = self.replicas[r].config.working_dir.clone();
+        let tree = snapshot(&root);
+        let files: Vec<PathBuf> = tree.iter().filter(|(_, c)| c.is_some()).map(|(p, _)| p.clone()).collect();
+        let dirs: Vec<PathBuf> = tree.iter().filter(|(_, c)| c.is_none()).map(|(p, _)| p.clone()).collect();
+
+        match self.rng.random_range(0..10) {
+            0..=2 => {
+                let path = self.random_path();
+                if root.join(&path).exists() {return;}
+
+                let contents = (0..self.rng.random_range(1..=3)).map(|_| self.paragraph()).collect::<Vec<_>>().join("\n\n");
+                println!("{}: create {:?}", r, path);
+                fs::create_dir_all(root.join(&path).parent().unwrap()).unwrap();
+                fs::write(root.join(&path), contents).unwrap();
+            },
+            3..=5 => {
+                let Some(path) = files.choose(&mut self.rng).cloned() else {return};
+
+                let contents = fs::read_to_string(root.join(&path)).unwrap();
+                let mut paragraphs: Vec<String> = contents.split("\n\n").filter(|p| !p.is_empty()).map(str::to_owned).collect();
+                let i = self.rng.random_range(0..=paragraphs.len());
+                match self.rng.random_range(0..3) {
+                    0 if i < paragraphs.len() => {paragraphs.remove(i);},
+                    1 if i < paragraphs.len() => paragraphs[i] = self.paragraph(),
+                    _ => {let p = self.paragraph(); paragraphs.insert(i, p);},
+                }
+
+                println!("{}: modify {:?}", r, path);
+                fs::write(root.join(&path), paragraphs.join("\n\n")).unwrap();
+            },
+            6 => {
+                let Some(from) = files.choose(&mut self.rng).cloned() else {return};
+                let to = self.random_path();
+                if root.join(&to).exists() {return;}
+
+                println!("{}: move {:?} to {:?}", r, from, to);
+                fs::create_dir_all(root.join(&to).parent().unwrap()).unwrap();
+                fs::rename(root.join(&from), root.join(&to)).unwrap();
+            },
+            7 => {
+                let Some(path) = files.choose(&mut self.rng).cloned() else {return};
+
+                println!("{}: delete {:?}", r, path);
+                fs::remove_file(root.join(&path)).unwrap();
+            },
+            8 => {
+                let Some(from) = dirs.choose(&mut self.rng).cloned() else {return};
+                let to = Path::new(DIRS.choose(&mut self.rng).unwrap()).join(format!("moved-{}", self.rng.random_range(0..4)));
+                if to.starts_with(&from) || root.join(&to).exists() {return;}
+
+                println!("{}: move dir {:?} to {:?}", r, from, to);
+                fs::create_dir_all(root.join(&to).parent().unwrap()).unwrap();
+                fs::rename(root.join(&from), root.join(&to)).unwrap();
+            },
+            _ => {
+                let Some(path) = dirs.choose(&mut self.rng).cloned() else {return};
+
+                println!("{}: delete dir {:?}", r, path);
+                fs::remove_dir_all(root.join(&path)).unwrap();
+            },
+        }
+
+        self.replicas[r].manager.update().unwrap();
+    }
+
+    /// Copy operations from replica `from` to replica `to`, and apply them in the given order.
+    /// With `one_by_one`, each is applied on its own, so operations arriving before their dependencies are queued.
+    fn deliver(&mut self, from: usize, to: usize, ops: &[types::Hash], one_by_one: bool) {
+        for hash in ops.iter() {
+            let loc = storage::object::Location::Object(*hash);
+            let mut buf = Vec::new(); storage::object::read_bytes(&self.replicas[from].config, &loc, &mut buf).unwrap();
+            storage::object::write(&self.replicas[to].config, &loc, &buf).unwrap();
+        }
+
+        let manager = &mut self.replicas[to].manager;
+        match one_by_one {
+            true => for hash in ops.iter() {manager.apply_ops(&vec![hash]).unwrap()},
+            false => manager.apply_ops(&ops.iter().collect()).unwrap(),
+        }
+    }
+
+    /// Operations replica `from` has which replica `to` hasn't applied.
+    fn missing(&self, from: usize, to: usize) -> Vec<types::Hash> {
+        let have = self.replicas[to].manager.get_history().all_hashes();
+        let mut ops: Vec<types::Hash> = self.replicas[from].manager.get_history().all_hashes().difference(&have).cloned().collect();
+        // Sorted first, so the order only depends on the seed.
+        ops.sort();
+        return ops;
+    }
+
+    /// Deliver a random subset of the operations one replica is missing from another, in a random order.
+    /// Occasionally redelivers operations already applied, which must have no effect.
+    fn random_delivery(&mut self) {
+        let n = self.replicas.len();
+        let from = self.rng.random_range(0..n);
+        let to = (from + self.rng.random_range(1..n)) % n;
+
+        let mut ops = self.missing(from, to);
+        if self.rng.random_bool(0.1) {
+            let theirs = self.replicas[to].manager.get_history().all_hashes();
+            let mut applied: Vec<types::Hash> = self.replicas[from].manager.get_history().all_hashes().intersection(&theirs).cloned().collect();
+            applied.sort();
+            ops.extend(applied.choose(&mut self.rng));
+        }
+        if ops.is_empty() {return;}
+
+        ops.shuffle(&mut self.rng);
+        ops.truncate(self.rng.random_range(1..=ops.len()));
+        let one_by_one = self.rng.random_bool(0.5);
+
+        println!("deliver {} ops from {} to {}{}", ops.len(), from, to, if one_by_one {" one by one"} else {""});
+        self.deliver(from, to, &ops, one_by_one);
+    }
+
+    /// Deliver every operation everywhere, each batch in a random order.
+    fn deliver_all(&mut self) {
+        let n = self.replicas.len();
+
+        for from in 0..n {
+            for to in 0..n {
+                if from == to {continue;}
+
+                let mut ops = self.missing(from, to);
+                ops.shuffle(&mut self.rng);
+                self.deliver(from, to, &ops, false);
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        for _ in 0..STEPS {
+            match self.rng.random_bool(0.5) {
+                true => {let r = self.rng.random_range(0..self.replicas.len()); self.edit(r)},
+                false => self.random_delivery(),
+            }
+        }
+
+        // Operations received by one replica from another are passed on in the next round.
+        for _ in 0..self.replicas.len() {self.deliver_all();}
+
+        // Histories needn't match: operations on a file deleted elsewhere are dropped, rather than applied.
+        for (i, replica) in self.replicas.iter_mut().enumerate() {
+            let pending = PendingOps::read_in(&replica.config).unwrap();
+            let reasons: Vec<_> = pending.ops().iter().map(|op| (types::hash_to_str(&op.hash), &op.reason)).collect();
+            assert!(pending.is_empty(), "Replica {} has operations pending: {:?}", i, reasons);
+
+            // Files are written out in canonical form, whichever replica wrote them.
+            replica.manager.canonize().unwrap();
+        }
+
+        let expected = snapshot(&self.replicas[0].config.working_dir);
+        for (i, replica) in self.replicas.iter().enumerate() {
+            assert_eq!(snapshot(&replica.config.working_dir), expected, "Replica {} has different files.", i);
+        }
+    }
+}
+
+fn run_seed(seed: u64) {
+    println!("Simulating with seed {}", seed);
+
+    let result = std::panic::catch_unwind(|| Simulation::new(seed).run());
+    types::seed_ids(None);
+
+    if result.is_err() {
+        panic!("Simulation failed with seed {}. Replay with CRFS_SIM_SEED={}", seed, seed);
+    }
+}
+
+#[test]
+fn simulation_test() {
+    match std::env::var("CRFS_SIM_SEED") {
+        Ok(seed) => run_seed(seed.parse().expect("CRFS_SIM_SEED must be a number.")),
+        Err(_) => SEEDS.for_each(run_seed),
+    }
+}
+
+#[test]
+fn simulation_deterministic_test() {
+    // The same seed makes the same edits, giving the same operations.
+    let histories: Vec<_> = (0..2).map(|_| {
+        let mut sim = Simulation::new(1);
+        for _ in 0..STEPS {sim.edit(0);}
+        types::seed_ids(None);
+
+        sim.replicas[0].manager.get_history().all_hashes()
+    }).collect();
+
+    assert!(!histories[0].is_empty());
+    assert_eq!(histories[0], histories[1]);
+}
@@ -11,6 +11,15 @@ use uuid::Uuid;
 
 pub const TESTFILEDIR: &str = ".testfiles";
 
+/// Create a new, empty working directory for a test, under the system temp dir.
+pub fn temp_working_dir(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push("crfs-tests"); path.push(format!("{}-{}", name, Uuid::now_v7()));
+
+    std::fs::create_dir_all(&path).unwrap();
+    return path;
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct MetaTest {
     a: u8, b: i16, c: String, d: Uuid, e: Hash,
@@ -19,9 +28,7 @@ struct MetaTest {
 #[test]
 pub fn test_hash_to_path() {
     // Setup
-    let config = storage::Config {
-        working_dir: PathBuf::from(TESTFILEDIR),
-    };
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
 
     let hash = Hash::from(hex!("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"));
     // let location = storage::ObjectLocation::ObjectStore(config.clone(), Some(GenericArray::from(hash)));
@@ -41,9 +48,7 @@ pub fn test_hash_to_path() {
 #[test]
 pub fn test_read_write_ondisk() {
     // Setup
-    let config = storage::Config {
-        working_dir: PathBuf::from(TESTFILEDIR),
-    };
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
     // ensure_dir(&config, &PathBuf::from(".")).unwrap();
 
     // let mut path = PathBuf::new();
@@ -65,9 +70,7 @@ pub fn test_read_write_ondisk() {
 #[test]
 pub fn test_read_ondisk_doesntexist() {
     // Setup
-    let config = storage::Config {
-        working_dir: PathBuf::from(TESTFILEDIR),
-    };
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
     // ensure_dir(&config, &PathBuf::from(".")).unwrap();
 
     // let mut path = config.working_dir.clone(); path.push("notexist.txt");
@@ -92,9 +95,7 @@ pub fn test_read_ondisk_doesntexist() {
 #[test]
 pub fn test_read_write_object() {
     // Setup
-    let config = storage::Config {
-        working_dir: PathBuf::from(TESTFILEDIR),
-    };
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
 
     // Write Data
     let write_buf = String::from("test data!\n");
@@ -121,9 +122,7 @@ pub fn test_read_write_object() {
 #[test]
 pub fn test_read_write_meta() {
     // Setup
-    let config = storage::Config {
-        working_dir: PathBuf::from(TESTFILEDIR),
-    };
+    let config = storage::Config::new(PathBuf::from(TESTFILEDIR));
 
     let name = String::from("meta_test");
 
@@ -145,3 +144,93 @@ pub fn test_read_write_meta() {
     // Check
     assert_eq!(data, result);
 }
+
+#[test]
+pub fn test_write_private() {
+    let path = temp_working_dir("write_private").join("config.json");
+    std::fs::write(&path, "{}").unwrap();
+
+    storage::meta::write_private(&path, &vec![1, 2, 3]).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "[1,2,3]");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    // Nothing is left behind.
+    assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+}
+
+#[test]
+pub fn test_path_policy_opts() {
+    let policy = storage::PathPolicy {nfc: false, case_insensitive: true};
+    assert_eq!(storage::PathPolicy::from_opts(&policy.to_opts()), policy);
+
+    // An FS registered without options uses the default policy.
+    assert_eq!(storage::PathPolicy::from_opts(&[]), storage::PathPolicy::default());
+
+    assert_eq!(policy.key(&PathBuf::from("Notes.MD")), PathBuf::from("notes.md"));
+
+    // The collision policy is stored alongside it.
+    let opts = [policy.to_opts(), storage::CollisionPolicy::Merge.to_opts()].concat();
+    assert_eq!(storage::PathPolicy::from_opts(&opts), policy);
+    assert_eq!(storage::CollisionPolicy::from_opts(&opts), storage::CollisionPolicy::Merge);
+    assert_eq!(storage::CollisionPolicy::from_opts(&policy.to_opts()), storage::CollisionPolicy::Rename);
+}
+
+#[test]
+pub fn test_seal_open() {
+    use storage::crypt::{self, Key};
+
+    let key = Key::from_passphrase("passphrase", Uuid::from_u128(1));
+    let sealed = crypt::seal(&key, b"plaintext");
+
+    // Sealing is deterministic, so replicas agree on the address of each object.
+    assert_eq!(sealed, crypt::seal(&key, b"plaintext"));
+    assert_ne!(sealed, crypt::seal(&key, b"plaintexts"));
+    assert!(!sealed.contains("plaintext"));
+    assert_eq!(crypt::open(&key, sealed.as_bytes()).unwrap(), b"plaintext");
+
+    // Keys depend on the FS, as well as the passphrase.
+    assert_ne!(key, Key::from_passphrase("passphrase", Uuid::from_u128(2)));
+    assert!(crypt::open(&Key::from_passphrase("passphrase", Uuid::from_u128(2)), sealed.as_bytes()).is_err());
+
+    // Tampered and unencrypted objects are rejected.
+    let mut tampered = sealed.clone().into_bytes(); let last = tampered.len() - 2; tampered[last] ^= 1;
+    assert!(crypt::open(&key, &tampered).is_err());
+    assert!(crypt::open(&key, b"plaintext").is_err());
+
+    // Keys round-trip as hex, and are checked against the FS's options without revealing them.
+    assert_eq!(Key::from_hex(&key.to_hex()).unwrap(), key);
+    assert!(Key::from_hex("abc").is_err());
+    let opts = vec!["no-nfc".to_owned(), key.to_opt()];
+    assert!(Key::required(&opts) && key.matches(&opts));
+    assert!(!Key::from_passphrase("other", Uuid::from_u128(1)).matches(&opts));
+    assert!(!format!("{:?}", key).contains(&key.to_hex()));
+}
+
+#[test]
+pub fn test_sign_verify() {
+    use storage::sign::{self, Keyring, Signer, SigningKey};
+
+    let signer = Signer {replica: Uuid::from_u128(1), key: SigningKey::generate()};
+    let keyring = Keyring::new([(signer.replica, signer.key.public_key())]);
+    let signed = sign::sign(&signer, "{\"op\":1}");
+
+    assert_eq!(sign::verify(&keyring, &signed), Ok(signer.replica));
+    assert_eq!(sign::strip(signed.clone()), "{\"op\":1}");
+    assert_eq!(sign::strip("{\"op\":1}".to_owned()), "{\"op\":1}");
+
+    // Unsigned, tampered and unknown operations are rejected.
+    assert!(sign::verify(&keyring, "{\"op\":1}").is_err());
+    assert!(sign::verify(&keyring, &signed.replace("{\\\"op\\\":1}", "{\\\"op\\\":2}")).is_err());
+    assert!(sign::verify(&Keyring::default(), &signed).is_err());
+
+    // Keys round-trip as hex, but private keys are never printed.
+    let public = signer.key.public_key();
+    assert_eq!(sign::PublicKey::from_hex(&public.to_hex()).unwrap(), public);
+    let private: String = serde_json::from_str(&serde_json::to_string(&signer.key).unwrap()).unwrap();
+    assert!(!format!("{:?}", signer.key).contains(&private));
+}
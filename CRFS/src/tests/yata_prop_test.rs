@@ -0,0 +1,229 @@
+// Property tests for YATA and documents built on it. Replicas edit a shared array concurrently, then exchange their
+// operations in arbitrary interleavings, which must always converge, and applying operations twice must change nothing.
+// Failing cases are shrunk and saved under `proptest-regressions/`, and replayed first on later runs.
+
+use crate::conflict_res::ast_doc::{crdt::DocOp, md, yata};
+use crate::conflict_res::file_tree::{DriverID, TreeOp};
+use crate::conflict_res::CmRDT::{Object, Operation};
+
+use proptest::prelude::*;
+use uuid::Uuid;
+
+type Array = yata::Array<u32, u8>;
+
+/// A local edit, with indices taken modulo the array's length when applied.
+#[derive(Clone, Debug)]
+enum Edit {
+    Insert(usize),
+    Delete(usize),
+}
+
+fn edit() -> impl Strategy<Value = Edit> {
+    prop_oneof![
+        3 => any::<usize>().prop_map(Edit::Insert),
+        1 => any::<usize>().prop_map(Edit::Delete),
+    ]
+}
+
+/// Make `edits` to a replica's copy of `arr` as `creator`, returning the operations made.
+/// Content is unique to each edit, so inserted items can be told apart.
+fn make_edits(arr: &mut Array, edits: &[Edit], creator: u8) -> Vec<yata::Op<u32, u8>> {
+    let mut ops = Vec::new();
+
+    for (i, edit) in edits.iter().enumerate() {
+        let content = (creator as u32 + 1) * 1000 + i as u32;
+        match edit {
+            Edit::Insert(ind) => {
+                let (id, ins) = arr.get_insertion(ind % (arr.len() + 1), content, creator);
+                assert_eq!(arr.insert(ins, Some(id)), Some(id));
+                ops.push(yata::Op::Insertion(id, ins));
+            },
+            Edit::Delete(ind) => {
+                let undel = arr.in_order_undel();
+                if undel.is_empty() {continue;}
+
+                let id = undel[ind % undel.len()];
+                arr.delete(id);
+                ops.push(yata::Op::Deletion(id));
+            },
+        }
+    }
+
+    return ops;
+}
+
+/// Interleave each replica's operations, keeping each replica's own in order, choosing the next replica with `picks`.
+fn interleave<T: Clone>(mut queues: Vec<Vec<T>>, picks: &[usize]) -> Vec<T> {
+    for q in queues.iter_mut() {q.reverse();}
+
+    let mut result = Vec::new();
+    let mut picks = picks.iter().cycle();
+    while queues.iter().any(|q| !q.is_empty()) {
+        let nonempty: Vec<usize> = (0..queues.len()).filter(|i| !queues[*i].is_empty()).collect();
+        let q = nonempty[picks.next().unwrap_or(&0) % nonempty.len()];
+        result.push(queues[q].pop().unwrap());
+    }
+
+    return result;
+}
+
+fn state(arr: &Array) -> Vec<(yata::ID, u32, bool)> {
+    arr.in_order().into_iter().map(|id| (id, arr[id].content, arr[id].deleted)).collect()
+}
+
+/// The base array and each replica's edits to it.
+fn concurrent_edits() -> impl Strategy<Value = (Vec<u32>, Vec<Vec<Edit>>, Vec<usize>)> {
+    (
+        prop::collection::vec(0..100u32, 0..6),
+        prop::collection::vec(prop::collection::vec(edit(), 0..8), 2..4),
+        prop::collection::vec(any::<usize>(), 1..16),
+    )
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn yata_commutative_prop((base, edits, picks) in concurrent_edits()) {
+        let base = Array::from((base.into_iter(), 0));
+
+        let mut replicas: Vec<Array> = edits.iter().map(|_| base.clone()).collect();
+        let ops: Vec<_> = replicas.iter_mut().zip(edits.iter()).enumerate()
+            .map(|(r, (arr, edits))| make_edits(arr, edits, r as u8 + 1))
+            .collect();
+
+        // Every replica receives the others' operations interleaved differently.
+        for (r, arr) in replicas.iter_mut().enumerate() {
+            let others = ops.iter().enumerate().filter(|(o, _)| *o != r).map(|(_, ops)| ops.clone()).collect();
+            let picks: Vec<usize> = picks.iter().map(|p| p.wrapping_add(r)).collect();
+
+            for op in interleave(others, &picks) {arr.apply(op);}
+        }
+
+        // Replaying every operation in one interleaving on the base gives the same result.
+        let mut replay = base.clone();
+        for op in interleave(ops.clone(), &picks) {replay.apply(op);}
+
+        for arr in replicas.iter() {
+            prop_assert_eq!(state(arr), state(&replay));
+        }
+    }
+
+    #[test]
+    fn yata_idempotent_prop((base, edits, picks) in concurrent_edits()) {
+        let base = Array::from((base.into_iter(), 0));
+        let ops: Vec<_> = edits.iter().enumerate().map(|(r, edits)| make_edits(&mut base.clone(), edits, r as u8 + 1)).collect();
+        let ops = interleave(ops, &picks);
+
+        let mut arr = base.clone();
+        for op in ops.iter() {arr.apply(*op);}
+        let once = state(&arr);
+
+        for op in ops.iter() {arr.apply(*op);}
+        prop_assert_eq!(state(&arr), once.clone());
+
+        // Each operation delivered twice in a row.
+        let mut twice = base.clone();
+        for op in ops.iter() {twice.apply(*op); twice.apply(*op);}
+        prop_assert_eq!(state(&twice), once);
+    }
+
+    #[test]
+    fn yata_insert_no_panic_prop(
+        base in prop::collection::vec(0..100u32, 0..6),
+        refs in prop::collection::vec((0..8usize, 0..8usize, 0..8usize, any::<u8>(), prop::option::of(0..8usize)), 0..16),
+    ) {
+        let mut arr = Array::from((base.into_iter(), 0));
+
+        // Indices pick an existing item, either end, or an ID not in the array.
+        let to_ref = |arr: &Array, i: usize| {
+            let in_order = arr.in_order();
+            match i {
+                0 => yata::Ref::Left,
+                1 => yata::Ref::Right,
+                i if i - 2 < in_order.len() => yata::Ref::Item(in_order[i - 2]),
+                i => yata::Ref::Item(i as yata::ID),
+            }
+        };
+
+        for (i, (origin, left, right, creator, id)) in refs.into_iter().enumerate() {
+            let ins = yata::Insertion {
+                origin: to_ref(&arr, origin), left: to_ref(&arr, left), right: to_ref(&arr, right),
+                content: i as u32, creator, deleted: false,
+            };
+            let id = id.map(|i| match to_ref(&arr, i + 2) {yata::Ref::Item(id) => id, _ => unreachable!()});
+
+            let len = arr.len();
+            match arr.insert(ins, id) {
+                Some(new) if Some(new) == id && len == arr.len() => {},
+                Some(_) => prop_assert_eq!(arr.len(), len + 1),
+                None => prop_assert_eq!(arr.len(), len),
+            }
+            arr.verify();
+        }
+    }
+
+    #[test]
+    fn deserialize_op_no_panic_prop(json in ".*") {
+        let _ = TreeOp::deserialize_from_str(json.clone());
+        let _ = DocOp::<md::MDTag, md::MDLeaf>::deserialize_from_str(json);
+    }
+
+    #[test]
+    fn md_parse_no_panic_prop(raw_md in "[ -~\n\t]{0,64}") {
+        if let Ok(int) = md::MDInterface::parse(&raw_md) {
+            let _ = int.get_canon();
+        }
+    }
+}
+
+const WORDS: [&str; 12] = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa", "lambda", "mu"];
+
+/// A document of paragraphs, each a distinct word, as diffing documents assumes content isn't repeated.
+fn document() -> impl Strategy<Value = Vec<&'static str>> {
+    prop::sample::subsequence(WORDS.to_vec(), 0..4).prop_shuffle()
+}
+
+fn to_md(paragraphs: &[&str]) -> md::MDInterface {
+    md::MDInterface::parse(&paragraphs.join("\n\n")).unwrap()
+}
+
+/// Apply operations making `obj` match `int`, returning them.
+fn update(obj: &mut md::MDObject, int: &md::MDInterface, creator: Uuid) -> Vec<DocOp<md::MDTag, md::MDLeaf>> {
+    let mut ops = Vec::new();
+    while let Some(op) = obj.prep(int, creator) {
+        obj.apply_op(&op);
+        ops.push(op);
+    }
+    return ops;
+}
+
+proptest! {
+    // Documents are slow to diff, so fewer cases are run.
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn doc_converges_prop(base in document(), edit1 in document(), edit2 in document()) {
+        // Each replica's edits use different words, so they don't repeat each other's content.
+        let edit2: Vec<&str> = edit2.into_iter().filter(|w| base.contains(w) || !edit1.contains(w)).collect();
+
+        let (id1, id2) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut obj1 = md::MDObject::init(DriverID::Driver(0));
+        let mut obj2 = md::MDObject::init(DriverID::Driver(1));
+
+        let init = update(&mut obj1, &to_md(&base), id1);
+        for op in init.iter() {obj2.apply_op(op);}
+
+        let ops1 = update(&mut obj1, &to_md(&edit1), id1);
+        let ops2 = update(&mut obj2, &to_md(&edit2), id2);
+
+        for op in ops2.iter() {obj1.apply_op(op);}
+        for op in ops1.iter() {obj2.apply_op(op);}
+        let canon = obj1.query().get_canon();
+        prop_assert_eq!(&canon, &obj2.query().get_canon());
+
+        // Operations already applied change nothing.
+        for op in init.iter().chain(ops1.iter()).chain(ops2.iter()) {obj1.apply_op(op);}
+        prop_assert_eq!(&canon, &obj1.query().get_canon());
+    }
+}
@@ -2,17 +2,62 @@
 
 use generic_array::GenericArray;
 use generic_array::typenum::U32;
+use rand::Rng;
 use sha2::{Sha256, Digest};
 
 pub type Sha256Hash = GenericArray<u8, U32>;
 pub type Hash = Sha256Hash;
 
+/// Write bytes as lowercase hex digits, as keys, MACs and signatures are sent and stored.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse exactly `N` bytes written by `to_hex`.
+pub fn from_hex<const N: usize>(s: &str) -> Result<[u8; N], String> {
+    let s = s.trim();
+    if s.len() != 2*N || !s.is_ascii() {return Err(format!("Expected {} hex digits.", 2*N));}
+
+    let mut bytes = [0u8; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2*i..2*i + 2], 16).map_err(|e| e.to_string())?;
+    }
+
+    return Ok(bytes);
+}
+
 pub fn hash_to_str(h: &Hash) -> String {
     return format!("{:x}", h);
 }
 
+/// Parse a hash written by `hash_to_str`.
+pub fn str_to_hash(s: &str) -> Result<Hash, String> {
+    from_hex::<32>(s).map(Hash::from)
+}
+
 pub fn calculate_hash(str: &str) -> Hash {
     let mut hasher = Sha256::new();
     hasher.update(str.as_bytes());
     return hasher.finalize();
 }
+
+/// A random value, for the IDs CRDTs give new objects.
+/// Tests can seed the values drawn on a thread with `seed_ids`, so simulations are reproducible.
+pub fn random_id<T>() -> T where rand::distr::StandardUniform: rand::distr::Distribution<T> {
+    #[cfg(test)]
+    if let Some(id) = SEEDED_IDS.with_borrow_mut(|rng| rng.as_mut().map(|rng| rng.random())) {return id;}
+
+    return rand::rng().random();
+}
+
+#[cfg(test)]
+thread_local! {
+    static SEEDED_IDS: std::cell::RefCell<Option<rand::rngs::StdRng>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Draw IDs on this thread from a generator seeded with `seed`, or from the system's generator again if `None`.
+#[cfg(test)]
+pub fn seed_ids(seed: Option<u64>) {
+    use rand::SeedableRng;
+    SEEDED_IDS.set(seed.map(rand::rngs::StdRng::seed_from_u64));
+}
@@ -1,8 +1,11 @@
 from django.contrib import admin
 
-from .models import FileSystem, Operation, Replica, User
+from .models import (FileSystem, Membership, Operation, Replica,
+                     RevokedReplica, User)
 
 admin.site.register(FileSystem)
 admin.site.register(Replica)
 admin.site.register(User)
 admin.site.register(Operation)
+admin.site.register(Membership)
+admin.site.register(RevokedReplica)
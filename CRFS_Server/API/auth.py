@@ -0,0 +1,133 @@
+"""Authentication of API requests.
+
+Users are issued a secret when they register, which is used to enrol replicas. Each replica is issued its own token,
+which only grants access to its FileSystem. Both are sent as `Authorization: Bearer <owner uuid>.<secret>`, and only
+their hashes are stored.
+"""
+import hashlib
+import hmac
+import secrets
+import uuid
+from dataclasses import dataclass
+from typing import Optional
+
+from django.http import HttpRequest
+from django.utils import timezone
+
+from .models import READ_ONLY, FileSystem, Replica, User
+
+
+@dataclass
+class Principal:
+    """Who a request was authenticated as."""
+
+    user: User
+    # The FileSystem a replica token is limited to, or None for the user's own secret.
+    filesystem: Optional[FileSystem]
+
+
+# The credentials each message type requires:
+#   None: no credentials.
+#   "user": the user's secret.
+#   "fs": the user's secret, or the token of a replica of the FileSystem.
+SCOPES: dict[str, Optional[str]] = {
+    "ping": None,
+    "check_user": None,
+    "register_user": None,  # Unless the user already exists, see `authorise`.
+    "register_fs": "user",
+    "enrol": "user",
+    "remove_replica": "user",
+    "invite": "user",
+    "remove_member": "user",
+}
+DEFAULT_SCOPE = "fs"
+
+
+def new_secret(owner: uuid.UUID) -> tuple[str, str]:
+    """Generate a secret for `owner`, returning it and its hash."""
+    secret = f"{owner}.{secrets.token_urlsafe(32)}"
+    return secret, hash_secret(secret)
+
+
+def hash_secret(secret: str) -> str:
+    return hashlib.sha256(secret.encode()).hexdigest()
+
+
+def authenticate(request: HttpRequest) -> Optional[Principal]:
+    """Find who sent a request, from its bearer token."""
+    header = request.headers.get("Authorization", "")
+    if not header.startswith("Bearer "):
+        return None
+
+    secret = header.removeprefix("Bearer ").strip()
+    try:
+        owner = uuid.UUID(secret.partition(".")[0])
+    except ValueError:
+        return None
+
+    digest = hash_secret(secret)
+
+    replica = Replica.objects.filter(uuid=owner).first()
+    if replica is not None and replica.token_hash and hmac.compare_digest(replica.token_hash, digest):
+        # Replicas authenticate on every sync, so this tracks when each device last synced.
+        replica.last_seen = timezone.now()
+        replica.save(update_fields=["last_seen"])
+        return Principal(replica.user or replica.filesystem.user, replica.filesystem)
+
+    user = User.objects.filter(uuid=owner).first()
+    if user is not None and user.secret_hash and hmac.compare_digest(user.secret_hash, digest):
+        return Principal(user, None)
+
+    return None
+
+
+def authorise(message_type: str, payload: dict, principal: Optional[Principal]) -> Optional[tuple[int, str]]:
+    """Check a message may be handled. Returns an error code and message if not."""
+    scope = SCOPES.get(message_type, DEFAULT_SCOPE)
+
+    try:
+        user_uuid = uuid.UUID(payload["user_uuid"]) if "user_uuid" in payload else None
+        fs_uuid = uuid.UUID(payload["fs_uuid"]) if "fs_uuid" in payload else None
+    except (ValueError, TypeError):
+        return 8, "Malformed UUID."
+
+    # Only a user may re-register themselves. Users registered before authentication have no secret, so can't, until
+    # an administrator issues them one with `manage.py issue_secret`.
+    if message_type == "register_user" and User.objects.filter(uuid=user_uuid).exists():
+        scope = "user"
+
+    if scope is None:
+        return None
+
+    if not User.objects.filter(uuid=user_uuid).exists():
+        return 3, "User doesn't exist."
+
+    if principal is None:
+        return 9, "Missing or invalid credentials."
+
+    if principal.user.uuid != user_uuid:
+        return 9, "Credentials belong to another user."
+
+    if scope == "user" and principal.filesystem is not None:
+        return 9, "Requires the user's secret, not a replica token."
+
+    if principal.filesystem is not None and fs_uuid is not None and principal.filesystem.uuid != fs_uuid:
+        return 9, "Replica isn't enrolled in this FileSystem."
+
+    return None
+
+
+def may_access_fs(principal: Optional[Principal], fs_uuid: uuid.UUID, write: bool = False) -> bool:
+    """Whether a principal may read, and with `write` also write, a FileSystem's operations."""
+    if principal is None:
+        return False
+
+    if principal.filesystem is not None and principal.filesystem.uuid != fs_uuid:
+        return False
+
+    fs = FileSystem.objects.filter(uuid=fs_uuid).first()
+    if fs is None:
+        return False
+
+    role = fs.role_of(principal.user.uuid)
+    return role is not None and not (write and role == READ_ONLY)
@@ -1,15 +1,26 @@
 """API Message handlers."""
 
+import hashlib
 import uuid
+from bisect import bisect_left
 from collections.abc import Callable
+from pathlib import Path
+from typing import Optional
 
+from django.conf import settings
 from django.core.exceptions import ObjectDoesNotExist
 from django.utils import timezone
 
-from .models import FileSystem, Operation, User
+from .auth import new_secret
+from .models import (OWNER, READ_ONLY, ROLES, FileSystem, Membership,
+                     Operation, Replica, RevokedReplica, User)
 
 VERSION = "0.0.1"
 
+# Must match the client's `networking::reconcile` constants.
+RECONCILE_ITEM_THRESHOLD = 16
+RECONCILE_BRANCHING = 16
+
 
 class JSONMessageHandler:
     """Handler class fr all message-based requests.
@@ -62,6 +73,19 @@ class JSONMessageHandler:
         }
 
 
+def membership_error(fs: FileSystem, user_uuid: uuid.UUID, write: bool = False) -> Optional[tuple[int, dict]]:
+    """Check a user may access a FileSystem, and with `write`, change it. Returns the reply refusing them if not."""
+    role = fs.role_of(user_uuid)
+
+    if role is None:
+        return 400, {"code": 9, "err_msg": "FileSystem with given UUID isn't shared with this user."}
+
+    if write and role == READ_ONLY:
+        return 400, {"code": 9, "err_msg": "User has read-only access to this FileSystem."}
+
+    return None
+
+
 def ping_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
     """Handle `ping` messages."""
     return 200, {}
@@ -91,13 +115,77 @@ def register_user_handler(message_type: str, payload: dict, http_method: str) ->
             last_seen=timezone.now()
         )
 
+    # Only new users are issued a secret. Anyone re-registering has already proven they hold theirs.
+    secret = None
+    if not user.secret_hash:
+        secret, user.secret_hash = new_secret(user.uuid)
+
     user.save()
 
-    return 200, {
+    reply = {
         "code": 0,
         "user_uuid": str(user.uuid),
         "display_name": user.display_name,
     }
+    if secret is not None:
+        reply["secret"] = secret
+
+    return 200, reply
+
+
+def enrol_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `enrol` messages, issuing a replica with a token for its FileSystem."""
+    for field in ("user_uuid", "fs_uuid", "replica_uuid"):
+        if field not in payload.keys():
+            return (400, {"code": 8, "err_msg": f"Missing field \"{field}\" required by type \"{message_type}\"."})
+
+    display_name = payload.get("display_name")
+    public_key = payload.get("public_key")
+    if public_key is not None and (len(public_key) != 64 or any(c not in "0123456789abcdef" for c in public_key)):
+        return (400, {"code": 8, "err_msg": "Public keys must be 64 lowercase hex digits."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(payload["fs_uuid"]))
+
+        refused = membership_error(fs, uuid.UUID(payload["user_uuid"]))
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    replica_uuid = uuid.UUID(payload["replica_uuid"])
+    if RevokedReplica.objects.filter(pk=replica_uuid).exists():
+        return 400, {"code": 9, "err_msg": "Replica with given UUID has been removed, and can't enrol again."}
+
+    try:
+        replica = Replica.objects.get(pk=replica_uuid)
+
+        if replica.filesystem != fs:
+            return 400, {"code": 2, "err_msg": "Replica with given UUID belongs to another FileSystem."}
+
+        # Only the user who enrolled a replica may enrol it again, and never with another key, which would let them
+        # sign operations as it.
+        enrolled_by = replica.user or replica.filesystem.user
+        if enrolled_by.uuid != uuid.UUID(payload["user_uuid"]):
+            return 400, {"code": 9, "err_msg": "Replica with given UUID was enrolled by another user."}
+        if replica.public_key and public_key is not None and public_key != replica.public_key:
+            return 400, {"code": 9, "err_msg": "Replica with given UUID is already enrolled with another key."}
+    except ObjectDoesNotExist:
+        replica = Replica(uuid=replica_uuid, filesystem=fs, user=User.objects.get(pk=uuid.UUID(payload["user_uuid"])))
+
+    if display_name is not None:
+        replica.display_name = display_name
+    if public_key is not None:
+        replica.public_key = public_key
+
+    # Re-enrolling replaces the replica's token, so a replica which lost its token can log in again.
+    token, replica.token_hash = new_secret(replica.uuid)
+    replica.last_seen = timezone.now()
+    replica.save()
+
+    return (200, {"code": 0, "token": token})
 
 
 def check_user_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
@@ -198,11 +286,9 @@ def check_fs_handler(message_type: str, payload: dict, http_method: str) -> tupl
     try:
         fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
 
-        if fs.user.uuid != uuid.UUID(user_uuid):
-            return 400, {
-                "code": 9,
-                "err_msg": "FileSystem with given UUID is owned by another user."
-            }
+        refused = membership_error(fs, uuid.UUID(user_uuid))
+        if refused is not None:
+            return refused
 
         return 200, {
             "code": 0,
@@ -210,6 +296,7 @@ def check_fs_handler(message_type: str, payload: dict, http_method: str) -> tupl
             "fs_uuid": str(fs.uuid),
             "display_name": fs.display_name,
             "fs_opts": fs.opts,
+            "role": fs.role_of(uuid.UUID(user_uuid)),
         }
     except ObjectDoesNotExist:
         return 400, {
@@ -217,6 +304,160 @@ def check_fs_handler(message_type: str, payload: dict, http_method: str) -> tupl
         }
 
 
+def list_replicas_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `list_replicas` messages."""
+    for field in ("user_uuid", "fs_uuid"):
+        if field not in payload.keys():
+            return (400, {"code": 8, "err_msg": f"Missing field \"{field}\" required by type \"{message_type}\"."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(payload["fs_uuid"]))
+
+        refused = membership_error(fs, uuid.UUID(payload["user_uuid"]))
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    replicas = [
+        {
+            "replica_uuid": str(replica.uuid),
+            "display_name": replica.display_name,
+            "last_seen": replica.last_seen.isoformat(),
+            "public_key": replica.public_key or None,
+        }
+        for replica in Replica.objects.filter(filesystem=fs).order_by("-last_seen")
+    ]
+
+    return (200, {"code": 0, "replicas": replicas})
+
+
+def remove_replica_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `remove_replica` messages. Removing a replica revokes its token, and it may never enrol again.
+
+    Owners may remove any replica of the FileSystem, and other users only the replicas they enrolled.
+    """
+    for field in ("user_uuid", "fs_uuid", "replica_uuid"):
+        if field not in payload.keys():
+            return (400, {"code": 8, "err_msg": f"Missing field \"{field}\" required by type \"{message_type}\"."})
+
+    user_uuid = uuid.UUID(payload["user_uuid"])
+    try:
+        replica = Replica.objects.get(pk=uuid.UUID(payload["replica_uuid"]), filesystem__uuid=uuid.UUID(payload["fs_uuid"]))
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 6, "err_msg": "Replica isn't enrolled in this FileSystem."
+        }
+
+    enrolled_by = replica.user or replica.filesystem.user
+    if replica.filesystem.role_of(user_uuid) != OWNER and enrolled_by.uuid != user_uuid:
+        return 400, {
+            "code": 9,
+            "err_msg": "Only owners may remove other users' replicas."
+        }
+
+    RevokedReplica.objects.get_or_create(uuid=replica.uuid)
+    replica.delete()
+
+    return (200, {"code": 0})
+
+
+def invite_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `invite` messages, sharing a FileSystem with another user. Inviting a member again changes their role."""
+    for field in ("user_uuid", "fs_uuid", "invitee_uuid", "role"):
+        if field not in payload.keys():
+            return (400, {"code": 8, "err_msg": f"Missing field \"{field}\" required by type \"{message_type}\"."})
+
+    role = payload["role"]
+    if role not in dict(ROLES):
+        return (400, {"code": 8, "err_msg": f"Unrecognised role <{role}>."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(payload["fs_uuid"]))
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    if fs.role_of(uuid.UUID(payload["user_uuid"])) != OWNER:
+        return 400, {"code": 9, "err_msg": "Only owners may share a FileSystem."}
+
+    try:
+        invitee = User.objects.get(pk=uuid.UUID(payload["invitee_uuid"]))
+    except ObjectDoesNotExist:
+        return 400, {"code": 3, "err_msg": "Invited user doesn't exist."}
+
+    if invitee == fs.user:
+        return (400, {"code": 8, "err_msg": "The FileSystem's creator is always an owner."})
+
+    Membership.objects.update_or_create(user=invitee, filesystem=fs, defaults={"role": role})
+
+    return (200, {"code": 0})
+
+
+def list_members_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `list_members` messages, listing the users a FileSystem is shared with, creator first."""
+    for field in ("user_uuid", "fs_uuid"):
+        if field not in payload.keys():
+            return (400, {"code": 8, "err_msg": f"Missing field \"{field}\" required by type \"{message_type}\"."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(payload["fs_uuid"]))
+
+        refused = membership_error(fs, uuid.UUID(payload["user_uuid"]))
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    members = [{"user_uuid": str(fs.user.uuid), "display_name": fs.user.display_name, "role": OWNER}]
+    members.extend(
+        {"user_uuid": str(m.user.uuid), "display_name": m.user.display_name, "role": m.role}
+        for m in Membership.objects.filter(filesystem=fs).order_by("user__display_name")
+    )
+
+    return (200, {"code": 0, "members": members})
+
+
+def remove_member_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `remove_member` messages, unsharing a FileSystem with a user and removing and revoking their replicas of it.
+
+    Owners may remove any member, and other users only themselves.
+    """
+    for field in ("user_uuid", "fs_uuid", "member_uuid"):
+        if field not in payload.keys():
+            return (400, {"code": 8, "err_msg": f"Missing field \"{field}\" required by type \"{message_type}\"."})
+
+    user_uuid, member_uuid = uuid.UUID(payload["user_uuid"]), uuid.UUID(payload["member_uuid"])
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(payload["fs_uuid"]))
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    if fs.role_of(user_uuid) != OWNER and user_uuid != member_uuid:
+        return 400, {"code": 9, "err_msg": "Only owners may remove other members."}
+
+    if fs.user.uuid == member_uuid:
+        return (400, {"code": 8, "err_msg": "The FileSystem's creator can't be removed."})
+
+    membership = Membership.objects.filter(filesystem=fs, user__uuid=member_uuid).first()
+    if membership is None:
+        return (400, {"code": 6, "err_msg": "User isn't a member of this FileSystem."})
+
+    replicas = Replica.objects.filter(filesystem=fs, user=membership.user)
+    RevokedReplica.objects.bulk_create([RevokedReplica(uuid=r.uuid) for r in replicas], ignore_conflicts=True)
+    replicas.delete()
+    membership.delete()
+
+    return (200, {"code": 0})
+
+
 def fetch_state_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
     """Handle `fetch_state` messages."""
     if "user_uuid" in payload.keys():
@@ -232,11 +473,9 @@ def fetch_state_handler(message_type: str, payload: dict, http_method: str) -> t
     try:
         fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
 
-        if fs.user.uuid != uuid.UUID(user_uuid):
-            return 400, {
-                "code": 9,
-                "err_msg": "FileSystem with given UUID is owned by another user."
-            }
+        refused = membership_error(fs, uuid.UUID(user_uuid))
+        if refused is not None:
+            return refused
     except ObjectDoesNotExist:
         return 400, {
             "code": 4, "err_msg": "FileSystem doesn't exist."
@@ -270,26 +509,261 @@ def push_state_handler(message_type: str, payload: dict, http_method: str) -> tu
     try:
         fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
 
-        if fs.user.uuid != uuid.UUID(user_uuid):
-            return 400, {
-                "code": 9,
-                "err_msg": "FileSystem with given UUID is owned by another user."
-            }
+        refused = membership_error(fs, uuid.UUID(user_uuid), write=True)
+        if refused is not None:
+            return refused
     except ObjectDoesNotExist:
         return 400, {
             "code": 4, "err_msg": "FileSystem doesn't exist."
         }
 
     for op in ops:
+        # Older clients send bare hashes, without parents.
+        if isinstance(op, dict):
+            op_hash = list_to_hash(op["hash"])
+            parents = [list_to_hash(p) for p in op.get("parents", [])]
+        else:
+            op_hash = list_to_hash(op)
+            parents = []
+
+        if Operation.objects.filter(filesystem=fs, hash=op_hash).exists():
+            continue
+
+        Operation.objects.filter(filesystem=fs, hash__in=parents).update(head=False)
+
+        # Operations may arrive after their children, in which case this is not a head.
+        is_head = not Operation.objects.filter(filesystem=fs, parents__contains=op_hash).exists()
+
         new_op = Operation(
             filesystem=fs,
-            hash=list_to_hash(op),
+            hash=op_hash,
+            parents=" ".join(parents),
+            head=is_head,
         )
         new_op.save()
 
     return (200, {"code": 0})
 
 
+def fetch_heads_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `fetch_heads` messages."""
+    if "user_uuid" in payload.keys():
+        user_uuid = payload["user_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"user_uuid\" required by type \"{message_type}\"."})
+
+    if "fs_uuid" in payload.keys():
+        fs_uuid = payload["fs_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"fs_uuid\" required by type \"{message_type}\"."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
+
+        refused = membership_error(fs, uuid.UUID(user_uuid))
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    heads = list(map(
+        lambda h: hash_to_list(h.hash),
+        Operation.objects.filter(filesystem=fs, head=True)
+    ))
+
+    return (200, {"code": 0, "heads": heads})
+
+
+def reconcile_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `reconcile` messages.
+
+    Answers every range whose fingerprint differs from ours, either with our hashes in that range, or split into
+    smaller ranges.
+    """
+    if "user_uuid" in payload.keys():
+        user_uuid = payload["user_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"user_uuid\" required by type \"{message_type}\"."})
+
+    if "fs_uuid" in payload.keys():
+        fs_uuid = payload["fs_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"fs_uuid\" required by type \"{message_type}\"."})
+
+    if "ranges" in payload.keys():
+        ranges = payload["ranges"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"ranges\" required by type \"{message_type}\"."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
+
+        refused = membership_error(fs, uuid.UUID(user_uuid))
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    hashes = sorted(Operation.objects.filter(filesystem=fs).values_list("hash", flat=True))
+
+    reply = []
+    for msg in ranges:
+        bounds = msg["bounds"]
+        own = hashes_in_bounds(hashes, bounds)
+
+        if msg["type"] == "fingerprint":
+            theirs = msg["fingerprint"]
+            if fingerprint(own) == {"xor": theirs["xor"], "count": theirs["count"]}:
+                continue
+        elif set(own) == set(map(list_to_hash, msg["items"])):
+            continue
+
+        if len(own) <= RECONCILE_ITEM_THRESHOLD:
+            reply.append({"type": "items", "bounds": bounds, "items": list(map(hash_to_list, own))})
+        else:
+            reply.extend(split_range(bounds, own))
+
+    return (200, {"code": 0, "ranges": reply})
+
+
+def hashes_in_bounds(hashes: list[str], bounds: dict) -> list[str]:
+    lo = bisect_left(hashes, list_to_hash(bounds["lower"]))
+    hi = len(hashes) if bounds.get("upper") is None else bisect_left(hashes, list_to_hash(bounds["upper"]))
+    return hashes[lo:max(lo, hi)]
+
+
+def fingerprint(hashes: list[str]) -> dict:
+    xor = 0
+    for h in hashes:
+        xor ^= int(h, 16)
+
+    return {"xor": hash_to_list(f"{xor:064x}"), "count": len(hashes)}
+
+
+def split_range(bounds: dict, hashes: list[str]) -> list[dict]:
+    size = -(-len(hashes) // RECONCILE_BRANCHING)
+    chunks = [hashes[i:i+size] for i in range(0, len(hashes), size)]
+
+    result = []
+    for i, chunk in enumerate(chunks):
+        sub = {
+            "lower": bounds["lower"] if i == 0 else hash_to_list(chunk[0]),
+            "upper": hash_to_list(chunks[i+1][0]) if i + 1 < len(chunks) else bounds.get("upper"),
+        }
+
+        if len(chunk) <= RECONCILE_ITEM_THRESHOLD:
+            result.append({"type": "items", "bounds": sub, "items": list(map(hash_to_list, chunk))})
+        else:
+            result.append({"type": "fingerprint", "bounds": sub, "fingerprint": fingerprint(chunk)})
+
+    return result
+
+
+def fetch_ops_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `fetch_ops` messages, returning the contents of many operations at once."""
+    if "user_uuid" in payload.keys():
+        user_uuid = payload["user_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"user_uuid\" required by type \"{message_type}\"."})
+
+    if "fs_uuid" in payload.keys():
+        fs_uuid = payload["fs_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"fs_uuid\" required by type \"{message_type}\"."})
+
+    if "hashes" in payload.keys():
+        hashes = payload["hashes"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"hashes\" required by type \"{message_type}\"."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
+
+        refused = membership_error(fs, uuid.UUID(user_uuid))
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    ops = []
+    for h in hashes:
+        try:
+            with open(operation_file(fs_uuid, list_to_hash(h))) as f:
+                ops.append({"hash": h, "data": f.read()})
+        except FileNotFoundError:
+            return 404, {
+                "code": 6, "err_msg": f"Operation {list_to_hash(h)} doesn't exist."
+            }
+
+    return (200, {"code": 0, "ops": ops})
+
+
+def push_ops_handler(message_type: str, payload: dict, http_method: str) -> tuple[int, dict]:
+    """Handle `push_ops` messages, storing the contents of many operations at once."""
+    if "user_uuid" in payload.keys():
+        user_uuid = payload["user_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"user_uuid\" required by type \"{message_type}\"."})
+
+    if "fs_uuid" in payload.keys():
+        fs_uuid = payload["fs_uuid"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"fs_uuid\" required by type \"{message_type}\"."})
+
+    if "ops" in payload.keys():
+        ops = payload["ops"]
+    else:
+        return (400, {"code": 8, "err_msg": f"Missing field \"ops\" required by type \"{message_type}\"."})
+
+    try:
+        fs = FileSystem.objects.get(pk=uuid.UUID(fs_uuid))
+
+        refused = membership_error(fs, uuid.UUID(user_uuid), write=True)
+        if refused is not None:
+            return refused
+    except ObjectDoesNotExist:
+        return 400, {
+            "code": 4, "err_msg": "FileSystem doesn't exist."
+        }
+
+    for op in ops:
+        hash = list_to_hash(op["hash"])
+        if not verify_operation(hash, op["data"]):
+            return (400, {"code": 8, "err_msg": f"Operation {hash} doesn't match its hash."})
+
+    for op in ops:
+        filename = operation_file(fs_uuid, list_to_hash(op["hash"]))
+        filename.parent.mkdir(parents=True, exist_ok=True)
+
+        # Operations are content addressed, so one already stored is identical.
+        if filename.exists():
+            continue
+
+        with open(filename, "w") as f:
+            f.write(op["data"])
+
+    return (200, {"code": 0})
+
+
+def verify_operation(hash: str, data: str) -> bool:
+    """Check an operation's contents match the hash it's addressed by.
+
+    For encrypted filesystems this is the hash of the ciphertext, so can be checked without the key.
+    """
+    return hashlib.sha256(data.encode()).hexdigest() == hash
+
+
+def operation_file(fs_uuid: str, hash: str) -> Path:
+    """Where an operation's contents are stored, matching the `/operation/` endpoint."""
+    return settings.BASE_DIR / "operations" / str(uuid.UUID(fs_uuid)) / hash
+
+
 def list_to_hash(hash: list[int]) -> str:
     result = ""
     for i in hash:
@@ -309,6 +783,16 @@ RegisterUserHandler = JSONMessageHandler(register_user_handler)
 CheckUserHandler = JSONMessageHandler(check_user_handler)
 RegisterFSHandler = JSONMessageHandler(register_filesystem_handler)
 CheckFSHandler = JSONMessageHandler(check_fs_handler)
+EnrolHandler = JSONMessageHandler(enrol_handler)
+ListReplicasHandler = JSONMessageHandler(list_replicas_handler)
+RemoveReplicaHandler = JSONMessageHandler(remove_replica_handler)
+InviteHandler = JSONMessageHandler(invite_handler)
+ListMembersHandler = JSONMessageHandler(list_members_handler)
+RemoveMemberHandler = JSONMessageHandler(remove_member_handler)
 
 FetchStateHandler = JSONMessageHandler(fetch_state_handler)
 PushStateHandler = JSONMessageHandler(push_state_handler)
+FetchHeadsHandler = JSONMessageHandler(fetch_heads_handler)
+ReconcileHandler = JSONMessageHandler(reconcile_handler)
+FetchOpsHandler = JSONMessageHandler(fetch_ops_handler)
+PushOpsHandler = JSONMessageHandler(push_ops_handler)
@@ -0,0 +1,27 @@
+"""Issue a secret to a user registered before authentication, who otherwise can't authenticate."""
+import uuid
+
+from django.core.management.base import BaseCommand, CommandError
+
+from API.auth import new_secret
+from API.models import User
+
+
+class Command(BaseCommand):
+    help = "Issue a secret to a user registered before authentication, printing it to pass on to them."
+
+    def add_arguments(self, parser):
+        parser.add_argument("user_uuid", type=uuid.UUID)
+        parser.add_argument("--replace", action="store_true", help="Replace the user's existing secret.")
+
+    def handle(self, *args, **options):
+        user = User.objects.filter(uuid=options["user_uuid"]).first()
+        if user is None:
+            raise CommandError("User doesn't exist.")
+        if user.secret_hash and not options["replace"]:
+            raise CommandError("User already has a secret. Use --replace to replace it.")
+
+        secret, user.secret_hash = new_secret(user.uuid)
+        user.save(update_fields=["secret_hash"])
+
+        self.stdout.write(secret)
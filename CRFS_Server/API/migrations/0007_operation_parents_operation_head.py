@@ -0,0 +1,23 @@
+# Generated by Django 5.1.4 on 2026-10-18 12:00
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('API', '0006_remove_operation_id_alter_operation_hash'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='operation',
+            name='parents',
+            field=models.TextField(blank=True, default=''),
+        ),
+        migrations.AddField(
+            model_name='operation',
+            name='head',
+            field=models.BooleanField(default=True),
+        ),
+    ]
@@ -0,0 +1,23 @@
+# Generated by Django 5.1.4 on 2026-10-18 12:00
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('API', '0007_operation_parents_operation_head'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='user',
+            name='secret_hash',
+            field=models.CharField(blank=True, default='', max_length=64),
+        ),
+        migrations.AddField(
+            model_name='replica',
+            name='token_hash',
+            field=models.CharField(blank=True, default='', max_length=64),
+        ),
+    ]
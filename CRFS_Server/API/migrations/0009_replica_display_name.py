@@ -0,0 +1,18 @@
+# Generated by Django 5.1.4 on 2026-10-18 14:00
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('API', '0008_user_secret_hash_replica_token_hash'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='replica',
+            name='display_name',
+            field=models.CharField(max_length=256, null=True),
+        ),
+    ]
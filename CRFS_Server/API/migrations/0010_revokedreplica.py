@@ -0,0 +1,19 @@
+# Generated by Django 5.1.4 on 2026-10-19 10:00
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('API', '0009_replica_display_name'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='RevokedReplica',
+            fields=[
+                ('uuid', models.UUIDField(editable=False, primary_key=True, serialize=False)),
+            ],
+        ),
+    ]
@@ -0,0 +1,18 @@
+# Generated by Django 5.1.4 on 2026-10-18 16:00
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('API', '0010_revokedreplica'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='replica',
+            name='public_key',
+            field=models.CharField(blank=True, default='', max_length=64),
+        ),
+    ]
@@ -0,0 +1,31 @@
+# Generated by Django 5.1.4 on 2026-10-18 18:00
+
+import django.db.models.deletion
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('API', '0011_replica_public_key'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='replica',
+            name='user',
+            field=models.ForeignKey(blank=True, null=True, on_delete=django.db.models.deletion.CASCADE, to='API.user'),
+        ),
+        migrations.CreateModel(
+            name='Membership',
+            fields=[
+                ('id', models.BigAutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('role', models.CharField(choices=[('owner', 'Owner'), ('editor', 'Editor'), ('read_only', 'Read-only')], default='editor', max_length=16)),
+                ('filesystem', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='API.filesystem')),
+                ('user', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='API.user')),
+            ],
+            options={
+                'unique_together': {('user', 'filesystem')},
+            },
+        ),
+    ]
@@ -1,5 +1,14 @@
+import uuid
+from typing import Optional
+
 from django.db import models
 
+# Roles a user may have in a FileSystem. Owners may share it, editors may change it, and read-only users may only sync it.
+OWNER = "owner"
+EDITOR = "editor"
+READ_ONLY = "read_only"
+ROLES = [(OWNER, "Owner"), (EDITOR, "Editor"), (READ_ONLY, "Read-only")]
+
 
 class User(models.Model):
     """A single user, identified by UUID."""
@@ -7,6 +16,8 @@ class User(models.Model):
     uuid: models.Field = models.UUIDField(primary_key=True, editable=False)
     display_name: models.Field = models.CharField(max_length=256, null=True)
     last_seen: models.Field = models.DateTimeField()
+    # Hash of the secret issued on registration. Empty for users registered before authentication.
+    secret_hash: models.Field = models.CharField(max_length=64, default="", blank=True)
 
     def __str__(self) -> str:
         if self.display_name:
@@ -27,6 +38,14 @@ class FileSystem(models.Model):
     last_seen: models.Field = models.DateTimeField()
     opts: models.Field = models.TextField(default="")
 
+    def role_of(self, user_uuid: uuid.UUID) -> Optional[str]:
+        """The role a user has in this FileSystem, or None if it isn't shared with them."""
+        if self.user.uuid == user_uuid:
+            return OWNER
+
+        membership = Membership.objects.filter(filesystem=self, user__uuid=user_uuid).first()
+        return membership.role if membership is not None else None
+
     def __str__(self) -> str:
         if self.user.display_name:
             owner = self.user.display_name
@@ -48,7 +67,26 @@ class Replica(models.Model):
 
     uuid: models.Field = models.UUIDField(primary_key=True, editable=False)
     filesystem: models.Field = models.ForeignKey(FileSystem, on_delete=models.CASCADE, null=False, blank=False)
+    # The user who enrolled the replica. None for replicas enrolled before sharing, which belong to the FileSystem's user.
+    user: models.Field = models.ForeignKey(User, on_delete=models.CASCADE, null=True, blank=True)
+    display_name: models.Field = models.CharField(max_length=256, null=True)
     last_seen: models.Field = models.DateTimeField()
+    # Hash of the token issued on enrolment.
+    token_hash: models.Field = models.CharField(max_length=64, default="", blank=True)
+    # Hex-encoded Ed25519 key the replica signs its operations with.
+    public_key: models.Field = models.CharField(max_length=64, default="", blank=True)
+
+    def __str__(self) -> str:
+        if self.display_name:
+            return f"Replica '{self.display_name}', of {self.filesystem}"
+        else:
+            return f"Unnamed Replica, of {self.filesystem}"
+
+
+class RevokedReplica(models.Model):
+    """A replica which has been removed from its FileSystem, and may not enrol again."""
+
+    uuid: models.Field = models.UUIDField(primary_key=True, editable=False)
 
 
 class Operation(models.Model):
@@ -59,3 +97,24 @@ class Operation(models.Model):
 
     filesystem: models.Field = models.ForeignKey(FileSystem, on_delete=models.CASCADE, null=False, blank=False)
     hash: models.Field = models.CharField(max_length=64, null=False, blank=False, primary_key=True)
+    # Space-separated hashes of the operations this one directly depends on.
+    parents: models.Field = models.TextField(default="", blank=True)
+    # Whether no other operation depends on this one.
+    head: models.Field = models.BooleanField(default=True)
+
+
+class Membership(models.Model):
+    """A user's role in a FileSystem shared with them.
+
+    The FileSystem's own user is always an owner, so has no membership.
+    """
+
+    user: models.Field = models.ForeignKey(User, on_delete=models.CASCADE, null=False, blank=False)
+    filesystem: models.Field = models.ForeignKey(FileSystem, on_delete=models.CASCADE, null=False, blank=False)
+    role: models.Field = models.CharField(max_length=16, choices=ROLES, default=EDITOR)
+
+    class Meta:
+        unique_together = [("user", "filesystem")]
+
+    def __str__(self) -> str:
+        return f"{self.get_role_display()} of {self.filesystem}"
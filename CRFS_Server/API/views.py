@@ -1,5 +1,6 @@
 import json
 from pathlib import Path
+from typing import Optional
 from uuid import UUID
 
 from django.conf import settings
@@ -8,16 +9,21 @@ from django.utils.decorators import method_decorator
 from django.views import View
 from django.views.decorators.csrf import csrf_exempt
 
-from .handlers import (CheckFSHandler, CheckUserHandler, FetchStateHandler,
-                       JSONMessageHandler, PingHandler, PushStateHandler,
-                       RegisterFSHandler, RegisterUserHandler)
+from .auth import Principal, authenticate, authorise, may_access_fs
+from .handlers import (CheckFSHandler, CheckUserHandler, EnrolHandler,
+                       FetchHeadsHandler, FetchOpsHandler, FetchStateHandler,
+                       InviteHandler, JSONMessageHandler, ListMembersHandler,
+                       ListReplicasHandler, PingHandler, PushOpsHandler,
+                       PushStateHandler, ReconcileHandler, RegisterFSHandler,
+                       RegisterUserHandler, RemoveMemberHandler,
+                       RemoveReplicaHandler, verify_operation)
 
 
 class GenericJSONView(View):
     """Generic view class for JSON API views."""
 
-    def json_handler(self, request_data: dict, http_method: str) -> tuple[int, dict]:
-        """Handler for JSON requests."""
+    def json_handler(self, request_data: dict, http_method: str, principal: Optional[Principal]) -> tuple[int, dict]:
+        """Handler for JSON requests, sent by `principal` if authenticated."""
         raise NotImplementedError
 
     def get(self, request: HttpRequest) -> HttpResponse:
@@ -27,7 +33,7 @@ class GenericJSONView(View):
         except json.decoder.JSONDecodeError:
             return JsonResponse({"code": 8, "err_msg": "Unable to decode JSON."}, status=400)
 
-        code, response_data = self.json_handler(request_data, "GET")
+        code, response_data = self.json_handler(request_data, "GET", authenticate(request))
         return JsonResponse(response_data, status=code)
 
     def post(self, request: HttpRequest) -> HttpResponse:
@@ -37,7 +43,7 @@ class GenericJSONView(View):
         except json.decoder.JSONDecodeError:
             return JsonResponse({"code": 8, "err_msg": "Unable to decode JSON."}, status=400)
 
-        code, response_data = self.json_handler(request_data, "POST")
+        code, response_data = self.json_handler(request_data, "POST", authenticate(request))
         return JsonResponse(response_data, status=code)
 
 
@@ -49,7 +55,7 @@ class JSONMessageView(GenericJSONView):
     This class can read said field and call one of a number of predefined callbacks as appropriate.
     """
 
-    def json_handler(self, request_data: dict, http_method: str) -> tuple[int, dict]:
+    def json_handler(self, request_data: dict, http_method: str, principal: Optional[Principal]) -> tuple[int, dict]:
         """Handler for JSON requests."""
         HANDLERS: dict[str, JSONMessageHandler] = {
             "ping": PingHandler,
@@ -57,8 +63,18 @@ class JSONMessageView(GenericJSONView):
             "check_user": CheckUserHandler,
             "register_fs": RegisterFSHandler,
             "check_fs": CheckFSHandler,
+            "enrol": EnrolHandler,
+            "list_replicas": ListReplicasHandler,
+            "remove_replica": RemoveReplicaHandler,
+            "invite": InviteHandler,
+            "list_members": ListMembersHandler,
+            "remove_member": RemoveMemberHandler,
             "fetch_state": FetchStateHandler,
             "push_state": PushStateHandler,
+            "fetch_heads": FetchHeadsHandler,
+            "reconcile": ReconcileHandler,
+            "fetch_ops": FetchOpsHandler,
+            "push_ops": PushOpsHandler,
         }
 
         try:
@@ -69,6 +85,15 @@ class JSONMessageView(GenericJSONView):
         if type not in HANDLERS.keys():
             return (400, {"code": 8, "err_msg": f"Unrecognised type <{type}>."})
 
+        payload = request_data.get("payload")
+        refused = authorise(type, payload if isinstance(payload, dict) else {}, principal)
+        if refused is not None:
+            err_code, err_msg = refused
+            http_code = 401 if err_code == 9 else 400
+            return JSONMessageHandler(
+                lambda *_: (http_code, {"code": err_code, "err_msg": err_msg})
+            ).handle_message(request_data, http_method)
+
         code, response_data = HANDLERS[type].handle_message(request_data, http_method)
         return (code, response_data)
 
@@ -76,6 +101,9 @@ class JSONMessageView(GenericJSONView):
 @csrf_exempt
 def operation(request: HttpRequest, fs: UUID, hash: str) -> HttpResponse:
     """Handle /operation/*."""
+    if not may_access_fs(authenticate(request), fs, write=request.method == "PUT"):
+        return HttpResponse(status=401)
+
     ops_dir: Path = settings.BASE_DIR / "operations"
     fs_dir = ops_dir / str(fs)
 
@@ -92,9 +120,13 @@ def operation(request: HttpRequest, fs: UUID, hash: str) -> HttpResponse:
         except FileNotFoundError:
             return HttpResponse(status=404)
     elif request.method == "PUT":
+        data = request.body.decode()
+        if not verify_operation(hash, data):
+            return HttpResponse(status=400, content="Operation doesn't match its hash.")
+
         try:
             with open(filename, "w") as f:
-                f.write(request.body.decode())
+                f.write(data)
 
             return HttpResponse(status=200)
         except Exception as e: